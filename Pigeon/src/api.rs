@@ -2,15 +2,23 @@ use std::path::{Path, PathBuf};
 use std::net::SocketAddr;
 
 use crate::config::{self, AppConfig};
-use crate::storage::contacts::{Contact, ContactStore};
-use crate::storage::queue::{DeadLetterRecord, MessageQueue, QueuedMessage};
+use crate::contact_card::ContactCard;
+use crate::discovery::{self, NearbyPeer};
+use crate::storage::contacts::{BlockEntry, BlockTarget, Contact, ContactPolicy, ContactStore};
+use crate::storage::queue::{
+    DeadLetterRecord, MessageQueue, QuarantinedMessage, QueuedMessage, SentMessage,
+};
 use crate::ops;
-use crate::settings::{self, AccessibilitySettings, AppState};
+use crate::settings::{self, AccessibilitySettings, AppState, Profile};
 use uuid::Uuid;
 use tokio::sync::mpsc;
 use tokio::task::JoinHandle;
 use tokio::time::{sleep, Duration};
 
+// Entry names inside an identity export archive
+const IDENTITY_ENTRY: &str = "identity.bin";
+const CONTACTS_ENTRY: &str = "contacts";
+
 /// Thin facade exposing core operations for GUI or other frontends.
 #[allow(dead_code)]
 pub struct Core {
@@ -42,6 +50,10 @@ impl Core {
         self.cfg.data_dir.join("queue_db")
     }
 
+    fn queue(&self) -> Result<MessageQueue, crate::error::Error> {
+        MessageQueue::open_in_dir(&self.cfg.data_dir).map_err(crate::error::Error::Storage)
+    }
+
     // Contacts
     pub fn contacts_add(
         &self,
@@ -96,14 +108,215 @@ impl Core {
             .map_err(crate::error::Error::Storage)
     }
 
+    /// Bind a contact to the libp2p PeerId (see `IdentityPreview::libp2p_peer_id`).
+    pub fn contacts_set_peer_id(
+        &self,
+        id: u64,
+        peer_id: &str,
+    ) -> Result<Contact, crate::error::Error> {
+        let store = ContactStore::open_in_dir(&self.cfg.data_dir)
+            .map_err(crate::error::Error::Storage)?;
+        store
+            .set_peer_id(id, peer_id)
+            .map_err(crate::error::Error::Storage)
+    }
+
+    /// Safety number to compare with the contact over a trusted channel.
+    pub fn contacts_safety_number(&self, id: u64) -> Result<String, crate::error::Error> {
+        let contact = self.contacts_get(id)?.ok_or_else(|| {
+            crate::error::Error::Storage(crate::storage::Error::ContactNotFound(id.to_string()))
+        })?;
+        let me = crate::identity::Identity::load_or_generate(&self.cfg.data_dir)?;
+        Ok(crate::crypto::safety_number(&me.sodium_box_pk.0, &contact.public_key))
+    }
+
+    /// Record that the safety number was (or was not) confirmed with the contact.
+    pub fn contacts_set_verified(
+        &self,
+        id: u64,
+        verified: bool,
+    ) -> Result<Contact, crate::error::Error> {
+        let store = ContactStore::open_in_dir(&self.cfg.data_dir)
+            .map_err(crate::error::Error::Storage)?;
+        store
+            .set_verified(id, verified)
+            .map_err(crate::error::Error::Storage)
+    }
+
+    /// Allow, mute or block a contact's inbound messages.
+    pub fn contacts_set_policy(
+        &self,
+        id: u64,
+        policy: ContactPolicy,
+    ) -> Result<Contact, crate::error::Error> {
+        let store = ContactStore::open_in_dir(&self.cfg.data_dir)
+            .map_err(crate::error::Error::Storage)?;
+        store
+            .set_policy(id, policy)
+            .map_err(crate::error::Error::Storage)
+    }
+
+    pub fn contacts_policy(&self, id: u64) -> Result<ContactPolicy, crate::error::Error> {
+        let store = ContactStore::open_in_dir(&self.cfg.data_dir)
+            .map_err(crate::error::Error::Storage)?;
+        store.policy(id).map_err(crate::error::Error::Storage)
+    }
+
+    /// Refuse messages from a PeerId or key; false if it was already blocked.
+    pub fn blocklist_add(&self, target: BlockTarget) -> Result<bool, crate::error::Error> {
+        let store = ContactStore::open_in_dir(&self.cfg.data_dir)
+            .map_err(crate::error::Error::Storage)?;
+        store.block(target).map_err(crate::error::Error::Storage)
+    }
+
+    pub fn blocklist_remove(&self, target: &BlockTarget) -> Result<bool, crate::error::Error> {
+        let store = ContactStore::open_in_dir(&self.cfg.data_dir)
+            .map_err(crate::error::Error::Storage)?;
+        store.unblock(target).map_err(crate::error::Error::Storage)
+    }
+
+    pub fn blocklist(&self) -> Result<Vec<BlockEntry>, crate::error::Error> {
+        let store = ContactStore::open_in_dir(&self.cfg.data_dir)
+            .map_err(crate::error::Error::Storage)?;
+        store.blocklist().map_err(crate::error::Error::Storage)
+    }
+
+    /// Add (or refresh) a contact from a signed `pigeon://` contact card.
+    pub fn contacts_import_card(&self, card: &str) -> Result<Contact, crate::error::Error> {
+        let card = ContactCard::from_uri(card)?;
+        let addr = card
+            .dial_addr()
+            .ok_or_else(|| crate::error::Error::Config("contact card has no address".into()))?;
+        let pubkey_hex = hex::encode(&card.box_public_key);
+        let peer_id = card
+            .peer_id
+            .clone()
+            .or_else(|| discovery::peer_id_from_addr(&addr).map(str::to_string));
+        let store = ContactStore::open_in_dir(&self.cfg.data_dir)
+            .map_err(crate::error::Error::Storage)?;
+        let existing = match &peer_id {
+            Some(peer) => store
+                .find_by_peer_id(peer)
+                .map_err(crate::error::Error::Storage)?,
+            None => None,
+        };
+        if let Some(c) = existing {
+            // Re-importing a peer's card refreshes its address; different keys are staged as a
+            // key change for the user to accept
+            store
+                .update_addr(c.id, &addr)
+                .map_err(crate::error::Error::Storage)?;
+            return store
+                .observe_keys(c.id, &card.box_public_key, Some(&card.sign_public_key))
+                .and_then(|c| store.set_devices(c.id, card.other_devices()))
+                .map_err(crate::error::Error::Storage);
+        }
+        let contact = store
+            .add(&card.name, &addr, &pubkey_hex)
+            .map_err(crate::error::Error::Storage)?;
+        if let Some(peer) = &peer_id {
+            store
+                .set_peer_id(contact.id, peer)
+                .map_err(crate::error::Error::Storage)?;
+        }
+        store
+            .set_sign_key(contact.id, &card.sign_public_key)
+            .and_then(|c| store.set_devices(c.id, card.other_devices()))
+            .map_err(crate::error::Error::Storage)
+    }
+
+    /// Contacts whose keys changed since they were pinned; sending to them is held.
+    pub fn contacts_key_changes(&self) -> Result<Vec<Contact>, crate::error::Error> {
+        let store = ContactStore::open_in_dir(&self.cfg.data_dir)
+            .map_err(crate::error::Error::Storage)?;
+        store
+            .pending_key_changes()
+            .map_err(crate::error::Error::Storage)
+    }
+
+    /// Accept a contact's new keys; the old ones move to its key history.
+    pub fn contacts_accept_key_change(&self, id: u64) -> Result<Contact, crate::error::Error> {
+        let store = ContactStore::open_in_dir(&self.cfg.data_dir)
+            .map_err(crate::error::Error::Storage)?;
+        let contact = store
+            .accept_key_change(id)
+            .map_err(crate::error::Error::Storage)?;
+        // Sessions were built on the old identity key
+        crate::session::reset(&self.cfg.data_dir, id)?;
+        Ok(contact)
+    }
+
+    /// Reject a contact's new keys and keep the pinned ones.
+    pub fn contacts_reject_key_change(&self, id: u64) -> Result<Contact, crate::error::Error> {
+        let store = ContactStore::open_in_dir(&self.cfg.data_dir)
+            .map_err(crate::error::Error::Storage)?;
+        store
+            .reject_key_change(id)
+            .map_err(crate::error::Error::Storage)
+    }
+
+    /// Verify and keep a prekey bundle fetched from a contact; the next session uses it.
+    pub fn contacts_store_prekey_bundle(
+        &self,
+        id: u64,
+        bundle: &[u8],
+    ) -> Result<crate::session::x3dh::PreKeyBundle, crate::error::Error> {
+        let contact = self.contacts_get(id)?.ok_or_else(|| {
+            crate::error::Error::Storage(crate::storage::Error::ContactNotFound(id.to_string()))
+        })?;
+        crate::session::prekeys::accept_bundle(&self.cfg.data_dir, &contact, bundle)
+    }
+
+    /// Our signed prekey and one-time prekey counts, creating prekeys on first use.
+    pub fn prekeys_status(
+        &self,
+    ) -> Result<crate::session::prekeys::PreKeyStatus, crate::error::Error> {
+        let me = crate::identity::Identity::load_or_generate(&self.cfg.data_dir)?;
+        crate::session::prekeys::status(&self.cfg.data_dir, &me)
+    }
+
+    /// Replace our signed prekey now; returns the new prekey id.
+    pub fn prekeys_rotate(&self) -> Result<u32, crate::error::Error> {
+        let me = crate::identity::Identity::load_or_generate(&self.cfg.data_dir)?;
+        me.rotate_signed_prekey(&self.cfg.data_dir)
+    }
+
+    fn ensure_send_allowed(&self, recipient_id: u64) -> Result<(), crate::error::Error> {
+        match self.contacts_get(recipient_id)? {
+            Some(c) if c.send_blocked() => Err(key_change_pending(&c)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Peers discovered over mDNS by a running listener that are not contacts yet.
+    pub fn nearby_peers(&self) -> Result<Vec<NearbyPeer>, crate::error::Error> {
+        discovery::load_nearby_peers(&self.cfg.data_dir).map_err(crate::error::Error::Io)
+    }
+
+    /// Turn a nearby peer into a contact using its last seen address.
+    pub fn add_nearby_peer(
+        &self,
+        peer_id: &str,
+        name: &str,
+        public_key_hex: &str,
+    ) -> Result<Contact, crate::error::Error> {
+        let peer = self
+            .nearby_peers()?
+            .into_iter()
+            .find(|p| p.peer_id == peer_id)
+            .ok_or_else(|| crate::error::Error::Config(format!("no nearby peer {peer_id}")))?;
+        let addr = peer
+            .dial_addr()
+            .ok_or_else(|| crate::error::Error::Config(format!("no address for {peer_id}")))?;
+        let contact = self.contacts_add(name, &addr, public_key_hex)?;
+        discovery::forget_nearby_peer(&self.cfg.data_dir, peer_id)?;
+        Ok(contact)
+    }
+
     // Messaging
     pub async fn compose(&self, recipient_id: u64, body: &str) -> Result<Uuid, crate::error::Error> {
-        crate::messaging::compose::compose_message(
-            recipient_id,
-            body,
-            self.queue_path().to_str().unwrap_or("queue_db"),
-        )
-        .await
+        self.ensure_send_allowed(recipient_id)?;
+        crate::messaging::compose::compose_on(&self.queue()?, recipient_id, body)
     }
 
     /// Encrypt immediately and enqueue for sending using local identity as sender.
@@ -128,21 +341,35 @@ impl Core {
         }
         let recipient_pk = sodiumoxide::crypto::box_::PublicKey::from_slice(&pk_bytes)
             .ok_or_else(|| crate::error::Error::Storage(crate::storage::Error::Validation("bad pk".into())))?;
+        self.ensure_send_allowed(recipient_id)?;
         let id = {
             let ident = crate::identity::Identity::load_or_generate(&self.cfg.data_dir)?;
-            crate::messaging::send::send_now(
-                self.queue_path().to_str().unwrap_or("queue_db"),
-                &ident.sodium_box_sk,
-                &recipient_pk,
-                recipient_id,
-                body.as_bytes(),
-            )
-            .await?
+            match self.contacts_get(recipient_id)? {
+                // Contacts get a ratchet session message; until one can start, the send loop
+                // holds it and fetches their prekey bundle (see `send::encode_for_contact`)
+                Some(contact) if contact.public_key == recipient_pk.0 => {
+                    crate::messaging::send::send_to_contact(
+                        self.queue_path().to_str().unwrap_or("queue_db"),
+                        &self.cfg.data_dir,
+                        &ident,
+                        &contact,
+                        body.as_bytes(),
+                    )?
+                }
+                _ => {
+                    crate::messaging::send::send_now_on(
+                        &self.queue()?,
+                        &ident.sodium_box_sk,
+                        &recipient_pk,
+                        recipient_id,
+                        body.as_bytes(),
+                    )?
+                }
+            }
         };
         if high_priority {
             // Move message to high lane by re-enqueueing with priority=0
-            let q = MessageQueue::new(self.queue_path().to_str().unwrap_or("queue_db"))
-                .map_err(crate::error::Error::Storage)?;
+            let q = self.queue()?;
             if let Some(mut msg) = q
                 .dequeue()
                 .map_err(crate::error::Error::Storage)?
@@ -157,8 +384,7 @@ impl Core {
 
     // Inbox helpers
     pub fn inbox_list(&self) -> Result<Vec<(Uuid, Vec<u8>)>, crate::error::Error> {
-        let q = MessageQueue::new(self.queue_path().to_str().unwrap_or("queue_db"))
-            .map_err(crate::error::Error::Storage)?;
+        let q = self.queue()?;
         q.list_inbox().map_err(crate::error::Error::Storage)
     }
 
@@ -174,8 +400,7 @@ impl Core {
     }
 
     pub fn inbox_show(&self, id: Uuid) -> Result<Option<Vec<u8>>, crate::error::Error> {
-        let q = MessageQueue::new(self.queue_path().to_str().unwrap_or("queue_db"))
-            .map_err(crate::error::Error::Storage)?;
+        let q = self.queue()?;
         q.get_inbox(id).map_err(crate::error::Error::Storage)
     }
 
@@ -210,22 +435,40 @@ impl Core {
             .map_err(|e| crate::error::Error::Storage(crate::storage::Error::Serialization(e.to_string())))
     }
 
+    // Quarantine (messages from peers that are not contacts)
+    pub fn quarantine_list(&self) -> Result<Vec<QuarantinedMessage>, crate::error::Error> {
+        let q = self.queue()?;
+        q.list_quarantine().map_err(crate::error::Error::Storage)
+    }
+
+    /// Decrypt a quarantined message into the inbox once its sender has been added as a contact.
+    pub fn quarantine_release(&self, id: Uuid) -> Result<(), crate::error::Error> {
+        let ident = crate::identity::Identity::load_or_generate(&self.cfg.data_dir)?;
+        crate::messaging::inbound::release_quarantined(&self.cfg.data_dir, &ident, id)?;
+        Ok(())
+    }
+
+    pub fn quarantine_discard(&self, id: Uuid) -> Result<bool, crate::error::Error> {
+        let q = self.queue()?;
+        Ok(q
+            .take_quarantine(id)
+            .map_err(crate::error::Error::Storage)?
+            .is_some())
+    }
+
     // Queue views for GUI
     pub fn queue_list_pending(&self) -> Result<Vec<QueuedMessage>, crate::error::Error> {
-        let q = MessageQueue::new(self.queue_path().to_str().unwrap_or("queue_db"))
-            .map_err(crate::error::Error::Storage)?;
+        let q = self.queue()?;
         q.get_pending_messages().map_err(crate::error::Error::Storage)
     }
 
     pub fn queue_list_dead_letters(&self) -> Result<Vec<DeadLetterRecord>, crate::error::Error> {
-        let q = MessageQueue::new(self.queue_path().to_str().unwrap_or("queue_db"))
-            .map_err(crate::error::Error::Storage)?;
+        let q = self.queue()?;
         q.list_dead_letters().map_err(crate::error::Error::Storage)
     }
 
     pub fn queue_stats(&self) -> Result<QueueStats, crate::error::Error> {
-        let q = MessageQueue::new(self.queue_path().to_str().unwrap_or("queue_db"))
-            .map_err(crate::error::Error::Storage)?;
+        let q = self.queue()?;
         Ok(QueueStats {
             pending: q.len() as u64,
             inbox: q.inbox_len() as u64,
@@ -235,8 +478,7 @@ impl Core {
 
     /// UI-friendly summaries of pending queue items.
     pub fn queue_list_pending_summaries(&self) -> Result<Vec<QueueItemSummary>, crate::error::Error> {
-        let q = MessageQueue::new(self.queue_path().to_str().unwrap_or("queue_db"))
-            .map_err(crate::error::Error::Storage)?;
+        let q = self.queue()?;
         let items = q
             .get_pending_messages()
             .map_err(crate::error::Error::Storage)?;
@@ -245,12 +487,12 @@ impl Core {
 
     /// Start a lightweight inbox watcher that emits snapshots on change.
     pub fn watch_inbox(&self, interval_ms: u64) -> InboxWatcher {
-        let queue_path = self.queue_path();
+        let data_dir = self.cfg.data_dir.clone();
         let (tx, rx) = mpsc::channel(8);
         let handle: JoinHandle<()> = tokio::spawn(async move {
             let mut last_len: usize = 0;
             loop {
-                let q = match MessageQueue::new(queue_path.to_str().unwrap_or("queue_db")) {
+                let q = match MessageQueue::open_in_dir(&data_dir) {
                     Ok(v) => v,
                     Err(_) => {
                         sleep(Duration::from_millis(interval_ms)).await;
@@ -283,12 +525,8 @@ impl Core {
         let sender_pk = sodiumoxide::crypto::box_::PublicKey::from_slice(&sender_pk_bytes)
             .ok_or_else(|| crate::error::Error::Storage(crate::storage::Error::Validation("bad pk".into())))?;
         let ident = crate::identity::Identity::load_or_generate(&self.cfg.data_dir)?;
-        crate::messaging::receive::receive_and_ack(
-            self.queue_path().to_str().unwrap_or("queue_db"),
-            &sender_pk,
-            &ident.sodium_box_sk,
-        )
-        .await
+        let q = self.queue()?;
+        crate::messaging::receive::receive_and_ack_on(&q, &sender_pk, &ident.sodium_box_sk)
     }
 
     /// Start the ops metrics HTTP server on the given address.
@@ -327,25 +565,161 @@ impl Core {
         Ok(IdentityPreview::from_identity(&id))
     }
 
-    /// Import a previously exported identity file.
+    /// Import a plaintext `identity.bin` copied from another install. Refuses to replace an
+    /// existing identity; encrypted exports go through `import_identity`.
     pub fn import_identity_from_file<P: AsRef<std::path::Path>>(
         &self,
         path: P,
     ) -> Result<(), crate::error::Error> {
-        let dest = crate::identity::Identity::file_path(&self.cfg.data_dir);
-        std::fs::create_dir_all(self.cfg.data_dir.as_path())?;
-        std::fs::copy(path, &dest)?;
-        // Validate loadable
-        let _ = crate::identity::Identity::load_or_generate(&self.cfg.data_dir)?;
+        let bytes = std::fs::read(path)?;
+        if crate::archive::is_archive(&bytes) {
+            return Err(crate::error::Error::Config(
+                "this is an encrypted identity export; import it with its passphrase".into(),
+            ));
+        }
+        crate::identity::Identity::install_bytes(&self.cfg.data_dir, &bytes, false)?;
+        Ok(())
+    }
+
+    /// Write the identity (and optionally all contacts) to a passphrase-encrypted archive.
+    pub fn export_identity(
+        &self,
+        out_path: &Path,
+        passphrase: &str,
+        include_contacts: bool,
+    ) -> Result<(), crate::error::Error> {
+        let mut archive = crate::archive::Archive::new(crate::archive::ArchiveKind::Identity);
+        archive.add(
+            IDENTITY_ENTRY,
+            crate::identity::Identity::export_bytes(&self.cfg.data_dir)?,
+        );
+        if include_contacts {
+            let contacts = self.contacts_list()?;
+            archive.add(
+                CONTACTS_ENTRY,
+                bincode::serialize(&contacts)
+                    .map_err(|e| crate::error::Error::Serialization(e.to_string()))?,
+            );
+        }
+        std::fs::write(out_path, archive.seal(passphrase)?)?;
         Ok(())
     }
 
+    /// Restore an identity exported with `export_identity`. An existing identity is only
+    /// replaced when `overwrite` is set; bundled contacts are merged, skipping duplicates.
+    pub fn import_identity(
+        &self,
+        path: &Path,
+        passphrase: &str,
+        overwrite: bool,
+    ) -> Result<IdentityImport, crate::error::Error> {
+        let archive = crate::archive::Archive::open(&std::fs::read(path)?, passphrase)?;
+        if archive.kind != crate::archive::ArchiveKind::Identity {
+            return Err(crate::error::Error::Config(
+                "archive is not an identity export".into(),
+            ));
+        }
+        let bytes = archive.get(IDENTITY_ENTRY).ok_or_else(|| {
+            crate::error::Error::Config("identity export has no identity".into())
+        })?;
+        let previous = if crate::identity::Identity::file_path(&self.cfg.data_dir).exists() {
+            Some(crate::identity::Identity::load_or_generate(&self.cfg.data_dir)?)
+        } else {
+            None
+        };
+        let id = crate::identity::Identity::install_bytes(&self.cfg.data_dir, bytes, overwrite)?;
+        if previous.is_some_and(|p| p.sodium_box_pk != id.sodium_box_pk) {
+            // Sessions and prekeys were bound to the identity we just replaced
+            crate::session::forget_all(&self.cfg.data_dir)?;
+        }
+
+        let mut contacts_added = 0;
+        if let Some(bytes) = archive.get(CONTACTS_ENTRY) {
+            let contacts: Vec<Contact> = bincode::deserialize(bytes)
+                .map_err(|e| crate::error::Error::Serialization(e.to_string()))?;
+            let store = ContactStore::open_in_dir(&self.cfg.data_dir)
+                .map_err(crate::error::Error::Storage)?;
+            for c in contacts {
+                if store.import(c).map_err(crate::error::Error::Storage)?.is_some() {
+                    contacts_added += 1;
+                }
+            }
+        }
+        Ok(IdentityImport {
+            preview: IdentityPreview::from_identity(&id),
+            contacts_added,
+        })
+    }
+
+    /// Replace every key in the identity (e.g. after a suspected compromise) and queue a
+    /// key-transition announcement, signed with the old signing key, for every contact.
+    pub fn rotate_identity(&self) -> Result<IdentityRotation, crate::error::Error> {
+        let contacts = ContactStore::open_in_dir(&self.cfg.data_dir)
+            .and_then(|s| s.list())
+            .map_err(crate::error::Error::Storage)?;
+        let (id, transition) = crate::rotation::rotate_identity(&self.cfg.data_dir)?;
+        let payload = bincode::serialize(&transition)
+            .map_err(|e| crate::error::Error::Serialization(e.to_string()))?;
+        let q = self.queue()?;
+        for c in &contacts {
+            crate::messaging::send::enqueue_on(&q, c.id, None, payload.clone(), 0)?;
+        }
+        Ok(IdentityRotation {
+            preview: IdentityPreview::from_identity(&id),
+            announced: contacts.len(),
+        })
+    }
+
+    /// Back up the whole data dir (identity, key envelope, settings, contacts, queue, inbox,
+    /// sessions and prekeys) into a passphrase-encrypted archive.
+    pub fn backup_create(
+        &self,
+        out_path: &Path,
+        passphrase: &str,
+    ) -> Result<crate::backup::BackupManifest, crate::error::Error> {
+        crate::backup::create(&self.cfg.data_dir, out_path, passphrase)
+    }
+
+    /// Restore a backup; an existing identity is only replaced when `overwrite` is set.
+    pub fn backup_restore(
+        &self,
+        path: &Path,
+        passphrase: &str,
+        overwrite: bool,
+    ) -> Result<crate::backup::BackupManifest, crate::error::Error> {
+        crate::backup::restore(&self.cfg.data_dir, path, passphrase, overwrite)
+    }
+
     /// Set a passphrase to protect the at-rest key file.
     pub fn set_passphrase(&self, passphrase: &str) -> Result<(), crate::error::Error> {
-        crate::storage::at_rest::set_passphrase_and_seal(&self.cfg.data_dir, passphrase)
+        crate::storage::at_rest::set_passphrase_with_params(
+            &self.cfg.data_dir,
+            passphrase,
+            &self.cfg.kdf,
+        )
+        .map_err(crate::error::Error::Storage)
+    }
+
+    /// Re-wrap the at-rest key under a new passphrase with the configured KDF costs. The
+    /// store must be unlocked; stored records are not touched.
+    pub fn change_passphrase(&self, new_passphrase: &str) -> Result<(), crate::error::Error> {
+        crate::storage::at_rest::change_passphrase(&self.cfg.data_dir, new_passphrase, &self.cfg.kdf)
+            .map_err(crate::error::Error::Storage)
+    }
+
+    /// Drop the passphrase and keep the at-rest key in a plain file again.
+    pub fn remove_passphrase(&self) -> Result<(), crate::error::Error> {
+        crate::storage::at_rest::remove_passphrase(&self.cfg.data_dir)
             .map_err(crate::error::Error::Storage)
     }
 
+    /// KDF costs recorded in the key file; `None` when no passphrase is set.
+    pub fn kdf_params(
+        &self,
+    ) -> Result<Option<crate::storage::at_rest::KdfParams>, crate::error::Error> {
+        crate::storage::at_rest::kdf_params(&self.cfg.data_dir).map_err(crate::error::Error::Storage)
+    }
+
     /// Unlock the at-rest key with the passphrase.
     pub fn unlock(&self, passphrase: &str) -> Result<(), crate::error::Error> {
         let _ = crate::storage::at_rest::unlock_with_passphrase(&self.cfg.data_dir, passphrase)
@@ -353,12 +727,315 @@ impl Core {
         Ok(())
     }
 
+    /// Keep this process's unlock in the OS keyring for `ttl_secs` (the configured
+    /// `keyring_timeout_secs` when `None`), so later runs start unlocked.
+    pub fn remember_unlock(&self, ttl_secs: Option<u64>) -> Result<u64, crate::error::Error> {
+        let secs = ttl_secs.unwrap_or(self.cfg.keyring_timeout_secs);
+        crate::storage::at_rest::remember_in_keyring(
+            &self.cfg.data_dir,
+            std::time::Duration::from_secs(secs),
+        )
+        .map_err(crate::error::Error::Storage)?;
+        Ok(secs)
+    }
+
+    /// Whether `unlock` should be kept in the OS keyring (the `use_keyring` setting).
+    pub fn keyring_enabled(&self) -> bool {
+        self.cfg.use_keyring
+    }
+
+    /// Forget the unlocked key in this process and in the OS keyring. Returns whether the
+    /// keyring held an unlock.
+    pub fn lock(&self) -> Result<bool, crate::error::Error> {
+        crate::storage::at_rest::lock(&self.cfg.data_dir).map_err(crate::error::Error::Storage)
+    }
+
+    /// Whether a passphrase is set and the at-rest key cannot be opened without it (no
+    /// unlock in this process, the keyring or PIGEON_PASSPHRASE). Checking does not hold
+    /// off the auto-lock.
+    pub fn is_locked(&self) -> bool {
+        self.cfg
+            .data_dir
+            .join(crate::storage::at_rest::ENC_KEY_FILE)
+            .exists()
+            && !crate::storage::at_rest::is_unlocked(&self.cfg.data_dir)
+            && crate::storage::at_rest::AtRestKey::load_or_create(&self.cfg.data_dir).is_err()
+    }
+
+    /// Lock the at-rest key after `auto_lock_secs` without use (off when 0). For
+    /// long-running interactive processes such as the GUI.
+    pub fn enable_auto_lock(&self) {
+        let idle = (self.cfg.auto_lock_secs > 0)
+            .then(|| std::time::Duration::from_secs(self.cfg.auto_lock_secs));
+        crate::storage::at_rest::set_auto_lock(&self.cfg.data_dir, idle);
+    }
+
+    /// Seconds until another unlock attempt is allowed after repeated wrong passphrases.
+    pub fn unlock_retry_after(&self) -> Option<u64> {
+        crate::storage::at_rest::unlock_retry_after(&self.cfg.data_dir)
+    }
+
     /// Rotate at-rest key and seal with passphrase
     pub fn rotate_at_rest_key(&self, passphrase: &str) -> Result<(), crate::error::Error> {
-        crate::storage::at_rest::rotate_key_and_seal(&self.cfg.data_dir, passphrase)
+        self.rotate_at_rest_key_with_progress(passphrase, |_| {})
+            .map(|_| ())
+    }
+
+    /// Rotate the at-rest key, re-sealing stored records and reporting progress per
+    /// database. Returns how many records moved to the new key.
+    pub fn rotate_at_rest_key_with_progress(
+        &self,
+        passphrase: &str,
+        progress: impl FnMut(&crate::storage::rekey::RekeyProgress),
+    ) -> Result<usize, crate::error::Error> {
+        crate::storage::at_rest::rotate_key_with_progress(
+            &self.cfg.data_dir,
+            passphrase,
+            &self.cfg.kdf,
+            progress,
+        )
+        .map_err(crate::error::Error::Storage)
+    }
+
+    /// Finish an interrupted rotation: re-seal what is left under the old key, then drop it.
+    pub fn resume_at_rest_rotation(
+        &self,
+        progress: impl FnMut(&crate::storage::rekey::RekeyProgress),
+    ) -> Result<usize, crate::error::Error> {
+        crate::storage::at_rest::resume_rotation(&self.cfg.data_dir, progress)
             .map_err(crate::error::Error::Storage)
     }
 
+    /// Schema versions of the stored databases and the `identity.bin` format, without
+    /// upgrading anything.
+    pub fn storage_status(&self) -> Result<StorageStatus, crate::error::Error> {
+        let databases = crate::storage::schema::status(&self.cfg.data_dir)
+            .map_err(crate::error::Error::Storage)?;
+        let identity_format = if self.is_locked() {
+            None
+        } else {
+            crate::identity::Identity::stored_format(&self.cfg.data_dir)?
+        };
+        Ok(StorageStatus {
+            databases,
+            identity_format,
+        })
+    }
+
+    /// Move the databases from one storage backend to another, reporting
+    /// progress per database. Returns how many records were copied.
+    pub fn storage_migrate(
+        &self,
+        from: crate::storage::backend::BackendKind,
+        to: crate::storage::backend::BackendKind,
+        mut progress: impl FnMut(&crate::storage::migrate::MigrateProgress),
+    ) -> Result<usize, crate::error::Error> {
+        crate::storage::migrate::migrate(&self.cfg.data_dir, from, to, &mut progress)
+            .map_err(crate::error::Error::Storage)
+    }
+
+    /// Whether an at-rest key rotation was interrupted and still keeps the old key around.
+    pub fn at_rest_rotation_pending(&self) -> Result<bool, crate::error::Error> {
+        crate::storage::at_rest::rotation_pending(&self.cfg.data_dir)
+            .map_err(crate::error::Error::Storage)
+    }
+
+    /// Signed contact card for the local identity, built from the profile settings.
+    pub fn my_contact_card(&self) -> Result<ContactCard, crate::error::Error> {
+        let id = crate::identity::Identity::load_or_generate(&self.cfg.data_dir)?;
+        let me = self.local_device_info()?;
+        let devices = crate::devices::linked(&self.cfg.data_dir)?
+            .map(|l| l.certs)
+            .unwrap_or_default();
+        ContactCard::new_signed_with_devices(&id, &me.name, me.peer_id, me.addrs, devices)
+    }
+
+    /// Name, PeerId and dialable addresses of this install, as shared in cards and links.
+    fn local_device_info(&self) -> Result<crate::devices::DeviceInfo, crate::error::Error> {
+        let profile = self.get_profile()?;
+        let name = if profile.display_name.trim().is_empty() {
+            "Pigeon user".to_string()
+        } else {
+            profile.display_name.clone()
+        };
+        #[cfg(feature = "network")]
+        let addrs = if profile.advertised_addrs.is_empty() {
+            // A wildcard listen address is not dialable by anyone else
+            self.cfg
+                .listen_addr
+                .iter()
+                .filter(|a| !a.contains("/0.0.0.0/") && !a.contains("/::/"))
+                .cloned()
+                .collect()
+        } else {
+            profile.advertised_addrs
+        };
+        #[cfg(not(feature = "network"))]
+        let addrs = profile.advertised_addrs;
+        #[cfg(feature = "network")]
+        let peer_id = {
+            let id = crate::identity::Identity::load_or_generate(&self.cfg.data_dir)?;
+            Some(libp2p::PeerId::from(id.libp2p.public()).to_string())
+        };
+        #[cfg(not(feature = "network"))]
+        let peer_id = None;
+        Ok(crate::devices::DeviceInfo {
+            name,
+            peer_id,
+            addrs,
+        })
+    }
+
+    // Devices
+    /// On a new device: the signed link request to pass to the primary device.
+    pub fn devices_link_request(
+        &self,
+        device_name: &str,
+    ) -> Result<crate::devices::LinkRequest, crate::error::Error> {
+        let id = crate::identity::Identity::load_or_generate(&self.cfg.data_dir)?;
+        let mut me = self.local_device_info()?;
+        if !device_name.trim().is_empty() {
+            me.name = device_name.trim().to_string();
+        }
+        Ok(crate::devices::LinkRequest::new_signed(&id, me))
+    }
+
+    /// On the primary: certify the device behind a `pigeon-link://` request and return the
+    /// grant to import on it.
+    pub fn devices_authorize(
+        &self,
+        request: &str,
+    ) -> Result<crate::devices::DeviceGrant, crate::error::Error> {
+        let request = crate::devices::LinkRequest::from_uri(request)?;
+        let id = crate::identity::Identity::load_or_generate(&self.cfg.data_dir)?;
+        let own = self.local_device_info()?;
+        crate::devices::authorize(&self.cfg.data_dir, &id, own, &request)
+    }
+
+    /// On a linked device: store the grant produced by `devices_authorize`.
+    pub fn devices_accept(
+        &self,
+        grant: &str,
+    ) -> Result<crate::devices::LinkedDevices, crate::error::Error> {
+        let grant = crate::devices::DeviceGrant::from_uri(grant)?;
+        let id = crate::identity::Identity::load_or_generate(&self.cfg.data_dir)?;
+        crate::devices::accept(&self.cfg.data_dir, &id, &grant)
+    }
+
+    pub fn devices_list(&self) -> Result<Option<crate::devices::LinkedDevices>, crate::error::Error> {
+        crate::devices::linked(&self.cfg.data_dir)
+    }
+
+    /// Revoke a device (on the primary) or leave the account (on a linked device).
+    pub fn devices_unlink(&self, name: &str) -> Result<bool, crate::error::Error> {
+        let id = crate::identity::Identity::load_or_generate(&self.cfg.data_dir)?;
+        crate::devices::unlink(&self.cfg.data_dir, &id, name)
+    }
+
+    /// Messages sent from this account, including copies synced from our other devices.
+    pub fn sent_list(&self) -> Result<Vec<SentMessage>, crate::error::Error> {
+        let q = self.queue()?;
+        q.list_sent().map_err(crate::error::Error::Storage)
+    }
+
+    // Groups
+    /// Create a group with these contacts; we are its admin.
+    pub fn groups_create(
+        &self,
+        name: &str,
+        contact_ids: &[u64],
+    ) -> Result<crate::groups::Group, crate::error::Error> {
+        let contacts = self.contacts_by_ids(contact_ids)?;
+        let id = crate::identity::Identity::load_or_generate(&self.cfg.data_dir)?;
+        crate::groups::create(&self.cfg.data_dir, &id, name, &contacts)
+    }
+
+    pub fn groups_list(&self) -> Result<Vec<crate::groups::Group>, crate::error::Error> {
+        crate::groups::list(&self.cfg.data_dir)
+    }
+
+    /// Find a group by id or, case-insensitively, by name.
+    pub fn groups_find(&self, key: &str) -> Result<crate::groups::Group, crate::error::Error> {
+        let groups = self.groups_list()?;
+        let found = match Uuid::parse_str(key.trim()) {
+            Ok(gid) => groups.into_iter().find(|g| g.id == gid),
+            Err(_) => groups
+                .into_iter()
+                .find(|g| g.name.eq_ignore_ascii_case(key.trim())),
+        };
+        found.ok_or_else(|| crate::error::Error::Config(format!("no such group: {key}")))
+    }
+
+    pub fn groups_add_members(
+        &self,
+        group_id: Uuid,
+        contact_ids: &[u64],
+    ) -> Result<crate::groups::Group, crate::error::Error> {
+        let contacts = self.contacts_by_ids(contact_ids)?;
+        let id = crate::identity::Identity::load_or_generate(&self.cfg.data_dir)?;
+        crate::groups::add_members(&self.cfg.data_dir, &id, group_id, &contacts)
+    }
+
+    /// Remove contacts from a group we administer; the group is rekeyed.
+    pub fn groups_remove_members(
+        &self,
+        group_id: Uuid,
+        contact_ids: &[u64],
+    ) -> Result<crate::groups::Group, crate::error::Error> {
+        let keys = self
+            .contacts_by_ids(contact_ids)?
+            .iter()
+            .filter_map(|c| <[u8; 32]>::try_from(c.public_key.as_slice()).ok())
+            .collect::<Vec<_>>();
+        let id = crate::identity::Identity::load_or_generate(&self.cfg.data_dir)?;
+        crate::groups::remove_members(&self.cfg.data_dir, &id, group_id, &keys)
+    }
+
+    /// Replace our sender key in a group.
+    pub fn groups_rekey(&self, group_id: Uuid) -> Result<crate::groups::Group, crate::error::Error> {
+        let id = crate::identity::Identity::load_or_generate(&self.cfg.data_dir)?;
+        crate::groups::rekey(&self.cfg.data_dir, &id, group_id)
+    }
+
+    /// Encrypt once under our sender key and queue the message for every member.
+    pub fn groups_send(
+        &self,
+        group_id: Uuid,
+        body: &[u8],
+    ) -> Result<crate::groups::GroupMessage, crate::error::Error> {
+        let id = crate::identity::Identity::load_or_generate(&self.cfg.data_dir)?;
+        crate::groups::send(&self.cfg.data_dir, &id, group_id, body)
+    }
+
+    /// A group's messages, oldest first.
+    pub fn groups_thread(
+        &self,
+        group_id: Uuid,
+    ) -> Result<Vec<crate::groups::GroupMessage>, crate::error::Error> {
+        crate::groups::thread(&self.cfg.data_dir, group_id)
+    }
+
+    fn contacts_by_ids(&self, ids: &[u64]) -> Result<Vec<Contact>, crate::error::Error> {
+        ids.iter()
+            .map(|cid| {
+                self.contacts_get(*cid)?.ok_or_else(|| {
+                    crate::error::Error::Storage(crate::storage::Error::ContactNotFound(
+                        cid.to_string(),
+                    ))
+                })
+            })
+            .collect()
+    }
+
+    // Profile (display name and addresses shared in contact cards)
+    pub fn get_profile(&self) -> Result<Profile, crate::error::Error> {
+        settings::load_profile(&self.cfg.data_dir).map_err(crate::error::Error::Io)
+    }
+
+    pub fn set_profile(&self, p: &Profile) -> Result<(), crate::error::Error> {
+        settings::save_profile(&self.cfg.data_dir, p).map_err(crate::error::Error::Io)
+    }
+
     // Accessibility settings
     pub fn get_accessibility(&self) -> Result<AccessibilitySettings, crate::error::Error> {
         settings::load_accessibility_settings(&self.cfg.data_dir).map_err(crate::error::Error::Io)
@@ -396,6 +1073,8 @@ impl Core {
         NetworkSettings {
             listen_addr: self.cfg.listen_addr.clone(),
             enable_mdns: self.cfg.enable_mdns,
+            unknown_peer_policy: self.cfg.unknown_peer_policy,
+            contacts_only: self.cfg.contacts_only,
         }
     }
 
@@ -413,10 +1092,20 @@ impl Core {
         }
         self.cfg.listen_addr = settings.listen_addr;
         self.cfg.enable_mdns = settings.enable_mdns;
+        self.cfg.unknown_peer_policy = settings.unknown_peer_policy;
+        self.cfg.contacts_only = settings.contacts_only;
         Ok(())
     }
 }
 
+/// Error for sends held back by an unaccepted key change.
+pub fn key_change_pending(c: &Contact) -> crate::error::Error {
+    crate::error::Error::Config(format!(
+        "keys of contact {} ({}) changed; accept or reject the change before sending",
+        c.name, c.id
+    ))
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct QueueStats {
     pub pending: u64,
@@ -445,6 +1134,28 @@ impl IdentityPreview {
     }
 }
 
+/// Outcome of `Core::storage_status`.
+#[derive(Debug, Clone)]
+pub struct StorageStatus {
+    pub databases: Vec<crate::storage::schema::DatabaseStatus>,
+    /// Format of `identity.bin`; `None` when there is none yet or the store is locked.
+    pub identity_format: Option<u8>,
+}
+
+/// Outcome of `Core::import_identity`.
+#[derive(Debug, Clone)]
+pub struct IdentityImport {
+    pub preview: IdentityPreview,
+    pub contacts_added: usize,
+}
+
+/// Outcome of `Core::rotate_identity`.
+#[derive(Debug, Clone)]
+pub struct IdentityRotation {
+    pub preview: IdentityPreview,
+    pub announced: usize, // contacts the transition was queued for
+}
+
 #[derive(Debug, Clone)]
 pub struct QueueItemSummary {
 	pub id: Uuid,
@@ -498,6 +1209,8 @@ pub struct InboxSnapshot {
 pub struct NetworkSettings {
 	pub listen_addr: Option<String>,
 	pub enable_mdns: bool,
+	pub unknown_peer_policy: crate::config::UnknownPeerPolicy,
+	pub contacts_only: bool,
 }
 
 
@@ -0,0 +1,201 @@
+//! Passphrase-encrypted archives for identity export and backups.
+//!
+//! Layout: `PGAR` | format version (u8) | Argon2id m_cost, t_cost, p_cost (u32 BE each) |
+//! salt (16) | nonce (24) | secretbox(bincode(`Archive`)). The KDF parameters feed the key,
+//! so tampering with the header fails the MAC like tampering with the body.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sodiumoxide::crypto::secretbox;
+
+pub const ARCHIVE_MAGIC: &[u8; 4] = b"PGAR";
+pub const ARCHIVE_VERSION: u8 = 1;
+
+const SALT_LEN: usize = 16;
+const HEADER_LEN: usize = 4 + 1 + 12 + SALT_LEN + secretbox::NONCEBYTES;
+// Refuse archives asking for more than 1 GiB of KDF memory
+const MAX_M_COST_KIB: u32 = 1 << 20;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Identity,
+    Backup,
+}
+
+/// One named file or snapshot in the archive, with its SHA-256 for integrity checks.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ArchiveEntry {
+    pub name: String,
+    pub sha256: [u8; 32],
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Archive {
+    pub kind: ArchiveKind,
+    pub created_at: u64,
+    pub app_version: String,
+    pub entries: Vec<ArchiveEntry>,
+}
+
+/// Argon2id cost parameters recorded in the archive header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    pub m_cost: u32, // KiB
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            m_cost: argon2::Params::DEFAULT_M_COST,
+            t_cost: argon2::Params::DEFAULT_T_COST,
+            p_cost: argon2::Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl Archive {
+    pub fn new(kind: ArchiveKind) -> Self {
+        Self {
+            kind,
+            created_at: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            app_version: env!("CARGO_PKG_VERSION").to_string(),
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, name: &str, bytes: Vec<u8>) {
+        self.entries.push(ArchiveEntry {
+            name: name.to_string(),
+            sha256: Sha256::digest(&bytes).into(),
+            bytes,
+        });
+    }
+
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.entries
+            .iter()
+            .find(|e| e.name == name)
+            .map(|e| e.bytes.as_slice())
+    }
+
+    /// Encrypt under a key derived from `passphrase` with fresh salt and nonce.
+    pub fn seal(&self, passphrase: &str) -> Result<Vec<u8>, crate::error::Error> {
+        self.seal_with(passphrase, KdfParams::default())
+    }
+
+    pub fn seal_with(
+        &self,
+        passphrase: &str,
+        params: KdfParams,
+    ) -> Result<Vec<u8>, crate::error::Error> {
+        if passphrase.is_empty() {
+            return Err(crate::error::Error::Config(
+                "archive passphrase cannot be empty".into(),
+            ));
+        }
+        let mut salt = [0u8; SALT_LEN];
+        sodiumoxide::randombytes::randombytes_into(&mut salt);
+        let key = derive_key(passphrase, &salt, params)?;
+        let nonce = secretbox::gen_nonce();
+        let body = bincode::serialize(self)
+            .map_err(|e| crate::error::Error::Serialization(e.to_string()))?;
+
+        let mut out = Vec::with_capacity(HEADER_LEN + body.len() + secretbox::MACBYTES);
+        out.extend_from_slice(ARCHIVE_MAGIC);
+        out.push(ARCHIVE_VERSION);
+        out.extend_from_slice(&params.m_cost.to_be_bytes());
+        out.extend_from_slice(&params.t_cost.to_be_bytes());
+        out.extend_from_slice(&params.p_cost.to_be_bytes());
+        out.extend_from_slice(&salt);
+        out.extend_from_slice(nonce.0.as_slice());
+        out.extend_from_slice(&secretbox::seal(&body, &nonce, &key));
+        Ok(out)
+    }
+
+    /// Decrypt and check every entry against its recorded digest.
+    pub fn open(bytes: &[u8], passphrase: &str) -> Result<Self, crate::error::Error> {
+        if !is_archive(bytes) {
+            return Err(crate::error::Error::Config(
+                "not a Pigeon archive".into(),
+            ));
+        }
+        if bytes[4] != ARCHIVE_VERSION {
+            return Err(crate::error::Error::Config(format!(
+                "unsupported archive version {}",
+                bytes[4]
+            )));
+        }
+        if bytes.len() < HEADER_LEN + secretbox::MACBYTES {
+            return Err(crate::error::Error::Config("archive is truncated".into()));
+        }
+        let word = |at: usize| u32::from_be_bytes(bytes[at..at + 4].try_into().expect("4 bytes"));
+        let params = KdfParams {
+            m_cost: word(5),
+            t_cost: word(9),
+            p_cost: word(13),
+        };
+        if params.m_cost > MAX_M_COST_KIB {
+            return Err(crate::error::Error::Config(
+                "archive KDF parameters are out of range".into(),
+            ));
+        }
+        let salt = &bytes[17..17 + SALT_LEN];
+        let mut nonce = [0u8; secretbox::NONCEBYTES];
+        nonce.copy_from_slice(&bytes[17 + SALT_LEN..HEADER_LEN]);
+
+        let key = derive_key(passphrase, salt, params)?;
+        let body = secretbox::open(&bytes[HEADER_LEN..], &secretbox::Nonce(nonce), &key)
+            .map_err(|_| {
+                crate::crypto::Error::Decryption(
+                    "archive does not open with this passphrase (or is corrupted)".into(),
+                )
+            })?;
+        let archive: Self = bincode::deserialize(&body)
+            .map_err(|e| crate::error::Error::Serialization(format!("archive: {e}")))?;
+        for entry in &archive.entries {
+            let digest: [u8; 32] = Sha256::digest(&entry.bytes).into();
+            if digest != entry.sha256 {
+                return Err(crate::crypto::Error::Decryption(format!(
+                    "archive entry {} failed its integrity check",
+                    entry.name
+                ))
+                .into());
+            }
+        }
+        Ok(archive)
+    }
+}
+
+pub fn is_archive(bytes: &[u8]) -> bool {
+    bytes.len() > 4 && bytes.starts_with(ARCHIVE_MAGIC)
+}
+
+fn derive_key(
+    passphrase: &str,
+    salt: &[u8],
+    params: KdfParams,
+) -> Result<secretbox::Key, crate::error::Error> {
+    let argon_params = argon2::Params::new(
+        params.m_cost,
+        params.t_cost,
+        params.p_cost,
+        Some(secretbox::KEYBYTES),
+    )
+    .map_err(|e| crate::error::Error::Config(format!("archive KDF parameters: {e}")))?;
+    let argon = argon2::Argon2::new(
+        argon2::Algorithm::Argon2id,
+        argon2::Version::V0x13,
+        argon_params,
+    );
+    let mut out = [0u8; secretbox::KEYBYTES];
+    argon
+        .hash_password_into(passphrase.as_bytes(), salt, &mut out)
+        .map_err(|e| crate::crypto::Error::KeyGeneration(format!("kdf: {e}")))?;
+    Ok(secretbox::Key(out))
+}
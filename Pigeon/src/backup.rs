@@ -0,0 +1,241 @@
+//! Whole-data-dir backups: identity, key envelope, settings and every sled database in one
+//! passphrase-encrypted archive (see `archive`).
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::archive::{Archive, ArchiveKind};
+use crate::storage::at_rest;
+use crate::storage::rekey::DATABASES;
+use crate::storage::backend::{self, BackendKind};
+use crate::storage::snapshot::{self, DbSnapshot};
+
+pub const BACKUP_FORMAT: u32 = 1;
+
+const MANIFEST_ENTRY: &str = "manifest";
+const FILE_PREFIX: &str = "file:";
+const DB_PREFIX: &str = "db:";
+
+/// Plain files copied byte-for-byte (the identity stays sealed if it was). Databases are
+/// snapshotted tree by tree (`DATABASES`).
+const FILES: &[&str] = &[
+    "identity.bin",
+    at_rest::KEY_FILE,
+    at_rest::ENC_KEY_FILE,
+    "profile.toml",
+    "a11y.toml",
+    "app_state.toml",
+    "nearby_peers.toml",
+];
+
+/// What a backup contains, checked against the archive entries on restore.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BackupManifest {
+    pub format: u32,
+    pub created_at: u64,
+    pub app_version: String,
+    pub files: Vec<String>,
+    pub databases: Vec<DatabaseSummary>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DatabaseSummary {
+    pub name: String,
+    pub records: usize,
+}
+
+/// Snapshot `data_dir` into an archive sealed with `passphrase`.
+pub fn create(
+    data_dir: &Path,
+    out_path: &Path,
+    passphrase: &str,
+) -> Result<BackupManifest, crate::error::Error> {
+    if !data_dir.join("identity.bin").exists() {
+        return Err(crate::error::Error::Config(
+            "nothing to back up: no identity in this data dir".into(),
+        ));
+    }
+    let mut archive = Archive::new(ArchiveKind::Backup);
+    let mut files = Vec::new();
+    for name in FILES {
+        let path = data_dir.join(name);
+        if path.exists() {
+            archive.add(&format!("{FILE_PREFIX}{name}"), fs::read(&path)?);
+            files.push(name.to_string());
+        }
+    }
+    let mut databases = Vec::new();
+    for name in DATABASES {
+        let snap = snapshot::snapshot(&data_dir.join(name)).map_err(crate::error::Error::Storage)?;
+        databases.push(DatabaseSummary {
+            name: name.to_string(),
+            records: snap.record_count(),
+        });
+        archive.add(
+            &format!("{DB_PREFIX}{name}"),
+            bincode::serialize(&snap)
+                .map_err(|e| crate::error::Error::Serialization(e.to_string()))?,
+        );
+    }
+    let manifest = BackupManifest {
+        format: BACKUP_FORMAT,
+        created_at: archive.created_at,
+        app_version: archive.app_version.clone(),
+        files,
+        databases,
+    };
+    archive.add(
+        MANIFEST_ENTRY,
+        bincode::serialize(&manifest)
+            .map_err(|e| crate::error::Error::Serialization(e.to_string()))?,
+    );
+    fs::write(out_path, archive.seal(passphrase)?)?;
+    Ok(manifest)
+}
+
+/// Restore a backup into `data_dir`. Refuses to touch a data dir that already has an
+/// identity unless `overwrite` is set, in which case its files and databases are replaced.
+/// The replacements are all written as `<name>.partial` before any is moved into place.
+pub fn restore(
+    data_dir: &Path,
+    backup_path: &Path,
+    passphrase: &str,
+    overwrite: bool,
+) -> Result<BackupManifest, crate::error::Error> {
+    let archive = Archive::open(&fs::read(backup_path)?, passphrase)?;
+    if archive.kind != ArchiveKind::Backup {
+        return Err(crate::error::Error::Config("archive is not a backup".into()));
+    }
+    let manifest: BackupManifest = bincode::deserialize(
+        archive
+            .get(MANIFEST_ENTRY)
+            .ok_or_else(|| crate::error::Error::Config("backup has no manifest".into()))?,
+    )
+    .map_err(|e| crate::error::Error::Serialization(format!("backup manifest: {e}")))?;
+    if manifest.format != BACKUP_FORMAT {
+        return Err(crate::error::Error::Config(format!(
+            "unsupported backup format {}",
+            manifest.format
+        )));
+    }
+
+    // Decode everything before touching the data dir
+    let missing = |name: &str| {
+        crate::error::Error::Config(format!("backup manifest lists {name} but it is missing"))
+    };
+    let mut files = Vec::new();
+    for name in &manifest.files {
+        if !FILES.contains(&name.as_str()) {
+            return Err(crate::error::Error::Config(format!(
+                "backup contains unexpected file {name}"
+            )));
+        }
+        let bytes = archive
+            .get(&format!("{FILE_PREFIX}{name}"))
+            .ok_or_else(|| missing(name))?;
+        files.push((name.as_str(), bytes));
+    }
+    let mut databases = Vec::new();
+    for db in &manifest.databases {
+        if !DATABASES.contains(&db.name.as_str()) {
+            return Err(crate::error::Error::Config(format!(
+                "backup contains unexpected database {}",
+                db.name
+            )));
+        }
+        let bytes = archive
+            .get(&format!("{DB_PREFIX}{}", db.name))
+            .ok_or_else(|| missing(&db.name))?;
+        let snap: DbSnapshot = bincode::deserialize(bytes)
+            .map_err(|e| crate::error::Error::Serialization(format!("{}: {e}", db.name)))?;
+        if snap.record_count() != db.records {
+            return Err(crate::error::Error::Config(format!(
+                "{} does not match the backup manifest",
+                db.name
+            )));
+        }
+        databases.push((db.name.as_str(), snap));
+    }
+
+    if data_dir.join("identity.bin").exists() && !overwrite {
+        return Err(crate::error::Error::Config(
+            "this data dir already has an identity; pass --force to replace it".into(),
+        ));
+    }
+    fs::create_dir_all(data_dir)?;
+    // Restored databases keep the backend this data dir already used for them
+    let kinds: Vec<BackendKind> = DATABASES
+        .iter()
+        .map(|name| backend::detect(&data_dir.join(name)).unwrap_or(BackendKind::Sled))
+        .collect();
+    // Everything is written beside what it replaces first, so a failure leaves the data
+    // dir as it was
+    if let Err(e) = stage(data_dir, &files, &databases, &kinds) {
+        discard_staged(data_dir, &kinds);
+        return Err(e);
+    }
+    for (name, kind) in DATABASES.iter().zip(&kinds) {
+        let path = data_dir.join(name);
+        backend::remove_path(&path)?;
+        let staged = staged_path(&backend::location(&path, *kind));
+        if staged.exists() {
+            fs::rename(staged, backend::location(&path, *kind))?;
+        }
+    }
+    for name in FILES {
+        let path = data_dir.join(name);
+        let _ = fs::remove_file(&path);
+        let staged = staged_path(&path);
+        if staged.exists() {
+            fs::rename(staged, path)?;
+        }
+    }
+    // Keys cached for this dir belong to what we just replaced
+    at_rest::forget_cached(data_dir);
+    Ok(manifest)
+}
+
+fn staged_path(path: &Path) -> PathBuf {
+    backend::with_suffix(path, ".partial")
+}
+
+fn stage(
+    data_dir: &Path,
+    files: &[(&str, &[u8])],
+    databases: &[(&str, DbSnapshot)],
+    kinds: &[BackendKind],
+) -> Result<(), crate::error::Error> {
+    discard_staged(data_dir, kinds);
+    for (name, bytes) in files {
+        let staged = staged_path(&data_dir.join(name));
+        fs::write(&staged, bytes)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let _ = fs::set_permissions(&staged, fs::Permissions::from_mode(0o600));
+        }
+    }
+    for (name, snap) in databases {
+        let kind = DATABASES
+            .iter()
+            .position(|db| db == name)
+            .map_or(BackendKind::Sled, |i| kinds[i]);
+        let location = backend::location(&data_dir.join(name), kind);
+        snapshot::restore_at(&staged_path(&location), snap, kind)
+            .map_err(crate::error::Error::Storage)?;
+    }
+    Ok(())
+}
+
+/// Remove whatever an earlier (or this) restore left staged.
+fn discard_staged(data_dir: &Path, kinds: &[BackendKind]) {
+    for name in FILES {
+        let _ = fs::remove_file(staged_path(&data_dir.join(name)));
+    }
+    for (name, kind) in DATABASES.iter().zip(kinds) {
+        let location = backend::location(&data_dir.join(name), *kind);
+        let _ = backend::remove_at(&staged_path(&location), *kind);
+    }
+}
@@ -7,11 +7,15 @@ enum Tab {
     Inbox,
     Compose,
     Contacts,
+    Groups,
     MyAddress,
 }
 
 enum Mode {
     Onboarding,
+    // Passphrase set but the at-rest key is not unlocked (at start, after `Lock` or the
+    // idle auto-lock); nothing that reads stored data is reachable
+    Locked,
     Main,
 }
 
@@ -20,6 +24,7 @@ struct App {
     inbox: Vec<(uuid::Uuid, Vec<u8>)>,
     mode: Mode,
     passphrase: String,
+    unlock_passphrase: String,
     status: String,
     active: Tab,
     // Inbox/search
@@ -33,15 +38,36 @@ struct App {
     new_contact_name: String,
     new_contact_addr: String,
     new_contact_pubhex: String,
+    new_contact_peer_id: String,
+    // Safety number being shown for (contact id, number)
+    safety: Option<(u64, String)>,
+    // Peers seen over mDNS; picking one prefills the add form
+    nearby: Vec<secure_p2p_msg::discovery::NearbyPeer>,
+    new_contact_peer: Option<String>,
+    // Pasted pigeon:// contact card
+    import_card: String,
+    // Groups: the selected group's thread, and the create/member forms (comma-separated ids)
+    groups: Vec<secure_p2p_msg::groups::Group>,
+    group_selected: Option<uuid::Uuid>,
+    group_thread: Vec<secure_p2p_msg::groups::GroupMessage>,
+    group_body: String,
+    new_group_name: String,
+    new_group_members: String,
+    group_member_ids: String,
+    // Our box key (hex), loaded the first time the Groups tab needs it
+    my_box_pk: Option<String>,
     // My Address (computed on load)
     my_addr: String,
     my_id: String,
+    my_card: String,
 }
 
 impl Default for App {
     fn default() -> Self {
         let core = secure_p2p_msg::api::Core::default();
+        core.enable_auto_lock();
         let mode = match core.get_app_state() {
+            Ok(s) if s.onboarded && core.is_locked() => Mode::Locked,
             Ok(s) if s.onboarded => Mode::Main,
             _ => Mode::Onboarding,
         };
@@ -50,7 +76,18 @@ impl Default for App {
         } else {
             Vec::new()
         };
-        let contacts = core.contacts_list().unwrap_or_default();
+        let unlocked = !matches!(mode, Mode::Locked);
+        let contacts = if unlocked {
+            core.contacts_list().unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+        let nearby = core.nearby_peers().unwrap_or_default();
+        let groups = if unlocked {
+            core.groups_list().unwrap_or_default()
+        } else {
+            Vec::new()
+        };
         // Precompute My Address and ID before moving `core`
         #[cfg(feature = "network")]
         let (my_addr, my_id) = {
@@ -75,11 +112,20 @@ impl Default for App {
             "network feature disabled".to_string(),
             "network feature disabled".to_string(),
         );
+        // Don't generate an identity behind the onboarding screen's back
+        let my_card = if core.first_run_required() || !unlocked {
+            String::new()
+        } else {
+            core.my_contact_card()
+                .and_then(|c| c.to_uri())
+                .unwrap_or_default()
+        };
         Self {
             core,
             inbox,
             mode,
             passphrase: String::new(),
+            unlock_passphrase: String::new(),
             status: String::new(),
             active: Tab::Inbox,
             search: String::new(),
@@ -90,14 +136,66 @@ impl Default for App {
             new_contact_name: String::new(),
             new_contact_addr: String::new(),
             new_contact_pubhex: String::new(),
+            new_contact_peer_id: String::new(),
+            safety: None,
+            nearby,
+            new_contact_peer: None,
+            import_card: String::new(),
+            groups,
+            group_selected: None,
+            group_thread: Vec::new(),
+            group_body: String::new(),
+            new_group_name: String::new(),
+            new_group_members: String::new(),
+            group_member_ids: String::new(),
+            my_box_pk: None,
             my_addr,
             my_id,
+            my_card,
+        }
+    }
+}
+
+impl App {
+    /// Switch to the locked screen and drop everything read from the store.
+    fn show_locked(&mut self) {
+        self.mode = Mode::Locked;
+        self.inbox.clear();
+        self.contacts.clear();
+        self.safety = None;
+        self.groups.clear();
+        self.group_selected = None;
+        self.group_thread.clear();
+        self.compose_body.clear();
+        self.group_body.clear();
+    }
+
+    /// Reload what `show_locked` dropped.
+    fn reload_after_unlock(&mut self) {
+        self.mode = Mode::Main;
+        self.inbox = self.core.inbox_list().unwrap_or_default();
+        self.contacts = self.core.contacts_list().unwrap_or_default();
+        self.groups = self.core.groups_list().unwrap_or_default();
+        if self.my_card.is_empty() {
+            self.my_card = self
+                .core
+                .my_contact_card()
+                .and_then(|c| c.to_uri())
+                .unwrap_or_default();
         }
     }
 }
 
 impl eframe::App for App {
     fn update(&mut self, ctx: &egui::Context, _: &mut eframe::Frame) {
+        // Notice the idle auto-lock (or a lock from elsewhere) without waiting for input
+        if let Mode::Main = self.mode {
+            if self.core.is_locked() {
+                self.show_locked();
+                self.status = "Locked after inactivity".into();
+            }
+            ctx.request_repaint_after(std::time::Duration::from_secs(1));
+        }
         // Poll inbox watcher for updates and raise a system notification for latest
         // (Use a lightweight single-thread runtime for inbox watcher events)
         // In this minimal example, we skip if no watcher is present.
@@ -135,13 +233,53 @@ impl eframe::App for App {
                     }
                 });
             }
+            Mode::Locked => {
+                egui::CentralPanel::default().show(ctx, |ui| {
+                    ui.heading("Pigeon is locked");
+                    ui.label("Enter your passphrase to open messages and contacts.");
+                    let field = ui.add(
+                        egui::TextEdit::singleline(&mut self.unlock_passphrase).password(true),
+                    );
+                    let submitted =
+                        field.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                    let wait = self.core.unlock_retry_after();
+                    let clicked = ui
+                        .add_enabled(wait.is_none(), egui::Button::new("Unlock"))
+                        .clicked();
+                    if (clicked || submitted) && wait.is_none() {
+                        match self.core.unlock(&self.unlock_passphrase) {
+                            Ok(()) => {
+                                self.status.clear();
+                                self.reload_after_unlock();
+                            }
+                            Err(e) => self.status = format!("Unlock failed: {e}"),
+                        }
+                        self.unlock_passphrase.clear();
+                    }
+                    if let Some(secs) = wait {
+                        ui.label(format!("Too many wrong passphrases; try again in {secs}s"));
+                        ctx.request_repaint_after(std::time::Duration::from_secs(1));
+                    }
+                    if !self.status.is_empty() {
+                        ui.separator();
+                        ui.label(&self.status);
+                    }
+                });
+            }
             Mode::Main => {
                 egui::TopBottomPanel::top("top").show(ctx, |ui| {
                     ui.horizontal(|ui| {
                         ui.selectable_value(&mut self.active, Tab::Inbox, "Inbox");
                         ui.selectable_value(&mut self.active, Tab::Compose, "Compose");
                         ui.selectable_value(&mut self.active, Tab::Contacts, "Contacts");
+                        ui.selectable_value(&mut self.active, Tab::Groups, "Groups");
                         ui.selectable_value(&mut self.active, Tab::MyAddress, "My Address");
+                        // Only a store with a passphrase can be locked
+                        if matches!(self.core.kdf_params(), Ok(Some(_))) && ui.button("Lock").clicked() {
+                            let _ = self.core.lock();
+                            self.show_locked();
+                            self.status.clear();
+                        }
                     });
                 });
                 egui::CentralPanel::default().show(ctx, |ui| match self.active {
@@ -181,13 +319,16 @@ impl eframe::App for App {
                             // Resolve contact by name or id
                             let mut recipient_id: Option<u64> = None;
                             let mut recipient_pk_hex: Option<String> = None;
+                            let mut recipient_verified = false;
                             if let Ok(id) = self.compose_contact.parse::<u64>() {
                                 if let Ok(Some(c)) = self.core.contacts_get(id) {
                                     recipient_id = Some(c.id);
+                                    recipient_verified = c.verified;
                                     recipient_pk_hex = Some(hex::encode(c.public_key));
                                 }
                             } else if let Ok(Some(c)) = self.core.contacts_find_by_name(&self.compose_contact) {
                                 recipient_id = Some(c.id);
+                                recipient_verified = c.verified;
                                 recipient_pk_hex = Some(hex::encode(c.public_key));
                             }
                             if let (Some(cid), Some(pkhex)) = (recipient_id, recipient_pk_hex) {
@@ -197,7 +338,11 @@ impl eframe::App for App {
                                     self.core.send_encrypt_and_enqueue(&pkhex, cid, &body, high),
                                 );
                                 if res.is_ok() {
-                                    self.status = "Enqueued".to_string();
+                                    self.status = if recipient_verified {
+                                        "Enqueued".to_string()
+                                    } else {
+                                        "Enqueued (warning: contact not verified)".to_string()
+                                    };
                                     self.compose_body.clear();
                                 } else {
                                     self.status = "Send failed".to_string();
@@ -215,19 +360,112 @@ impl eframe::App for App {
                         ui.horizontal(|ui| {
                             if ui.button("Refresh").clicked() {
                                 self.contacts = self.core.contacts_list().unwrap_or_default();
+                                self.nearby = self.core.nearby_peers().unwrap_or_default();
                             }
                         });
                         ui.separator();
+                        let mut changed = false;
+                        let key_changes: Vec<_> =
+                            self.contacts.iter().filter(|c| c.send_blocked()).cloned().collect();
+                        for c in &key_changes {
+                            ui.horizontal(|ui| {
+                                ui.colored_label(
+                                    egui::Color32::YELLOW,
+                                    format!("⚠ Keys of {} changed; sending is held.", c.name),
+                                );
+                                if ui.button("Accept new keys").clicked() {
+                                    let _ = self.core.contacts_accept_key_change(c.id);
+                                    changed = true;
+                                }
+                                if ui.button("Keep old keys").clicked() {
+                                    let _ = self.core.contacts_reject_key_change(c.id);
+                                    changed = true;
+                                }
+                            });
+                        }
                         egui::ScrollArea::vertical().max_height(200.0).show(ui, |ui| {
                             for c in &self.contacts {
                                 ui.horizontal(|ui| {
                                     ui.label(format!("{}: {}", c.id, c.name));
+                                    if c.peer_id.is_none() {
+                                        ui.weak("(no PeerId)");
+                                    }
+                                    if c.verified {
+                                        ui.label("✔ verified");
+                                    } else {
+                                        ui.weak("unverified");
+                                    }
+                                    if ui.button("Safety number").clicked() {
+                                        self.safety = self
+                                            .core
+                                            .contacts_safety_number(c.id)
+                                            .ok()
+                                            .map(|n| (c.id, n));
+                                    }
                                     if ui.button("Remove").clicked() {
                                         let _ = self.core.contacts_remove(c.id);
                                     }
                                 });
                             }
                         });
+                        if let Some((cid, number)) = self.safety.clone() {
+                            ui.separator();
+                            ui.label(format!("Safety number with contact {cid}:"));
+                            ui.monospace(&number);
+                            ui.label("Compare it with your contact in person or over a call.");
+                            ui.horizontal(|ui| {
+                                if ui.button("Mark verified").clicked() {
+                                    let _ = self.core.contacts_set_verified(cid, true);
+                                    changed = true;
+                                }
+                                if ui.button("Mark unverified").clicked() {
+                                    let _ = self.core.contacts_set_verified(cid, false);
+                                    changed = true;
+                                }
+                                if ui.button("Close").clicked() {
+                                    self.safety = None;
+                                }
+                            });
+                        }
+                        if changed {
+                            self.contacts = self.core.contacts_list().unwrap_or_default();
+                        }
+                        if !self.nearby.is_empty() {
+                            ui.separator();
+                            ui.heading("Nearby Peers");
+                            for p in &self.nearby {
+                                ui.horizontal(|ui| {
+                                    ui.label(&p.peer_id);
+                                    if ui.button("Add…").clicked() {
+                                        self.new_contact_addr = p.dial_addr().unwrap_or_default();
+                                        self.new_contact_peer = Some(p.peer_id.clone());
+                                    }
+                                });
+                            }
+                        }
+                        ui.separator();
+                        ui.heading("Import Contact Card");
+                        ui.horizontal(|ui| {
+                            ui.label("Card:");
+                            ui.text_edit_singleline(&mut self.import_card);
+                            if ui.button("Import").clicked() {
+                                match self.core.contacts_import_card(&self.import_card) {
+                                    Ok(c) => {
+                                        self.status = if c.verified {
+                                            format!("Imported {}", c.name)
+                                        } else {
+                                            format!(
+                                                "Imported {} (unverified: compare safety numbers)",
+                                                c.name
+                                            )
+                                        };
+                                        self.import_card.clear();
+                                        self.contacts = self.core.contacts_list().unwrap_or_default();
+                                    }
+                                    Err(e) => self.status = format!("Import failed: {e}"),
+                                }
+                            }
+                        });
                         ui.separator();
                         ui.heading("Add Contact");
                         ui.horizontal(|ui| {
@@ -242,24 +480,170 @@ impl eframe::App for App {
                             ui.label("PubKey (hex):");
                             ui.text_edit_singleline(&mut self.new_contact_pubhex);
                         });
+                        ui.horizontal(|ui| {
+                            ui.label("PeerId (optional):");
+                            ui.text_edit_singleline(&mut self.new_contact_peer_id);
+                        });
                         if ui.button("Add").clicked() {
-                            match self.core.contacts_add(
-                                &self.new_contact_name,
-                                &self.new_contact_addr,
-                                &self.new_contact_pubhex,
-                            ) {
+                            let added = match &self.new_contact_peer {
+                                Some(peer) => self.core.add_nearby_peer(
+                                    peer,
+                                    &self.new_contact_name,
+                                    &self.new_contact_pubhex,
+                                ),
+                                None => self.core.contacts_add(
+                                    &self.new_contact_name,
+                                    &self.new_contact_addr,
+                                    &self.new_contact_pubhex,
+                                ),
+                            };
+                            let added = match added {
+                                Ok(c) if !self.new_contact_peer_id.trim().is_empty() => self
+                                    .core
+                                    .contacts_set_peer_id(c.id, self.new_contact_peer_id.trim()),
+                                other => other,
+                            };
+                            match added {
                                 Ok(_) => {
                                     self.status = "Contact added".to_string();
                                     self.new_contact_name.clear();
                                     self.new_contact_addr.clear();
                                     self.new_contact_pubhex.clear();
+                                    self.new_contact_peer_id.clear();
+                                    self.new_contact_peer = None;
                                     self.contacts = self.core.contacts_list().unwrap_or_default();
+                                    self.nearby = self.core.nearby_peers().unwrap_or_default();
                                 }
                                 Err(e) => self.status = format!("Add failed: {e}"),
                             }
                         }
                         if !self.status.is_empty() { ui.label(&self.status); }
                     }
+                    Tab::Groups => {
+                        ui.horizontal(|ui| {
+                            if ui.button("Refresh").clicked() {
+                                self.groups = self.core.groups_list().unwrap_or_default();
+                                if let Some(gid) = self.group_selected {
+                                    self.group_thread =
+                                        self.core.groups_thread(gid).unwrap_or_default();
+                                }
+                            }
+                        });
+                        ui.separator();
+                        let mut selected = self.group_selected;
+                        for g in &self.groups {
+                            let label = if g.active {
+                                format!("{} ({} members)", g.name, g.members.len())
+                            } else {
+                                format!("{} (removed)", g.name)
+                            };
+                            ui.selectable_value(&mut selected, Some(g.id), label);
+                        }
+                        if selected != self.group_selected {
+                            self.group_selected = selected;
+                            self.group_thread = selected
+                                .and_then(|gid| self.core.groups_thread(gid).ok())
+                                .unwrap_or_default();
+                        }
+                        let group = self
+                            .group_selected
+                            .and_then(|gid| self.groups.iter().find(|g| g.id == gid))
+                            .cloned();
+                        if let Some(g) = group {
+                            ui.separator();
+                            ui.heading(&g.name);
+                            let names: Vec<&str> =
+                                g.members.iter().map(|m| m.name.as_str()).collect();
+                            ui.label(format!("Members: {}", names.join(", ")));
+                            egui::ScrollArea::vertical().max_height(200.0).show(ui, |ui| {
+                                for m in &self.group_thread {
+                                    ui.label(format!(
+                                        "{}: {}",
+                                        m.sender_name,
+                                        String::from_utf8_lossy(&m.body)
+                                    ));
+                                }
+                            });
+                            if g.active {
+                                ui.text_edit_multiline(&mut self.group_body);
+                                ui.horizontal(|ui| {
+                                    if ui.button("Send").clicked() {
+                                        match self.core.groups_send(g.id, self.group_body.as_bytes()) {
+                                            Ok(m) => {
+                                                self.group_thread.push(m);
+                                                self.group_body.clear();
+                                                self.status = "Enqueued".to_string();
+                                            }
+                                            Err(e) => self.status = format!("Send failed: {e}"),
+                                        }
+                                    }
+                                    if ui.button("Rekey").clicked() {
+                                        self.status = match self.core.groups_rekey(g.id) {
+                                            Ok(_) => "New sender key queued".to_string(),
+                                            Err(e) => format!("Rekey failed: {e}"),
+                                        };
+                                    }
+                                });
+                            }
+                            if self.my_box_pk.is_none() {
+                                self.my_box_pk = self
+                                    .core
+                                    .ensure_identity_and_preview()
+                                    .ok()
+                                    .map(|p| p.sodium_box_pk_hex);
+                            }
+                            let is_admin =
+                                self.my_box_pk.as_deref() == Some(hex::encode(g.admin).as_str());
+                            if is_admin {
+                                ui.horizontal(|ui| {
+                                    ui.label("Contact ids:");
+                                    ui.text_edit_singleline(&mut self.group_member_ids);
+                                    let ids = parse_ids(&self.group_member_ids);
+                                    let changed = if ui.button("Add").clicked() {
+                                        Some(self.core.groups_add_members(g.id, &ids))
+                                    } else if ui.button("Remove").clicked() {
+                                        Some(self.core.groups_remove_members(g.id, &ids))
+                                    } else {
+                                        None
+                                    };
+                                    match changed {
+                                        Some(Ok(_)) => {
+                                            self.group_member_ids.clear();
+                                            self.groups = self.core.groups_list().unwrap_or_default();
+                                            self.status = "Members updated".to_string();
+                                        }
+                                        Some(Err(e)) => self.status = format!("Update failed: {e}"),
+                                        None => {}
+                                    }
+                                });
+                            }
+                        }
+                        ui.separator();
+                        ui.heading("New Group");
+                        ui.horizontal(|ui| {
+                            ui.label("Name:");
+                            ui.text_edit_singleline(&mut self.new_group_name);
+                        });
+                        ui.horizontal(|ui| {
+                            ui.label("Contact ids:");
+                            ui.text_edit_singleline(&mut self.new_group_members);
+                        });
+                        if ui.button("Create").clicked() {
+                            let ids = parse_ids(&self.new_group_members);
+                            match self.core.groups_create(&self.new_group_name, &ids) {
+                                Ok(g) => {
+                                    self.status = format!("Created {}", g.name);
+                                    self.group_selected = Some(g.id);
+                                    self.group_thread.clear();
+                                    self.new_group_name.clear();
+                                    self.new_group_members.clear();
+                                    self.groups = self.core.groups_list().unwrap_or_default();
+                                }
+                                Err(e) => self.status = format!("Create failed: {e}"),
+                            }
+                        }
+                        if !self.status.is_empty() { ui.label(&self.status); }
+                    }
                     Tab::MyAddress => {
                         ui.heading("Your ID and dialable address");
                         ui.horizontal(|ui| {
@@ -278,6 +662,21 @@ impl eframe::App for App {
                             }
                         });
                         ui.label("Share this with peers so they can connect to you.");
+                        ui.separator();
+                        ui.label("Your contact card:");
+                        ui.horizontal(|ui| {
+                            if self.my_card.is_empty() && ui.button("Generate").clicked() {
+                                match self.core.my_contact_card().and_then(|c| c.to_uri()) {
+                                    Ok(uri) => self.my_card = uri,
+                                    Err(e) => self.status = format!("Card failed: {e}"),
+                                }
+                            }
+                            ui.text_edit_singleline(&mut self.my_card);
+                            if ui.button("Copy").clicked() {
+                                ui.output_mut(|o| o.copied_text = self.my_card.clone());
+                            }
+                        });
+                        ui.label("Peers can paste it under Contacts to add you in one step.");
                     }
                 });
             }
@@ -285,6 +684,13 @@ impl eframe::App for App {
     }
 }
 
+/// Contact ids typed as "1, 2 3".
+fn parse_ids(s: &str) -> Vec<u64> {
+    s.split(|c: char| c == ',' || c.is_whitespace())
+        .filter_map(|t| t.parse().ok())
+        .collect()
+}
+
 fn main() -> eframe::Result<()> {
     let native_options = eframe::NativeOptions::default();
     eframe::run_native(
@@ -1,5 +1,31 @@
-use serde::Deserialize;
-use std::{env, fs, path::PathBuf};
+use serde::{Deserialize, Serialize};
+use std::{env, fs, path::PathBuf, str::FromStr};
+
+/// What the listener does with envelopes from a PeerId that maps to no contact.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownPeerPolicy {
+    /// Decrypt and deliver to the inbox like any other message.
+    Accept,
+    /// Keep the raw envelope aside until the sender is added as a contact.
+    #[default]
+    Quarantine,
+    /// Reject without storing anything.
+    Refuse,
+}
+
+impl FromStr for UnknownPeerPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "accept" => Ok(Self::Accept),
+            "quarantine" => Ok(Self::Quarantine),
+            "refuse" => Ok(Self::Refuse),
+            other => Err(format!("unknown peer policy: {other}")),
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 #[allow(dead_code)]
@@ -10,8 +36,38 @@ pub struct AppConfig {
     pub listen_addr: Option<String>,
     #[cfg(feature = "network")]
     pub enable_mdns: bool,
+    pub unknown_peer_policy: UnknownPeerPolicy,
+    /// Refuse everything that is not from a contact or one of its devices, whatever
+    /// `unknown_peer_policy` says, including requests that are not envelopes at all.
+    pub contacts_only: bool,
+    /// How far an envelope's send time may be from our clock, either way, before it is
+    /// rejected; seen nonces are kept this long.
+    pub replay_window_secs: u64,
+    /// Per-peer request rate, connections per peer, inbound bandwidth and the stamp asked
+    /// of unknown peers.
+    pub inbound_limits: crate::messaging::limits::InboundLimits,
+    /// Cache the unlocked at-rest key in the OS keyring (needs the `os-keyring` feature).
+    pub use_keyring: bool,
+    /// How long a keyring-cached unlock lasts.
+    pub keyring_timeout_secs: u64,
+    /// Argon2id costs for new passphrase wraps (existing key files keep theirs).
+    pub kdf: crate::storage::at_rest::KdfParams,
+    /// Lock the at-rest key after this long unused, in processes that enable the
+    /// auto-lock (the GUI); 0 never locks.
+    pub auto_lock_secs: u64,
+    /// Send to a contact under the static identity keys, without forward secrecy, when no
+    /// session can be started (no prekey bundle could be fetched, or this install is a
+    /// linked device). Off: such messages wait in the queue.
+    pub static_key_fallback: bool,
 }
 
+/// Default lifetime of a keyring-cached unlock.
+pub const DEFAULT_KEYRING_TIMEOUT_SECS: u64 = 15 * 60;
+/// Default idle time before the GUI locks the at-rest key again.
+pub const DEFAULT_AUTO_LOCK_SECS: u64 = 10 * 60;
+/// Default replay window: long enough for a message to wait out the sender's retries.
+pub const DEFAULT_REPLAY_WINDOW_SECS: u64 = 24 * 60 * 60;
+
 impl Default for AppConfig {
     fn default() -> Self {
         Self {
@@ -21,6 +77,15 @@ impl Default for AppConfig {
             listen_addr: None,
             #[cfg(feature = "network")]
             enable_mdns: false,
+            unknown_peer_policy: UnknownPeerPolicy::default(),
+            contacts_only: false,
+            replay_window_secs: DEFAULT_REPLAY_WINDOW_SECS,
+            inbound_limits: Default::default(),
+            use_keyring: false,
+            keyring_timeout_secs: DEFAULT_KEYRING_TIMEOUT_SECS,
+            kdf: crate::storage::at_rest::KdfParams::default(),
+            auto_lock_secs: DEFAULT_AUTO_LOCK_SECS,
+            static_key_fallback: false,
         }
     }
 }
@@ -54,6 +119,63 @@ pub fn load() -> AppConfig {
             cfg.enable_mdns = v == "1" || v == "true" || v == "yes";
         }
     }
+    if let Ok(v) = env::var("PIGEON_UNKNOWN_PEER_POLICY") {
+        if let Ok(p) = v.parse() {
+            cfg.unknown_peer_policy = p;
+        }
+    }
+    if let Ok(v) = env::var("PIGEON_CONTACTS_ONLY") {
+        let v = v.to_ascii_lowercase();
+        cfg.contacts_only = v == "1" || v == "true" || v == "yes";
+    }
+    if let Ok(v) = env::var("PIGEON_REPLAY_WINDOW_SECS") {
+        if let Ok(secs) = v.parse() {
+            cfg.replay_window_secs = secs;
+        }
+    }
+    if let Ok(v) = env::var("PIGEON_PEER_REQUESTS_PER_MIN") {
+        if let Ok(n) = v.parse() {
+            cfg.inbound_limits.peer_requests_per_min = n;
+        }
+    }
+    if let Ok(v) = env::var("PIGEON_PEER_BURST") {
+        if let Ok(n) = v.parse() {
+            cfg.inbound_limits.peer_burst = n;
+        }
+    }
+    if let Ok(v) = env::var("PIGEON_MAX_CONNECTIONS_PER_PEER") {
+        if let Ok(n) = v.parse() {
+            cfg.inbound_limits.max_connections_per_peer = n;
+        }
+    }
+    if let Ok(v) = env::var("PIGEON_MAX_INBOUND_BYTES_PER_SEC") {
+        if let Ok(n) = v.parse() {
+            cfg.inbound_limits.max_inbound_bytes_per_sec = n;
+        }
+    }
+    if let Ok(v) = env::var("PIGEON_STAMP_BITS") {
+        if let Ok(n) = v.parse() {
+            cfg.inbound_limits.stamp_bits = n;
+        }
+    }
+    if let Ok(v) = env::var("PIGEON_USE_KEYRING") {
+        let v = v.to_ascii_lowercase();
+        cfg.use_keyring = v == "1" || v == "true" || v == "yes";
+    }
+    if let Ok(v) = env::var("PIGEON_KEYRING_TIMEOUT_SECS") {
+        if let Ok(secs) = v.parse() {
+            cfg.keyring_timeout_secs = secs;
+        }
+    }
+    if let Ok(v) = env::var("PIGEON_AUTO_LOCK_SECS") {
+        if let Ok(secs) = v.parse() {
+            cfg.auto_lock_secs = secs;
+        }
+    }
+    if let Ok(v) = env::var("PIGEON_STATIC_KEY_FALLBACK") {
+        let v = v.to_ascii_lowercase();
+        cfg.static_key_fallback = v == "1" || v == "true" || v == "yes";
+    }
 
     cfg
 }
@@ -95,12 +217,26 @@ struct NetworkSection {
     listen_addr: Option<String>,
     #[cfg(feature = "network")]
     enable_mdns: Option<bool>,
+    unknown_peer_policy: Option<UnknownPeerPolicy>,
+    contacts_only: Option<bool>,
+    replay_window_secs: Option<u64>,
+    peer_requests_per_min: Option<u32>,
+    peer_burst: Option<u32>,
+    max_connections_per_peer: Option<u32>,
+    max_inbound_bytes_per_sec: Option<u64>,
+    stamp_bits: Option<u8>,
 }
 
 #[derive(Deserialize, Debug, Default)]
 #[allow(dead_code)]
 struct SecuritySection {
-    // Placeholder for future options (e.g., encrypt_at_rest)
+    use_keyring: Option<bool>,
+    keyring_timeout_secs: Option<u64>,
+    kdf_memory_kib: Option<u32>,
+    kdf_iterations: Option<u32>,
+    kdf_parallelism: Option<u32>,
+    auto_lock_secs: Option<u64>,
+    static_key_fallback: Option<bool>,
 }
 
 impl RootConfig {
@@ -118,6 +254,56 @@ impl RootConfig {
         if let Some(l) = self.log_level {
             cfg.log_level = l;
         }
+        if let Some(p) = self.network.as_ref().and_then(|n| n.unknown_peer_policy) {
+            cfg.unknown_peer_policy = p;
+        }
+        if let Some(c) = self.network.as_ref().and_then(|n| n.contacts_only) {
+            cfg.contacts_only = c;
+        }
+        if let Some(w) = self.network.as_ref().and_then(|n| n.replay_window_secs) {
+            cfg.replay_window_secs = w;
+        }
+        if let Some(net) = &self.network {
+            let limits = &mut cfg.inbound_limits;
+            if let Some(n) = net.peer_requests_per_min {
+                limits.peer_requests_per_min = n;
+            }
+            if let Some(n) = net.peer_burst {
+                limits.peer_burst = n;
+            }
+            if let Some(n) = net.max_connections_per_peer {
+                limits.max_connections_per_peer = n;
+            }
+            if let Some(n) = net.max_inbound_bytes_per_sec {
+                limits.max_inbound_bytes_per_sec = n;
+            }
+            if let Some(n) = net.stamp_bits {
+                limits.stamp_bits = n;
+            }
+        }
+        if let Some(sec) = &self.security {
+            if let Some(k) = sec.use_keyring {
+                cfg.use_keyring = k;
+            }
+            if let Some(t) = sec.keyring_timeout_secs {
+                cfg.keyring_timeout_secs = t;
+            }
+            if let Some(m) = sec.kdf_memory_kib {
+                cfg.kdf.memory_kib = m;
+            }
+            if let Some(t) = sec.kdf_iterations {
+                cfg.kdf.iterations = t;
+            }
+            if let Some(p) = sec.kdf_parallelism {
+                cfg.kdf.parallelism = p;
+            }
+            if let Some(a) = sec.auto_lock_secs {
+                cfg.auto_lock_secs = a;
+            }
+            if let Some(f) = sec.static_key_fallback {
+                cfg.static_key_fallback = f;
+            }
+        }
         #[cfg(feature = "network")]
         {
             if let Some(net) = self.network {
@@ -155,7 +341,7 @@ fn ensure_default_file() {
     let _ = fs::create_dir_all(&dir);
     let data_dir = default_data_dir();
     let tmpl = format!(
-        "# Pigeon config\n\n[storage]\n# Where to store app data (dbs, keys, inbox)\n# data_dir can be overridden by PIGEON_DATA_DIR\n# Default below is the OS data dir\n# On Windows: %APPDATA%/pigeon\n# On Linux: ~/.local/share/pigeon\n# On macOS: ~/Library/Application Support/pigeon\n#\n# data_dir = \"{data}\"\n\n[network]\n# listen_addr example: \"/ip4/0.0.0.0/tcp/4001\"\n# enable_mdns = false\n\n[security]\n# Reserved for future options (e.g., encrypt_at_rest)\n",
+        "# Pigeon config\n\n[storage]\n# Where to store app data (dbs, keys, inbox)\n# data_dir can be overridden by PIGEON_DATA_DIR\n# Default below is the OS data dir\n# On Windows: %APPDATA%/pigeon\n# On Linux: ~/.local/share/pigeon\n# On macOS: ~/Library/Application Support/pigeon\n#\n# data_dir = \"{data}\"\n\n[network]\n# listen_addr example: \"/ip4/0.0.0.0/tcp/4001\"\n# enable_mdns = false\n# What to do with messages from peers that are not contacts: accept, quarantine, refuse\n# unknown_peer_policy = \"quarantine\"\n# Refuse everything not from a contact, whatever unknown_peer_policy says\n# contacts_only = false\n# Reject envelopes sent more than this many seconds from our clock (and forget their nonces)\n# replay_window_secs = 86400\n# Requests a peer may send per minute, and in one burst (0 = unlimited)\n# peer_requests_per_min = 60\n# peer_burst = 20\n# Simultaneous connections from one peer (0 = unlimited)\n# max_connections_per_peer = 4\n# Inbound bytes per second over all peers (0 = unlimited)\n# max_inbound_bytes_per_sec = 1048576\n# Proof-of-work stamp (leading zero bits, at most 24) asked of peers that are not contacts; 0 = none\n# stamp_bits = 0\n\n[security]\n# Keep `security unlock` in the OS keyring for a while (builds with the os-keyring feature)\n# use_keyring = false\n# keyring_timeout_secs = 900\n# Argon2id costs used when a passphrase is set or changed (the key file records them)\n# kdf_memory_kib = 19456\n# kdf_iterations = 2\n# kdf_parallelism = 1\n# Lock the GUI again after this many idle seconds (0 = never)\n# auto_lock_secs = 600\n# Send without forward secrecy (static keys) when no session can be started with a contact\n# static_key_fallback = false\n",
         data = data_dir.display()
     );
     let _ = fs::write(path, tmpl);
@@ -0,0 +1,199 @@
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::sign;
+
+use crate::devices::DeviceCert;
+use crate::identity::Identity;
+
+/// URI scheme prefix for shareable contact cards.
+pub const CARD_SCHEME: &str = "pigeon://";
+
+// Version 2 adds the device certificates of multi-device accounts
+const CARD_VERSION: u8 = 2;
+const LEGACY_CARD_VERSION: u8 = 1;
+// Domain separation so a card signature can never be replayed as an envelope signature
+const SIGN_CONTEXT: &[u8] = b"pigeon-contact-card-v1";
+
+/// Everything a peer needs to add us as a contact, signed with our ed25519 key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContactCard {
+    pub version: u8,
+    pub name: String,
+    pub peer_id: Option<String>,
+    pub box_public_key: Vec<u8>,  // 32 bytes (sodium box)
+    pub sign_public_key: Vec<u8>, // 32 bytes (ed25519)
+    pub addrs: Vec<String>,       // multiaddrs, most preferred first
+    pub signature: Vec<u8>,
+    pub devices: Vec<DeviceCert>, // other devices of the same account
+}
+
+/// Version 1 layout, before device certificates.
+#[derive(Deserialize)]
+struct LegacyCard {
+    version: u8,
+    name: String,
+    peer_id: Option<String>,
+    box_public_key: Vec<u8>,
+    sign_public_key: Vec<u8>,
+    addrs: Vec<String>,
+    signature: Vec<u8>,
+}
+
+#[derive(Serialize)]
+struct UnsignedCard<'a> {
+    version: u8,
+    name: &'a str,
+    peer_id: &'a Option<String>,
+    box_public_key: &'a [u8],
+    sign_public_key: &'a [u8],
+    addrs: &'a [String],
+}
+
+impl ContactCard {
+    /// Build and sign a card for `id`.
+    pub fn new_signed(
+        id: &Identity,
+        name: &str,
+        peer_id: Option<String>,
+        addrs: Vec<String>,
+    ) -> Result<Self, crate::error::Error> {
+        Self::new_signed_with_devices(id, name, peer_id, addrs, Vec::new())
+    }
+
+    /// Build and sign a card listing the certificates of the account's devices.
+    pub fn new_signed_with_devices(
+        id: &Identity,
+        name: &str,
+        peer_id: Option<String>,
+        addrs: Vec<String>,
+        devices: Vec<DeviceCert>,
+    ) -> Result<Self, crate::error::Error> {
+        let mut card = Self {
+            version: CARD_VERSION,
+            name: name.trim().to_string(),
+            peer_id,
+            box_public_key: id.sodium_box_pk.0.to_vec(),
+            sign_public_key: id.sign_pk.0.to_vec(),
+            addrs,
+            signature: Vec::new(),
+            devices,
+        };
+        let sig = sign::sign_detached(&card.signed_bytes()?, &id.sign_sk);
+        card.signature = sig.to_bytes().to_vec();
+        Ok(card)
+    }
+
+    fn signed_bytes(&self) -> Result<Vec<u8>, crate::error::Error> {
+        let unsigned = UnsignedCard {
+            version: self.version,
+            name: &self.name,
+            peer_id: &self.peer_id,
+            box_public_key: &self.box_public_key,
+            sign_public_key: &self.sign_public_key,
+            addrs: &self.addrs,
+        };
+        let mut out = SIGN_CONTEXT.to_vec();
+        out.extend(
+            bincode::serialize(&unsigned)
+                .map_err(|e| crate::error::Error::Serialization(e.to_string()))?,
+        );
+        if self.version >= 2 {
+            out.extend(
+                bincode::serialize(&self.devices)
+                    .map_err(|e| crate::error::Error::Serialization(e.to_string()))?,
+            );
+        }
+        Ok(out)
+    }
+
+    /// Check the card is self-consistent and signed by the key it carries.
+    pub fn verify(&self) -> Result<(), crate::error::Error> {
+        let bad = |m: &str| crate::error::Error::Crypto(crate::crypto::Error::Signature(m.into()));
+        if self.version != CARD_VERSION && self.version != LEGACY_CARD_VERSION {
+            return Err(crate::error::Error::Config(format!(
+                "unsupported contact card version {}",
+                self.version
+            )));
+        }
+        if self.box_public_key.len() != 32 {
+            return Err(bad("card box key must be 32 bytes"));
+        }
+        let pk = sign::PublicKey::from_slice(&self.sign_public_key)
+            .ok_or_else(|| bad("card sign key must be 32 bytes"))?;
+        let sig = sign::Signature::from_bytes(&self.signature)
+            .map_err(|_| bad("malformed card signature"))?;
+        if !sign::verify_detached(&sig, &self.signed_bytes()?, &pk) {
+            return Err(bad("contact card signature does not match"));
+        }
+        // Device certificates must come from the account the card's signer belongs to
+        if let Some(account) = crate::devices::verify_certs(&self.devices)? {
+            let signer_in_account = account.as_slice() == self.sign_public_key.as_slice()
+                || self
+                    .devices
+                    .iter()
+                    .any(|c| c.sign_key.as_slice() == self.sign_public_key.as_slice());
+            if !signer_in_account {
+                return Err(bad("card devices belong to another account"));
+            }
+        }
+        Ok(())
+    }
+
+    /// The account's devices other than the one that issued the card.
+    pub fn other_devices(&self) -> Vec<crate::storage::contacts::DeviceKey> {
+        self.devices
+            .iter()
+            .filter(|c| c.sign_key.as_slice() != self.sign_public_key.as_slice())
+            .map(DeviceCert::to_device_key)
+            .collect()
+    }
+
+    /// Encode as a `pigeon://<base58>` URI.
+    pub fn to_uri(&self) -> Result<String, crate::error::Error> {
+        let bytes = bincode::serialize(self)
+            .map_err(|e| crate::error::Error::Serialization(e.to_string()))?;
+        Ok(format!(
+            "{CARD_SCHEME}{}",
+            bs58::encode(bytes).into_string()
+        ))
+    }
+
+    /// Parse a card from a `pigeon://` URI or a bare base58 blob and verify its signature.
+    pub fn from_uri(s: &str) -> Result<Self, crate::error::Error> {
+        let s = s.trim();
+        let blob = s.strip_prefix(CARD_SCHEME).unwrap_or(s);
+        let bytes = bs58::decode(blob)
+            .into_vec()
+            .map_err(|e| crate::error::Error::Serialization(format!("contact card: {e}")))?;
+        let card: Self = match bytes.first() {
+            Some(&LEGACY_CARD_VERSION) => {
+                let c: LegacyCard = bincode::deserialize(&bytes).map_err(|e| {
+                    crate::error::Error::Serialization(format!("contact card: {e}"))
+                })?;
+                Self {
+                    version: c.version,
+                    name: c.name,
+                    peer_id: c.peer_id,
+                    box_public_key: c.box_public_key,
+                    sign_public_key: c.sign_public_key,
+                    addrs: c.addrs,
+                    signature: c.signature,
+                    devices: Vec::new(),
+                }
+            }
+            _ => bincode::deserialize(&bytes)
+                .map_err(|e| crate::error::Error::Serialization(format!("contact card: {e}")))?,
+        };
+        card.verify()?;
+        Ok(card)
+    }
+
+    /// Address to store on the contact: the first advertised addr pinned to the PeerId.
+    pub fn dial_addr(&self) -> Option<String> {
+        match (self.addrs.first(), &self.peer_id) {
+            (Some(a), Some(p)) => Some(crate::discovery::with_peer_suffix(a, p)),
+            (Some(a), None) => Some(a.clone()),
+            (None, Some(p)) => Some(format!("/p2p/{p}")),
+            (None, None) => None,
+        }
+    }
+}
@@ -75,3 +75,32 @@ pub fn decrypt_message(
     )
     .map_err(|_| Error::Decryption("Decryption failed".into()))
 }
+
+// Iterations of the per-key fingerprint hash (same order of cost as Signal's)
+const FINGERPRINT_ITERATIONS: usize = 5200;
+
+/// Human-comparable safety number for a conversation: 12 groups of 5 digits derived from
+/// both parties' box public keys. Both sides compute the same string regardless of order.
+pub fn safety_number(local_pk: &[u8], remote_pk: &[u8]) -> String {
+    let mut halves = [fingerprint_digits(local_pk), fingerprint_digits(remote_pk)];
+    halves.sort();
+    halves.concat().join(" ")
+}
+
+fn fingerprint_digits(pk: &[u8]) -> Vec<String> {
+    use sha2::{Digest, Sha512};
+    let mut hash = Sha512::new()
+        .chain_update(b"pigeon-safety-number-v1")
+        .chain_update(pk)
+        .finalize();
+    for _ in 1..FINGERPRINT_ITERATIONS {
+        hash = Sha512::new().chain_update(hash).chain_update(pk).finalize();
+    }
+    hash[..30]
+        .chunks(5)
+        .map(|c| {
+            let n = c.iter().fold(0u64, |acc, b| (acc << 8) | u64::from(*b));
+            format!("{:05}", n % 100_000)
+        })
+        .collect()
+}
@@ -0,0 +1,422 @@
+//! Multi-device accounts. Each device keeps its own `Identity`; the primary device signs a
+//! certificate for every linked device's keys. Contact cards carry the certificates so
+//! senders can fan out to all devices, and devices copy what they send to each other.
+
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::sign;
+
+use crate::identity::Identity;
+use crate::storage::contacts::DeviceKey;
+use crate::storage::devices::DeviceStore;
+
+pub const LINK_REQUEST_SCHEME: &str = "pigeon-link://";
+pub const DEVICE_GRANT_SCHEME: &str = "pigeon-device://";
+
+/// `contact_id` of queued copies addressed to our own devices.
+pub const OWN_DEVICES: u64 = u64::MAX;
+
+const CERT_CONTEXT: &[u8] = b"pigeon-device-cert-v1";
+const REQUEST_CONTEXT: &[u8] = b"pigeon-link-request-v1";
+
+/// How to reach one device.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub name: String,
+    pub peer_id: Option<String>, // libp2p PeerId (base58)
+    pub addrs: Vec<String>,      // multiaddrs, most preferred first
+}
+
+impl DeviceInfo {
+    /// First advertised addr pinned to the PeerId (see `ContactCard::dial_addr`).
+    pub fn dial_addr(&self) -> Option<String> {
+        match (self.addrs.first(), &self.peer_id) {
+            (Some(a), Some(p)) => Some(crate::discovery::with_peer_suffix(a, p)),
+            (Some(a), None) => Some(a.clone()),
+            (None, Some(p)) => Some(format!("/p2p/{p}")),
+            (None, None) => None,
+        }
+    }
+
+    fn encode_into(&self, out: &mut Vec<u8>) {
+        put_bytes(out, self.name.as_bytes());
+        match &self.peer_id {
+            Some(p) => {
+                out.push(1);
+                put_bytes(out, p.as_bytes());
+            }
+            None => out.push(0),
+        }
+        out.extend_from_slice(&(self.addrs.len() as u32).to_be_bytes());
+        for a in &self.addrs {
+            put_bytes(out, a.as_bytes());
+        }
+    }
+}
+
+/// A device's keys, signed by the account (primary device) signing key.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DeviceCert {
+    pub account_sign_key: [u8; 32],
+    pub device: DeviceInfo,
+    pub box_key: [u8; 32],
+    pub sign_key: [u8; 32],
+    pub issued_at: u64,
+    pub signature: Vec<u8>,
+}
+
+impl DeviceCert {
+    fn issue(account: &Identity, device: DeviceInfo, box_key: [u8; 32], sign_key: [u8; 32]) -> Self {
+        let mut cert = Self {
+            account_sign_key: account.sign_pk.0,
+            device,
+            box_key,
+            sign_key,
+            issued_at: now_secs(),
+            signature: Vec::new(),
+        };
+        cert.signature = sign::sign_detached(&cert.signed_bytes(), &account.sign_sk)
+            .to_bytes()
+            .to_vec();
+        cert
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut out = CERT_CONTEXT.to_vec();
+        out.extend_from_slice(&self.account_sign_key);
+        self.device.encode_into(&mut out);
+        out.extend_from_slice(&self.box_key);
+        out.extend_from_slice(&self.sign_key);
+        out.extend_from_slice(&self.issued_at.to_be_bytes());
+        out
+    }
+
+    pub fn verify(&self) -> Result<(), crate::error::Error> {
+        if verify_detached(&self.signed_bytes(), &self.signature, &self.account_sign_key) {
+            Ok(())
+        } else {
+            Err(bad_signature("device certificate signature does not match"))
+        }
+    }
+
+    /// The certificate as a contact's extra device.
+    pub fn to_device_key(&self) -> DeviceKey {
+        DeviceKey {
+            name: self.device.name.clone(),
+            public_key: self.box_key.to_vec(),
+            sign_public_key: self.sign_key.to_vec(),
+            peer_id: self.device.peer_id.clone(),
+            addr: self.device.dial_addr(),
+        }
+    }
+}
+
+/// A new device's keys, signed by that device to prove it holds them, for the primary to
+/// authorize.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LinkRequest {
+    pub device: DeviceInfo,
+    pub box_key: [u8; 32],
+    pub sign_key: [u8; 32],
+    pub signature: Vec<u8>,
+}
+
+impl LinkRequest {
+    pub fn new_signed(id: &Identity, device: DeviceInfo) -> Self {
+        let mut req = Self {
+            device,
+            box_key: id.sodium_box_pk.0,
+            sign_key: id.sign_pk.0,
+            signature: Vec::new(),
+        };
+        req.signature = sign::sign_detached(&req.signed_bytes(), &id.sign_sk)
+            .to_bytes()
+            .to_vec();
+        req
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut out = REQUEST_CONTEXT.to_vec();
+        self.device.encode_into(&mut out);
+        out.extend_from_slice(&self.box_key);
+        out.extend_from_slice(&self.sign_key);
+        out
+    }
+
+    pub fn verify(&self) -> Result<(), crate::error::Error> {
+        if verify_detached(&self.signed_bytes(), &self.signature, &self.sign_key) {
+            Ok(())
+        } else {
+            Err(bad_signature("link request signature does not match"))
+        }
+    }
+
+    pub fn to_uri(&self) -> Result<String, crate::error::Error> {
+        to_uri(LINK_REQUEST_SCHEME, self)
+    }
+
+    pub fn from_uri(s: &str) -> Result<Self, crate::error::Error> {
+        let req: Self = from_uri(LINK_REQUEST_SCHEME, s, "link request")?;
+        req.verify()?;
+        Ok(req)
+    }
+}
+
+/// Every certificate of an account, handed from the primary to its devices.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DeviceGrant {
+    pub certs: Vec<DeviceCert>,
+}
+
+impl DeviceGrant {
+    /// Check every certificate and that they all belong to one account; returns its key.
+    pub fn verify(&self) -> Result<[u8; 32], crate::error::Error> {
+        verify_certs(&self.certs)?
+            .ok_or_else(|| crate::error::Error::Config("device grant is empty".into()))
+    }
+
+    pub fn to_uri(&self) -> Result<String, crate::error::Error> {
+        to_uri(DEVICE_GRANT_SCHEME, self)
+    }
+
+    pub fn from_uri(s: &str) -> Result<Self, crate::error::Error> {
+        let grant: Self = from_uri(DEVICE_GRANT_SCHEME, s, "device grant")?;
+        grant.verify()?;
+        Ok(grant)
+    }
+}
+
+/// This install's place in a multi-device account.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LinkedDevices {
+    pub account_sign_key: [u8; 32],
+    pub primary: bool,
+    pub certs: Vec<DeviceCert>, // every device of the account, this one included
+}
+
+impl LinkedDevices {
+    /// Devices of the account other than `id`.
+    pub fn others<'a>(&'a self, id: &'a Identity) -> impl Iterator<Item = &'a DeviceCert> {
+        self.certs.iter().filter(move |c| c.sign_key != id.sign_pk.0)
+    }
+}
+
+/// A message one of our devices sent, copied to the others.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SentCopy {
+    pub contact_box_key: [u8; 32], // recipient contact, by its (primary) box key
+    pub body: Vec<u8>,
+    pub sent_at: u64,
+}
+
+/// Verify certificates found together (in a grant or on a card) and return the account key
+/// they share, or `None` when there are none.
+pub fn verify_certs(certs: &[DeviceCert]) -> Result<Option<[u8; 32]>, crate::error::Error> {
+    let Some(first) = certs.first() else {
+        return Ok(None);
+    };
+    for cert in certs {
+        if cert.account_sign_key != first.account_sign_key {
+            return Err(crate::error::Error::Config(
+                "device certificates belong to different accounts".into(),
+            ));
+        }
+        cert.verify()?;
+    }
+    Ok(Some(first.account_sign_key))
+}
+
+pub fn linked(data_dir: &Path) -> Result<Option<LinkedDevices>, crate::error::Error> {
+    DeviceStore::open_in_dir(data_dir)
+        .and_then(|s| s.load())
+        .map_err(crate::error::Error::Storage)
+}
+
+/// Whether this install was linked to another device's account. Sessions run between
+/// primaries, so a linked device sends signed box envelopes instead.
+pub fn is_linked_device(data_dir: &Path) -> Result<bool, crate::error::Error> {
+    Ok(linked(data_dir)?.is_some_and(|l| !l.primary))
+}
+
+/// On the primary: certify the device behind `request` and return the grant to hand back to
+/// it. The first link makes this install the primary and certifies it as `own`.
+pub fn authorize(
+    data_dir: &Path,
+    id: &Identity,
+    own: DeviceInfo,
+    request: &LinkRequest,
+) -> Result<DeviceGrant, crate::error::Error> {
+    request.verify()?;
+    if request.sign_key == id.sign_pk.0 {
+        return Err(crate::error::Error::Config(
+            "cannot link this device to itself".into(),
+        ));
+    }
+    let store = DeviceStore::open_in_dir(data_dir).map_err(crate::error::Error::Storage)?;
+    let mut linked = match store.load().map_err(crate::error::Error::Storage)? {
+        Some(l) if !l.primary => {
+            return Err(crate::error::Error::Config(
+                "only the primary device can link devices".into(),
+            ))
+        }
+        Some(l) => l,
+        None => LinkedDevices {
+            account_sign_key: id.sign_pk.0,
+            primary: true,
+            certs: Vec::new(),
+        },
+    };
+    // Re-issue our own certificate so it carries the current addresses
+    linked.certs.retain(|c| c.sign_key != id.sign_pk.0);
+    linked.certs.insert(
+        0,
+        DeviceCert::issue(id, own, id.sodium_box_pk.0, id.sign_pk.0),
+    );
+    if linked
+        .certs
+        .iter()
+        .any(|c| c.device.name == request.device.name && c.sign_key != request.sign_key)
+    {
+        return Err(crate::error::Error::Config(format!(
+            "a device named {} is already linked",
+            request.device.name
+        )));
+    }
+    linked.certs.retain(|c| c.sign_key != request.sign_key);
+    linked.certs.push(DeviceCert::issue(
+        id,
+        request.device.clone(),
+        request.box_key,
+        request.sign_key,
+    ));
+    store.save(&linked).map_err(crate::error::Error::Storage)?;
+    Ok(DeviceGrant {
+        certs: linked.certs,
+    })
+}
+
+/// On a linked device: store the grant from the primary. A newer grant from the same
+/// account replaces the device list.
+pub fn accept(
+    data_dir: &Path,
+    id: &Identity,
+    grant: &DeviceGrant,
+) -> Result<LinkedDevices, crate::error::Error> {
+    let account = grant.verify()?;
+    if account == id.sign_pk.0 {
+        return Err(crate::error::Error::Config(
+            "this grant was issued by this device".into(),
+        ));
+    }
+    if !grant
+        .certs
+        .iter()
+        .any(|c| c.sign_key == id.sign_pk.0 && c.box_key == id.sodium_box_pk.0)
+    {
+        return Err(crate::error::Error::Config(
+            "the grant does not certify this device's keys".into(),
+        ));
+    }
+    let store = DeviceStore::open_in_dir(data_dir).map_err(crate::error::Error::Storage)?;
+    if let Some(existing) = store.load().map_err(crate::error::Error::Storage)? {
+        if existing.account_sign_key != account {
+            return Err(crate::error::Error::Config(
+                "this device is already linked to another account; unlink it first".into(),
+            ));
+        }
+    }
+    let linked = LinkedDevices {
+        account_sign_key: account,
+        primary: false,
+        certs: grant.certs.clone(),
+    };
+    store.save(&linked).map_err(crate::error::Error::Storage)?;
+    Ok(linked)
+}
+
+/// On the primary: revoke the device called `name`. Contacts stop sending to it once they
+/// import a fresh contact card.
+pub fn unlink(data_dir: &Path, id: &Identity, name: &str) -> Result<bool, crate::error::Error> {
+    let store = DeviceStore::open_in_dir(data_dir).map_err(crate::error::Error::Storage)?;
+    let Some(mut linked) = store.load().map_err(crate::error::Error::Storage)? else {
+        return Ok(false);
+    };
+    if !linked.primary {
+        // A linked device can only leave the account
+        store.clear().map_err(crate::error::Error::Storage)?;
+        return Ok(true);
+    }
+    let before = linked.certs.len();
+    linked
+        .certs
+        .retain(|c| c.sign_key == id.sign_pk.0 || c.device.name != name);
+    if linked.certs.len() == before {
+        return Ok(false);
+    }
+    store.save(&linked).map_err(crate::error::Error::Storage)?;
+    Ok(true)
+}
+
+/// Our own device with box key `box_key`, if it is linked to this account.
+pub fn own_device(
+    data_dir: &Path,
+    box_key: &[u8],
+) -> Result<Option<DeviceCert>, crate::error::Error> {
+    Ok(linked(data_dir)?.and_then(|l| l.certs.into_iter().find(|c| c.box_key == box_key)))
+}
+
+/// Our own device connecting as `peer_id`, if it is linked to this account.
+pub fn own_device_by_peer(
+    data_dir: &Path,
+    peer_id: &str,
+) -> Result<Option<DeviceCert>, crate::error::Error> {
+    Ok(linked(data_dir)?.and_then(|l| {
+        l.certs
+            .into_iter()
+            .find(|c| c.device.peer_id.as_deref() == Some(peer_id))
+    }))
+}
+
+fn to_uri<T: Serialize>(scheme: &str, value: &T) -> Result<String, crate::error::Error> {
+    let bytes =
+        bincode::serialize(value).map_err(|e| crate::error::Error::Serialization(e.to_string()))?;
+    Ok(format!("{scheme}{}", bs58::encode(bytes).into_string()))
+}
+
+fn from_uri<T: serde::de::DeserializeOwned>(
+    scheme: &str,
+    s: &str,
+    what: &str,
+) -> Result<T, crate::error::Error> {
+    let s = s.trim();
+    let blob = s.strip_prefix(scheme).unwrap_or(s);
+    let bytes = bs58::decode(blob)
+        .into_vec()
+        .map_err(|e| crate::error::Error::Serialization(format!("{what}: {e}")))?;
+    bincode::deserialize(&bytes)
+        .map_err(|e| crate::error::Error::Serialization(format!("{what}: {e}")))
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    out.extend_from_slice(bytes);
+}
+
+fn verify_detached(msg: &[u8], signature: &[u8], key: &[u8; 32]) -> bool {
+    sign::Signature::from_bytes(signature)
+        .map(|s| sign::verify_detached(&s, msg, &sign::PublicKey(*key)))
+        .unwrap_or(false)
+}
+
+fn bad_signature(what: &str) -> crate::error::Error {
+    crate::error::Error::Crypto(crate::crypto::Error::Signature(what.into()))
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -0,0 +1,164 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::storage::contacts::ContactStore;
+
+/// A peer seen on the local network that is not (yet) a contact.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NearbyPeer {
+    pub peer_id: String,
+    pub addrs: Vec<String>,
+    pub last_seen: u64,
+}
+
+impl NearbyPeer {
+    /// Dialable multiaddr for the most recently seen address, including the `/p2p/` suffix.
+    pub fn dial_addr(&self) -> Option<String> {
+        self.addrs.last().map(|a| with_peer_suffix(a, &self.peer_id))
+    }
+}
+
+/// Result of applying a single mDNS discovery.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Discovery {
+    /// A known contact was seen at a new address and its `addr` was refreshed.
+    ContactUpdated { contact_id: u64, addr: String },
+    /// A known contact was seen at the address we already have.
+    ContactUnchanged { contact_id: u64 },
+    /// An unknown peer was recorded in the nearby list.
+    Nearby(NearbyPeer),
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct NearbyFile {
+    #[serde(default)]
+    peers: Vec<NearbyPeer>,
+}
+
+fn file_path(data_dir: &Path) -> PathBuf {
+    data_dir.join("nearby_peers.toml")
+}
+
+pub fn load_nearby_peers(data_dir: &Path) -> Result<Vec<NearbyPeer>, std::io::Error> {
+    let path = file_path(data_dir);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let s = fs::read_to_string(&path)?;
+    let file = toml::from_str::<NearbyFile>(&s).map_err(std::io::Error::other)?;
+    Ok(file.peers)
+}
+
+fn save_nearby_peers(data_dir: &Path, peers: Vec<NearbyPeer>) -> Result<(), std::io::Error> {
+    fs::create_dir_all(data_dir)?;
+    let s = toml::to_string_pretty(&NearbyFile { peers }).map_err(std::io::Error::other)?;
+    fs::write(file_path(data_dir), s)
+}
+
+/// Drop a peer from the nearby list (e.g. after it has been added as a contact).
+pub fn forget_nearby_peer(data_dir: &Path, peer_id: &str) -> Result<bool, std::io::Error> {
+    let mut peers = load_nearby_peers(data_dir)?;
+    let before = peers.len();
+    peers.retain(|p| p.peer_id != peer_id);
+    let removed = peers.len() != before;
+    if removed {
+        save_nearby_peers(data_dir, peers)?;
+    }
+    Ok(removed)
+}
+
+/// Match a discovered `(peer_id, addr)` against contacts by PeerId. Known contacts get
+/// their `addr` refreshed; unknown peers are remembered as nearby so a frontend can offer
+/// to add them.
+pub fn apply_discovery(
+    data_dir: &Path,
+    peer_id: &str,
+    addr: &str,
+) -> Result<Discovery, crate::error::Error> {
+    let store = ContactStore::open_in_dir(data_dir).map_err(crate::error::Error::Storage)?;
+    if let Some(contact) = store
+        .find_by_peer_id(peer_id)
+        .map_err(crate::error::Error::Storage)?
+    {
+        let dial = with_peer_suffix(addr, peer_id);
+        if contact.addr == dial {
+            return Ok(Discovery::ContactUnchanged {
+                contact_id: contact.id,
+            });
+        }
+        store
+            .update_addr(contact.id, &dial)
+            .map_err(crate::error::Error::Storage)?;
+        return Ok(Discovery::ContactUpdated {
+            contact_id: contact.id,
+            addr: dial,
+        });
+    }
+    drop(store);
+
+    let mut peers = load_nearby_peers(data_dir)?;
+    let now = now_secs();
+    let peer = match peers.iter_mut().find(|p| p.peer_id == peer_id) {
+        Some(p) => {
+            p.addrs.retain(|a| a != addr);
+            p.addrs.push(addr.to_string());
+            p.last_seen = now;
+            p.clone()
+        }
+        None => {
+            let p = NearbyPeer {
+                peer_id: peer_id.to_string(),
+                addrs: vec![addr.to_string()],
+                last_seen: now,
+            };
+            peers.push(p.clone());
+            p
+        }
+    };
+    save_nearby_peers(data_dir, peers)?;
+    Ok(Discovery::Nearby(peer))
+}
+
+/// Forget an address reported as expired by mDNS. Peers with no remaining address are removed.
+pub fn apply_expiry(data_dir: &Path, peer_id: &str, addr: &str) -> Result<(), std::io::Error> {
+    let mut peers = load_nearby_peers(data_dir)?;
+    let before: usize = peers.iter().map(|p| p.addrs.len()).sum();
+    for p in peers.iter_mut().filter(|p| p.peer_id == peer_id) {
+        p.addrs.retain(|a| a != addr);
+    }
+    peers.retain(|p| !p.addrs.is_empty());
+    let after: usize = peers.iter().map(|p| p.addrs.len()).sum();
+    if before != after {
+        save_nearby_peers(data_dir, peers)?;
+    }
+    Ok(())
+}
+
+/// Append `/p2p/<peer_id>` to a multiaddr unless it already carries one.
+pub fn with_peer_suffix(addr: &str, peer_id: &str) -> String {
+    if addr.contains("/p2p/") {
+        addr.to_string()
+    } else {
+        format!("{}/p2p/{}", addr.trim_end_matches('/'), peer_id)
+    }
+}
+
+/// Extract the PeerId from a multiaddr's trailing `/p2p/<peer_id>` component, if any.
+pub fn peer_id_from_addr(addr: &str) -> Option<&str> {
+    let (_, tail) = addr.rsplit_once("/p2p/")?;
+    let id = tail.split('/').next().unwrap_or("");
+    if id.is_empty() {
+        None
+    } else {
+        Some(id)
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
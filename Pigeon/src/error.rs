@@ -12,6 +12,12 @@ pub enum Error {
     #[error("Storage error: {0}")]
     Storage(#[from] crate::storage::Error),
 
+    #[error("Session error: {0}")]
+    Session(#[from] crate::session::Error),
+
+    #[error("Group error: {0}")]
+    Group(#[from] crate::groups::Error),
+
     #[error("Configuration error: {0}")]
     Config(String),
 
@@ -0,0 +1,731 @@
+//! Group conversations. Every member encrypts to the group with its own sender key (see
+//! `sender_key`), handed to the other members over their pairwise sessions; a group message
+//! is encrypted once and queued unchanged for each member. The member who created the group
+//! is its admin and the only one who can add or remove members.
+
+pub mod sender_key;
+
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::identity::Identity;
+use crate::messaging::message::GroupEnvelope;
+use crate::messaging::send;
+use crate::storage::contacts::{Contact, ContactStore};
+use crate::storage::groups::GroupStore;
+use crate::storage::queue::MessageQueue;
+use sender_key::{ReceiverKey, SenderKey, SenderKeyDistribution};
+
+// Prefix that marks a pairwise plaintext as a group control message rather than a chat message
+const CONTROL_MAGIC: &[u8] = b"\0pigeon-group-control-v1\0";
+
+// Chains kept per member: the current one plus the previous, for messages sent before a rekey
+const KEYS_PER_MEMBER: usize = 2;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("no such group: {0}")]
+    UnknownGroup(Uuid),
+    #[error("only the group admin can change its members")]
+    NotAdmin,
+    #[error("sender is not a member of the group")]
+    NotAMember,
+    #[error("you are no longer a member of this group")]
+    Inactive,
+    #[error("message was already received")]
+    Duplicate,
+    #[error("message skips more than {} keys", sender_key::MAX_SKIP)]
+    TooManySkipped,
+    #[error("group message does not decrypt")]
+    Decrypt,
+    #[error("group message signature does not verify")]
+    BadSignature,
+    #[error("invalid group key: {0}")]
+    InvalidKey(String),
+}
+
+/// One member as every other member knows them: by their identity keys.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GroupMember {
+    pub name: String,
+    pub public_key: [u8; 32],
+    pub sign_public_key: Option<[u8; 32]>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Group {
+    pub id: Uuid,
+    pub name: String,
+    /// Box key of the member who created the group.
+    pub admin: [u8; 32],
+    /// Bumped by the admin on every membership change; older announcements are ignored.
+    pub epoch: u64,
+    /// Everyone in the group, ourselves included.
+    pub members: Vec<GroupMember>,
+    /// False once the admin removed us; the thread is kept.
+    pub active: bool,
+    pub created_at: u64,
+    own_key: SenderKey,
+    member_keys: Vec<ReceiverKey>,
+}
+
+impl Group {
+    pub fn is_admin(&self, id: &Identity) -> bool {
+        self.admin == id.sodium_box_pk.0
+    }
+
+    /// Members other than `id`.
+    pub fn others<'a>(&'a self, id: &'a Identity) -> impl Iterator<Item = &'a GroupMember> + 'a {
+        self.members
+            .iter()
+            .filter(move |m| m.public_key != id.sodium_box_pk.0)
+    }
+
+    fn is_member(&self, public_key: &[u8]) -> bool {
+        self.members.iter().any(|m| m.public_key == public_key)
+    }
+
+    /// Keep the newest chains per member and forget those of anyone no longer in the group.
+    fn store_member_key(&mut self, key: ReceiverKey) {
+        if self.member_keys.iter().any(|k| k.sign_key == key.sign_key) {
+            return;
+        }
+        self.member_keys.insert(0, key);
+        let mut seen: Vec<[u8; 32]> = Vec::new();
+        let members: Vec<[u8; 32]> = self.members.iter().map(|m| m.public_key).collect();
+        self.member_keys.retain(|k| {
+            let count = seen.iter().filter(|m| **m == k.member).count();
+            seen.push(k.member);
+            count < KEYS_PER_MEMBER && members.contains(&k.member)
+        });
+    }
+}
+
+/// One message in a group thread.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GroupMessage {
+    pub id: Uuid,
+    pub group_id: Uuid,
+    /// Contact that sent it; `None` for our own messages.
+    pub contact_id: Option<u64>,
+    pub sender_name: String,
+    pub body: Vec<u8>,
+    pub at: u64,
+}
+
+/// Something that arrived before the group or sender key it needs, kept until it does.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum HeldItem {
+    SenderKey {
+        member: [u8; 32],
+        distribution: SenderKeyDistribution,
+    },
+    Message {
+        contact_id: u64,
+        envelope: GroupEnvelope,
+    },
+}
+
+/// Sent pairwise, inside a contact's session, prefixed with `CONTROL_MAGIC`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum GroupControl {
+    /// The admin's current view of the group.
+    Membership {
+        group_id: Uuid,
+        name: String,
+        admin: [u8; 32],
+        epoch: u64,
+        members: Vec<GroupMember>,
+    },
+    /// The sender's chain, for reading its group messages.
+    SenderKey {
+        group_id: Uuid,
+        distribution: SenderKeyDistribution,
+    },
+}
+
+impl GroupControl {
+    pub fn encode(&self) -> Result<Vec<u8>, crate::error::Error> {
+        let mut out = CONTROL_MAGIC.to_vec();
+        out.extend(
+            bincode::serialize(self)
+                .map_err(|e| crate::error::Error::Serialization(e.to_string()))?,
+        );
+        Ok(out)
+    }
+
+    /// `None` when `plaintext` is an ordinary message.
+    pub fn decode(plaintext: &[u8]) -> Option<Self> {
+        bincode::deserialize(plaintext.strip_prefix(CONTROL_MAGIC)?).ok()
+    }
+
+    pub fn group_id(&self) -> Uuid {
+        match self {
+            Self::Membership { group_id, .. } | Self::SenderKey { group_id, .. } => *group_id,
+        }
+    }
+}
+
+/// What a received control message did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GroupEvent {
+    /// We were added to a group we did not know.
+    Joined(Uuid),
+    /// Members, name or keys changed.
+    Updated(Uuid),
+    /// The admin removed us.
+    Removed(Uuid),
+    /// Kept until the group it belongs to arrives.
+    Held(Uuid),
+}
+
+/// Create a group of ourselves and `contacts`, with us as admin, and queue the membership and
+/// our sender key to each of them.
+pub fn create(
+    data_dir: &Path,
+    id: &Identity,
+    name: &str,
+    contacts: &[Contact],
+) -> Result<Group, crate::error::Error> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(crate::error::Error::Config("group name is empty".into()));
+    }
+    let mut members = vec![own_member(data_dir, id)];
+    for c in contacts {
+        let m = member_of(c)?;
+        if !members.iter().any(|x| x.public_key == m.public_key) {
+            members.push(m);
+        }
+    }
+    let group = Group {
+        id: Uuid::new_v4(),
+        name: name.to_string(),
+        admin: id.sodium_box_pk.0,
+        epoch: 1,
+        members,
+        active: true,
+        created_at: now_secs(),
+        own_key: SenderKey::generate(),
+        member_keys: Vec::new(),
+    };
+    open_store(data_dir)?
+        .save(&group)
+        .map_err(crate::error::Error::Storage)?;
+    let q = open_queue(data_dir)?;
+    let everyone: Vec<[u8; 32]> = group.others(id).map(|m| m.public_key).collect();
+    announce(data_dir, id, &q, &group, &everyone)?;
+    distribute_own_key(data_dir, id, &q, &group, &everyone)?;
+    Ok(group)
+}
+
+/// Admin only: add `contacts`. Everyone learns the new membership and the new members get our
+/// sender key; the other members send theirs when they see the change.
+pub fn add_members(
+    data_dir: &Path,
+    id: &Identity,
+    group_id: Uuid,
+    contacts: &[Contact],
+) -> Result<Group, crate::error::Error> {
+    let store = open_store(data_dir)?;
+    let mut group = load_for_admin(&store, id, group_id)?;
+    let mut added = Vec::new();
+    for c in contacts {
+        let m = member_of(c)?;
+        if !group.is_member(&m.public_key) {
+            added.push(m.public_key);
+            group.members.push(m);
+        }
+    }
+    if added.is_empty() {
+        return Ok(group);
+    }
+    group.epoch += 1;
+    store.save(&group).map_err(crate::error::Error::Storage)?;
+    drop(store);
+    let q = open_queue(data_dir)?;
+    let everyone: Vec<[u8; 32]> = group.others(id).map(|m| m.public_key).collect();
+    announce(data_dir, id, &q, &group, &everyone)?;
+    distribute_own_key(data_dir, id, &q, &group, &added)?;
+    Ok(group)
+}
+
+/// Admin only: remove the members with these box keys and rekey, so they cannot read what
+/// is sent from now on. The removed members are told they were removed.
+pub fn remove_members(
+    data_dir: &Path,
+    id: &Identity,
+    group_id: Uuid,
+    public_keys: &[[u8; 32]],
+) -> Result<Group, crate::error::Error> {
+    if public_keys.contains(&id.sodium_box_pk.0) {
+        return Err(crate::error::Error::Config(
+            "the admin cannot remove themselves from a group".into(),
+        ));
+    }
+    let store = open_store(data_dir)?;
+    let mut group = load_for_admin(&store, id, group_id)?;
+    let removed: Vec<[u8; 32]> = group
+        .members
+        .iter()
+        .map(|m| m.public_key)
+        .filter(|k| public_keys.contains(k))
+        .collect();
+    if removed.is_empty() {
+        return Ok(group);
+    }
+    group.members.retain(|m| !removed.contains(&m.public_key));
+    group.member_keys.retain(|k| !removed.contains(&k.member));
+    group.epoch += 1;
+    group.own_key = SenderKey::generate();
+    store.save(&group).map_err(crate::error::Error::Storage)?;
+    drop(store);
+    let q = open_queue(data_dir)?;
+    let remaining: Vec<[u8; 32]> = group.others(id).map(|m| m.public_key).collect();
+    let mut told = remaining.clone();
+    told.extend(&removed);
+    announce(data_dir, id, &q, &group, &told)?;
+    distribute_own_key(data_dir, id, &q, &group, &remaining)?;
+    Ok(group)
+}
+
+/// Replace our sender key and hand the new one to the other members.
+pub fn rekey(data_dir: &Path, id: &Identity, group_id: Uuid) -> Result<Group, crate::error::Error> {
+    let store = open_store(data_dir)?;
+    let mut group = load(&store, group_id)?;
+    if !group.active {
+        return Err(Error::Inactive.into());
+    }
+    group.own_key = SenderKey::generate();
+    store.save(&group).map_err(crate::error::Error::Storage)?;
+    drop(store);
+    let q = open_queue(data_dir)?;
+    let everyone: Vec<[u8; 32]> = group.others(id).map(|m| m.public_key).collect();
+    distribute_own_key(data_dir, id, &q, &group, &everyone)?;
+    Ok(group)
+}
+
+/// Encrypt `body` once and queue it for every member we can reach, then add it to the thread.
+pub fn send(
+    data_dir: &Path,
+    id: &Identity,
+    group_id: Uuid,
+    body: &[u8],
+) -> Result<GroupMessage, crate::error::Error> {
+    let store = open_store(data_dir)?;
+    let mut group = load(&store, group_id)?;
+    if !group.active {
+        return Err(Error::Inactive.into());
+    }
+    let env = group.own_key.encrypt(group.id, body)?;
+    // The chain moved; persist it before anything leaves
+    store.save(&group).map_err(crate::error::Error::Storage)?;
+    let payload =
+        bincode::serialize(&env).map_err(|e| crate::error::Error::Serialization(e.to_string()))?;
+    let q = open_queue(data_dir)?;
+    for member in group.others(id) {
+        match contact_for(data_dir, &member.public_key)? {
+            Some(c) => {
+                send::enqueue_on(&q, c.id, None, payload.clone(), 1)?;
+            }
+            None => log::warn!(
+                "group {}: member {} is not a contact; not sent to them",
+                group.name,
+                member.name
+            ),
+        }
+    }
+    let message = GroupMessage {
+        id: Uuid::new_v4(),
+        group_id,
+        contact_id: None,
+        sender_name: own_member(data_dir, id).name,
+        body: body.to_vec(),
+        at: now_secs(),
+    };
+    store
+        .append_message(&message)
+        .map_err(crate::error::Error::Storage)?;
+    Ok(message)
+}
+
+pub fn list(data_dir: &Path) -> Result<Vec<Group>, crate::error::Error> {
+    open_store(data_dir)?
+        .list()
+        .map_err(crate::error::Error::Storage)
+}
+
+pub fn get(data_dir: &Path, group_id: Uuid) -> Result<Option<Group>, crate::error::Error> {
+    open_store(data_dir)?
+        .get(group_id)
+        .map_err(crate::error::Error::Storage)
+}
+
+pub fn thread(data_dir: &Path, group_id: Uuid) -> Result<Vec<GroupMessage>, crate::error::Error> {
+    open_store(data_dir)?
+        .thread(group_id)
+        .map_err(crate::error::Error::Storage)
+}
+
+/// Apply a control message that `contact` sent us pairwise. `q` is the open queue that any
+/// replies (our sender key for new members, or a new one after a removal) are added to.
+pub fn handle_control(
+    data_dir: &Path,
+    id: &Identity,
+    q: &MessageQueue,
+    contact: &Contact,
+    control: GroupControl,
+) -> Result<GroupEvent, crate::error::Error> {
+    let store = open_store(data_dir)?;
+    match control {
+        GroupControl::Membership {
+            group_id,
+            name,
+            admin,
+            epoch,
+            members,
+        } => {
+            if contact.public_key != admin {
+                return Err(Error::NotAdmin.into());
+            }
+            let existing = store.get(group_id).map_err(crate::error::Error::Storage)?;
+            if let Some(g) = &existing {
+                if g.admin != admin {
+                    return Err(Error::NotAdmin.into());
+                }
+                if epoch <= g.epoch {
+                    return Ok(GroupEvent::Updated(group_id));
+                }
+            }
+            let me = id.sodium_box_pk.0;
+            let Some(mut group) = existing else {
+                if !members.iter().any(|m| m.public_key == me) {
+                    return Err(Error::NotAMember.into());
+                }
+                let group = Group {
+                    id: group_id,
+                    name,
+                    admin,
+                    epoch,
+                    members,
+                    active: true,
+                    created_at: now_secs(),
+                    own_key: SenderKey::generate(),
+                    member_keys: Vec::new(),
+                };
+                store.save(&group).map_err(crate::error::Error::Storage)?;
+                drop(store);
+                let everyone: Vec<[u8; 32]> = group.others(id).map(|m| m.public_key).collect();
+                distribute_own_key(data_dir, id, q, &group, &everyone)?;
+                release_held(data_dir, group_id)?;
+                return Ok(GroupEvent::Joined(group_id));
+            };
+            let old: Vec<[u8; 32]> = group.members.iter().map(|m| m.public_key).collect();
+            let removed = old.iter().any(|k| !members.iter().any(|m| m.public_key == *k));
+            let added: Vec<[u8; 32]> = members
+                .iter()
+                .map(|m| m.public_key)
+                .filter(|k| *k != me && !old.contains(k))
+                .collect();
+            group.name = name;
+            group.epoch = epoch;
+            group.members = members;
+            if !group.is_member(&me) {
+                group.active = false;
+                group.member_keys.clear();
+                store.save(&group).map_err(crate::error::Error::Storage)?;
+                return Ok(GroupEvent::Removed(group_id));
+            }
+            let rejoined = !group.active;
+            group.active = true;
+            let everyone: Vec<[u8; 32]> = group.others(id).map(|m| m.public_key).collect();
+            group
+                .member_keys
+                .retain(|k| everyone.contains(&k.member));
+            // After a removal everyone rekeys, so the removed member cannot read on
+            let recipients = if removed || rejoined {
+                group.own_key = SenderKey::generate();
+                everyone
+            } else {
+                added
+            };
+            store.save(&group).map_err(crate::error::Error::Storage)?;
+            drop(store);
+            distribute_own_key(data_dir, id, q, &group, &recipients)?;
+            release_held(data_dir, group_id)?;
+            Ok(GroupEvent::Updated(group_id))
+        }
+        GroupControl::SenderKey {
+            group_id,
+            distribution,
+        } => {
+            let member = member_key(contact)?;
+            let Some(mut group) = store.get(group_id).map_err(crate::error::Error::Storage)?
+            else {
+                store
+                    .hold(
+                        group_id,
+                        &HeldItem::SenderKey {
+                            member,
+                            distribution,
+                        },
+                    )
+                    .map_err(crate::error::Error::Storage)?;
+                return Ok(GroupEvent::Held(group_id));
+            };
+            if !group.is_member(&member) {
+                return Err(Error::NotAMember.into());
+            }
+            group.store_member_key(ReceiverKey::new(member, &distribution));
+            store.save(&group).map_err(crate::error::Error::Storage)?;
+            drop(store);
+            release_held(data_dir, group_id)?;
+            Ok(GroupEvent::Updated(group_id))
+        }
+    }
+}
+
+/// Decrypt a group message `contact` sent and add it to the thread. `None` when it was held
+/// because its group or sender key has not arrived yet.
+pub fn handle_envelope(
+    data_dir: &Path,
+    contact: &Contact,
+    env: &GroupEnvelope,
+) -> Result<Option<GroupMessage>, crate::error::Error> {
+    let store = open_store(data_dir)?;
+    let member = member_key(contact)?;
+    let Some(mut group) = store.get(env.group_id).map_err(crate::error::Error::Storage)? else {
+        hold_message(&store, contact, env)?;
+        return Ok(None);
+    };
+    if !group.active {
+        return Err(Error::Inactive.into());
+    }
+    if !group.is_member(&member) {
+        return Err(Error::NotAMember.into());
+    }
+    let Some(key) = group
+        .member_keys
+        .iter_mut()
+        .find(|k| k.member == member && k.sign_key == env.sender_key)
+    else {
+        hold_message(&store, contact, env)?;
+        return Ok(None);
+    };
+    let body = key.decrypt(env)?;
+    store.save(&group).map_err(crate::error::Error::Storage)?;
+    let message = GroupMessage {
+        id: Uuid::new_v4(),
+        group_id: group.id,
+        contact_id: Some(contact.id),
+        sender_name: contact.name.clone(),
+        body,
+        at: now_secs(),
+    };
+    store
+        .append_message(&message)
+        .map_err(crate::error::Error::Storage)?;
+    Ok(Some(message))
+}
+
+/// Retry what was held for a group now that it or one of its keys arrived.
+fn release_held(data_dir: &Path, group_id: Uuid) -> Result<(), crate::error::Error> {
+    let store = open_store(data_dir)?;
+    let held = store
+        .take_held(group_id)
+        .map_err(crate::error::Error::Storage)?;
+    if held.is_empty() {
+        return Ok(());
+    }
+    let Some(mut group) = store.get(group_id).map_err(crate::error::Error::Storage)? else {
+        return Ok(());
+    };
+    // Keys first, so messages waiting on them open in the same pass
+    let (keys, messages): (Vec<_>, Vec<_>) = held
+        .into_iter()
+        .partition(|h| matches!(h, HeldItem::SenderKey { .. }));
+    for item in keys {
+        if let HeldItem::SenderKey {
+            member,
+            distribution,
+        } = item
+        {
+            if group.is_member(&member) {
+                group.store_member_key(ReceiverKey::new(member, &distribution));
+            }
+        }
+    }
+    store.save(&group).map_err(crate::error::Error::Storage)?;
+    drop(store);
+    for item in messages {
+        let HeldItem::Message {
+            contact_id,
+            envelope,
+        } = item
+        else {
+            continue;
+        };
+        let contact = ContactStore::open_in_dir(data_dir)
+            .and_then(|s| s.get(contact_id))
+            .map_err(crate::error::Error::Storage)?;
+        let Some(contact) = contact else {
+            continue;
+        };
+        if let Err(e) = handle_envelope(data_dir, &contact, &envelope) {
+            log::warn!("held group message from {} dropped: {e}", contact.name);
+        }
+    }
+    Ok(())
+}
+
+fn hold_message(
+    store: &GroupStore,
+    contact: &Contact,
+    env: &GroupEnvelope,
+) -> Result<(), crate::error::Error> {
+    store
+        .hold(
+            env.group_id,
+            &HeldItem::Message {
+                contact_id: contact.id,
+                envelope: env.clone(),
+            },
+        )
+        .map_err(crate::error::Error::Storage)
+}
+
+/// Queue the admin's view of the group to each of `recipients` (box keys).
+fn announce(
+    data_dir: &Path,
+    id: &Identity,
+    q: &MessageQueue,
+    group: &Group,
+    recipients: &[[u8; 32]],
+) -> Result<(), crate::error::Error> {
+    let control = GroupControl::Membership {
+        group_id: group.id,
+        name: group.name.clone(),
+        admin: group.admin,
+        epoch: group.epoch,
+        members: group.members.clone(),
+    };
+    send_control(data_dir, id, q, recipients, &control)
+}
+
+fn distribute_own_key(
+    data_dir: &Path,
+    id: &Identity,
+    q: &MessageQueue,
+    group: &Group,
+    recipients: &[[u8; 32]],
+) -> Result<(), crate::error::Error> {
+    let control = GroupControl::SenderKey {
+        group_id: group.id,
+        distribution: group.own_key.distribution(),
+    };
+    send_control(data_dir, id, q, recipients, &control)
+}
+
+/// Queue `control` inside each recipient's pairwise session, ahead of ordinary messages so
+/// keys arrive before what they unlock.
+fn send_control(
+    data_dir: &Path,
+    id: &Identity,
+    q: &MessageQueue,
+    recipients: &[[u8; 32]],
+    control: &GroupControl,
+) -> Result<(), crate::error::Error> {
+    let plaintext = control.encode()?;
+    for key in recipients {
+        let Some(contact) = contact_for(data_dir, key)? else {
+            log::warn!(
+                "group {}: a member is not a contact; they will not get this update",
+                control.group_id()
+            );
+            continue;
+        };
+        let payload = send::encode_for_contact(data_dir, id, &contact, &plaintext)?;
+        send::enqueue_on(q, contact.id, None, payload, 0)?;
+    }
+    Ok(())
+}
+
+fn load(store: &GroupStore, group_id: Uuid) -> Result<Group, crate::error::Error> {
+    store
+        .get(group_id)
+        .map_err(crate::error::Error::Storage)?
+        .ok_or_else(|| Error::UnknownGroup(group_id).into())
+}
+
+fn load_for_admin(
+    store: &GroupStore,
+    id: &Identity,
+    group_id: Uuid,
+) -> Result<Group, crate::error::Error> {
+    let group = load(store, group_id)?;
+    if !group.is_admin(id) {
+        return Err(Error::NotAdmin.into());
+    }
+    Ok(group)
+}
+
+fn contact_for(data_dir: &Path, public_key: &[u8; 32]) -> Result<Option<Contact>, crate::error::Error> {
+    Ok(ContactStore::open_in_dir(data_dir)
+        .and_then(|s| s.list())
+        .map_err(crate::error::Error::Storage)?
+        .into_iter()
+        .find(|c| c.public_key == public_key))
+}
+
+fn member_key(contact: &Contact) -> Result<[u8; 32], crate::error::Error> {
+    contact
+        .public_key
+        .as_slice()
+        .try_into()
+        .map_err(|_| crate::error::Error::Config("contact has invalid pubkey".into()))
+}
+
+fn member_of(contact: &Contact) -> Result<GroupMember, crate::error::Error> {
+    Ok(GroupMember {
+        name: contact.name.clone(),
+        public_key: member_key(contact)?,
+        sign_public_key: contact
+            .sign_public_key
+            .as_deref()
+            .and_then(|k| k.try_into().ok()),
+    })
+}
+
+fn own_member(data_dir: &Path, id: &Identity) -> GroupMember {
+    let name = crate::settings::load_profile(data_dir)
+        .map(|p| p.display_name)
+        .unwrap_or_default();
+    GroupMember {
+        name: if name.trim().is_empty() {
+            "Pigeon user".to_string()
+        } else {
+            name
+        },
+        public_key: id.sodium_box_pk.0,
+        sign_public_key: Some(id.sign_pk.0),
+    }
+}
+
+fn open_store(data_dir: &Path) -> Result<GroupStore, crate::error::Error> {
+    GroupStore::open_in_dir(data_dir).map_err(crate::error::Error::Storage)
+}
+
+fn open_queue(data_dir: &Path) -> Result<MessageQueue, crate::error::Error> {
+    MessageQueue::open_in_dir(data_dir).map_err(crate::error::Error::Storage)
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
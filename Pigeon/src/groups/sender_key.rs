@@ -0,0 +1,196 @@
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sodiumoxide::crypto::aead::xchacha20poly1305_ietf as aead;
+use sodiumoxide::crypto::sign;
+use uuid::Uuid;
+
+use super::Error;
+use crate::messaging::message::{GroupEnvelope, GROUP_ENVELOPE_VERSION};
+use crate::session::hkdf;
+
+/// Most message keys we derive ahead when an envelope skips forward.
+pub const MAX_SKIP: u32 = 1000;
+// Upper bound on stored skipped keys per chain; oldest are dropped first
+const MAX_SKIPPED_KEYS: usize = 2000;
+
+const MESSAGE_INFO: &[u8] = b"pigeon-sender-key-message";
+
+/// Our sending chain in one group. The chain key moves forward with every message, so a
+/// member that learns it later cannot read what was sent before.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SenderKey {
+    chain_key: [u8; 32],
+    iteration: u32,
+    sign_public: [u8; 32],
+    sign_secret: Vec<u8>,
+}
+
+/// What a member needs to read our chain from its current position on.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SenderKeyDistribution {
+    pub chain_key: [u8; 32],
+    pub iteration: u32,
+    pub sign_key: [u8; 32],
+}
+
+/// Another member's chain as far as we have read it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ReceiverKey {
+    /// Box key of the member the chain belongs to.
+    pub member: [u8; 32],
+    pub sign_key: [u8; 32],
+    chain_key: [u8; 32],
+    iteration: u32,
+    skipped: Vec<(u32, [u8; 32])>,
+}
+
+impl SenderKey {
+    pub fn generate() -> Self {
+        let (pk, sk) = sign::gen_keypair();
+        let mut chain_key = [0u8; 32];
+        chain_key.copy_from_slice(&sodiumoxide::randombytes::randombytes(32));
+        Self {
+            chain_key,
+            iteration: 0,
+            sign_public: pk.0,
+            sign_secret: sk.0.to_vec(),
+        }
+    }
+
+    pub fn sign_key(&self) -> [u8; 32] {
+        self.sign_public
+    }
+
+    pub fn distribution(&self) -> SenderKeyDistribution {
+        SenderKeyDistribution {
+            chain_key: self.chain_key,
+            iteration: self.iteration,
+            sign_key: self.sign_public,
+        }
+    }
+
+    /// Encrypt and sign one message for `group_id`, advancing the chain.
+    pub fn encrypt(&mut self, group_id: Uuid, plaintext: &[u8]) -> Result<GroupEnvelope, Error> {
+        let sign_sk = sign::SecretKey::from_slice(&self.sign_secret)
+            .ok_or_else(|| Error::InvalidKey("sender signing key".into()))?;
+        let (next, mk) = kdf_chain(&self.chain_key);
+        let mut env = GroupEnvelope {
+            version: GROUP_ENVELOPE_VERSION,
+            group_id,
+            sender_key: self.sign_public,
+            iteration: self.iteration,
+            sent_at: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            payload: Vec::new(),
+            signature: Vec::new(),
+        };
+        env.payload = seal(&mk, &associated_data(&env), plaintext);
+        env.signature = sign::sign_detached(&env.signed_bytes(), &sign_sk)
+            .to_bytes()
+            .to_vec();
+        self.chain_key = next;
+        self.iteration = self
+            .iteration
+            .checked_add(1)
+            .ok_or_else(|| Error::InvalidKey("sender key exhausted; rekey the group".into()))?;
+        Ok(env)
+    }
+}
+
+impl ReceiverKey {
+    pub fn new(member: [u8; 32], dist: &SenderKeyDistribution) -> Self {
+        Self {
+            member,
+            sign_key: dist.sign_key,
+            chain_key: dist.chain_key,
+            iteration: dist.iteration,
+            skipped: Vec::new(),
+        }
+    }
+
+    /// Check the envelope's signature and decrypt it, keeping keys for skipped iterations.
+    pub fn decrypt(&mut self, env: &GroupEnvelope) -> Result<Vec<u8>, Error> {
+        let verified = sign::Signature::from_bytes(&env.signature)
+            .map(|s| {
+                sign::verify_detached(&s, &env.signed_bytes(), &sign::PublicKey(self.sign_key))
+            })
+            .unwrap_or(false);
+        if env.sender_key != self.sign_key || !verified {
+            return Err(Error::BadSignature);
+        }
+        let ad = associated_data(env);
+        if env.iteration < self.iteration {
+            let pos = self
+                .skipped
+                .iter()
+                .position(|(n, _)| *n == env.iteration)
+                .ok_or(Error::Duplicate)?;
+            let plaintext = open(&self.skipped[pos].1, &ad, &env.payload)?;
+            self.skipped.remove(pos);
+            return Ok(plaintext);
+        }
+        if env.iteration - self.iteration > MAX_SKIP {
+            return Err(Error::TooManySkipped);
+        }
+        // Work on copies so a forged payload cannot move the chain
+        let mut chain_key = self.chain_key;
+        let mut skipped = Vec::new();
+        for n in self.iteration..env.iteration {
+            let (next, mk) = kdf_chain(&chain_key);
+            skipped.push((n, mk));
+            chain_key = next;
+        }
+        let (next, mk) = kdf_chain(&chain_key);
+        let plaintext = open(&mk, &ad, &env.payload)?;
+        self.chain_key = next;
+        self.iteration = env.iteration + 1;
+        self.skipped.extend(skipped);
+        if self.skipped.len() > MAX_SKIPPED_KEYS {
+            let excess = self.skipped.len() - MAX_SKIPPED_KEYS;
+            self.skipped.drain(..excess);
+        }
+        Ok(plaintext)
+    }
+}
+
+fn associated_data(env: &GroupEnvelope) -> Vec<u8> {
+    let mut ad = vec![env.version];
+    ad.extend_from_slice(env.group_id.as_bytes());
+    ad.extend_from_slice(&env.sender_key);
+    ad.extend_from_slice(&env.iteration.to_be_bytes());
+    ad.extend_from_slice(&env.sent_at.to_be_bytes());
+    ad
+}
+
+/// Advance a chain key: returns (next chain key, message key).
+fn kdf_chain(chain_key: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let step = |byte: u8| {
+        let mut mac =
+            <Hmac<Sha256> as Mac>::new_from_slice(chain_key).expect("HMAC accepts any key size");
+        mac.update(&[byte]);
+        let mut out = [0u8; 32];
+        out.copy_from_slice(&mac.finalize().into_bytes());
+        out
+    };
+    (step(0x02), step(0x01))
+}
+
+fn message_key_material(mk: &[u8; 32]) -> (aead::Key, aead::Nonce) {
+    let okm = hkdf(&[0u8; 32], mk, MESSAGE_INFO, aead::KEYBYTES + aead::NONCEBYTES);
+    let key = aead::Key::from_slice(&okm[..aead::KEYBYTES]).expect("key length");
+    let nonce = aead::Nonce::from_slice(&okm[aead::KEYBYTES..]).expect("nonce length");
+    (key, nonce)
+}
+
+fn seal(mk: &[u8; 32], ad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let (key, nonce) = message_key_material(mk);
+    aead::seal(plaintext, Some(ad), &nonce, &key)
+}
+
+fn open(mk: &[u8; 32], ad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+    let (key, nonce) = message_key_material(mk);
+    aead::open(ciphertext, Some(ad), &nonce, &key).map_err(|_| Error::Decrypt)
+}
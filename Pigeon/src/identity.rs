@@ -1,5 +1,4 @@
 use std::fs;
-use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 
 #[cfg(feature = "network")]
@@ -29,96 +28,261 @@ struct StoredIdentity {
     sign_sk: Vec<u8>,
 }
 
+const IDENTITY_FILE: &str = "identity.bin";
+
+// `identity.bin` starts with this magic and a format version byte. Files without it hold
+// the unversioned format 1 layout, a bare bincode `StoredIdentity`.
+const IDENTITY_MAGIC: &[u8; 3] = b"PGI";
+
+/// The `identity.bin` format this build writes.
+pub const IDENTITY_FORMAT: u8 = 2;
+
+/// Format version of plaintext `identity.bin` bytes.
+pub fn format_version(buf: &[u8]) -> u8 {
+    match buf.strip_prefix(IDENTITY_MAGIC.as_slice()) {
+        Some([version, ..]) => *version,
+        _ => 1,
+    }
+}
+
+fn encode(stored: &StoredIdentity) -> Result<Vec<u8>, crate::error::Error> {
+    let mut out = IDENTITY_MAGIC.to_vec();
+    out.push(IDENTITY_FORMAT);
+    bincode::serialize_into(&mut out, stored)
+        .map_err(|e| crate::error::Error::Config(e.to_string()))?;
+    Ok(out)
+}
+
+fn decode_stored(buf: &[u8]) -> Result<StoredIdentity, crate::error::Error> {
+    let body = match format_version(buf) {
+        1 => buf,
+        // Format 2 only added the header
+        2 => &buf[IDENTITY_MAGIC.len() + 1..],
+        v => {
+            return Err(crate::error::Error::Config(format!(
+                "identity.bin has format {v}, newer than the {IDENTITY_FORMAT} this build reads"
+            )))
+        }
+    };
+    bincode::deserialize(body).map_err(|e| crate::error::Error::Config(e.to_string()))
+}
+
 impl Identity {
+    /// Load `identity.bin`, generating it on first run. Once a passphrase is set the file is
+    /// sealed under the passphrase key (see `storage::at_rest`), so loading needs the store
+    /// unlocked; a plaintext file from before is sealed on the next unlocked load.
     pub fn load_or_generate(data_dir: &Path) -> Result<Self, crate::error::Error> {
         fs::create_dir_all(data_dir).map_err(|e| crate::error::Error::Config(e.to_string()))?;
-        let path = data_dir.join("identity.bin");
+        let path = data_dir.join(IDENTITY_FILE);
         if path.exists() {
-            let mut f =
-                fs::File::open(&path).map_err(|e| crate::error::Error::Config(e.to_string()))?;
-            let mut buf = Vec::new();
-            f.read_to_end(&mut buf)
-                .map_err(|e| crate::error::Error::Config(e.to_string()))?;
-            let stored: StoredIdentity = bincode::deserialize(&buf)
-                .map_err(|e| crate::error::Error::Config(e.to_string()))?;
+            let buf = crate::storage::at_rest::read_sealed_file(data_dir, IDENTITY_FILE)
+                .map_err(crate::error::Error::Storage)?;
+            let id = Self::decode(&buf)?;
+            if format_version(&buf) < IDENTITY_FORMAT {
+                Self::upgrade_file(data_dir, &buf)?;
+            }
+            Ok(id)
+        } else {
+            let (id, bytes) = Self::generate()?;
+            // Owner-only perms; sealed when a passphrase is set
+            crate::storage::at_rest::write_sealed_file(data_dir, IDENTITY_FILE, &bytes)
+                .map_err(crate::error::Error::Storage)?;
+            Ok(id)
+        }
+    }
 
-            #[cfg(feature = "network")]
-            let libp2p = {
-                if !stored.libp2p_ed25519.is_empty() {
-                    let mut bytes = stored.libp2p_ed25519.clone();
-                    let ed = identity::ed25519::Keypair::try_from_bytes(bytes.as_mut_slice())
-                        .map_err(|e| crate::error::Error::Config(format!("ed25519 decode: {e}")))?;
-                    identity::Keypair::from(ed)
-                } else {
-                    let ed = identity::ed25519::Keypair::generate();
-                    identity::Keypair::from(ed)
-                }
-            };
-
-            let sodium_box_pk =
-                sodiumoxide::crypto::box_::PublicKey::from_slice(&stored.sodium_box_pk)
-                    .ok_or_else(|| crate::error::Error::Config("invalid sodium pk".to_string()))?;
-            let sodium_box_sk =
-                sodiumoxide::crypto::box_::SecretKey::from_slice(&stored.sodium_box_sk)
-                    .ok_or_else(|| crate::error::Error::Config("invalid sodium sk".to_string()))?;
-            let sign_pk = sodiumoxide::crypto::sign::PublicKey::from_slice(&stored.sign_pk)
-                .ok_or_else(|| crate::error::Error::Config("invalid sign pk".to_string()))?;
-            let sign_sk = sodiumoxide::crypto::sign::SecretKey::from_slice(&stored.sign_sk)
-                .ok_or_else(|| crate::error::Error::Config("invalid sign sk".to_string()))?;
-
-            Ok(Self {
+    /// Fresh keys and their plaintext `identity.bin` encoding.
+    fn generate() -> Result<(Self, Vec<u8>), crate::error::Error> {
+        #[cfg(feature = "network")]
+        let ed = identity::ed25519::Keypair::generate();
+        #[cfg(feature = "network")]
+        let libp2p = identity::Keypair::from(ed.clone());
+        #[cfg(feature = "network")]
+        let ed_bytes: Vec<u8> = ed.to_bytes().to_vec();
+        #[cfg(not(feature = "network"))]
+        let ed_bytes: Vec<u8> = Vec::new();
+        let (sodium_box_pk, sodium_box_sk) = sodiumoxide::crypto::box_::gen_keypair();
+        let (sign_pk, sign_sk) = sodiumoxide::crypto::sign::gen_keypair();
+
+        let stored = StoredIdentity {
+            libp2p_ed25519: ed_bytes,
+            sodium_box_pk: sodium_box_pk.0.to_vec(),
+            sodium_box_sk: sodium_box_sk.0.to_vec(),
+            sign_pk: sign_pk.0.to_vec(),
+            sign_sk: sign_sk.0.to_vec(),
+        };
+        let bytes = encode(&stored)?;
+        Ok((
+            Self {
                 #[cfg(feature = "network")]
                 libp2p,
                 sodium_box_pk,
                 sodium_box_sk,
                 sign_pk,
                 sign_sk,
-            })
-        } else {
-            // Generate fresh identity
-            #[cfg(feature = "network")]
-            let ed = identity::ed25519::Keypair::generate();
-            #[cfg(feature = "network")]
-            let libp2p = identity::Keypair::from(ed.clone());
-            #[cfg(feature = "network")]
-            let ed_bytes: Vec<u8> = ed.to_bytes().to_vec();
-            #[cfg(not(feature = "network"))]
-            let ed_bytes: Vec<u8> = Vec::new();
-            let (sodium_box_pk, sodium_box_sk) = sodiumoxide::crypto::box_::gen_keypair();
-            let (sign_pk, sign_sk) = sodiumoxide::crypto::sign::gen_keypair();
-
-            let stored = StoredIdentity {
-                libp2p_ed25519: ed_bytes,
-                sodium_box_pk: sodium_box_pk.0.to_vec(),
-                sodium_box_sk: sodium_box_sk.0.to_vec(),
-                sign_pk: sign_pk.0.to_vec(),
-                sign_sk: sign_sk.0.to_vec(),
-            };
-            let bytes = bincode::serialize(&stored)
-                .map_err(|e| crate::error::Error::Config(e.to_string()))?;
-            let mut f =
-                fs::File::create(&path).map_err(|e| crate::error::Error::Config(e.to_string()))?;
-            // Best-effort restrictive perms
-            #[cfg(unix)]
-            {
-                use std::os::unix::fs::PermissionsExt;
-                let _ = fs::set_permissions(&path, fs::Permissions::from_mode(0o600));
+            },
+            bytes,
+        ))
+    }
+
+    /// Replace the identity in `data_dir` with freshly generated keys and return them. The
+    /// caller is responsible for announcing the change (see `rotation`).
+    pub fn regenerate(data_dir: &Path) -> Result<Self, crate::error::Error> {
+        fs::create_dir_all(data_dir).map_err(|e| crate::error::Error::Config(e.to_string()))?;
+        let (id, bytes) = Self::generate()?;
+        crate::storage::at_rest::write_sealed_file(data_dir, IDENTITY_FILE, &bytes)
+            .map_err(crate::error::Error::Storage)?;
+        Ok(id)
+    }
+
+    /// Rewrite an older-format `identity.bin` in the current format, keeping a copy of the
+    /// file as it was (still sealed, if it was) in `schema_backups/` first.
+    fn upgrade_file(data_dir: &Path, buf: &[u8]) -> Result<(), crate::error::Error> {
+        let stored = decode_stored(buf)?;
+        let dir = data_dir.join(crate::storage::schema::BACKUP_DIR);
+        fs::create_dir_all(&dir).map_err(|e| crate::error::Error::Config(e.to_string()))?;
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let original = fs::read(data_dir.join(IDENTITY_FILE))?;
+        let backup = dir.join(format!("{IDENTITY_FILE}-v{}-{now}", format_version(buf)));
+        crate::storage::at_rest::write_private(&backup, &original)
+            .map_err(crate::error::Error::Storage)?;
+        crate::storage::at_rest::write_sealed_file(data_dir, IDENTITY_FILE, &encode(&stored)?)
+            .map_err(crate::error::Error::Storage)
+    }
+
+    /// Format version of the `identity.bin` in `data_dir`, if there is one. Needs the store
+    /// unlocked once a passphrase is set.
+    pub fn stored_format(data_dir: &Path) -> Result<Option<u8>, crate::error::Error> {
+        if !data_dir.join(IDENTITY_FILE).exists() {
+            return Ok(None);
+        }
+        let buf = crate::storage::at_rest::read_sealed_file(data_dir, IDENTITY_FILE)
+            .map_err(crate::error::Error::Storage)?;
+        Ok(Some(format_version(&buf)))
+    }
+
+    /// Parse plaintext `identity.bin` bytes of any format this build knows.
+    fn decode(buf: &[u8]) -> Result<Self, crate::error::Error> {
+        let stored = decode_stored(buf)?;
+        #[cfg(feature = "network")]
+        let libp2p = {
+            if !stored.libp2p_ed25519.is_empty() {
+                let mut bytes = stored.libp2p_ed25519.clone();
+                let ed = identity::ed25519::Keypair::try_from_bytes(bytes.as_mut_slice())
+                    .map_err(|e| crate::error::Error::Config(format!("ed25519 decode: {e}")))?;
+                identity::Keypair::from(ed)
+            } else {
+                let ed = identity::ed25519::Keypair::generate();
+                identity::Keypair::from(ed)
             }
-            f.write_all(&bytes)
-                .map_err(|e| crate::error::Error::Config(e.to_string()))?;
-            Ok(Self {
-                #[cfg(feature = "network")]
-                libp2p,
-                sodium_box_pk,
-                sodium_box_sk,
-                sign_pk,
-                sign_sk,
-            })
+        };
+
+        let sodium_box_pk =
+            sodiumoxide::crypto::box_::PublicKey::from_slice(&stored.sodium_box_pk)
+                .ok_or_else(|| crate::error::Error::Config("invalid sodium pk".to_string()))?;
+        let sodium_box_sk =
+            sodiumoxide::crypto::box_::SecretKey::from_slice(&stored.sodium_box_sk)
+                .ok_or_else(|| crate::error::Error::Config("invalid sodium sk".to_string()))?;
+        let sign_pk = sodiumoxide::crypto::sign::PublicKey::from_slice(&stored.sign_pk)
+            .ok_or_else(|| crate::error::Error::Config("invalid sign pk".to_string()))?;
+        let sign_sk = sodiumoxide::crypto::sign::SecretKey::from_slice(&stored.sign_sk)
+            .ok_or_else(|| crate::error::Error::Config("invalid sign sk".to_string()))?;
+
+        Ok(Self {
+            #[cfg(feature = "network")]
+            libp2p,
+            sodium_box_pk,
+            sodium_box_sk,
+            sign_pk,
+            sign_sk,
+        })
+    }
+
+    /// Plaintext `identity.bin` bytes for export (unsealed if a passphrase is set).
+    pub fn export_bytes(data_dir: &Path) -> Result<Vec<u8>, crate::error::Error> {
+        let buf = crate::storage::at_rest::read_sealed_file(data_dir, IDENTITY_FILE)
+            .map_err(crate::error::Error::Storage)?;
+        // Refuse to export something we could not load again
+        Self::decode(&buf)?;
+        Ok(buf)
+    }
+
+    /// Validate and install plaintext identity bytes (e.g. from an export), sealing them if a
+    /// passphrase is set. Refuses to replace an existing identity unless `overwrite` is set.
+    pub fn install_bytes(
+        data_dir: &Path,
+        bytes: &[u8],
+        overwrite: bool,
+    ) -> Result<Self, crate::error::Error> {
+        let id = Self::decode(bytes)?;
+        if data_dir.join(IDENTITY_FILE).exists() && !overwrite {
+            return Err(crate::error::Error::Config(
+                "an identity already exists here; pass --force to replace it".into(),
+            ));
         }
+        crate::storage::at_rest::write_sealed_file(data_dir, IDENTITY_FILE, bytes)
+            .map_err(crate::error::Error::Storage)?;
+        Ok(id)
+    }
+
+    /// Generate a signed prekey: a fresh box keypair whose public half is signed with our
+    /// ed25519 key.
+    pub fn generate_signed_prekey(
+        &self,
+        id: u32,
+        created_at: u64,
+    ) -> crate::session::prekeys::SignedPreKey {
+        let (public, secret) = crate::session::prekeys::new_keypair();
+        let sig = sodiumoxide::crypto::sign::sign_detached(
+            &crate::session::x3dh::signed_prekey_bytes(id, &public),
+            &self.sign_sk,
+        );
+        crate::session::prekeys::SignedPreKey {
+            id,
+            public,
+            secret,
+            signature: sig.to_bytes().to_vec(),
+            created_at,
+        }
+    }
+
+    /// Generate `count` one-time prekeys with consecutive ids starting at `first_id`.
+    pub fn generate_one_time_prekeys(
+        &self,
+        first_id: u32,
+        count: usize,
+    ) -> Vec<crate::session::prekeys::OneTimePreKey> {
+        (0..count as u32)
+            .map(|i| {
+                let (public, secret) = crate::session::prekeys::new_keypair();
+                crate::session::prekeys::OneTimePreKey {
+                    id: first_id.wrapping_add(i),
+                    public,
+                    secret,
+                    issued: false,
+                }
+            })
+            .collect()
+    }
+
+    /// Our prekey bundle for a peer, creating or rotating prekeys as needed.
+    pub fn prekey_bundle(
+        &self,
+        data_dir: &Path,
+    ) -> Result<crate::session::x3dh::PreKeyBundle, crate::error::Error> {
+        crate::session::prekeys::local_bundle(data_dir, self)
+    }
+
+    /// Replace the signed prekey now; returns the new prekey id.
+    pub fn rotate_signed_prekey(&self, data_dir: &Path) -> Result<u32, crate::error::Error> {
+        crate::session::prekeys::rotate(data_dir, self)
     }
 
     #[allow(dead_code)]
     pub fn file_path(base: &Path) -> PathBuf {
-        base.join("identity.bin")
+        base.join(IDENTITY_FILE)
     }
 }
@@ -1,12 +1,20 @@
+pub mod archive;
+pub mod backup;
 pub mod config;
+pub mod contact_card;
 pub mod crypto;
+pub mod devices;
+pub mod discovery;
 pub mod error;
+pub mod groups;
 pub mod identity;
 pub mod messaging;
 #[cfg(feature = "network")]
 pub mod network;
 pub mod ops;
+pub mod rotation;
 pub mod storage;
 pub mod ui;
 pub mod api;
+pub mod session;
 pub mod settings;
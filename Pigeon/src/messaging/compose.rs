@@ -1,11 +1,22 @@
 use crate::storage::queue::{MessageQueue, MessageStatus, QueuedMessage};
+use std::path::Path;
 use uuid::Uuid;
 
 #[allow(dead_code)]
 pub async fn compose_message(
     recipient_id: u64,
     body: &str,
-    queue_path: &str,
+    data_dir: &Path,
+) -> Result<Uuid, crate::error::Error> {
+    let q = MessageQueue::open_in_dir(data_dir).map_err(crate::error::Error::Storage)?;
+    compose_on(&q, recipient_id, body)
+}
+
+/// `compose_message` on a queue that is already open.
+pub fn compose_on(
+    q: &MessageQueue,
+    recipient_id: u64,
+    body: &str,
 ) -> Result<Uuid, crate::error::Error> {
     let plaintext = body.as_bytes().to_vec();
     // For M0-060 we do not have contacts wired; store plaintext as payload placeholder
@@ -20,8 +31,8 @@ pub async fn compose_message(
         retry_count: 0,
         next_attempt_at: 0,
         max_retries: 5,
+        device: None,
     };
-    let q = MessageQueue::new(queue_path).map_err(crate::error::Error::Storage)?;
     q.enqueue(msg).map_err(crate::error::Error::Storage)?;
     Ok(id)
 }
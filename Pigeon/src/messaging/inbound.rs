@@ -0,0 +1,713 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::UnknownPeerPolicy;
+use crate::groups::{self, GroupControl, GroupEvent};
+use crate::identity::Identity;
+use crate::messaging::limits::Throttle;
+use crate::messaging::message::{key_id, Envelope, EnvelopeV1, EnvelopeV2};
+use crate::messaging::stamp;
+use crate::rotation::{self, TransitionOutcome};
+use crate::session;
+use crate::devices::SentCopy;
+use crate::storage::contacts::{Contact, ContactPolicy, ContactStore, DeviceKey};
+use crate::storage::queue::{MessageQueue, QuarantinedMessage, SentMessage};
+use uuid::Uuid;
+
+/// What happened to one inbound request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InboundOutcome {
+    /// Decrypted and stored in the inbox.
+    Delivered {
+        id: Uuid,
+        contact_id: Option<u64>,
+        plaintext: Vec<u8>,
+    },
+    /// Sender is not a contact, or its envelope does not open with the pinned key; raw
+    /// envelope kept in quarantine.
+    Quarantined(Uuid),
+    /// Sender is not a contact and the policy refuses unknown peers.
+    Refused,
+    /// The peer or one of its keys is on the blocklist, or its contact is blocked. Checked
+    /// before anything is decrypted; answered like `Refused`.
+    Blocked,
+    /// From a muted contact: acknowledged so it stops retrying, but dropped unread.
+    Muted,
+    /// Sender is not a contact and must resend the request with a stamp of this many bits.
+    StampRequired(u8),
+    /// Turned away by the listener's rate or bandwidth limits before being looked at.
+    Throttled(Throttle),
+    /// Nonce was already seen for this sender.
+    Replay,
+    /// Envelope failed verification or decryption.
+    Rejected(String),
+    /// A contact announced new keys. `staged` when they await the user instead of being
+    /// applied (no signing key was pinned to check the announcement).
+    KeysRotated { contact_id: u64, staged: bool },
+    /// One of our own devices copied a message it sent; stored with the sent messages.
+    Synced(Uuid),
+    /// A group message, decrypted and added to the group's thread.
+    GroupDelivered {
+        group_id: Uuid,
+        id: Uuid,
+        contact_id: u64,
+        plaintext: Vec<u8>,
+    },
+    /// A group control message (membership or sender key), or a group message held until
+    /// its key arrives.
+    Group(GroupEvent),
+    /// Not an envelope; legacy plain-text request.
+    PlainText(Vec<u8>),
+}
+
+impl InboundOutcome {
+    /// Response bytes sent back over request-response.
+    pub fn response(&self) -> Vec<u8> {
+        match self {
+            Self::Delivered { .. }
+            | Self::Quarantined(_)
+            | Self::KeysRotated { .. }
+            | Self::Synced(_)
+            | Self::GroupDelivered { .. }
+            | Self::Group(_)
+            | Self::Muted
+            | Self::PlainText(_) => b"ACK".to_vec(),
+            Self::Refused | Self::Blocked => b"REFUSED".to_vec(),
+            Self::StampRequired(bits) => stamp::challenge(*bits),
+            Self::Throttled(_) => b"BUSY".to_vec(),
+            Self::Replay => b"REPLAY".to_vec(),
+            Self::Rejected(_) => b"NACK".to_vec(),
+        }
+    }
+}
+
+/// What the listener accepts: the unknown-peer policy, contacts-only mode, the replay window
+/// and the stamp asked of unknown peers. The blocklist and per-contact policies are read
+/// from the contact store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InboundPolicy {
+    pub unknown_peers: UnknownPeerPolicy,
+    /// Refuse every peer that is not a contact (or a contact's device), and reject requests
+    /// that are not envelopes instead of acknowledging them as plain text.
+    pub contacts_only: bool,
+    /// How far an envelope's send time may be from our clock, in seconds.
+    pub replay_window_secs: u64,
+    /// Leading zero bits of the proof-of-work stamp a peer that is not a contact must put
+    /// on its requests before they are quarantined or accepted; 0 asks for none.
+    pub stamp_bits: u8,
+}
+
+impl InboundPolicy {
+    pub fn from_config(cfg: &crate::config::AppConfig) -> Self {
+        Self {
+            unknown_peers: cfg.unknown_peer_policy,
+            contacts_only: cfg.contacts_only,
+            replay_window_secs: cfg.replay_window_secs,
+            stamp_bits: cfg.inbound_limits.stamp_bits,
+        }
+    }
+
+    /// Whether an envelope sent at `sent_at` is inside the window around `now`.
+    pub fn in_window(&self, sent_at: u64, now: u64) -> bool {
+        sent_at.abs_diff(now) <= self.replay_window_secs
+    }
+}
+
+impl From<UnknownPeerPolicy> for InboundPolicy {
+    fn from(unknown_peers: UnknownPeerPolicy) -> Self {
+        Self {
+            unknown_peers,
+            contacts_only: false,
+            replay_window_secs: crate::config::DEFAULT_REPLAY_WINDOW_SECS,
+            stamp_bits: 0,
+        }
+    }
+}
+
+/// Handle one request from `peer_id`, the remote PeerId authenticated by the Noise handshake.
+pub fn handle_request(
+    data_dir: &Path,
+    id: &Identity,
+    peer_id: &str,
+    request: &[u8],
+    policy: impl Into<InboundPolicy>,
+) -> Result<InboundOutcome, crate::error::Error> {
+    let policy = policy.into();
+    // Stamps only matter for unknown peers; everyone else's stamp is simply taken off
+    let stamped = stamp::open(request);
+    let request = stamped.as_ref().map_or(request, |s| s.request.as_slice());
+    // A rotated contact dials from its new PeerId, so the announcement is matched by the
+    // old keys it names rather than by the peer
+    if let Some(Envelope::Transition(t)) = Envelope::decode(request) {
+        let named = ContactStore::open_in_dir(data_dir)
+            .and_then(|s| s.list())
+            .map_err(crate::error::Error::Storage)?
+            .into_iter()
+            .find(|c| c.public_key == t.old_box_key);
+        let keys = [
+            t.old_box_key.as_slice(),
+            &t.old_sign_key,
+            &t.new_box_key,
+            &t.new_sign_key,
+        ];
+        // A muted contact's new keys are still applied; only a block stops them
+        if let Some(InboundOutcome::Blocked) = screen(data_dir, peer_id, named.as_ref(), &keys)? {
+            return Ok(InboundOutcome::Blocked);
+        }
+        return Ok(match rotation::apply(data_dir, peer_id, &t) {
+            Ok(TransitionOutcome::Applied(c) | TransitionOutcome::AlreadyApplied(c)) => {
+                InboundOutcome::KeysRotated {
+                    contact_id: c.id,
+                    staged: false,
+                }
+            }
+            Ok(TransitionOutcome::Staged(c)) => InboundOutcome::KeysRotated {
+                contact_id: c.id,
+                staged: true,
+            },
+            Err(crate::error::Error::Storage(e)) => return Err(crate::error::Error::Storage(e)),
+            Err(e) => InboundOutcome::Rejected(e.to_string()),
+        });
+    }
+    if let Some(Envelope::Sync(env)) = Envelope::decode(request) {
+        if let Some(out) = screen(data_dir, peer_id, None, &[])? {
+            return Ok(out);
+        }
+        return handle_sync(data_dir, id, peer_id, &env, &policy);
+    }
+    let contact = lookup_contact(data_dir, peer_id)?;
+    if let Some(out) = screen(data_dir, peer_id, contact.as_ref(), &[])? {
+        return Ok(out);
+    }
+    let q = open_queue(data_dir)?;
+    if contact.is_none() {
+        let store = ContactStore::open_in_dir(data_dir).map_err(crate::error::Error::Storage)?;
+        let by_device = store
+            .find_by_device_peer(peer_id)
+            .map_err(crate::error::Error::Storage)?;
+        drop(store);
+        if let Some((c, device)) = &by_device {
+            let keys = [device.public_key.as_slice(), &device.sign_public_key];
+            if let Some(out) = screen(data_dir, peer_id, Some(c), &keys)? {
+                return Ok(out);
+            }
+        }
+        if let Some(sender) = by_device {
+            return handle_device_message(data_dir, id, &q, &sender, peer_id, request, &policy);
+        }
+    }
+    if contact.is_none() {
+        if policy.contacts_only {
+            return Ok(InboundOutcome::Refused);
+        }
+        // A refusal costs us nothing, so it needs no stamp either
+        let refused = policy.unknown_peers == UnknownPeerPolicy::Refuse;
+        if !refused && policy.stamp_bits > 0 {
+            let me = id.sodium_box_pk.0;
+            let Some(s) = stamped.as_ref().filter(|s| {
+                s.bits(peer_id, &me) >= u32::from(policy.stamp_bits)
+                    && policy.in_window(s.stamped_at, now_secs())
+            }) else {
+                return Ok(InboundOutcome::StampRequired(policy.stamp_bits));
+            };
+            // Each stamp buys one request; it ages out of the store with the window
+            let fresh = q
+                .nonce_store()
+                .and_then(|ns| {
+                    ns.insert_if_fresh(STAMP_SENDER, &s.digest(peer_id, &me), s.stamped_at)
+                })
+                .map_err(crate::error::Error::Storage)?;
+            if !fresh {
+                return Ok(InboundOutcome::Replay);
+            }
+        }
+        match policy.unknown_peers {
+            UnknownPeerPolicy::Refuse => return Ok(InboundOutcome::Refused),
+            UnknownPeerPolicy::Quarantine => return quarantine(&q, peer_id, request),
+            UnknownPeerPolicy::Accept => {}
+        }
+    }
+
+    let env = match Envelope::decode(request) {
+        None | Some(Envelope::Transition(_) | Envelope::Sync(_)) => {
+            if policy.contacts_only {
+                return Ok(InboundOutcome::Rejected("not an envelope".into()));
+            }
+            return Ok(InboundOutcome::PlainText(request.to_vec()));
+        }
+        Some(Envelope::Group(env)) => {
+            // Sender keys are only exchanged with contacts
+            let Some(c) = contact else {
+                return quarantine(&q, peer_id, request);
+            };
+            return handle_group_message(data_dir, &c, &env, &policy);
+        }
+        Some(Envelope::V2(env)) => {
+            // Sessions are per contact; an unknown (accepted) peer's handshake waits until it
+            // is added
+            let Some(c) = contact else {
+                return quarantine(&q, peer_id, request);
+            };
+            return handle_session_message(data_dir, id, &q, (&c, &env), peer_id, request, &policy);
+        }
+        Some(Envelope::V1(env)) => env,
+    };
+    // Contacts are authenticated by PeerId, by their signing key when we learned it from a
+    // contact card, and by opening the box with their key. Unknown senders can only be
+    // checked against our own keys (loopback sends).
+    let sign_pk = match &contact {
+        Some(c) => contact_sign_key(c),
+        None => Some(id.sign_pk),
+    };
+    if sign_pk.is_some_and(|pk| !verify_signature(&env, &pk)) {
+        return Ok(InboundOutcome::Rejected("signature verify failed".into()));
+    }
+    if !policy.in_window(env.sent_at(), now_secs()) {
+        return Ok(InboundOutcome::Rejected(OUTSIDE_WINDOW.into()));
+    }
+    let sender_pk = match &contact {
+        Some(c) => contact_box_key(c)?,
+        None => id.sodium_box_pk,
+    };
+    let Some(plaintext) = open_envelope(&env, &sender_pk, &id.sodium_box_sk) else {
+        if let Some(c) = &contact {
+            // The peer proved its PeerId but not the pinned key: it may have new keys. Keep
+            // the envelope so it can be released once the user accepts the key change.
+            log::warn!(
+                "message from contact {} ({}) does not open with the pinned key; quarantined",
+                c.id,
+                c.name
+            );
+            return quarantine(&q, peer_id, request);
+        }
+        return Ok(InboundOutcome::Rejected("failed to decrypt".into()));
+    };
+    if env.sender_id != 0 && env.sender_id != key_id(&sender_pk.0) {
+        return Ok(InboundOutcome::Rejected("sender id does not match the sealing key".into()));
+    }
+    if !first_seen(&q, &sender_pk.0, &env)? {
+        return Ok(InboundOutcome::Replay);
+    }
+    deliver(data_dir, id, &q, contact.as_ref(), plaintext)
+}
+
+/// Decide whether `peer_id` may fetch our prekey bundle, with the blocklist, contact
+/// policies and contacts-only mode that apply to its messages. Every bundle hands out a
+/// one-time prekey, so peers that are neither contacts nor their devices only get one when
+/// unknown peers are accepted. `None` lets the request be served.
+pub fn screen_prekey_request(
+    data_dir: &Path,
+    peer_id: &str,
+    policy: impl Into<InboundPolicy>,
+) -> Result<Option<InboundOutcome>, crate::error::Error> {
+    let policy = policy.into();
+    let mut contact = lookup_contact(data_dir, peer_id)?;
+    let mut device_keys = Vec::new();
+    if contact.is_none() {
+        let store = ContactStore::open_in_dir(data_dir).map_err(crate::error::Error::Storage)?;
+        if let Some((c, device)) = store
+            .find_by_device_peer(peer_id)
+            .map_err(crate::error::Error::Storage)?
+        {
+            device_keys = vec![device.public_key, device.sign_public_key];
+            contact = Some(c);
+        }
+    }
+    let keys: Vec<&[u8]> = device_keys.iter().map(Vec::as_slice).collect();
+    if let Some(out) = screen(data_dir, peer_id, contact.as_ref(), &keys)? {
+        return Ok(Some(out));
+    }
+    if contact.is_none()
+        && (policy.contacts_only || policy.unknown_peers != UnknownPeerPolicy::Accept)
+    {
+        return Ok(Some(InboundOutcome::Refused));
+    }
+    Ok(None)
+}
+
+const OUTSIDE_WINDOW: &str = "sent outside the replay window";
+
+// Accepted stamps are kept in the nonce store under this in place of a sender key
+const STAMP_SENDER: &[u8] = b"stamp";
+
+/// Check `peer_id`, the keys of `contact` and `keys` against the blocklist, then the
+/// contact's policy. `None` lets the request through to be decrypted.
+fn screen(
+    data_dir: &Path,
+    peer_id: &str,
+    contact: Option<&Contact>,
+    keys: &[&[u8]],
+) -> Result<Option<InboundOutcome>, crate::error::Error> {
+    let store = ContactStore::open_in_dir(data_dir).map_err(crate::error::Error::Storage)?;
+    let mut keys = keys.to_vec();
+    let contact_policy = match contact {
+        Some(c) => {
+            keys.push(&c.public_key);
+            keys.extend(c.sign_public_key.as_deref());
+            store.policy(c.id).map_err(crate::error::Error::Storage)?
+        }
+        None => ContactPolicy::Allow,
+    };
+    let blocked = store
+        .is_blocked(Some(peer_id), &keys)
+        .map_err(crate::error::Error::Storage)?;
+    Ok(match contact_policy {
+        _ if blocked => Some(InboundOutcome::Blocked),
+        ContactPolicy::Block => Some(InboundOutcome::Blocked),
+        ContactPolicy::Mute => Some(InboundOutcome::Muted),
+        ContactPolicy::Allow => None,
+    })
+}
+
+/// Record the nonce of an envelope that opened with `sender`'s key; false for a replay.
+/// Only authenticated envelopes are recorded, so nobody can fill the store for others.
+fn first_seen(q: &MessageQueue, sender: &[u8], env: &EnvelopeV1) -> Result<bool, crate::error::Error> {
+    q.nonce_store()
+        .and_then(|ns| ns.insert_if_fresh(sender, &env.nonce, env.sent_at()))
+        .map_err(crate::error::Error::Storage)
+}
+
+/// How often a running listener prunes seen nonces.
+pub const NONCE_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Prune seen nonces now and then every `every`, for as long as the future runs.
+pub async fn prune_nonces_every(data_dir: PathBuf, policy: InboundPolicy, every: Duration) {
+    let mut ticks = tokio::time::interval(every);
+    loop {
+        ticks.tick().await;
+        match prune_nonces(&data_dir, &policy) {
+            Ok(0) => {}
+            Ok(n) => log::info!("pruned {n} expired nonces"),
+            Err(e) => log::warn!("pruning nonces: {e}"),
+        }
+    }
+}
+
+/// Forget seen nonces too old to matter: anything sent before the replay window is
+/// rejected by its timestamp instead. Returns how many were dropped.
+pub fn prune_nonces(data_dir: &Path, policy: &InboundPolicy) -> Result<usize, crate::error::Error> {
+    let cutoff = now_secs().saturating_sub(policy.replay_window_secs);
+    open_queue(data_dir)?
+        .nonce_store()
+        .and_then(|ns| ns.prune(cutoff))
+        .map_err(crate::error::Error::Storage)
+}
+
+fn handle_session_message(
+    data_dir: &Path,
+    id: &Identity,
+    q: &MessageQueue,
+    (contact, env): (&Contact, &EnvelopeV2),
+    peer_id: &str,
+    request: &[u8],
+    policy: &InboundPolicy,
+) -> Result<InboundOutcome, crate::error::Error> {
+    if contact_sign_key(contact).is_some_and(|pk| !verify_signature_v2(env, &pk)) {
+        return Ok(InboundOutcome::Rejected("signature verify failed".into()));
+    }
+    // Checked before decrypting, so a stale message does not move the ratchet
+    if !policy.in_window(env.sent_at, now_secs()) {
+        return Ok(InboundOutcome::Rejected(OUTSIDE_WINDOW.into()));
+    }
+    let plaintext = match session::open_from_contact(data_dir, id, contact, env) {
+        Ok(p) => p,
+        Err(crate::error::Error::Session(session::Error::Duplicate)) => {
+            return Ok(InboundOutcome::Replay)
+        }
+        Err(crate::error::Error::Session(e)) => {
+            log::warn!(
+                "session message from contact {} ({}) did not open ({e}); quarantined",
+                contact.id,
+                contact.name
+            );
+            return quarantine(q, peer_id, request);
+        }
+        Err(e) => return Err(e),
+    };
+    deliver(data_dir, id, q, Some(contact), plaintext)
+}
+
+/// Store a decrypted message in the inbox, or apply it when it is a group control message
+/// from a contact.
+fn deliver(
+    data_dir: &Path,
+    id: &Identity,
+    q: &MessageQueue,
+    contact: Option<&Contact>,
+    plaintext: Vec<u8>,
+) -> Result<InboundOutcome, crate::error::Error> {
+    if let (Some(c), Some(control)) = (contact, GroupControl::decode(&plaintext)) {
+        return Ok(match groups::handle_control(data_dir, id, q, c, control) {
+            Ok(event) => InboundOutcome::Group(event),
+            Err(crate::error::Error::Storage(e)) => return Err(crate::error::Error::Storage(e)),
+            Err(e) => InboundOutcome::Rejected(e.to_string()),
+        });
+    }
+    let msg_id = Uuid::new_v4();
+    q.store_inbox(msg_id, plaintext.clone())
+        .map_err(crate::error::Error::Storage)?;
+    Ok(InboundOutcome::Delivered {
+        id: msg_id,
+        contact_id: contact.map(|c| c.id),
+        plaintext,
+    })
+}
+
+fn handle_group_message(
+    data_dir: &Path,
+    contact: &Contact,
+    env: &crate::messaging::message::GroupEnvelope,
+    policy: &InboundPolicy,
+) -> Result<InboundOutcome, crate::error::Error> {
+    // The sender key's signature and the message key both cover the timestamp
+    if !policy.in_window(env.sent_at, now_secs()) {
+        return Ok(InboundOutcome::Rejected(OUTSIDE_WINDOW.into()));
+    }
+    match groups::handle_envelope(data_dir, contact, env) {
+        Ok(Some(m)) => Ok(InboundOutcome::GroupDelivered {
+            group_id: m.group_id,
+            id: m.id,
+            contact_id: contact.id,
+            plaintext: m.body,
+        }),
+        Ok(None) => Ok(InboundOutcome::Group(GroupEvent::Held(env.group_id))),
+        Err(crate::error::Error::Group(groups::Error::Duplicate)) => Ok(InboundOutcome::Replay),
+        Err(crate::error::Error::Storage(e)) => Err(crate::error::Error::Storage(e)),
+        Err(e) => Ok(InboundOutcome::Rejected(e.to_string())),
+    }
+}
+
+/// A message from one of a contact's other devices. Sessions run between primary devices,
+/// so only signed box envelopes are accepted from them.
+fn handle_device_message(
+    data_dir: &Path,
+    id: &Identity,
+    q: &MessageQueue,
+    (contact, device): &(Contact, DeviceKey),
+    peer_id: &str,
+    request: &[u8],
+    policy: &InboundPolicy,
+) -> Result<InboundOutcome, crate::error::Error> {
+    let env = match Envelope::decode(request) {
+        Some(Envelope::V1(env)) => env,
+        Some(_) => return quarantine(q, peer_id, request),
+        None => return Ok(InboundOutcome::PlainText(request.to_vec())),
+    };
+    let sign_pk = sodiumoxide::crypto::sign::PublicKey::from_slice(&device.sign_public_key)
+        .ok_or_else(|| crate::error::Error::Config("device has invalid sign key".into()))?;
+    if !verify_signature(&env, &sign_pk) {
+        return Ok(InboundOutcome::Rejected("signature verify failed".into()));
+    }
+    if !policy.in_window(env.sent_at(), now_secs()) {
+        return Ok(InboundOutcome::Rejected(OUTSIDE_WINDOW.into()));
+    }
+    let box_pk = sodiumoxide::crypto::box_::PublicKey::from_slice(&device.public_key)
+        .ok_or_else(|| crate::error::Error::Config("device has invalid pubkey".into()))?;
+    let Some(plaintext) = open_envelope(&env, &box_pk, &id.sodium_box_sk) else {
+        return Ok(InboundOutcome::Rejected("failed to decrypt".into()));
+    };
+    if !first_seen(q, &box_pk.0, &env)? {
+        return Ok(InboundOutcome::Replay);
+    }
+    deliver(data_dir, id, q, Some(contact), plaintext)
+}
+
+/// A sent-message copy from one of our own linked devices.
+fn handle_sync(
+    data_dir: &Path,
+    id: &Identity,
+    peer_id: &str,
+    env: &EnvelopeV1,
+    policy: &InboundPolicy,
+) -> Result<InboundOutcome, crate::error::Error> {
+    let linked = crate::devices::linked(data_dir)?;
+    // The copy must be signed by a device of our account connecting from its own PeerId
+    let cert = linked.as_ref().and_then(|l| {
+        l.others(id).find(|c| {
+            c.device.peer_id.as_deref().is_none_or(|p| p == peer_id)
+                && verify_signature(env, &sodiumoxide::crypto::sign::PublicKey(c.sign_key))
+        })
+    });
+    let Some(cert) = cert else {
+        return Ok(InboundOutcome::Rejected(
+            "sync copy not from a linked device".into(),
+        ));
+    };
+    if !policy.in_window(env.sent_at(), now_secs()) {
+        return Ok(InboundOutcome::Rejected(OUTSIDE_WINDOW.into()));
+    }
+    let q = open_queue(data_dir)?;
+    let box_pk = sodiumoxide::crypto::box_::PublicKey(cert.box_key);
+    let Some(plain) = open_envelope(env, &box_pk, &id.sodium_box_sk) else {
+        return Ok(InboundOutcome::Rejected("failed to decrypt".into()));
+    };
+    if !first_seen(&q, &box_pk.0, env)? {
+        return Ok(InboundOutcome::Replay);
+    }
+    let copy: SentCopy = bincode::deserialize(&plain)
+        .map_err(|e| crate::error::Error::Serialization(format!("sync copy: {e}")))?;
+    let contact_id = ContactStore::open_in_dir(data_dir)
+        .and_then(|s| s.list())
+        .map_err(crate::error::Error::Storage)?
+        .into_iter()
+        .find(|c| {
+            c.public_key == copy.contact_box_key
+                || c.devices.iter().any(|d| d.public_key == copy.contact_box_key)
+        })
+        .map(|c| c.id);
+    let record = SentMessage {
+        id: Uuid::new_v4(),
+        contact_id,
+        body: copy.body,
+        sent_at: copy.sent_at,
+        from_device: Some(cert.device.name.clone()),
+    };
+    q.store_sent(&record).map_err(crate::error::Error::Storage)?;
+    Ok(InboundOutcome::Synced(record.id))
+}
+
+/// Decrypt a quarantined envelope now that its sender is a contact and move it to the inbox.
+pub fn release_quarantined(
+    data_dir: &Path,
+    id: &Identity,
+    message_id: Uuid,
+) -> Result<Uuid, crate::error::Error> {
+    let q = open_queue(data_dir)?;
+    let record = q
+        .take_quarantine(message_id)
+        .map_err(crate::error::Error::Storage)?
+        .ok_or_else(|| crate::error::Error::Config(format!("not quarantined: {message_id}")))?;
+    let outcome = (|| {
+        let contact = lookup_contact(data_dir, &record.peer_id)?.ok_or_else(|| {
+            crate::error::Error::Config(format!("peer {} is not a contact", record.peer_id))
+        })?;
+        let bad_signature = || {
+            crate::error::Error::Crypto(crate::crypto::Error::Signature(
+                "quarantined envelope".into(),
+            ))
+        };
+        let env = match Envelope::decode(&record.envelope) {
+            Some(Envelope::V1(env)) => env,
+            Some(Envelope::V2(env)) => {
+                if contact_sign_key(&contact).is_some_and(|pk| !verify_signature_v2(&env, &pk)) {
+                    return Err(bad_signature());
+                }
+                return session::open_from_contact(data_dir, id, &contact, &env);
+            }
+            None | Some(Envelope::Transition(_) | Envelope::Sync(_) | Envelope::Group(_)) => {
+                return Err(crate::error::Error::Serialization(
+                    "quarantined request is not an envelope".into(),
+                ))
+            }
+        };
+        if contact_sign_key(&contact).is_some_and(|pk| !verify_signature(&env, &pk)) {
+            return Err(bad_signature());
+        }
+        let pk = contact_box_key(&contact)?;
+        open_envelope(&env, &pk, &id.sodium_box_sk).ok_or_else(|| {
+            crate::error::Error::Crypto(crate::crypto::Error::Decryption(
+                "quarantined envelope".into(),
+            ))
+        })
+    })();
+    match outcome {
+        Ok(plaintext) => {
+            if let Some(control) = GroupControl::decode(&plaintext) {
+                if let Some(c) = lookup_contact(data_dir, &record.peer_id)? {
+                    groups::handle_control(data_dir, id, &q, &c, control)?;
+                }
+                return Ok(record.id);
+            }
+            q.store_inbox(record.id, plaintext)
+                .map_err(crate::error::Error::Storage)?;
+            Ok(record.id)
+        }
+        Err(e) => {
+            // Keep it quarantined so the user can retry after fixing the contact
+            q.store_quarantine(&record)
+                .map_err(crate::error::Error::Storage)?;
+            Err(e)
+        }
+    }
+}
+
+fn quarantine(
+    q: &MessageQueue,
+    peer_id: &str,
+    request: &[u8],
+) -> Result<InboundOutcome, crate::error::Error> {
+    let record = QuarantinedMessage {
+        id: Uuid::new_v4(),
+        peer_id: peer_id.to_string(),
+        received_at: now_secs(),
+        envelope: request.to_vec(),
+    };
+    q.store_quarantine(&record)
+        .map_err(crate::error::Error::Storage)?;
+    Ok(InboundOutcome::Quarantined(record.id))
+}
+
+fn lookup_contact(data_dir: &Path, peer_id: &str) -> Result<Option<Contact>, crate::error::Error> {
+    let store = ContactStore::open_in_dir(data_dir).map_err(crate::error::Error::Storage)?;
+    store
+        .find_by_peer_id(peer_id)
+        .map_err(crate::error::Error::Storage)
+}
+
+fn open_queue(data_dir: &Path) -> Result<MessageQueue, crate::error::Error> {
+    MessageQueue::open_in_dir(data_dir).map_err(crate::error::Error::Storage)
+}
+
+fn contact_box_key(c: &Contact) -> Result<sodiumoxide::crypto::box_::PublicKey, crate::error::Error> {
+    sodiumoxide::crypto::box_::PublicKey::from_slice(&c.public_key)
+        .ok_or_else(|| crate::error::Error::Config("contact has invalid pubkey".into()))
+}
+
+fn contact_sign_key(c: &Contact) -> Option<sodiumoxide::crypto::sign::PublicKey> {
+    c.sign_public_key
+        .as_deref()
+        .and_then(sodiumoxide::crypto::sign::PublicKey::from_slice)
+}
+
+fn open_envelope(
+    env: &EnvelopeV1,
+    sender_pk: &sodiumoxide::crypto::box_::PublicKey,
+    receiver_sk: &sodiumoxide::crypto::box_::SecretKey,
+) -> Option<Vec<u8>> {
+    let nonce = sodiumoxide::crypto::box_::Nonce::from_slice(&env.nonce)?;
+    sodiumoxide::crypto::box_::open(&env.payload, &nonce, sender_pk, receiver_sk).ok()
+}
+
+/// Check the detached signature over (version|sender|recipient|nonce|payload).
+pub fn verify_signature(env: &EnvelopeV1, pk: &sodiumoxide::crypto::sign::PublicKey) -> bool {
+    let mut to_verify = Vec::new();
+    to_verify.push(env.version);
+    to_verify.extend_from_slice(&env.sender_id.to_be_bytes());
+    to_verify.extend_from_slice(&env.recipient_id.to_be_bytes());
+    to_verify.extend_from_slice(&env.nonce);
+    to_verify.extend_from_slice(&env.payload);
+    verify_detached(&to_verify, &env.signature, pk)
+}
+
+/// Check a session envelope's detached signature (see `EnvelopeV2::signed_bytes`).
+pub fn verify_signature_v2(env: &EnvelopeV2, pk: &sodiumoxide::crypto::sign::PublicKey) -> bool {
+    verify_detached(&env.signed_bytes(), &env.signature, pk)
+}
+
+fn verify_detached(msg: &[u8], signature: &[u8], pk: &sodiumoxide::crypto::sign::PublicKey) -> bool {
+    let Ok(arr) = <[u8; 64]>::try_from(signature) else {
+        return false;
+    };
+    let sig = ed25519_dalek::Signature::from_bytes(&arr);
+    match ed25519_dalek::VerifyingKey::from_bytes(&pk.0) {
+        Ok(vk) => vk.verify_strict(msg, &sig).is_ok(),
+        Err(_) => false,
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
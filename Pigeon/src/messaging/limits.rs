@@ -0,0 +1,179 @@
+//! Limits on what the listener does for each peer: a token bucket of requests per peer,
+//! a cap on simultaneous connections per peer, and a global budget of inbound bytes. They
+//! are checked before a request touches the stores.
+
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// Default sustained request rate per peer.
+pub const DEFAULT_PEER_REQUESTS_PER_MIN: u32 = 60;
+/// Default number of requests a peer may send in a burst.
+pub const DEFAULT_PEER_BURST: u32 = 20;
+/// Default cap on simultaneous connections from one peer.
+pub const DEFAULT_MAX_CONNECTIONS_PER_PEER: u32 = 4;
+/// Default inbound bandwidth over all peers.
+pub const DEFAULT_MAX_INBOUND_BYTES_PER_SEC: u64 = 1024 * 1024;
+
+/// Most peers the limiter keeps a bucket for. Past it, buckets that have refilled are
+/// dropped first, then the one seen longest ago.
+pub const MAX_TRACKED_PEERS: usize = 1024;
+
+/// Inbound limits from `[network]`; 0 turns a limit off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InboundLimits {
+    pub peer_requests_per_min: u32,
+    pub peer_burst: u32,
+    pub max_connections_per_peer: u32,
+    pub max_inbound_bytes_per_sec: u64,
+    /// Leading zero bits of the proof-of-work stamp asked of peers that are not contacts.
+    pub stamp_bits: u8,
+}
+
+impl Default for InboundLimits {
+    fn default() -> Self {
+        Self {
+            peer_requests_per_min: DEFAULT_PEER_REQUESTS_PER_MIN,
+            peer_burst: DEFAULT_PEER_BURST,
+            max_connections_per_peer: DEFAULT_MAX_CONNECTIONS_PER_PEER,
+            max_inbound_bytes_per_sec: DEFAULT_MAX_INBOUND_BYTES_PER_SEC,
+            stamp_bits: 0,
+        }
+    }
+}
+
+/// Which limit turned a request away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Throttle {
+    /// The peer used up its request bucket.
+    Peer,
+    /// All peers together sent more bytes than the listener takes per second.
+    Bandwidth,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(capacity: f64, now: Instant) -> Self {
+        Self {
+            tokens: capacity,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, capacity: f64, per_sec: f64, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_sec).min(capacity);
+        self.updated = now;
+    }
+}
+
+/// The listener's limiter state; one per listener, checked from its event loop.
+#[derive(Debug)]
+pub struct Limiter {
+    limits: InboundLimits,
+    peers: HashMap<String, Bucket>,
+    bandwidth: Bucket,
+    connections: HashMap<String, u32>,
+}
+
+impl Limiter {
+    pub fn new(limits: InboundLimits) -> Self {
+        Self::starting_at(limits, Instant::now())
+    }
+
+    /// A limiter whose buckets are full at `now` (tests pass their own clock).
+    pub fn starting_at(limits: InboundLimits, now: Instant) -> Self {
+        Self {
+            limits,
+            peers: HashMap::new(),
+            bandwidth: Bucket::full(limits.max_inbound_bytes_per_sec as f64, now),
+            connections: HashMap::new(),
+        }
+    }
+
+    /// Take one request of `bytes` from `peer` at `now` out of the buckets, or say which
+    /// limit it ran into. A refused request costs nothing.
+    pub fn check_request(&mut self, peer: &str, bytes: usize, now: Instant) -> Result<(), Throttle> {
+        let rate = self.limits.peer_requests_per_min;
+        let burst = f64::from(self.limits.peer_burst.max(1));
+        if rate > 0 {
+            if self.peers.len() >= MAX_TRACKED_PEERS && !self.peers.contains_key(peer) {
+                self.forget_idle_peers(now);
+            }
+            let per_sec = f64::from(rate) / 60.0;
+            let bucket = self
+                .peers
+                .entry(peer.to_string())
+                .or_insert_with(|| Bucket::full(burst, now));
+            bucket.refill(burst, per_sec, now);
+            if bucket.tokens < 1.0 {
+                return Err(Throttle::Peer);
+            }
+        }
+        let cap = self.limits.max_inbound_bytes_per_sec;
+        if cap > 0 {
+            self.bandwidth.refill(cap as f64, cap as f64, now);
+            // A request larger than a second's budget is let through on a full bucket and
+            // paid back before the next one
+            if self.bandwidth.tokens < (bytes as f64).min(cap as f64) {
+                return Err(Throttle::Bandwidth);
+            }
+            self.bandwidth.tokens -= bytes as f64;
+        }
+        if let Some(bucket) = self.peers.get_mut(peer) {
+            bucket.tokens -= 1.0;
+        }
+        Ok(())
+    }
+
+    /// Number of peers with a request bucket.
+    pub fn tracked_peers(&self) -> usize {
+        self.peers.len()
+    }
+
+    fn forget_idle_peers(&mut self, now: Instant) {
+        let burst = f64::from(self.limits.peer_burst.max(1));
+        let per_sec = f64::from(self.limits.peer_requests_per_min) / 60.0;
+        // Left unrefilled so `updated` still says when each peer was last seen
+        self.peers.retain(|_, b| {
+            let idle = now.saturating_duration_since(b.updated).as_secs_f64();
+            b.tokens + idle * per_sec < burst
+        });
+        if self.peers.len() >= MAX_TRACKED_PEERS {
+            let oldest = self
+                .peers
+                .iter()
+                .min_by_key(|(_, b)| b.updated)
+                .map(|(peer, _)| peer.clone());
+            if let Some(peer) = oldest {
+                self.peers.remove(&peer);
+            }
+        }
+    }
+
+    /// Count a new connection from `peer`; false when it is over the per-peer cap, in which
+    /// case it is not counted and the caller closes it.
+    pub fn connection_opened(&mut self, peer: &str) -> bool {
+        let open = self.connections.entry(peer.to_string()).or_insert(0);
+        let max = self.limits.max_connections_per_peer;
+        if max > 0 && *open >= max {
+            return false;
+        }
+        *open += 1;
+        true
+    }
+
+    /// Forget a counted connection from `peer`.
+    pub fn connection_closed(&mut self, peer: &str) {
+        if let Some(open) = self.connections.get_mut(peer) {
+            *open = open.saturating_sub(1);
+            if *open == 0 {
+                self.connections.remove(peer);
+            }
+        }
+    }
+}
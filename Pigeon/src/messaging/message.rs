@@ -1,11 +1,14 @@
 use serde::{Deserialize, Serialize};
 
+/// Signed box envelope. Builds from before nonces carried the send time used random nonces,
+/// which read as a time far outside the replay window, so their envelopes are rejected: this
+/// layout only interoperates with builds that timestamp nonces.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct EnvelopeV1 {
     pub version: u8,
-    pub sender_id: u64,
-    pub recipient_id: u64,
-    pub nonce: [u8; 24],
+    pub sender_id: u64,    // `key_id` of the sender's box key (0 = not given)
+    pub recipient_id: u64, // `key_id` of the recipient's box key (0 = not given)
+    pub nonce: [u8; 24], // starts with the send time, see `timestamped_nonce`
     pub payload: Vec<u8>,   // ciphertext
     pub signature: Vec<u8>, // ed25519 signature over (version|sender|recipient|nonce|payload)
 }
@@ -28,4 +31,205 @@ impl EnvelopeV1 {
             signature,
         }
     }
+
+    /// When the sender sealed it (Unix seconds), from the nonce.
+    pub fn sent_at(&self) -> u64 {
+        nonce_timestamp(&self.nonce)
+    }
+}
+
+/// Short id of a box public key for the `sender_id`/`recipient_id` fields: its first 8 bytes,
+/// big-endian. It names the key an envelope was sealed with; receivers still authenticate by
+/// signature and box key, and only check that a non-zero sender id matches the key that
+/// opened the box.
+pub fn key_id(box_pk: &[u8; 32]) -> u64 {
+    let mut id = [0u8; 8];
+    id.copy_from_slice(&box_pk[..8]);
+    u64::from_be_bytes(id)
+}
+
+/// A box nonce for a message sealed at `sent_at` (Unix seconds): the time in the first 8
+/// bytes, big-endian, the other 16 random. The signature and the box both cover the nonce,
+/// so the timestamp cannot be altered without breaking them.
+pub fn timestamped_nonce(sent_at: u64) -> [u8; 24] {
+    let mut nonce = sodiumoxide::crypto::box_::gen_nonce().0;
+    nonce[..8].copy_from_slice(&sent_at.to_be_bytes());
+    nonce
+}
+
+/// The send time carried in a `timestamped_nonce`.
+pub fn nonce_timestamp(nonce: &[u8; 24]) -> u64 {
+    let mut ts = [0u8; 8];
+    ts.copy_from_slice(&nonce[..8]);
+    u64::from_be_bytes(ts)
+}
+
+/// Double Ratchet header sent in the clear (authenticated as associated data).
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RatchetHeader {
+    pub dh_public: [u8; 32], // sender's current ratchet public key
+    pub prev_chain_len: u32, // messages sent in the previous sending chain
+    pub n: u32,              // message number in the current sending chain
+}
+
+/// X3DH parameters the initiator attaches until the responder has answered.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PreKeyMessage {
+    pub identity_key: [u8; 32],  // initiator's box public key
+    pub ephemeral_key: [u8; 32], // initiator's X3DH ephemeral public key
+    pub signed_prekey_id: u32,   // 0 = responder's identity key (older senders only)
+    pub one_time_prekey_id: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct EnvelopeV2 {
+    pub version: u8,
+    pub sender_id: u64,
+    pub recipient_id: u64,
+    pub sent_at: u64, // Unix seconds
+    pub prekey: Option<PreKeyMessage>,
+    pub header: RatchetHeader,
+    pub payload: Vec<u8>,   // AEAD ciphertext under a single-use message key
+    pub signature: Vec<u8>, // ed25519 signature over signed_bytes()
+}
+
+impl EnvelopeV2 {
+    /// An unsigned envelope stamped with the current time.
+    pub fn new(
+        prekey: Option<PreKeyMessage>,
+        header: RatchetHeader,
+        payload: Vec<u8>,
+    ) -> Self {
+        Self {
+            version: 2,
+            sender_id: 0,
+            recipient_id: 0,
+            sent_at: now_secs(),
+            prekey,
+            header,
+            payload,
+            signature: Vec::new(),
+        }
+    }
+
+    /// Bytes covered by the signature: (version|sender|recipient|sent at|prekey|header|payload).
+    pub fn signed_bytes(&self) -> Vec<u8> {
+        let mut out = vec![self.version];
+        out.extend_from_slice(&self.sender_id.to_be_bytes());
+        out.extend_from_slice(&self.recipient_id.to_be_bytes());
+        out.extend_from_slice(&self.sent_at.to_be_bytes());
+        if let Some(p) = &self.prekey {
+            out.push(1);
+            out.extend_from_slice(&p.identity_key);
+            out.extend_from_slice(&p.ephemeral_key);
+        } else {
+            out.push(0);
+        }
+        out.extend_from_slice(&self.header.dh_public);
+        out.extend_from_slice(&self.header.prev_chain_len.to_be_bytes());
+        out.extend_from_slice(&self.header.n.to_be_bytes());
+        out.extend_from_slice(&self.payload);
+        out
+    }
+}
+
+pub const KEY_TRANSITION_VERSION: u8 = 3;
+/// Version byte of a `EnvelopeV1`-layout copy of a sent message for one of our own devices.
+pub const SYNC_ENVELOPE_VERSION: u8 = 4;
+/// Version byte of a `GroupEnvelope`.
+pub const GROUP_ENVELOPE_VERSION: u8 = 5;
+const KEY_TRANSITION_CONTEXT: &[u8] = b"pigeon-key-transition-v1";
+
+/// Announcement that an identity moved to new keys. Signed by the old signing key, which
+/// contacts have pinned, and by the new one to prove the sender holds it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct KeyTransition {
+    pub version: u8,
+    pub old_box_key: [u8; 32],
+    pub old_sign_key: [u8; 32],
+    pub new_box_key: [u8; 32],
+    pub new_sign_key: [u8; 32],
+    pub new_peer_id: Option<String>, // libp2p PeerId (base58) of the new transport key
+    pub issued_at: u64,
+    pub old_signature: Vec<u8>,
+    pub new_signature: Vec<u8>,
+}
+
+impl KeyTransition {
+    /// Bytes covered by both signatures.
+    pub fn signed_bytes(&self) -> Vec<u8> {
+        let mut out = KEY_TRANSITION_CONTEXT.to_vec();
+        out.push(self.version);
+        out.extend_from_slice(&self.old_box_key);
+        out.extend_from_slice(&self.old_sign_key);
+        out.extend_from_slice(&self.new_box_key);
+        out.extend_from_slice(&self.new_sign_key);
+        match &self.new_peer_id {
+            Some(p) => {
+                out.push(1);
+                out.extend_from_slice(&(p.len() as u32).to_be_bytes());
+                out.extend_from_slice(p.as_bytes());
+            }
+            None => out.push(0),
+        }
+        out.extend_from_slice(&self.issued_at.to_be_bytes());
+        out
+    }
+}
+
+/// A group message, encrypted once under the sender's sender key (see `groups::sender_key`)
+/// and queued unchanged for every member.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct GroupEnvelope {
+    pub version: u8,
+    pub group_id: uuid::Uuid,
+    pub sender_key: [u8; 32], // public signing key of the sender key chain
+    pub iteration: u32,
+    pub sent_at: u64,       // Unix seconds
+    pub payload: Vec<u8>,   // ciphertext
+    pub signature: Vec<u8>, // ed25519 signature over signed_bytes(), by the sender key
+}
+
+impl GroupEnvelope {
+    /// Bytes covered by the signature: (version|group|sender key|iteration|sent at|payload).
+    pub fn signed_bytes(&self) -> Vec<u8> {
+        let mut out = vec![self.version];
+        out.extend_from_slice(self.group_id.as_bytes());
+        out.extend_from_slice(&self.sender_key);
+        out.extend_from_slice(&self.iteration.to_be_bytes());
+        out.extend_from_slice(&self.sent_at.to_be_bytes());
+        out.extend_from_slice(&self.payload);
+        out
+    }
+}
+
+/// Any envelope version we understand; the leading version byte selects the layout.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Envelope {
+    V1(EnvelopeV1),
+    V2(EnvelopeV2),
+    Transition(KeyTransition),
+    Sync(EnvelopeV1),
+    Group(GroupEnvelope),
+}
+
+impl Envelope {
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        match bytes.first()? {
+            1 => bincode::deserialize(bytes).ok().map(Self::V1),
+            2 => bincode::deserialize(bytes).ok().map(Self::V2),
+            3 => bincode::deserialize(bytes).ok().map(Self::Transition),
+            4 => bincode::deserialize(bytes).ok().map(Self::Sync),
+            5 => bincode::deserialize(bytes).ok().map(Self::Group),
+            // 6 wraps another request in a proof-of-work stamp (see `stamp`)
+            _ => None,
+        }
+    }
+}
+
+fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
 }
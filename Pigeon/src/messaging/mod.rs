@@ -1,7 +1,10 @@
 pub mod compose;
+pub mod inbound;
+pub mod limits;
 pub mod message;
 pub mod queue;
 pub mod receive;
 pub mod send;
 #[cfg(feature = "network")]
 pub mod send_loop;
+pub mod stamp;
@@ -1,14 +1,24 @@
 use crate::crypto;
 use crate::storage::queue::MessageQueue;
 use sodiumoxide::crypto::box_::{PublicKey, SecretKey};
+use std::path::Path;
 
 #[allow(dead_code)]
 pub async fn receive_and_ack(
-    queue_path: &str,
+    data_dir: &Path,
+    sender_pk: &PublicKey,
+    receiver_sk: &SecretKey,
+) -> Result<(), crate::error::Error> {
+    let q = MessageQueue::open_in_dir(data_dir).map_err(crate::error::Error::Storage)?;
+    receive_and_ack_on(&q, sender_pk, receiver_sk)
+}
+
+/// `receive_and_ack` on a queue that is already open.
+pub fn receive_and_ack_on(
+    q: &MessageQueue,
     sender_pk: &PublicKey,
     receiver_sk: &SecretKey,
 ) -> Result<(), crate::error::Error> {
-    let q = MessageQueue::new(queue_path).map_err(crate::error::Error::Storage)?;
     if let Some(msg) = q.dequeue().map_err(crate::error::Error::Storage)? {
         let plaintext = crypto::decrypt_message(&msg.payload, sender_pk, receiver_sk)
             .map_err(crate::error::Error::Crypto)?;
@@ -1,11 +1,25 @@
 use crate::crypto;
-use crate::storage::queue::{MessageQueue, MessageStatus, QueuedMessage};
+use crate::messaging::message::{key_id, timestamped_nonce, EnvelopeV1, SYNC_ENVELOPE_VERSION};
+use crate::storage::queue::{MessageQueue, MessageStatus, QueuedMessage, SentMessage};
 use sodiumoxide::crypto::box_::{PublicKey, SecretKey};
+use std::path::Path;
 use uuid::Uuid;
 
 #[allow(dead_code)]
 pub async fn send_now(
-    queue_path: &str,
+    data_dir: &Path,
+    sender_sk: &SecretKey,
+    recipient_pk: &PublicKey,
+    recipient_id: u64,
+    plaintext: &[u8],
+) -> Result<Uuid, crate::error::Error> {
+    let q = MessageQueue::open_in_dir(data_dir).map_err(crate::error::Error::Storage)?;
+    send_now_on(&q, sender_sk, recipient_pk, recipient_id, plaintext)
+}
+
+/// `send_now` on a queue that is already open.
+pub fn send_now_on(
+    q: &MessageQueue,
     sender_sk: &SecretKey,
     recipient_pk: &PublicKey,
     recipient_id: u64,
@@ -14,7 +28,6 @@ pub async fn send_now(
     let ciphertext = crypto::encrypt_message(sender_sk, recipient_pk, plaintext)
         .map_err(crate::error::Error::Crypto)?;
     let id = Uuid::new_v4();
-    let q = MessageQueue::new(queue_path).map_err(crate::error::Error::Storage)?;
     let msg = QueuedMessage {
         id,
         contact_id: recipient_id,
@@ -25,7 +38,259 @@ pub async fn send_now(
         retry_count: 0,
         next_attempt_at: 0,
         max_retries: 5,
+        device: None,
     };
     q.enqueue(msg).map_err(crate::error::Error::Storage)?;
     Ok(id)
 }
+
+/// Encrypt for a contact and enqueue the signed envelope, ready for the send loop to deliver
+/// as-is, plus copies for the contact's other devices and our own (see `fan_out_copies`).
+pub fn send_to_contact(
+    queue_path: &str,
+    data_dir: &std::path::Path,
+    id: &crate::identity::Identity,
+    contact: &crate::storage::contacts::Contact,
+    plaintext: &[u8],
+) -> Result<Uuid, crate::error::Error> {
+    let payload = encode_for_contact(data_dir, id, contact, plaintext)?;
+    let q = open_queue(queue_path, data_dir)?;
+    let msg_id = enqueue_on(&q, contact.id, None, payload, 1)?;
+    fan_out_on(&q, data_dir, id, contact, plaintext)?;
+    Ok(msg_id)
+}
+
+/// The queue at `queue_path`, sealed under the at-rest key of `data_dir`.
+fn open_queue(
+    queue_path: &str,
+    data_dir: &std::path::Path,
+) -> Result<MessageQueue, crate::error::Error> {
+    let key = crate::storage::at_rest::AtRestKey::load_or_create(data_dir)
+        .map_err(crate::error::Error::Storage)?;
+    MessageQueue::open(queue_path, key).map_err(crate::error::Error::Storage)
+}
+
+/// Leading byte of a queued payload that is not sealed yet (after the envelope versions 1
+/// to 5 and stamps at 6): the plaintext of a message to a contact with whom no session could
+/// be started. It never goes on the wire; the send loop seals it first (see `seal_pending`).
+pub const PENDING_SEAL_VERSION: u8 = 7;
+
+/// Payload to queue for a contact's main device: a ratchet session message, or the message
+/// left pending (see `PENDING_SEAL_VERSION`) when no session can be started yet, because no
+/// prekey bundle was fetched from the contact or this install is a linked device.
+pub fn encode_for_contact(
+    data_dir: &std::path::Path,
+    id: &crate::identity::Identity,
+    contact: &crate::storage::contacts::Contact,
+    plaintext: &[u8],
+) -> Result<Vec<u8>, crate::error::Error> {
+    let mut pending = vec![PENDING_SEAL_VERSION];
+    pending.extend_from_slice(plaintext);
+    Ok(seal_pending(data_dir, id, contact, &pending)?.unwrap_or(pending))
+}
+
+/// Whether `payload` is a message `encode_for_contact` left to be sealed later.
+pub fn is_pending(payload: &[u8]) -> bool {
+    payload.first() == Some(&PENDING_SEAL_VERSION)
+}
+
+/// Seal a queued payload for the wire. Sealed payloads come back unchanged and pending ones
+/// are sealed into the contact's session; `None` when that still cannot start (no bundle
+/// fetched yet, or this install is a linked device, where sessions do not run).
+pub fn seal_pending(
+    data_dir: &std::path::Path,
+    id: &crate::identity::Identity,
+    contact: &crate::storage::contacts::Contact,
+    payload: &[u8],
+) -> Result<Option<Vec<u8>>, crate::error::Error> {
+    if !is_pending(payload) {
+        return Ok(Some(payload.to_vec()));
+    }
+    if crate::devices::is_linked_device(data_dir)? {
+        return Ok(None);
+    }
+    match crate::session::seal_for_contact(data_dir, id, contact, &payload[1..]) {
+        Ok(env) => bincode::serialize(&env)
+            .map(Some)
+            .map_err(|e| crate::error::Error::Serialization(e.to_string())),
+        Err(crate::error::Error::Session(crate::session::Error::NoBundle)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Seal a pending payload as a signed box envelope under the static identity keys, for
+/// installs that opted into `static_key_fallback`. It has no forward secrecy, so every use
+/// is logged with the contact it went to.
+pub fn seal_static(
+    id: &crate::identity::Identity,
+    contact: &crate::storage::contacts::Contact,
+    payload: &[u8],
+) -> Result<Vec<u8>, crate::error::Error> {
+    let pk = PublicKey::from_slice(&contact.public_key)
+        .ok_or_else(|| crate::error::Error::Config("contact has invalid pubkey".into()))?;
+    let plaintext = payload.strip_prefix(&[PENDING_SEAL_VERSION]).unwrap_or(payload);
+    log::warn!(
+        "contact {} ({}): no session, sending under the static keys without forward secrecy",
+        contact.id,
+        contact.name
+    );
+    let env = seal_box_envelope(1, id, &pk, plaintext);
+    bincode::serialize(&env).map_err(|e| crate::error::Error::Serialization(e.to_string()))
+}
+
+/// Queue a signed box envelope of `plaintext` for each of the contact's other devices and a
+/// sent-message copy for each of our own other devices, then record the message as sent.
+/// Returns how many copies were queued.
+pub fn fan_out_copies(
+    queue_path: &str,
+    data_dir: &std::path::Path,
+    id: &crate::identity::Identity,
+    contact: &crate::storage::contacts::Contact,
+    plaintext: &[u8],
+) -> Result<usize, crate::error::Error> {
+    let q = open_queue(queue_path, data_dir)?;
+    fan_out_on(&q, data_dir, id, contact, plaintext)
+}
+
+fn fan_out_on(
+    q: &MessageQueue,
+    data_dir: &std::path::Path,
+    id: &crate::identity::Identity,
+    contact: &crate::storage::contacts::Contact,
+    plaintext: &[u8],
+) -> Result<usize, crate::error::Error> {
+    let mut copies = 0;
+    for device in &contact.devices {
+        let Some(pk) = PublicKey::from_slice(&device.public_key) else {
+            continue;
+        };
+        let env = seal_box_envelope(1, id, &pk, plaintext);
+        let payload = bincode::serialize(&env)
+            .map_err(|e| crate::error::Error::Serialization(e.to_string()))?;
+        enqueue_on(q, contact.id, Some(device.public_key.clone()), payload, 1)?;
+        copies += 1;
+    }
+    let sent_at = now_secs();
+    if let Some(linked) = crate::devices::linked(data_dir)? {
+        let copy = crate::devices::SentCopy {
+            contact_box_key: contact.public_key.as_slice().try_into().map_err(|_| {
+                crate::error::Error::Config("contact has invalid pubkey".into())
+            })?,
+            body: plaintext.to_vec(),
+            sent_at,
+        };
+        let copy = bincode::serialize(&copy)
+            .map_err(|e| crate::error::Error::Serialization(e.to_string()))?;
+        for cert in linked.others(id) {
+            let env = seal_box_envelope(
+                SYNC_ENVELOPE_VERSION,
+                id,
+                &PublicKey(cert.box_key),
+                &copy,
+            );
+            let payload = bincode::serialize(&env)
+                .map_err(|e| crate::error::Error::Serialization(e.to_string()))?;
+            enqueue_on(
+                q,
+                crate::devices::OWN_DEVICES,
+                Some(cert.box_key.to_vec()),
+                payload,
+                1,
+            )?;
+            copies += 1;
+        }
+    }
+    q.store_sent(&SentMessage {
+        id: Uuid::new_v4(),
+        contact_id: Some(contact.id),
+        body: plaintext.to_vec(),
+        sent_at,
+        from_device: None,
+    })
+    .map_err(crate::error::Error::Storage)?;
+    Ok(copies)
+}
+
+/// Box `plaintext` for `recipient_pk` in a signed `EnvelopeV1`-layout envelope. Version 1 is
+/// a message; `SYNC_ENVELOPE_VERSION` marks a sent-message copy for one of our devices.
+pub fn seal_box_envelope(
+    version: u8,
+    id: &crate::identity::Identity,
+    recipient_pk: &PublicKey,
+    plaintext: &[u8],
+) -> EnvelopeV1 {
+    let nonce = sodiumoxide::crypto::box_::Nonce(timestamped_nonce(now_secs()));
+    let ciphertext = sodiumoxide::crypto::box_::seal(plaintext, &nonce, recipient_pk, &id.sodium_box_sk);
+    let mut env = EnvelopeV1::new(
+        key_id(&id.sodium_box_pk.0),
+        key_id(&recipient_pk.0),
+        nonce.0,
+        ciphertext,
+        Vec::new(),
+    );
+    env.version = version;
+    // sign over (version|sender_id|recipient_id|nonce|ciphertext)
+    let mut to_sign = vec![env.version];
+    to_sign.extend_from_slice(&env.sender_id.to_be_bytes());
+    to_sign.extend_from_slice(&env.recipient_id.to_be_bytes());
+    to_sign.extend_from_slice(&env.nonce);
+    to_sign.extend_from_slice(&env.payload);
+    env.signature = sodiumoxide::crypto::sign::sign_detached(&to_sign, &id.sign_sk)
+        .to_bytes()
+        .to_vec();
+    env
+}
+
+/// Enqueue bytes the send loop delivers unchanged (an encoded envelope or announcement).
+/// Priority 0 is the high lane.
+pub fn enqueue_payload(
+    data_dir: &Path,
+    contact_id: u64,
+    payload: Vec<u8>,
+    priority: u8,
+) -> Result<Uuid, crate::error::Error> {
+    enqueue(data_dir, contact_id, None, payload, priority)
+}
+
+fn enqueue(
+    data_dir: &Path,
+    contact_id: u64,
+    device: Option<Vec<u8>>,
+    payload: Vec<u8>,
+    priority: u8,
+) -> Result<Uuid, crate::error::Error> {
+    let q = MessageQueue::open_in_dir(data_dir).map_err(crate::error::Error::Storage)?;
+    enqueue_on(&q, contact_id, device, payload, priority)
+}
+
+/// `enqueue_payload` on a queue that is already open (e.g. while handling a request).
+pub fn enqueue_on(
+    q: &MessageQueue,
+    contact_id: u64,
+    device: Option<Vec<u8>>,
+    payload: Vec<u8>,
+    priority: u8,
+) -> Result<Uuid, crate::error::Error> {
+    let id = Uuid::new_v4();
+    q.enqueue(QueuedMessage {
+        id,
+        contact_id,
+        payload,
+        created: 0,
+        priority,
+        status: MessageStatus::Pending,
+        retry_count: 0,
+        next_attempt_at: 0,
+        max_retries: 5,
+        device,
+    })
+    .map_err(crate::error::Error::Storage)?;
+    Ok(id)
+}
+
+fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
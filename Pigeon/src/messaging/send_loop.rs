@@ -9,8 +9,14 @@ pub struct SendLoopConfig {
     pub base_backoff_secs: u64,
     pub interval_ms: u64,
     pub high_to_normal_ratio: u8,
+    /// Seal messages under the static keys when no session can be started with the contact,
+    /// instead of holding them (see `AppConfig::static_key_fallback`).
+    pub static_key_fallback: bool,
 }
 
+// How long the send loop waits for a contact's prekey bundle
+const BUNDLE_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
 /// Drain the queue periodically. For each due message, try to send over the network
 /// if contact info is available; otherwise requeue with backoff or dead-letter.
 pub async fn run(config: SendLoopConfig) -> Result<(), crate::error::Error> {
@@ -22,7 +28,9 @@ pub async fn run(config: SendLoopConfig) -> Result<(), crate::error::Error> {
     let metrics = crate::ops::Metrics::default();
     let mut high_budget: u8 = ratio;
     loop {
-        let q = MessageQueue::new(&config.queue_path).map_err(crate::error::Error::Storage)?;
+        let key = crate::storage::at_rest::AtRestKey::load_or_create(&config.data_dir)
+            .map_err(crate::error::Error::Storage)?;
+        let q = MessageQueue::open(&config.queue_path, key).map_err(crate::error::Error::Storage)?;
         loop {
             // Fair scheduling: consume high-priority until budget, then serve one normal
             let candidate = if high_budget > 0 {
@@ -85,24 +93,82 @@ pub async fn run(config: SendLoopConfig) -> Result<(), crate::error::Error> {
 async fn try_send_one(
     config: &SendLoopConfig,
     q: &MessageQueue,
-    msg: QueuedMessage,
+    mut msg: QueuedMessage,
     metrics: &crate::ops::Metrics,
 ) -> Result<bool, crate::error::Error> {
-    // Lookup contact
-    let store =
-        ContactStore::open_in_dir(&config.data_dir).map_err(crate::error::Error::Storage)?;
-    let contact_opt = store
-        .get(msg.contact_id)
-        .map_err(crate::error::Error::Storage)?;
-    let Some(contact) = contact_opt else {
-        let _ = q.requeue_or_dead_letter(msg, config.base_backoff_secs, "missing contact")?;
+    let id = crate::identity::Identity::load_or_generate(&config.data_dir)?;
+    // Copies for our own linked devices have no contact; they are routed by device key.
+    // The recipient's box key goes into a stamp if one is asked for
+    let target = if msg.contact_id == crate::devices::OWN_DEVICES {
+        match &msg.device {
+            Some(key) => crate::devices::own_device(&config.data_dir, key)?
+                .and_then(|c| c.device.dial_addr())
+                .map(|addr| (addr, key.clone())),
+            None => None,
+        }
+    } else {
+        // Lookup contact
+        let store =
+            ContactStore::open_in_dir(&config.data_dir).map_err(crate::error::Error::Storage)?;
+        let contact_opt = store
+            .get(msg.contact_id)
+            .map_err(crate::error::Error::Storage)?;
+        let Some(contact) = contact_opt else {
+            let _ = q.requeue_or_dead_letter(msg, config.base_backoff_secs, "missing contact")?;
+            return Ok(true);
+        };
+        if contact.send_blocked() {
+            // Hold (without spending a retry) until the user accepts or rejects the key change
+            log::warn!("holding message {} for contact {}: key change pending", msg.id, contact.id);
+            let mut msg = msg;
+            msg.next_attempt_at = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+                .saturating_add(config.base_backoff_secs.max(1));
+            msg.status = MessageStatus::Pending;
+            q.enqueue(msg).map_err(crate::error::Error::Storage)?;
+            return Ok(true);
+        }
+        if msg.device.is_none() && crate::messaging::send::is_pending(&msg.payload) {
+            // Sealed once here, so retries resend the same session message
+            match seal_queued(config, &id, &contact, &msg.payload).await? {
+                Some(payload) => msg.payload = payload,
+                None => {
+                    let _ = q.requeue_or_dead_letter(
+                        msg,
+                        config.base_backoff_secs,
+                        "no session: prekey bundle unavailable and static_key_fallback is off",
+                    )?;
+                    return Ok(true);
+                }
+            }
+        }
+        match &msg.device {
+            // One of the contact's other devices (its addr is already pinned to its PeerId)
+            Some(key) => contact
+                .devices
+                .iter()
+                .find(|d| &d.public_key == key)
+                .and_then(|d| d.addr.clone())
+                .map(|addr| (addr, key.clone())),
+            // Pin the dial to the contact's PeerId so Noise rejects anyone else at that address
+            None => Some((
+                match &contact.peer_id {
+                    Some(peer) => crate::discovery::with_peer_suffix(&contact.addr, peer),
+                    None => contact.addr.clone(),
+                },
+                contact.public_key.clone(),
+            )),
+        }
+    };
+    let Some((dial_addr, recipient_key)) = target else {
+        let _ = q.requeue_or_dead_letter(msg, config.base_backoff_secs, "unknown device")?;
         return Ok(true);
     };
 
     // Build a minimal one-shot rr client and send bytes
     use libp2p::{Multiaddr, Transport};
-    let cfg = crate::config::load();
-    let id = crate::identity::Identity::load_or_generate(&cfg.data_dir)?;
     let local_key = id.libp2p;
     let transport =
         libp2p::tcp::tokio::Transport::new(libp2p::tcp::Config::default().nodelay(true))
@@ -120,6 +186,7 @@ async fn try_send_one(
     let behaviour: libp2p::request_response::Behaviour<crate::network::rr::PigeonCodec> =
         libp2p::request_response::Behaviour::new(protocols, rr_cfg);
     let peer_id = local_key.public().to_peer_id();
+    let me = peer_id.to_string();
     let mut swarm = libp2p::Swarm::new(
         transport,
         behaviour,
@@ -127,8 +194,7 @@ async fn try_send_one(
         libp2p::swarm::Config::with_tokio_executor(),
     );
 
-    let addr: Multiaddr = contact
-        .addr
+    let addr: Multiaddr = dial_addr
         .parse()
         .map_err(|e: libp2p::multiaddr::Error| crate::error::Error::Config(e.to_string()))?;
     libp2p::Swarm::dial(&mut swarm, addr).map_err(|e| {
@@ -141,6 +207,7 @@ async fn try_send_one(
     use libp2p::futures::StreamExt;
     let mut done = false;
     let mut delivered = false;
+    let mut stamped = false;
     while !done {
         match swarm.select_next_some().await {
             libp2p::swarm::SwarmEvent::ConnectionEstablished { peer_id, .. } => {
@@ -153,18 +220,42 @@ async fn try_send_one(
                 Vec<u8>,
                 Vec<u8>,
             >::Message {
+                peer,
                 message,
-                ..
             }) => {
                 match message {
                     libp2p::request_response::Message::<Vec<u8>, Vec<u8>>::Response {
                         response,
                         ..
-                    } => {
-                        let _ = response; // ACK ignored
-                        delivered = true;
-                        done = true;
-                    }
+                    } => match crate::messaging::stamp::requested_bits(&response) {
+                        // A recipient that does not know us may ask for a stamp; pay it once
+                        Some(bits) if !stamped => {
+                            stamped = true;
+                            // Hashing for the stamp can take seconds; keep it off the runtime
+                            let (payload, sender, key) =
+                                (msg.payload.clone(), me.clone(), recipient_key.clone());
+                            match tokio::task::spawn_blocking(move || {
+                                crate::messaging::stamp::stamp(&payload, &sender, &key, bits)
+                            })
+                            .await
+                            {
+                                Ok(stamped_payload) => {
+                                    let _ =
+                                        swarm.behaviour_mut().send_request(&peer, stamped_payload);
+                                }
+                                Err(e) => {
+                                    log::warn!("stamping message {}: {e}", msg.id);
+                                    done = true;
+                                }
+                            }
+                        }
+                        Some(_) => done = true,
+                        None => {
+                            // ACK ignored
+                            delivered = true;
+                            done = true;
+                        }
+                    },
                     _ => {}
                 }
             }
@@ -188,6 +279,51 @@ async fn try_send_one(
     }
 }
 
+/// Seal a message `send::encode_for_contact` left pending: into a session, fetching the
+/// contact's prekey bundle first if none is stored, or under the static keys when the config
+/// allows it. `None` while it has to wait.
+async fn seal_queued(
+    config: &SendLoopConfig,
+    id: &crate::identity::Identity,
+    contact: &crate::storage::contacts::Contact,
+    payload: &[u8],
+) -> Result<Option<Vec<u8>>, crate::error::Error> {
+    use crate::messaging::send;
+    if let Some(sealed) = send::seal_pending(&config.data_dir, id, contact, payload)? {
+        return Ok(Some(sealed));
+    }
+    if !crate::devices::is_linked_device(&config.data_dir)? {
+        let addr = match &contact.peer_id {
+            Some(peer) => crate::discovery::with_peer_suffix(&contact.addr, peer),
+            None => contact.addr.clone(),
+        };
+        let fetched = match crate::network::prekeys::fetch_bundle(
+            id.libp2p.clone(),
+            &addr,
+            BUNDLE_FETCH_TIMEOUT,
+        )
+        .await
+        {
+            Ok(response) => {
+                crate::session::prekeys::accept_bundle(&config.data_dir, contact, &response)
+            }
+            Err(e) => Err(e),
+        };
+        match fetched {
+            Ok(_) => {
+                if let Some(sealed) = send::seal_pending(&config.data_dir, id, contact, payload)? {
+                    return Ok(Some(sealed));
+                }
+            }
+            Err(e) => log::warn!("prekey bundle for contact {}: {e}", contact.id),
+        }
+    }
+    if config.static_key_fallback {
+        return send::seal_static(id, contact, payload).map(Some);
+    }
+    Ok(None)
+}
+
 fn now_secs() -> u64 {
     use std::time::{SystemTime, UNIX_EPOCH};
     SystemTime::now()
@@ -0,0 +1,118 @@
+//! Proof-of-work stamps. A listener that asks peers which are not contacts for a stamp
+//! answers their unstamped requests with `STAMP <bits>`; the sender then wraps the same
+//! request with its stamping time and a counter for which SHA-256 over (domain | sender
+//! PeerId | recipient box key | time | request | counter) starts with at least that many
+//! zero bits, and sends it again. Binding the sender and recipient keeps a stamp from being
+//! replayed from other PeerIds or to other listeners; the listener records each accepted
+//! stamp's hash for the replay window, and refuses stamps older than that.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Leading byte of a stamped request (after the envelope versions 1 to 5).
+pub const STAMP_VERSION: u8 = 6;
+
+/// Senders refuse to work for more bits than this (about 16M hashes on average).
+pub const MAX_STAMP_BITS: u8 = 24;
+
+const DOMAIN: &[u8] = b"pigeon-stamp/2";
+const CHALLENGE: &str = "STAMP ";
+
+/// A request with the counter that makes its stamp.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Stamped {
+    pub version: u8,
+    pub counter: u64,
+    /// When the stamp was made (Unix seconds); it is only accepted inside the replay window.
+    pub stamped_at: u64,
+    pub request: Vec<u8>,
+}
+
+impl Stamped {
+    /// The stamp's hash for a request from `sender` (its PeerId) to the listener whose box
+    /// public key is `recipient`.
+    pub fn digest(&self, sender: &str, recipient: &[u8]) -> [u8; 32] {
+        let mut h = prefix(sender, recipient, self.stamped_at, &self.request);
+        h.update(self.counter.to_be_bytes());
+        h.finalize().into()
+    }
+
+    /// Leading zero bits of the stamp's hash: the work it proves.
+    pub fn bits(&self, sender: &str, recipient: &[u8]) -> u32 {
+        zero_bits(&self.digest(sender, recipient))
+    }
+}
+
+/// Unwrap a stamped request; `None` when `bytes` is not one.
+pub fn open(bytes: &[u8]) -> Option<Stamped> {
+    if bytes.first() != Some(&STAMP_VERSION) {
+        return None;
+    }
+    bincode::deserialize(bytes).ok()
+}
+
+/// Find a counter giving `request`, sent by `sender` to `recipient`, a stamp of `bits`
+/// and wrap it.
+pub fn stamp(request: &[u8], sender: &str, recipient: &[u8], bits: u8) -> Vec<u8> {
+    let stamped_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let start = prefix(sender, recipient, stamped_at, request);
+    let counter = (0u64..)
+        .find(|c| {
+            let mut h = start.clone();
+            h.update(c.to_be_bytes());
+            zero_bits(&h.finalize().into()) >= u32::from(bits)
+        })
+        .unwrap_or_default();
+    let stamped = Stamped {
+        version: STAMP_VERSION,
+        counter,
+        stamped_at,
+        request: request.to_vec(),
+    };
+    bincode::serialize(&stamped).expect("stamped request serializes")
+}
+
+/// The response asking for a stamp of `bits`.
+pub fn challenge(bits: u8) -> Vec<u8> {
+    format!("{CHALLENGE}{bits}").into_bytes()
+}
+
+/// The stamp size a response asks for, if it is a challenge a sender should answer.
+pub fn requested_bits(response: &[u8]) -> Option<u8> {
+    let bits: u8 = std::str::from_utf8(response)
+        .ok()?
+        .strip_prefix(CHALLENGE)?
+        .trim()
+        .parse()
+        .ok()?;
+    (bits <= MAX_STAMP_BITS).then_some(bits)
+}
+
+// Everything but the counter; variable-length parts are length-prefixed
+fn prefix(sender: &str, recipient: &[u8], stamped_at: u64, request: &[u8]) -> Sha256 {
+    let mut h = Sha256::new();
+    h.update(DOMAIN);
+    for part in [sender.as_bytes(), recipient] {
+        h.update((part.len() as u64).to_be_bytes());
+        h.update(part);
+    }
+    h.update(stamped_at.to_be_bytes());
+    h.update(request);
+    h
+}
+
+fn zero_bits(digest: &[u8; 32]) -> u32 {
+    let mut bits = 0;
+    for byte in digest {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
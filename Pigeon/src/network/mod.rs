@@ -1,4 +1,5 @@
 pub mod ping;
+pub mod prekeys;
 pub mod protocol;
 pub mod rr;
 
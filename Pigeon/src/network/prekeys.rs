@@ -0,0 +1,76 @@
+use libp2p::request_response as rr;
+use libp2p::{Multiaddr, Transport};
+
+/// Request-response protocol serving our prekey bundle.
+pub const PROTOCOL: &str = "/pigeon/prekeys/1";
+
+pub fn behaviour() -> super::rr::Behaviour {
+    rr::Behaviour::new(
+        vec![(PROTOCOL.to_string(), rr::ProtocolSupport::Full)],
+        rr::Config::default(),
+    )
+}
+
+/// Dial `addr` and ask the peer for a prekey bundle; returns the raw response.
+pub async fn fetch_bundle(
+    local_key: libp2p::identity::Keypair,
+    addr: &str,
+    timeout: std::time::Duration,
+) -> Result<Vec<u8>, crate::error::Error> {
+    let transport =
+        libp2p::tcp::tokio::Transport::new(libp2p::tcp::Config::default().nodelay(true))
+            .upgrade(libp2p::core::upgrade::Version::V1)
+            .authenticate(libp2p::noise::Config::new(&local_key).map_err(|e| {
+                crate::error::Error::Network(super::Error::Handshake(e.to_string()))
+            })?)
+            .multiplex(libp2p::yamux::Config::default())
+            .boxed();
+    let peer_id = local_key.public().to_peer_id();
+    let mut swarm = libp2p::Swarm::new(
+        transport,
+        behaviour(),
+        peer_id,
+        libp2p::swarm::Config::with_tokio_executor(),
+    );
+    let addr: Multiaddr = addr
+        .parse()
+        .map_err(|e: libp2p::multiaddr::Error| crate::error::Error::Config(e.to_string()))?;
+    libp2p::Swarm::dial(&mut swarm, addr).map_err(|e| {
+        crate::error::Error::Network(super::Error::Connection(format!("dial: {}", e)))
+    })?;
+
+    use libp2p::futures::StreamExt;
+    let exchange = async {
+        loop {
+            match swarm.select_next_some().await {
+                libp2p::swarm::SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                    let _ = swarm.behaviour_mut().send_request(
+                        &peer_id,
+                        crate::session::prekeys::BUNDLE_REQUEST.to_vec(),
+                    );
+                }
+                libp2p::swarm::SwarmEvent::Behaviour(rr::Event::<Vec<u8>, Vec<u8>>::Message {
+                    message: rr::Message::<Vec<u8>, Vec<u8>>::Response { response, .. },
+                    ..
+                }) => return Ok(response),
+                libp2p::swarm::SwarmEvent::Behaviour(rr::Event::<Vec<u8>, Vec<u8>>::OutboundFailure {
+                    error,
+                    ..
+                }) => {
+                    return Err(crate::error::Error::Network(super::Error::Protocol(format!(
+                        "prekey request: {error}"
+                    ))))
+                }
+                libp2p::swarm::SwarmEvent::OutgoingConnectionError { error, .. } => {
+                    return Err(crate::error::Error::Network(super::Error::Connection(
+                        error.to_string(),
+                    )))
+                }
+                _ => {}
+            }
+        }
+    };
+    tokio::time::timeout(timeout, exchange).await.map_err(|_| {
+        crate::error::Error::Network(super::Error::Connection("prekey fetch timed out".into()))
+    })?
+}
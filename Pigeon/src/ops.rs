@@ -4,15 +4,48 @@ use std::sync::Arc;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::time::{timeout, Duration};
 
+use crate::messaging::inbound::InboundOutcome;
+use crate::messaging::limits::Throttle;
+
 #[derive(Default, Clone)]
 pub struct Metrics {
     pub sent_messages: Arc<AtomicU64>,
     pub delivered_messages: Arc<AtomicU64>,
     pub failed_messages: Arc<AtomicU64>,
     pub received_messages: Arc<AtomicU64>,
+    /// Inbound requests turned away, by reason (see `count_inbound`).
+    pub blocked_messages: Arc<AtomicU64>,
+    pub muted_messages: Arc<AtomicU64>,
+    pub refused_messages: Arc<AtomicU64>,
+    pub rejected_messages: Arc<AtomicU64>,
+    pub replayed_messages: Arc<AtomicU64>,
+    pub rate_limited_messages: Arc<AtomicU64>,
+    pub bandwidth_limited_messages: Arc<AtomicU64>,
+    pub stamp_required_messages: Arc<AtomicU64>,
+    /// Connections closed for going over the per-peer cap.
+    pub refused_connections: Arc<AtomicU64>,
 }
 
 impl Metrics {
+    /// Count what the listener did with one inbound request.
+    pub fn count_inbound(&self, outcome: &InboundOutcome) {
+        let counter = match outcome {
+            InboundOutcome::Delivered { .. } | InboundOutcome::GroupDelivered { .. } => {
+                &self.received_messages
+            }
+            InboundOutcome::Blocked => &self.blocked_messages,
+            InboundOutcome::Muted => &self.muted_messages,
+            InboundOutcome::Refused => &self.refused_messages,
+            InboundOutcome::Rejected(_) => &self.rejected_messages,
+            InboundOutcome::Replay => &self.replayed_messages,
+            InboundOutcome::Throttled(Throttle::Peer) => &self.rate_limited_messages,
+            InboundOutcome::Throttled(Throttle::Bandwidth) => &self.bandwidth_limited_messages,
+            InboundOutcome::StampRequired(_) => &self.stamp_required_messages,
+            _ => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
     pub fn render_prometheus(&self) -> String {
         format!(
             concat!(
@@ -28,11 +61,33 @@ impl Metrics {
                 "# HELP pigeon_received_messages Total messages received\n",
                 "# TYPE pigeon_received_messages counter\n",
                 "pigeon_received_messages {}\n",
+                "# HELP pigeon_inbound_rejected Inbound requests not delivered, by reason\n",
+                "# TYPE pigeon_inbound_rejected counter\n",
+                "pigeon_inbound_rejected{{reason=\"blocked\"}} {}\n",
+                "pigeon_inbound_rejected{{reason=\"muted\"}} {}\n",
+                "pigeon_inbound_rejected{{reason=\"unknown_peer\"}} {}\n",
+                "pigeon_inbound_rejected{{reason=\"invalid\"}} {}\n",
+                "pigeon_inbound_rejected{{reason=\"replay\"}} {}\n",
+                "pigeon_inbound_rejected{{reason=\"rate_limited\"}} {}\n",
+                "pigeon_inbound_rejected{{reason=\"bandwidth\"}} {}\n",
+                "pigeon_inbound_rejected{{reason=\"stamp_required\"}} {}\n",
+                "# HELP pigeon_inbound_connections_refused Connections closed for exceeding the per-peer cap\n",
+                "# TYPE pigeon_inbound_connections_refused counter\n",
+                "pigeon_inbound_connections_refused {}\n",
             ),
             self.sent_messages.load(Ordering::Relaxed),
             self.delivered_messages.load(Ordering::Relaxed),
             self.failed_messages.load(Ordering::Relaxed),
             self.received_messages.load(Ordering::Relaxed),
+            self.blocked_messages.load(Ordering::Relaxed),
+            self.muted_messages.load(Ordering::Relaxed),
+            self.refused_messages.load(Ordering::Relaxed),
+            self.rejected_messages.load(Ordering::Relaxed),
+            self.replayed_messages.load(Ordering::Relaxed),
+            self.rate_limited_messages.load(Ordering::Relaxed),
+            self.bandwidth_limited_messages.load(Ordering::Relaxed),
+            self.stamp_required_messages.load(Ordering::Relaxed),
+            self.refused_connections.load(Ordering::Relaxed),
         )
     }
 }
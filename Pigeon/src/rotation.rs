@@ -0,0 +1,161 @@
+//! Identity rotation: replace the box, signing and libp2p keys and announce the move to
+//! contacts with a `KeyTransition` signed by the old signing key they have pinned.
+
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sodiumoxide::crypto::sign;
+
+use crate::identity::Identity;
+use crate::messaging::message::{KeyTransition, KEY_TRANSITION_VERSION};
+use crate::session;
+use crate::storage::contacts::{Contact, ContactStore};
+
+/// What receiving a key transition did to the sender's contact record.
+#[derive(Debug, Clone)]
+pub enum TransitionOutcome {
+    /// The old-key signature matched the pinned signing key; the contact now has the new keys.
+    Applied(Contact),
+    /// The contact already has the announced keys (a resent announcement).
+    AlreadyApplied(Contact),
+    /// No signing key was pinned to check the announcement against, so the new keys wait as
+    /// a pending key change for the user. Only staged when sent from the contact's PeerId.
+    Staged(Contact),
+}
+
+/// Replace the identity in `data_dir` with fresh keys and return it with the signed
+/// announcement for contacts. Sessions and prekeys belonged to the old keys and are dropped.
+pub fn rotate_identity(data_dir: &Path) -> Result<(Identity, KeyTransition), crate::error::Error> {
+    let old = Identity::load_or_generate(data_dir)?;
+    let new = Identity::regenerate(data_dir)?;
+    let transition = sign_transition(&old, &new, now_secs());
+    session::forget_all(data_dir)?;
+    Ok((new, transition))
+}
+
+/// Build the announcement of a move from `old` to `new`, signed by both signing keys.
+pub fn sign_transition(old: &Identity, new: &Identity, issued_at: u64) -> KeyTransition {
+    #[cfg(feature = "network")]
+    let new_peer_id = Some(new.libp2p.public().to_peer_id().to_base58());
+    #[cfg(not(feature = "network"))]
+    let new_peer_id = None;
+    let mut t = KeyTransition {
+        version: KEY_TRANSITION_VERSION,
+        old_box_key: old.sodium_box_pk.0,
+        old_sign_key: old.sign_pk.0,
+        new_box_key: new.sodium_box_pk.0,
+        new_sign_key: new.sign_pk.0,
+        new_peer_id,
+        issued_at,
+        old_signature: Vec::new(),
+        new_signature: Vec::new(),
+    };
+    let bytes = t.signed_bytes();
+    t.old_signature = sign::sign_detached(&bytes, &old.sign_sk).to_bytes().to_vec();
+    t.new_signature = sign::sign_detached(&bytes, &new.sign_sk).to_bytes().to_vec();
+    t
+}
+
+/// Check both signatures of an announcement against the keys it names.
+pub fn verify(t: &KeyTransition) -> Result<(), crate::error::Error> {
+    let bad = |what: &str| {
+        crate::error::Error::Crypto(crate::crypto::Error::Signature(format!(
+            "key transition: {what}"
+        )))
+    };
+    if t.version != KEY_TRANSITION_VERSION {
+        return Err(bad("unsupported version"));
+    }
+    let bytes = t.signed_bytes();
+    let checks = [
+        (&t.old_sign_key, &t.old_signature, "old-key signature does not verify"),
+        (&t.new_sign_key, &t.new_signature, "new-key signature does not verify"),
+    ];
+    for (key, signature, what) in checks {
+        let ok = sign::Signature::from_bytes(signature)
+            .map(|s| sign::verify_detached(&s, &bytes, &sign::PublicKey(*key)))
+            .unwrap_or(false);
+        if !ok {
+            return Err(bad(what));
+        }
+    }
+    Ok(())
+}
+
+/// Apply an announcement received from `peer_id`, the PeerId authenticated by the Noise
+/// handshake, to the contact pinned to its old keys.
+pub fn apply(
+    data_dir: &Path,
+    peer_id: &str,
+    t: &KeyTransition,
+) -> Result<TransitionOutcome, crate::error::Error> {
+    verify(t)?;
+    if t.new_peer_id.as_deref().is_some_and(|p| p != peer_id) {
+        return Err(crate::error::Error::Config(
+            "key transition was not sent from the announced peer id".into(),
+        ));
+    }
+    let store = ContactStore::open_in_dir(data_dir).map_err(crate::error::Error::Storage)?;
+    let contacts = store.list().map_err(crate::error::Error::Storage)?;
+    if let Some(c) = contacts.iter().find(|c| {
+        c.public_key == t.new_box_key
+            && c.sign_public_key.as_deref() == Some(t.new_sign_key.as_slice())
+    }) {
+        return Ok(TransitionOutcome::AlreadyApplied(c.clone()));
+    }
+    let contact = contacts
+        .into_iter()
+        .find(|c| c.public_key == t.old_box_key)
+        .ok_or_else(|| {
+            crate::error::Error::Config("key transition names no known contact".into())
+        })?;
+    match contact.sign_public_key.as_deref() {
+        Some(pinned) if pinned == t.old_sign_key => {
+            let updated = store
+                .apply_key_transition(
+                    contact.id,
+                    &t.new_box_key,
+                    &t.new_sign_key,
+                    t.new_peer_id.as_deref(),
+                )
+                .map_err(crate::error::Error::Storage)?;
+            // The ratchet was keyed to the old identity key
+            session::reset(data_dir, contact.id)?;
+            log::info!(
+                "contact {} ({}) rotated its keys; the safety number changed",
+                updated.id,
+                updated.name
+            );
+            Ok(TransitionOutcome::Applied(updated))
+        }
+        Some(_) => Err(crate::error::Error::Crypto(crate::crypto::Error::Signature(
+            "key transition is not signed by the pinned signing key".into(),
+        ))),
+        None => {
+            // Nothing vouches for the old-key signature, so only the PeerId the contact is
+            // bound to may stage new keys for it
+            let bound = contact
+                .peer_id
+                .as_deref()
+                .or_else(|| crate::discovery::peer_id_from_addr(&contact.addr));
+            if bound != Some(peer_id) {
+                return Err(crate::error::Error::Config(
+                    "key transition for a contact without a pinned signing key was not sent \
+                     from its peer id"
+                        .into(),
+                ));
+            }
+            let staged = store
+                .observe_keys(contact.id, &t.new_box_key, Some(&t.new_sign_key))
+                .map_err(crate::error::Error::Storage)?;
+            Ok(TransitionOutcome::Staged(staged))
+        }
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -0,0 +1,263 @@
+//! Forward-secret per-contact sessions: an X3DH handshake seeds a Double Ratchet whose
+//! message keys are used once and then forgotten.
+
+pub mod prekeys;
+pub mod ratchet;
+pub mod x3dh;
+
+use std::path::Path;
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sodiumoxide::crypto::scalarmult::curve25519 as x25519;
+use thiserror::Error;
+
+use crate::identity::Identity;
+use crate::messaging::message::{EnvelopeV2, PreKeyMessage};
+use crate::storage::contacts::Contact;
+use crate::storage::sessions::SessionStore;
+use ratchet::Ratchet;
+
+// Older sessions are kept briefly so messages from a simultaneous handshake still open
+const MAX_SESSIONS: usize = 3;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("session cannot send until the peer's first message arrives")]
+    NotReady,
+    #[error("message was already received")]
+    Duplicate,
+    #[error("message skips more than {} keys", ratchet::MAX_SKIP)]
+    TooManySkipped,
+    #[error("message does not decrypt in any session")]
+    Decrypt,
+    #[error("invalid session key: {0}")]
+    InvalidKey(String),
+    #[error("no prekey bundle was fetched for the contact")]
+    NoBundle,
+}
+
+/// One ratchet plus the handshake bookkeeping around it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Session {
+    pub ratchet: Ratchet,
+    /// Attached to every message until the peer answers, so it can build its side.
+    pub pending_prekey: Option<PreKeyMessage>,
+    /// X3DH ephemeral key of the initiator, for sessions we responded to.
+    pub remote_ephemeral: Option<[u8; 32]>,
+}
+
+/// All sessions with one contact, most recently used first.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SessionRecord {
+    pub sessions: Vec<Session>,
+}
+
+impl SessionRecord {
+    fn promote(&mut self, index: usize, session: Session) {
+        self.sessions.remove(index);
+        self.push_front(session);
+    }
+
+    fn push_front(&mut self, session: Session) {
+        self.sessions.insert(0, session);
+        self.sessions.truncate(MAX_SESSIONS);
+    }
+}
+
+/// Encrypt `plaintext` for `contact`, starting a session if none can send yet. Starting one
+/// needs a bundle fetched from the contact (`Error::NoBundle` otherwise).
+pub fn seal_for_contact(
+    data_dir: &Path,
+    id: &Identity,
+    contact: &Contact,
+    plaintext: &[u8],
+) -> Result<EnvelopeV2, crate::error::Error> {
+    let store = SessionStore::open_in_dir(data_dir).map_err(crate::error::Error::Storage)?;
+    let mut record = store
+        .load(contact.id)
+        .map_err(crate::error::Error::Storage)?
+        .unwrap_or_default();
+    if !record.sessions.first().is_some_and(|s| s.ratchet.can_send()) {
+        record.push_front(initiate(data_dir, id, contact)?);
+    }
+    let session = &mut record.sessions[0];
+    let (header, payload) = session.ratchet.encrypt(plaintext)?;
+    let mut env = EnvelopeV2::new(session.pending_prekey, header, payload);
+    env.signature = sodiumoxide::crypto::sign::sign_detached(&env.signed_bytes(), &id.sign_sk)
+        .to_bytes()
+        .to_vec();
+    store
+        .save(contact.id, &record)
+        .map_err(crate::error::Error::Storage)?;
+    Ok(env)
+}
+
+/// Decrypt an envelope from `contact`, answering its X3DH handshake if it starts a session.
+pub fn open_from_contact(
+    data_dir: &Path,
+    id: &Identity,
+    contact: &Contact,
+    env: &EnvelopeV2,
+) -> Result<Vec<u8>, crate::error::Error> {
+    let store = SessionStore::open_in_dir(data_dir).map_err(crate::error::Error::Storage)?;
+    let mut record = store
+        .load(contact.id)
+        .map_err(crate::error::Error::Storage)?
+        .unwrap_or_default();
+
+    let mut duplicate = false;
+    for (i, existing) in record.sessions.iter().enumerate() {
+        let mut session = existing.clone();
+        match session.ratchet.decrypt(&env.header, &env.payload) {
+            Ok(plaintext) => {
+                // The peer has our handshake now; stop resending it
+                session.pending_prekey = None;
+                record.promote(i, session);
+                store
+                    .save(contact.id, &record)
+                    .map_err(crate::error::Error::Storage)?;
+                return Ok(plaintext);
+            }
+            Err(Error::Duplicate) => duplicate = true,
+            Err(_) => {}
+        }
+    }
+    let known_handshake = env.prekey.is_some_and(|p| {
+        record
+            .sessions
+            .iter()
+            .any(|s| s.remote_ephemeral == Some(p.ephemeral_key))
+    });
+    let (Some(prekey), false) = (env.prekey, known_handshake) else {
+        return Err(if duplicate || known_handshake {
+            Error::Duplicate
+        } else {
+            Error::Decrypt
+        }
+        .into());
+    };
+
+    let mut session = respond(data_dir, id, contact, &prekey)?;
+    let plaintext = session.ratchet.decrypt(&env.header, &env.payload)?;
+    if let Some(opk_id) = prekey.one_time_prekey_id {
+        prekeys::consume_one_time(data_dir, opk_id)?;
+    }
+    record.push_front(session);
+    store
+        .save(contact.id, &record)
+        .map_err(crate::error::Error::Storage)?;
+    Ok(plaintext)
+}
+
+/// Forget all sessions with a contact, and any bundle fetched from it (e.g. after accepting
+/// new keys).
+pub fn reset(data_dir: &Path, contact_id: u64) -> Result<bool, crate::error::Error> {
+    prekeys::forget_bundle(data_dir, contact_id)?;
+    let store = SessionStore::open_in_dir(data_dir).map_err(crate::error::Error::Storage)?;
+    store.remove(contact_id).map_err(crate::error::Error::Storage)
+}
+
+/// Forget every session and our own prekeys, which belong to the identity being replaced.
+pub fn forget_all(data_dir: &Path) -> Result<(), crate::error::Error> {
+    SessionStore::open_in_dir(data_dir)
+        .and_then(|s| s.clear())
+        .map_err(crate::error::Error::Storage)?;
+    crate::storage::prekeys::PreKeyStore::open_in_dir(data_dir)
+        .and_then(|s| s.clear_local())
+        .map_err(crate::error::Error::Storage)
+}
+
+fn initiate(
+    data_dir: &Path,
+    id: &Identity,
+    contact: &Contact,
+) -> Result<Session, crate::error::Error> {
+    let remote_ik = key32(&contact.public_key)?;
+    // Standing in the identity key for the signed prekey would leave the session without
+    // the signed prekey's forward secrecy, so no session is started without a bundle
+    let bundle = prekeys::bundle_for(data_dir, contact, remote_ik)?.ok_or(Error::NoBundle)?;
+    let init = x3dh::initiate(&id.sodium_box_sk, &id.sodium_box_pk, &bundle)?;
+    let ratchet = Ratchet::init_initiator(
+        init.shared_secret,
+        bundle.signed_prekey,
+        init.associated_data,
+    )?;
+    Ok(Session {
+        ratchet,
+        pending_prekey: Some(PreKeyMessage {
+            identity_key: id.sodium_box_pk.0,
+            ephemeral_key: init.ephemeral_public,
+            signed_prekey_id: bundle.signed_prekey_id,
+            one_time_prekey_id: bundle.one_time_prekey.map(|(opk_id, _)| opk_id),
+        }),
+        remote_ephemeral: None,
+    })
+}
+
+fn respond(
+    data_dir: &Path,
+    id: &Identity,
+    contact: &Contact,
+    prekey: &PreKeyMessage,
+) -> Result<Session, crate::error::Error> {
+    // The handshake must come from the key we pinned for this contact
+    if prekey.identity_key != key32(&contact.public_key)? {
+        return Err(Error::InvalidKey(
+            "handshake identity does not match the contact's pinned key".into(),
+        )
+        .into());
+    }
+    let keys = prekeys::responder_secrets(data_dir, id, prekey)?;
+    let (shared_secret, ad) = x3dh::respond(
+        &id.sodium_box_sk,
+        &id.sodium_box_pk,
+        &keys.signed_sk,
+        keys.one_time_sk.as_ref(),
+        &prekey.identity_key,
+        &prekey.ephemeral_key,
+    )?;
+    Ok(Session {
+        ratchet: Ratchet::init_responder(shared_secret, keys.signed_sk, keys.signed_pk, ad),
+        pending_prekey: None,
+        remote_ephemeral: Some(prekey.ephemeral_key),
+    })
+}
+
+fn key32(bytes: &[u8]) -> Result<[u8; 32], Error> {
+    bytes
+        .try_into()
+        .map_err(|_| Error::InvalidKey("expected 32 bytes".into()))
+}
+
+/// X25519 shared secret; rejects low-order points that would give an all-zero output.
+fn dh(sk: &[u8; 32], pk: &[u8; 32]) -> Result<[u8; 32], Error> {
+    let out = x25519::scalarmult(&x25519::Scalar(*sk), &x25519::GroupElement(*pk))
+        .map_err(|_| Error::InvalidKey("low-order public key".into()))?;
+    Ok(out.0)
+}
+
+/// HKDF-SHA256 (RFC 5869) extract-and-expand.
+pub(crate) fn hkdf(salt: &[u8], ikm: &[u8], info: &[u8], len: usize) -> Vec<u8> {
+    let new_mac = |key: &[u8]| {
+        <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts any key size")
+    };
+    let mut extract = new_mac(salt);
+    extract.update(ikm);
+    let prk = extract.finalize().into_bytes();
+    let mut out = Vec::with_capacity(len);
+    let mut block: Vec<u8> = Vec::new();
+    let mut counter = 1u8;
+    while out.len() < len {
+        let mut expand = new_mac(&prk);
+        expand.update(&block);
+        expand.update(info);
+        expand.update(&[counter]);
+        block = expand.finalize().into_bytes().to_vec();
+        out.extend_from_slice(&block);
+        counter += 1;
+    }
+    out.truncate(len);
+    out
+}
@@ -0,0 +1,352 @@
+//! Our signed and one-time prekeys, and the bundles peers fetch to start a session with us
+//! while we are offline.
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::box_;
+
+use super::x3dh::PreKeyBundle;
+use super::Error;
+use crate::identity::Identity;
+use crate::messaging::message::PreKeyMessage;
+use crate::storage::contacts::Contact;
+use crate::storage::prekeys::PreKeyStore;
+
+/// Request body for `/pigeon/prekeys/1`; the response is a bincode `PreKeyBundle`.
+pub const BUNDLE_REQUEST: &[u8] = b"pigeon-prekeys-v1";
+/// Signed prekey id meaning "the identity key". Older initiators used it when they had no
+/// bundle; such handshakes are still answered, but no longer started.
+pub const IDENTITY_PREKEY_ID: u32 = 0;
+/// Signed prekeys are replaced once they are this old (checked whenever a bundle is served).
+pub const SIGNED_PREKEY_MAX_AGE_SECS: u64 = 7 * 24 * 60 * 60;
+/// One-time prekeys generated per top-up.
+pub const ONE_TIME_BATCH: usize = 20;
+// Top up when fewer than this many one-time prekeys are still unissued
+const ONE_TIME_LOW_WATER: usize = 5;
+// Issued but never used one-time prekeys are dropped beyond this, oldest first
+const ONE_TIME_MAX: usize = 100;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SignedPreKey {
+    pub id: u32,
+    pub public: [u8; 32],
+    pub secret: [u8; 32],
+    pub signature: Vec<u8>, // ed25519 over x3dh::signed_prekey_bytes(id, public)
+    pub created_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct OneTimePreKey {
+    pub id: u32,
+    pub public: [u8; 32],
+    pub secret: [u8; 32],
+    /// Handed out in a bundle; never handed out again.
+    pub issued: bool,
+}
+
+/// All our prekey secrets. The previous signed prekey is kept so handshakes started just
+/// before a rotation still complete.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LocalPreKeys {
+    pub signed: SignedPreKey,
+    pub previous_signed: Option<SignedPreKey>,
+    pub one_time: Vec<OneTimePreKey>,
+    next_id: u32,
+}
+
+/// Snapshot of our prekeys for display.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreKeyStatus {
+    pub signed_prekey_id: u32,
+    pub signed_prekey_created_at: u64,
+    pub one_time_available: usize,
+    pub one_time_issued: usize,
+    pub one_time_consumed: usize,
+}
+
+impl LocalPreKeys {
+    pub fn generate(id: &Identity, now: u64) -> Self {
+        let mut keys = Self {
+            signed: id.generate_signed_prekey(1, now),
+            previous_signed: None,
+            one_time: Vec::new(),
+            next_id: 2,
+        };
+        keys.top_up(id);
+        keys
+    }
+
+    fn take_id(&mut self) -> u32 {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1).max(1);
+        id
+    }
+
+    pub fn rotation_due(&self, now: u64) -> bool {
+        now.saturating_sub(self.signed.created_at) >= SIGNED_PREKEY_MAX_AGE_SECS
+    }
+
+    /// Replace the signed prekey, keeping the current one as `previous_signed`.
+    pub fn rotate(&mut self, id: &Identity, now: u64) {
+        let spk_id = self.take_id();
+        let old = std::mem::replace(&mut self.signed, id.generate_signed_prekey(spk_id, now));
+        self.previous_signed = Some(old);
+    }
+
+    fn top_up(&mut self, id: &Identity) {
+        if self.one_time.iter().filter(|k| !k.issued).count() >= ONE_TIME_LOW_WATER {
+            return;
+        }
+        let first = self.next_id;
+        let batch = id.generate_one_time_prekeys(first, ONE_TIME_BATCH);
+        self.next_id = first.wrapping_add(ONE_TIME_BATCH as u32).max(1);
+        self.one_time.extend(batch);
+        while self.one_time.len() > ONE_TIME_MAX {
+            match self.one_time.iter().position(|k| k.issued) {
+                Some(i) => self.one_time.remove(i),
+                None => break,
+            };
+        }
+    }
+
+    /// Build a bundle, handing out the next unissued one-time prekey.
+    fn issue_bundle(&mut self, id: &Identity) -> PreKeyBundle {
+        let one_time_prekey = self.one_time.iter_mut().find(|k| !k.issued).map(|k| {
+            k.issued = true;
+            (k.id, k.public)
+        });
+        PreKeyBundle {
+            identity_key: id.sodium_box_pk.0,
+            sign_key: id.sign_pk.0,
+            signed_prekey_id: self.signed.id,
+            signed_prekey: self.signed.public,
+            signed_prekey_signature: self.signed.signature.clone(),
+            one_time_prekey,
+        }
+    }
+
+    fn signed_pair(&self, spk_id: u32) -> Option<([u8; 32], [u8; 32])> {
+        [Some(&self.signed), self.previous_signed.as_ref()]
+            .into_iter()
+            .flatten()
+            .find(|k| k.id == spk_id)
+            .map(|k| (k.secret, k.public))
+    }
+}
+
+/// Load our prekeys, creating them on first use and rotating or topping up as needed.
+fn load_maintained(
+    store: &PreKeyStore,
+    id: &Identity,
+    now: u64,
+) -> Result<LocalPreKeys, crate::error::Error> {
+    let mut keys = match store.load_local().map_err(crate::error::Error::Storage)? {
+        Some(keys) => keys,
+        None => LocalPreKeys::generate(id, now),
+    };
+    if keys.rotation_due(now) {
+        keys.rotate(id, now);
+    }
+    keys.top_up(id);
+    Ok(keys)
+}
+
+/// Our current bundle for a peer that asked for it. Each call issues a fresh one-time prekey.
+pub fn local_bundle(data_dir: &Path, id: &Identity) -> Result<PreKeyBundle, crate::error::Error> {
+    let store = PreKeyStore::open_in_dir(data_dir).map_err(crate::error::Error::Storage)?;
+    let mut keys = load_maintained(&store, id, now_secs())?;
+    let bundle = keys.issue_bundle(id);
+    store.save_local(&keys).map_err(crate::error::Error::Storage)?;
+    Ok(bundle)
+}
+
+/// Replace the signed prekey now; returns the new prekey id.
+pub fn rotate(data_dir: &Path, id: &Identity) -> Result<u32, crate::error::Error> {
+    let store = PreKeyStore::open_in_dir(data_dir).map_err(crate::error::Error::Storage)?;
+    let now = now_secs();
+    let mut keys = load_maintained(&store, id, now)?;
+    keys.rotate(id, now);
+    store.save_local(&keys).map_err(crate::error::Error::Storage)?;
+    Ok(keys.signed.id)
+}
+
+pub fn status(data_dir: &Path, id: &Identity) -> Result<PreKeyStatus, crate::error::Error> {
+    let store = PreKeyStore::open_in_dir(data_dir).map_err(crate::error::Error::Storage)?;
+    let keys = load_maintained(&store, id, now_secs())?;
+    store.save_local(&keys).map_err(crate::error::Error::Storage)?;
+    let issued = keys.one_time.iter().filter(|k| k.issued).count();
+    Ok(PreKeyStatus {
+        signed_prekey_id: keys.signed.id,
+        signed_prekey_created_at: keys.signed.created_at,
+        one_time_available: keys.one_time.len() - issued,
+        one_time_issued: issued,
+        one_time_consumed: store.consumed_count(),
+    })
+}
+
+/// Answer a `/pigeon/prekeys/1` request with a serialized bundle.
+pub fn serve_request(
+    data_dir: &Path,
+    id: &Identity,
+    request: &[u8],
+) -> Result<Vec<u8>, crate::error::Error> {
+    if request != BUNDLE_REQUEST {
+        return Err(crate::error::Error::Config(
+            "unsupported prekey request".into(),
+        ));
+    }
+    let bundle = local_bundle(data_dir, id)?;
+    bincode::serialize(&bundle).map_err(|e| crate::error::Error::Serialization(e.to_string()))
+}
+
+/// Verify a bundle fetched from `contact` and keep it for the next session we start.
+pub fn accept_bundle(
+    data_dir: &Path,
+    contact: &Contact,
+    response: &[u8],
+) -> Result<PreKeyBundle, crate::error::Error> {
+    let bundle: PreKeyBundle = bincode::deserialize(response)
+        .map_err(|e| crate::error::Error::Serialization(format!("prekey bundle: {e}")))?;
+    bundle.verify()?;
+    if bundle.identity_key.as_slice() != contact.public_key.as_slice() {
+        return Err(Error::InvalidKey(
+            "bundle identity does not match the contact's pinned key".into(),
+        )
+        .into());
+    }
+    if let Some(sign) = &contact.sign_public_key {
+        if bundle.sign_key.as_slice() != sign.as_slice() {
+            return Err(Error::InvalidKey(
+                "bundle is not signed by the contact's pinned signing key".into(),
+            )
+            .into());
+        }
+    }
+    let store = PreKeyStore::open_in_dir(data_dir).map_err(crate::error::Error::Storage)?;
+    store
+        .save_remote(contact.id, &bundle)
+        .map_err(crate::error::Error::Storage)?;
+    Ok(bundle)
+}
+
+/// Bundle to start a session with `contact`: the fetched one (its one-time prekey is used
+/// at most once), or `None` when no bundle for its current identity key was fetched.
+pub(super) fn bundle_for(
+    data_dir: &Path,
+    contact: &Contact,
+    remote_ik: [u8; 32],
+) -> Result<Option<PreKeyBundle>, crate::error::Error> {
+    let store = PreKeyStore::open_in_dir(data_dir).map_err(crate::error::Error::Storage)?;
+    let Some(bundle) = store
+        .load_remote(contact.id)
+        .map_err(crate::error::Error::Storage)?
+        .filter(|b| b.identity_key == remote_ik)
+    else {
+        return Ok(None);
+    };
+    if bundle.one_time_prekey.is_some() {
+        let spent = PreKeyBundle {
+            one_time_prekey: None,
+            ..bundle.clone()
+        };
+        store
+            .save_remote(contact.id, &spent)
+            .map_err(crate::error::Error::Storage)?;
+    }
+    Ok(Some(bundle))
+}
+
+/// Our side of the keys a handshake was made against.
+pub(super) struct ResponderKeys {
+    pub signed_sk: [u8; 32],
+    pub signed_pk: [u8; 32],
+    pub one_time_sk: Option<[u8; 32]>,
+}
+
+/// Look up the secrets a responder needs for `prekey`.
+pub(super) fn responder_secrets(
+    data_dir: &Path,
+    id: &Identity,
+    prekey: &PreKeyMessage,
+) -> Result<ResponderKeys, crate::error::Error> {
+    if prekey.signed_prekey_id == IDENTITY_PREKEY_ID && prekey.one_time_prekey_id.is_none() {
+        return Ok(ResponderKeys {
+            signed_sk: id.sodium_box_sk.0,
+            signed_pk: id.sodium_box_pk.0,
+            one_time_sk: None,
+        });
+    }
+    let store = PreKeyStore::open_in_dir(data_dir).map_err(crate::error::Error::Storage)?;
+    let keys = store
+        .load_local()
+        .map_err(crate::error::Error::Storage)?
+        .ok_or_else(|| Error::InvalidKey("no prekeys were published".into()))?;
+    let (signed_sk, signed_pk) = if prekey.signed_prekey_id == IDENTITY_PREKEY_ID {
+        (id.sodium_box_sk.0, id.sodium_box_pk.0)
+    } else {
+        keys.signed_pair(prekey.signed_prekey_id).ok_or_else(|| {
+            Error::InvalidKey(format!(
+                "unknown signed prekey {}",
+                prekey.signed_prekey_id
+            ))
+        })?
+    };
+    let one_time_sk = match prekey.one_time_prekey_id {
+        None => None,
+        Some(opk_id) => {
+            if store
+                .is_consumed(opk_id)
+                .map_err(crate::error::Error::Storage)?
+            {
+                return Err(
+                    Error::InvalidKey(format!("one-time prekey {opk_id} was already used")).into(),
+                );
+            }
+            let k = keys
+                .one_time
+                .iter()
+                .find(|k| k.id == opk_id)
+                .ok_or_else(|| Error::InvalidKey(format!("unknown one-time prekey {opk_id}")))?;
+            Some(k.secret)
+        }
+    };
+    Ok(ResponderKeys {
+        signed_sk,
+        signed_pk,
+        one_time_sk,
+    })
+}
+
+/// Forget a one-time prekey's secret once a handshake used it.
+pub(super) fn consume_one_time(data_dir: &Path, opk_id: u32) -> Result<(), crate::error::Error> {
+    let store = PreKeyStore::open_in_dir(data_dir).map_err(crate::error::Error::Storage)?;
+    if let Some(mut keys) = store.load_local().map_err(crate::error::Error::Storage)? {
+        keys.one_time.retain(|k| k.id != opk_id);
+        store.save_local(&keys).map_err(crate::error::Error::Storage)?;
+    }
+    store
+        .mark_consumed(opk_id, now_secs())
+        .map_err(crate::error::Error::Storage)
+}
+
+/// Drop a contact's fetched bundle (e.g. after their identity key changed).
+pub(super) fn forget_bundle(data_dir: &Path, contact_id: u64) -> Result<(), crate::error::Error> {
+    let store = PreKeyStore::open_in_dir(data_dir).map_err(crate::error::Error::Storage)?;
+    store
+        .remove_remote(contact_id)
+        .map_err(crate::error::Error::Storage)?;
+    Ok(())
+}
+
+pub(crate) fn new_keypair() -> ([u8; 32], [u8; 32]) {
+    let (pk, sk) = box_::gen_keypair();
+    (pk.0, sk.0)
+}
+
+fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
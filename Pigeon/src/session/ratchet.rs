@@ -0,0 +1,225 @@
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sodiumoxide::crypto::aead::xchacha20poly1305_ietf as aead;
+use sodiumoxide::crypto::box_;
+
+use super::{dh, hkdf, Error};
+use crate::messaging::message::RatchetHeader;
+
+/// Most message keys we derive ahead when a header skips forward.
+pub const MAX_SKIP: u32 = 1000;
+// Upper bound on stored skipped keys across all chains; oldest are dropped first
+const MAX_SKIPPED_KEYS: usize = 2000;
+
+const ROOT_INFO: &[u8] = b"pigeon-ratchet-root";
+const MESSAGE_INFO: &[u8] = b"pigeon-ratchet-message";
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+struct SkippedKey {
+    dh_public: [u8; 32],
+    n: u32,
+    key: [u8; 32],
+}
+
+/// Double Ratchet state for one session with one peer.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Ratchet {
+    root_key: [u8; 32],
+    dh_self_sk: [u8; 32],
+    dh_self_pk: [u8; 32],
+    dh_remote: Option<[u8; 32]>,
+    send_chain: Option<[u8; 32]>,
+    recv_chain: Option<[u8; 32]>,
+    send_n: u32,
+    recv_n: u32,
+    prev_send_n: u32,
+    skipped: Vec<SkippedKey>,
+    associated_data: Vec<u8>,
+}
+
+impl Ratchet {
+    /// Initiator side: we know the responder's ratchet key (its signed prekey) up front.
+    pub fn init_initiator(
+        shared_secret: [u8; 32],
+        remote_ratchet_key: [u8; 32],
+        associated_data: Vec<u8>,
+    ) -> Result<Self, Error> {
+        let (pk, sk) = box_::gen_keypair();
+        let dh_out = dh(&sk.0, &remote_ratchet_key)?;
+        let (root_key, send_chain) = kdf_root(&shared_secret, &dh_out);
+        Ok(Self {
+            root_key,
+            dh_self_sk: sk.0,
+            dh_self_pk: pk.0,
+            dh_remote: Some(remote_ratchet_key),
+            send_chain: Some(send_chain),
+            recv_chain: None,
+            send_n: 0,
+            recv_n: 0,
+            prev_send_n: 0,
+            skipped: Vec::new(),
+            associated_data,
+        })
+    }
+
+    /// Responder side: our signed prekey pair is the first ratchet key pair.
+    pub fn init_responder(
+        shared_secret: [u8; 32],
+        ratchet_sk: [u8; 32],
+        ratchet_pk: [u8; 32],
+        associated_data: Vec<u8>,
+    ) -> Self {
+        Self {
+            root_key: shared_secret,
+            dh_self_sk: ratchet_sk,
+            dh_self_pk: ratchet_pk,
+            dh_remote: None,
+            send_chain: None,
+            recv_chain: None,
+            send_n: 0,
+            recv_n: 0,
+            prev_send_n: 0,
+            skipped: Vec::new(),
+            associated_data,
+        }
+    }
+
+    /// A responder cannot send until the initiator's first message arrives.
+    pub fn can_send(&self) -> bool {
+        self.send_chain.is_some()
+    }
+
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<(RatchetHeader, Vec<u8>), Error> {
+        let chain = self.send_chain.ok_or(Error::NotReady)?;
+        let (next, mk) = kdf_chain(&chain);
+        let header = RatchetHeader {
+            dh_public: self.dh_self_pk,
+            prev_chain_len: self.prev_send_n,
+            n: self.send_n,
+        };
+        self.send_chain = Some(next);
+        self.send_n += 1;
+        Ok((header, seal(&mk, &self.ad_for(&header), plaintext)))
+    }
+
+    /// Decrypt a message. State only changes when decryption succeeds, so a failed attempt
+    /// (e.g. trying the wrong session) leaves the ratchet untouched.
+    pub fn decrypt(&mut self, header: &RatchetHeader, ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+        let ad = self.ad_for(header);
+        if let Some(pos) = self
+            .skipped
+            .iter()
+            .position(|k| k.dh_public == header.dh_public && k.n == header.n)
+        {
+            let pt = open(&self.skipped[pos].key, &ad, ciphertext)?;
+            self.skipped.remove(pos);
+            return Ok(pt);
+        }
+        let mut next = self.clone();
+        if next.dh_remote != Some(header.dh_public) {
+            next.skip_to(header.prev_chain_len)?;
+            next.dh_step(header.dh_public)?;
+        } else if header.n < next.recv_n {
+            // Same chain, already consumed and not among the skipped keys
+            return Err(Error::Duplicate);
+        }
+        next.skip_to(header.n)?;
+        let chain = next.recv_chain.ok_or(Error::NotReady)?;
+        let (chain, mk) = kdf_chain(&chain);
+        let pt = open(&mk, &ad, ciphertext)?;
+        next.recv_chain = Some(chain);
+        next.recv_n += 1;
+        *self = next;
+        Ok(pt)
+    }
+
+    fn skip_to(&mut self, until: u32) -> Result<(), Error> {
+        let Some(mut chain) = self.recv_chain else {
+            return Ok(());
+        };
+        if until.saturating_sub(self.recv_n) > MAX_SKIP {
+            return Err(Error::TooManySkipped);
+        }
+        let dh_public = self.dh_remote.unwrap_or_default();
+        while self.recv_n < until {
+            let (next, key) = kdf_chain(&chain);
+            self.skipped.push(SkippedKey {
+                dh_public,
+                n: self.recv_n,
+                key,
+            });
+            chain = next;
+            self.recv_n += 1;
+        }
+        self.recv_chain = Some(chain);
+        if self.skipped.len() > MAX_SKIPPED_KEYS {
+            let excess = self.skipped.len() - MAX_SKIPPED_KEYS;
+            self.skipped.drain(..excess);
+        }
+        Ok(())
+    }
+
+    fn dh_step(&mut self, remote: [u8; 32]) -> Result<(), Error> {
+        self.prev_send_n = self.send_n;
+        self.send_n = 0;
+        self.recv_n = 0;
+        self.dh_remote = Some(remote);
+        let (root, recv) = kdf_root(&self.root_key, &dh(&self.dh_self_sk, &remote)?);
+        let (pk, sk) = box_::gen_keypair();
+        self.dh_self_pk = pk.0;
+        self.dh_self_sk = sk.0;
+        let (root, send) = kdf_root(&root, &dh(&self.dh_self_sk, &remote)?);
+        self.root_key = root;
+        self.recv_chain = Some(recv);
+        self.send_chain = Some(send);
+        Ok(())
+    }
+
+    fn ad_for(&self, header: &RatchetHeader) -> Vec<u8> {
+        let mut ad = self.associated_data.clone();
+        ad.extend_from_slice(&header.dh_public);
+        ad.extend_from_slice(&header.prev_chain_len.to_be_bytes());
+        ad.extend_from_slice(&header.n.to_be_bytes());
+        ad
+    }
+}
+
+fn kdf_root(root_key: &[u8; 32], dh_out: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let okm = hkdf(root_key, dh_out, ROOT_INFO, 64);
+    let mut root = [0u8; 32];
+    let mut chain = [0u8; 32];
+    root.copy_from_slice(&okm[..32]);
+    chain.copy_from_slice(&okm[32..]);
+    (root, chain)
+}
+
+/// Advance a chain key: returns (next chain key, message key).
+fn kdf_chain(chain_key: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let step = |byte: u8| {
+        let mut mac =
+            <Hmac<Sha256> as Mac>::new_from_slice(chain_key).expect("HMAC accepts any key size");
+        mac.update(&[byte]);
+        let mut out = [0u8; 32];
+        out.copy_from_slice(&mac.finalize().into_bytes());
+        out
+    };
+    (step(0x02), step(0x01))
+}
+
+fn message_key_material(mk: &[u8; 32]) -> (aead::Key, aead::Nonce) {
+    let okm = hkdf(&[0u8; 32], mk, MESSAGE_INFO, aead::KEYBYTES + aead::NONCEBYTES);
+    let key = aead::Key::from_slice(&okm[..aead::KEYBYTES]).expect("key length");
+    let nonce = aead::Nonce::from_slice(&okm[aead::KEYBYTES..]).expect("nonce length");
+    (key, nonce)
+}
+
+fn seal(mk: &[u8; 32], ad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let (key, nonce) = message_key_material(mk);
+    aead::seal(plaintext, Some(ad), &nonce, &key)
+}
+
+fn open(mk: &[u8; 32], ad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+    let (key, nonce) = message_key_material(mk);
+    aead::open(ciphertext, Some(ad), &nonce, &key).map_err(|_| Error::Decrypt)
+}
@@ -0,0 +1,116 @@
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::{box_, sign};
+
+use super::{dh, hkdf, Error};
+
+const X3DH_INFO: &[u8] = b"pigeon-x3dh";
+// Domain separation so a prekey signature can never pass as an envelope or card signature
+const PREKEY_SIGN_CONTEXT: &[u8] = b"pigeon-signed-prekey-v1";
+
+/// What the initiator needs to know about the responder to start a session. Served over
+/// `/pigeon/prekeys/1`; each fetch hands out a different one-time prekey while any remain.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PreKeyBundle {
+    pub identity_key: [u8; 32], // box public key
+    pub sign_key: [u8; 32],     // ed25519 key that signed the prekey
+    pub signed_prekey_id: u32,
+    pub signed_prekey: [u8; 32],
+    pub signed_prekey_signature: Vec<u8>,
+    pub one_time_prekey: Option<(u32, [u8; 32])>,
+}
+
+impl PreKeyBundle {
+    /// Check the signed prekey is signed by the bundle's sign key.
+    pub fn verify(&self) -> Result<(), Error> {
+        let pk = sign::PublicKey(self.sign_key);
+        let sig = sign::Signature::from_bytes(&self.signed_prekey_signature)
+            .map_err(|_| Error::InvalidKey("malformed signed prekey signature".into()))?;
+        if sign::verify_detached(
+            &sig,
+            &signed_prekey_bytes(self.signed_prekey_id, &self.signed_prekey),
+            &pk,
+        ) {
+            Ok(())
+        } else {
+            Err(Error::InvalidKey(
+                "signed prekey signature does not match".into(),
+            ))
+        }
+    }
+}
+
+/// Bytes covered by a signed prekey's signature.
+pub fn signed_prekey_bytes(id: u32, public: &[u8; 32]) -> Vec<u8> {
+    let mut out = PREKEY_SIGN_CONTEXT.to_vec();
+    out.extend_from_slice(&id.to_be_bytes());
+    out.extend_from_slice(public);
+    out
+}
+
+/// Result of the initiator's half of the handshake.
+pub struct Initiated {
+    pub shared_secret: [u8; 32],
+    pub ephemeral_public: [u8; 32],
+    pub associated_data: Vec<u8>,
+}
+
+/// Initiator: DH1 = DH(IK_a, SPK_b), DH2 = DH(EK_a, IK_b), DH3 = DH(EK_a, SPK_b), plus
+/// DH4 = DH(EK_a, OPK_b) when the bundle carries a one-time prekey.
+pub fn initiate(
+    identity_sk: &box_::SecretKey,
+    identity_pk: &box_::PublicKey,
+    bundle: &PreKeyBundle,
+) -> Result<Initiated, Error> {
+    let (ek_pk, ek_sk) = box_::gen_keypair();
+    let dh1 = dh(&identity_sk.0, &bundle.signed_prekey)?;
+    let dh2 = dh(&ek_sk.0, &bundle.identity_key)?;
+    let dh3 = dh(&ek_sk.0, &bundle.signed_prekey)?;
+    let mut dhs = vec![dh1, dh2, dh3];
+    if let Some((_, opk)) = &bundle.one_time_prekey {
+        dhs.push(dh(&ek_sk.0, opk)?);
+    }
+    Ok(Initiated {
+        shared_secret: derive(&dhs),
+        ephemeral_public: ek_pk.0,
+        associated_data: associated_data(&identity_pk.0, &bundle.identity_key),
+    })
+}
+
+/// Responder: mirrors `initiate` with our identity, signed prekey and one-time prekey secrets.
+pub fn respond(
+    identity_sk: &box_::SecretKey,
+    identity_pk: &box_::PublicKey,
+    signed_prekey_sk: &[u8; 32],
+    one_time_prekey_sk: Option<&[u8; 32]>,
+    remote_identity: &[u8; 32],
+    remote_ephemeral: &[u8; 32],
+) -> Result<([u8; 32], Vec<u8>), Error> {
+    let dh1 = dh(signed_prekey_sk, remote_identity)?;
+    let dh2 = dh(&identity_sk.0, remote_ephemeral)?;
+    let dh3 = dh(signed_prekey_sk, remote_ephemeral)?;
+    let mut dhs = vec![dh1, dh2, dh3];
+    if let Some(opk) = one_time_prekey_sk {
+        dhs.push(dh(opk, remote_ephemeral)?);
+    }
+    Ok((
+        derive(&dhs),
+        associated_data(remote_identity, &identity_pk.0),
+    ))
+}
+
+/// AD = IK_initiator || IK_responder, bound into every ratchet message.
+fn associated_data(initiator: &[u8; 32], responder: &[u8; 32]) -> Vec<u8> {
+    [initiator.as_slice(), responder.as_slice()].concat()
+}
+
+fn derive(dhs: &[[u8; 32]]) -> [u8; 32] {
+    // 0xFF prefix as in the X3DH spec, so the input never collides with a plain DH output
+    let mut ikm = vec![0xFFu8; 32];
+    for d in dhs {
+        ikm.extend_from_slice(d);
+    }
+    let okm = hkdf(&[0u8; 32], &ikm, X3DH_INFO, 32);
+    let mut out = [0u8; 32];
+    out.copy_from_slice(&okm);
+    out
+}
@@ -59,3 +59,34 @@ pub fn save_app_state(data_dir: &Path, state: &AppState) -> Result<(), std::io::
 }
 
 
+
+
+// Profile shared with peers through contact cards
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub struct Profile {
+	#[serde(default)]
+	pub display_name: String,
+	/// Multiaddrs peers should dial; falls back to the configured listen_addr when empty
+	#[serde(default)]
+	pub advertised_addrs: Vec<String>,
+}
+
+fn profile_path(data_dir: &Path) -> PathBuf {
+	data_dir.join("profile.toml")
+}
+
+pub fn load_profile(data_dir: &Path) -> Result<Profile, std::io::Error> {
+	let path = profile_path(data_dir);
+	if !path.exists() {
+		return Ok(Profile::default());
+	}
+	let s = fs::read_to_string(&path)?;
+	toml::from_str::<Profile>(&s).map_err(std::io::Error::other)
+}
+
+pub fn save_profile(data_dir: &Path, profile: &Profile) -> Result<(), std::io::Error> {
+	fs::create_dir_all(data_dir)?;
+	let s = toml::to_string_pretty(profile).map_err(std::io::Error::other)?;
+	fs::write(profile_path(data_dir), s)
+}
@@ -1,29 +1,51 @@
 use argon2::Argon2;
 use once_cell::sync::Lazy;
 use rand::RngCore;
+use serde::{Deserialize, Serialize};
 use sodiumoxide::crypto::secretbox;
 use std::collections::HashMap;
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::sync::Mutex;
+use std::sync::{Mutex, Once};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-const KEY_FILE: &str = "at_rest.key";
-const ENC_KEY_FILE: &str = "at_rest.key.enc";
-const MAGIC: &[u8; 4] = b"PGN1"; // Pigeon v1
+pub const KEY_FILE: &str = "at_rest.key";
+pub const ENC_KEY_FILE: &str = "at_rest.key.enc";
+const MAGIC: &[u8; 4] = b"PGN1"; // Pigeon v1: one sealed data key
+const RING_MAGIC: &[u8; 4] = b"PGN2"; // key ring with retired keys, during a rotation
+const PARAMS_MAGIC: &[u8; 4] = b"PGN3"; // key ring with the KDF parameters it was sealed with
+const SEALED_FILE_MAGIC: &[u8; 4] = b"PGS1"; // file sealed under the passphrase KEK
+/// Files sealed directly under the passphrase key-encryption key rather than the data key.
+const KEK_SEALED_FILES: &[&str] = &["identity.bin"];
 
-pub struct AtRestKey(secretbox::Key);
+/// Every sealed record starts with magic || version || algorithm || key id (u32 BE), then
+/// the nonce and ciphertext. Records written before the header are nonce || ciphertext.
+const RECORD_MAGIC: &[u8; 3] = b"PGR";
+pub const RECORD_VERSION: u8 = 1;
+/// XSalsa20-Poly1305 (sodium secretbox).
+pub const ALG_SECRETBOX: u8 = 1;
+const RECORD_HEADER_LEN: usize = RECORD_MAGIC.len() + 2 + 4;
+
+/// The data keys of one store, current first. Older keys stay until a rotation has
+/// re-sealed every record they sealed, so records can be opened whatever key they name.
+pub struct AtRestKey {
+    keys: Vec<(u32, secretbox::Key)>,
+}
 
 impl AtRestKey {
     pub fn load_or_create(data_dir: &Path) -> Result<Self, super::Error> {
         fs::create_dir_all(data_dir).map_err(|e| super::Error::Serialization(e.to_string()))?;
-        // Return cached key if present for this data_dir (unlocked session)
-        if let Some(k) = get_cached_key_for(data_dir) {
-            return Ok(Self(k));
+        // Return cached keys if present for this data_dir (unlocked session)
+        if let Some(ring) = get_cached_ring_for(data_dir) {
+            return Ok(Self::from_keys(ring.keys));
         }
 
-        // If encrypted keyfile exists, try env-based unlock else report locked
+        // If encrypted keyfile exists, try the keyring, then env-based unlock, else report locked
         let enc_path = data_dir.join(ENC_KEY_FILE);
         if enc_path.exists() {
+            if let Some(ring) = unlock_from_keyring(data_dir) {
+                return Ok(Self::from_keys(ring.keys));
+            }
             if let Ok(pass) = std::env::var("PIGEON_PASSPHRASE") {
                 return unlock_with_passphrase(data_dir, &pass);
             }
@@ -34,28 +56,67 @@ impl AtRestKey {
 
         let path = data_dir.join(KEY_FILE);
         if let Ok(bytes) = fs::read(&path) {
-            let mut arr = [0u8; secretbox::KEYBYTES];
-            if bytes.len() != arr.len() {
-                return Err(super::Error::Crypto("invalid key length".into()));
-            }
-            arr.copy_from_slice(&bytes);
-            Ok(Self(secretbox::Key(arr)))
+            let key = secretbox::Key::from_slice(&bytes)
+                .ok_or_else(|| super::Error::Crypto("invalid key length".into()))?;
+            Ok(Self::from_keys(vec![key]))
         } else {
             let key = secretbox::gen_key();
             fs::write(&path, key.0.as_slice())
                 .map_err(|e| super::Error::Serialization(e.to_string()))?;
-            Ok(Self(key))
+            Ok(Self::from_keys(vec![key]))
+        }
+    }
+
+    /// A fresh key kept only in memory, for stores on a `MemoryBackend`.
+    pub fn ephemeral() -> Self {
+        Self::from_keys(vec![secretbox::gen_key()])
+    }
+
+    fn from_keys(keys: Vec<secretbox::Key>) -> Self {
+        Self {
+            keys: keys.into_iter().map(|k| (key_id(&k), k)).collect(),
         }
     }
 
+    /// The key new records are sealed with.
     pub fn key(&self) -> &secretbox::Key {
-        &self.0
+        &self.keys[0].1
+    }
+
+    /// Id of the current key, as written in record headers.
+    pub fn key_id(&self) -> u32 {
+        self.keys[0].0
     }
+
+    /// Whether older keys are still kept because a rotation has not finished.
+    pub fn has_retired_keys(&self) -> bool {
+        self.keys.len() > 1
+    }
+
+    fn by_id(&self, id: u32) -> Option<&secretbox::Key> {
+        self.keys.iter().find(|(kid, _)| *kid == id).map(|(_, k)| k)
+    }
+}
+
+/// Short id naming a data key in record headers: the first four bytes of a hash of it.
+pub fn key_id(key: &secretbox::Key) -> u32 {
+    use sha2::{Digest, Sha256};
+    let mut h = Sha256::new();
+    h.update(b"pigeon-at-rest-key-id");
+    h.update(key.0);
+    let digest = h.finalize();
+    u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]])
 }
 
 pub fn encrypt(key: &AtRestKey, plaintext: &[u8]) -> Result<Vec<u8>, super::Error> {
     let nonce = secretbox::gen_nonce();
-    let mut out = Vec::with_capacity(secretbox::NONCEBYTES + plaintext.len() + secretbox::MACBYTES);
+    let mut out = Vec::with_capacity(
+        RECORD_HEADER_LEN + secretbox::NONCEBYTES + plaintext.len() + secretbox::MACBYTES,
+    );
+    out.extend_from_slice(RECORD_MAGIC);
+    out.push(RECORD_VERSION);
+    out.push(ALG_SECRETBOX);
+    out.extend_from_slice(&key.key_id().to_be_bytes());
     out.extend_from_slice(nonce.0.as_slice());
     let ct = secretbox::seal(plaintext, &nonce, key.key());
     out.extend_from_slice(&ct);
@@ -63,145 +124,833 @@ pub fn encrypt(key: &AtRestKey, plaintext: &[u8]) -> Result<Vec<u8>, super::Erro
 }
 
 pub fn decrypt(key: &AtRestKey, ciphertext: &[u8]) -> Result<Vec<u8>, super::Error> {
-    if ciphertext.len() < secretbox::NONCEBYTES + secretbox::MACBYTES {
-        return Err(super::Error::Crypto("ciphertext too short".into()));
+    let header = record_key_id(ciphertext);
+    if let Ok(Some(id)) = header {
+        if let Some(pt) = key
+            .by_id(id)
+            .and_then(|k| open_record_body(k, &ciphertext[RECORD_HEADER_LEN..]))
+        {
+            return Ok(pt);
+        }
+    }
+    // Headerless legacy record (or a legacy nonce that merely looks like a header)
+    if let Some(pt) = key.keys.iter().find_map(|(_, k)| open_record_body(k, ciphertext)) {
+        return Ok(pt);
+    }
+    match header? {
+        Some(id) if key.by_id(id).is_none() => Err(super::Error::Crypto(format!(
+            "record sealed with unknown at-rest key {id:08x}"
+        ))),
+        _ if ciphertext.len() < secretbox::NONCEBYTES + secretbox::MACBYTES => {
+            Err(super::Error::Crypto("ciphertext too short".into()))
+        }
+        _ => Err(super::Error::Crypto("decryption failed".into())),
+    }
+}
+
+/// Key id from a record's header; `None` for headerless legacy records. A record with the
+/// header magic but a version or algorithm this build does not know is an error, so it is
+/// not mistaken for a damaged record.
+pub fn record_key_id(record: &[u8]) -> Result<Option<u32>, super::Error> {
+    if record.len() < RECORD_HEADER_LEN || !record.starts_with(RECORD_MAGIC) {
+        return Ok(None);
+    }
+    let (version, alg) = (record[3], record[4]);
+    if version != RECORD_VERSION {
+        return Err(super::Error::Crypto(format!(
+            "unsupported record version {version}"
+        )));
+    }
+    if alg != ALG_SECRETBOX {
+        return Err(super::Error::Crypto(format!(
+            "unsupported record algorithm {alg}"
+        )));
+    }
+    Ok(Some(u32::from_be_bytes([record[5], record[6], record[7], record[8]])))
+}
+
+fn open_record_body(key: &secretbox::Key, body: &[u8]) -> Option<Vec<u8>> {
+    if body.len() < secretbox::NONCEBYTES + secretbox::MACBYTES {
+        return None;
+    }
+    let nonce = secretbox::Nonce::from_slice(&body[..secretbox::NONCEBYTES])?;
+    secretbox::open(&body[secretbox::NONCEBYTES..], &nonce, key).ok()
+}
+
+/// Highest Argon2id costs a key file may carry (1 GiB, 64 passes, 16 lanes). Key files asking
+/// for more are refused before deriving, so a tampered file cannot stall or exhaust the
+/// machine on unlock; the same bounds apply when sealing.
+pub const MAX_KDF_MEMORY_KIB: u32 = 1024 * 1024;
+pub const MAX_KDF_ITERATIONS: u32 = 64;
+pub const MAX_KDF_PARALLELISM: u32 = 16;
+
+/// Argon2id cost parameters for deriving the passphrase KEK. They are written into the
+/// key file, so they can be raised later without losing the ability to unlock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    /// The argon2 crate's defaults, which key files without stored parameters used.
+    fn default() -> Self {
+        Self {
+            memory_kib: argon2::Params::DEFAULT_M_COST,
+            iterations: argon2::Params::DEFAULT_T_COST,
+            parallelism: argon2::Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl KdfParams {
+    /// Refuse costs over the `MAX_KDF_*` bounds.
+    fn check_bounds(&self) -> Result<(), super::Error> {
+        if self.memory_kib > MAX_KDF_MEMORY_KIB
+            || self.iterations > MAX_KDF_ITERATIONS
+            || self.parallelism > MAX_KDF_PARALLELISM
+        {
+            return Err(super::Error::Crypto(format!(
+                "unsupported kdf parameters: {} KiB, {} iterations, {} lanes",
+                self.memory_kib, self.iterations, self.parallelism
+            )));
+        }
+        Ok(())
+    }
+
+    fn argon2(&self) -> Result<Argon2<'static>, super::Error> {
+        let params = argon2::Params::new(self.memory_kib, self.iterations, self.parallelism, None)
+            .map_err(|e| super::Error::Crypto(format!("kdf parameters: {e}")))?;
+        Ok(Argon2::new(
+            argon2::Algorithm::Argon2id,
+            argon2::Version::V0x13,
+            params,
+        ))
     }
-    let mut nonce_bytes = [0u8; secretbox::NONCEBYTES];
-    nonce_bytes.copy_from_slice(&ciphertext[..secretbox::NONCEBYTES]);
-    let nonce = secretbox::Nonce(nonce_bytes);
-    let pt = secretbox::open(&ciphertext[secretbox::NONCEBYTES..], &nonce, key.key())
-        .map_err(|_| super::Error::Crypto("decryption failed".into()))?;
-    Ok(pt)
 }
 
 // Optional: derive a key from passphrase using Argon2id
 pub fn derive_key_from_passphrase(
     passphrase: &str,
     salt: &[u8],
+) -> Result<secretbox::Key, super::Error> {
+    derive_key_with_params(passphrase, salt, &KdfParams::default())
+}
+
+pub fn derive_key_with_params(
+    passphrase: &str,
+    salt: &[u8],
+    params: &KdfParams,
 ) -> Result<secretbox::Key, super::Error> {
     let mut out = [0u8; secretbox::KEYBYTES];
-    let params = Argon2::default();
     params
+        .argon2()?
         .hash_password_into(passphrase.as_bytes(), salt, &mut out)
         .map_err(|e| super::Error::Crypto(format!("kdf: {e}")))?;
     Ok(secretbox::Key(out))
 }
 
-static CACHED_KEYS: Lazy<Mutex<HashMap<PathBuf, secretbox::Key>>> =
+/// What an unlock yields for one data dir: data keys and passphrase KEKs, current first.
+/// A retired KEK is kept while a KEK-sealed file may not have been re-sealed yet.
+#[derive(Clone)]
+struct KeyRing {
+    keys: Vec<secretbox::Key>,
+    keks: Vec<secretbox::Key>,
+}
+
+/// Keys as sealed inside a `PGN2`/`PGN3` key file; the current KEK is never stored.
+#[derive(Serialize, Deserialize)]
+struct SealedRing {
+    keys: Vec<[u8; secretbox::KEYBYTES]>,
+    old_keks: Vec<[u8; secretbox::KEYBYTES]>,
+}
+
+/// An unlocked ring and when it was last used, for the idle auto-lock.
+struct CachedRing {
+    ring: KeyRing,
+    last_used: Instant,
+}
+
+static CACHED_RINGS: Lazy<Mutex<HashMap<PathBuf, CachedRing>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+/// Idle timeouts set with `set_auto_lock`, per data dir.
+static AUTO_LOCK: Lazy<Mutex<HashMap<PathBuf, Duration>>> =
     Lazy::new(|| Mutex::new(HashMap::new()));
+static AUTO_LOCK_SWEEPER: Once = Once::new();
+
+/// The cached ring of `data_dir`, counting as a use for the idle timer. A ring idle past
+/// its timeout is dropped here even if the sweeper has not got to it yet.
+fn get_cached_ring_for(data_dir: &Path) -> Option<KeyRing> {
+    if lock_if_idle(data_dir) {
+        return None;
+    }
+    let mut guard = CACHED_RINGS.lock().ok()?;
+    let cached = guard.get_mut(data_dir)?;
+    cached.last_used = Instant::now();
+    Some(cached.ring.clone())
+}
+
+fn cache_ring_for(data_dir: &Path, ring: &KeyRing) {
+    if let Ok(mut guard) = CACHED_RINGS.lock() {
+        guard.insert(
+            data_dir.to_path_buf(),
+            CachedRing {
+                ring: ring.clone(),
+                last_used: Instant::now(),
+            },
+        );
+    }
+}
+
+/// Whether this process holds the unlocked keys of `data_dir`. Unlike opening a store this
+/// does not count as a use, so it can be polled without holding off the auto-lock.
+pub fn is_unlocked(data_dir: &Path) -> bool {
+    !lock_if_idle(data_dir)
+        && CACHED_RINGS
+            .lock()
+            .map(|g| g.contains_key(data_dir))
+            .unwrap_or(false)
+}
+
+/// Forget the unlocked keys of `data_dir` once they sit unused for `idle` (`None` turns
+/// the auto-lock off). A background thread checks every second, so keys do not linger in
+/// memory until the next access.
+pub fn set_auto_lock(data_dir: &Path, idle: Option<Duration>) {
+    if let Ok(mut guard) = AUTO_LOCK.lock() {
+        match idle {
+            Some(d) => guard.insert(data_dir.to_path_buf(), d),
+            None => guard.remove(data_dir),
+        };
+    }
+    if idle.is_some() {
+        AUTO_LOCK_SWEEPER.call_once(|| {
+            let _ = std::thread::Builder::new()
+                .name("pigeon-auto-lock".into())
+                .spawn(|| loop {
+                    std::thread::sleep(Duration::from_secs(1));
+                    let dirs: Vec<PathBuf> = AUTO_LOCK
+                        .lock()
+                        .map(|g| g.keys().cloned().collect())
+                        .unwrap_or_default();
+                    for dir in dirs {
+                        lock_if_idle(&dir);
+                    }
+                });
+        });
+    }
+}
+
+/// Lock `data_dir` if its keys sat unused past the auto-lock timeout; true if it did.
+fn lock_if_idle(data_dir: &Path) -> bool {
+    let Some(idle) = AUTO_LOCK.lock().ok().and_then(|g| g.get(data_dir).copied()) else {
+        return false;
+    };
+    let expired = CACHED_RINGS
+        .lock()
+        .ok()
+        .and_then(|g| g.get(data_dir).map(|c| c.last_used.elapsed() >= idle))
+        .unwrap_or(false);
+    if expired {
+        let _ = lock(data_dir);
+    }
+    expired
+}
+
+/// Drop cached keys for `data_dir` (e.g. after its key files were replaced by a restore),
+/// including a copy in the OS keyring.
+pub fn forget_cached(data_dir: &Path) {
+    if let Ok(mut guard) = CACHED_RINGS.lock() {
+        guard.remove(data_dir);
+    }
+    let _ = super::os_keyring::clear(data_dir);
+}
+
+/// Lock `data_dir` again: forget the unlocked keys here and in the OS keyring. Returns
+/// whether the keyring held an unlock.
+pub fn lock(data_dir: &Path) -> Result<bool, super::Error> {
+    if let Ok(mut guard) = CACHED_RINGS.lock() {
+        guard.remove(data_dir);
+    }
+    super::os_keyring::clear(data_dir)
+}
+
+/// Copy this process's unlock of `data_dir` into the OS keyring for `ttl`, so later runs
+/// start unlocked.
+pub fn remember_in_keyring(data_dir: &Path, ttl: Duration) -> Result<(), super::Error> {
+    match get_cached_ring_for(data_dir) {
+        Some(ring) if !ring.keks.is_empty() => {
+            super::os_keyring::store(data_dir, &ring.keys, &ring.keks, ttl)
+        }
+        _ => Err(super::Error::Crypto(
+            "at-rest key is not unlocked in this process".into(),
+        )),
+    }
+}
+
+/// Load keys cached by `remember_in_keyring` into this process.
+fn unlock_from_keyring(data_dir: &Path) -> Option<KeyRing> {
+    let (keys, keks) = super::os_keyring::load(data_dir)?;
+    let ring = KeyRing { keys, keks };
+    cache_ring_for(data_dir, &ring);
+    Some(ring)
+}
+
+/// The passphrase KEKs for `data_dir`, current first: `None` when no passphrase is set, an
+/// error when one is set but the store is still locked.
+fn current_keks(data_dir: &Path) -> Result<Option<Vec<secretbox::Key>>, super::Error> {
+    if !data_dir.join(ENC_KEY_FILE).exists() {
+        return Ok(None);
+    }
+    if let Some(ring) = get_cached_ring_for(data_dir).filter(|r| !r.keks.is_empty()) {
+        return Ok(Some(ring.keks));
+    }
+    if let Some(ring) = unlock_from_keyring(data_dir) {
+        return Ok(Some(ring.keks));
+    }
+    if let Ok(pass) = std::env::var("PIGEON_PASSPHRASE") {
+        unlock_with_passphrase(data_dir, &pass)?;
+        return Ok(get_cached_ring_for(data_dir).map(|r| r.keks));
+    }
+    Err(super::Error::Crypto(
+        "at-rest key is locked; run `security unlock` or set PIGEON_PASSPHRASE".into(),
+    ))
+}
+
+fn seal_file_bytes(kek: &secretbox::Key, plaintext: &[u8]) -> Vec<u8> {
+    let nonce = secretbox::gen_nonce();
+    let mut out = SEALED_FILE_MAGIC.to_vec();
+    out.extend_from_slice(nonce.0.as_slice());
+    out.extend_from_slice(&secretbox::seal(plaintext, &nonce, kek));
+    out
+}
+
+fn open_file_bytes(kek: &secretbox::Key, bytes: &[u8]) -> Result<Vec<u8>, super::Error> {
+    let body = &bytes[SEALED_FILE_MAGIC.len()..];
+    if body.len() < secretbox::NONCEBYTES + secretbox::MACBYTES {
+        return Err(super::Error::Crypto("sealed file too short".into()));
+    }
+    let mut nonce = [0u8; secretbox::NONCEBYTES];
+    nonce.copy_from_slice(&body[..secretbox::NONCEBYTES]);
+    secretbox::open(&body[secretbox::NONCEBYTES..], &secretbox::Nonce(nonce), kek)
+        .map_err(|_| super::Error::Crypto("sealed file does not open with this passphrase".into()))
+}
+
+/// Open with the current KEK, or a retired one while a rotation is unfinished.
+fn open_file_with_any(keks: &[secretbox::Key], bytes: &[u8]) -> Result<Vec<u8>, super::Error> {
+    let mut last = super::Error::Crypto("no passphrase key to open sealed file".into());
+    for kek in keks {
+        match open_file_bytes(kek, bytes) {
+            Ok(plain) => return Ok(plain),
+            Err(e) => last = e,
+        }
+    }
+    Err(last)
+}
+
+fn is_sealed_file(bytes: &[u8]) -> bool {
+    bytes.starts_with(SEALED_FILE_MAGIC)
+}
+
+/// Write with owner-only permissions via a temp file, so a crash never leaves half a file.
+pub(crate) fn write_private(path: &Path, bytes: &[u8]) -> Result<(), super::Error> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, bytes).map_err(|e| super::Error::Serialization(e.to_string()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let _ = fs::set_permissions(&tmp, fs::Permissions::from_mode(0o600));
+    }
+    fs::rename(&tmp, path).map_err(|e| super::Error::Serialization(e.to_string()))
+}
+
+/// Read a file that is kept sealed under the passphrase KEK. Plaintext files (written before
+/// a passphrase was set) are returned as-is and sealed in place once the KEK is available.
+pub fn read_sealed_file(data_dir: &Path, name: &str) -> Result<Vec<u8>, super::Error> {
+    let path = data_dir.join(name);
+    let bytes = fs::read(&path).map_err(|e| super::Error::Serialization(e.to_string()))?;
+    if is_sealed_file(&bytes) {
+        let keks = current_keks(data_dir)?.ok_or_else(|| {
+            super::Error::Crypto(format!("{name} is sealed but no passphrase is set"))
+        })?;
+        return open_file_with_any(&keks, &bytes);
+    }
+    // Migrate a plaintext file as soon as we can; a locked store just reads it this time
+    if let Ok(Some(keks)) = current_keks(data_dir) {
+        write_private(&path, &seal_file_bytes(&keks[0], &bytes))?;
+    }
+    Ok(bytes)
+}
 
-fn get_cached_key_for(data_dir: &Path) -> Option<secretbox::Key> {
-    if let Ok(guard) = CACHED_KEYS.lock() {
-        return guard.get(data_dir).cloned();
+/// Write a file sealed under the passphrase KEK, or in plaintext when no passphrase is set.
+pub fn write_sealed_file(data_dir: &Path, name: &str, plaintext: &[u8]) -> Result<(), super::Error> {
+    fs::create_dir_all(data_dir).map_err(|e| super::Error::Serialization(e.to_string()))?;
+    let bytes = match current_keks(data_dir)? {
+        Some(keks) => seal_file_bytes(&keks[0], plaintext),
+        None => plaintext.to_vec(),
+    };
+    write_private(&data_dir.join(name), &bytes)
+}
+
+/// Decrypt the KEK-sealed files with the current KEK (if any) so they can be re-sealed
+/// under a new one. Done before anything is written so a locked store fails cleanly.
+fn read_kek_sealed_files(data_dir: &Path) -> Result<Vec<(PathBuf, Vec<u8>)>, super::Error> {
+    let mut out = Vec::new();
+    for name in KEK_SEALED_FILES {
+        let path = data_dir.join(name);
+        let Ok(bytes) = fs::read(&path) else {
+            continue;
+        };
+        let plain = if is_sealed_file(&bytes) {
+            let keks = current_keks(data_dir)?.ok_or_else(|| {
+                super::Error::Crypto(format!("{name} is sealed but no passphrase is set"))
+            })?;
+            open_file_with_any(&keks, &bytes)?
+        } else {
+            bytes
+        };
+        out.push((path, plain));
     }
-    None
+    Ok(out)
 }
 
-fn cache_key_for(data_dir: &Path, key: &secretbox::Key) {
-    if let Ok(mut guard) = CACHED_KEYS.lock() {
-        guard.insert(data_dir.to_path_buf(), key.clone());
+fn reseal_files(files: &[(PathBuf, Vec<u8>)], kek: &secretbox::Key) -> Result<(), super::Error> {
+    for (path, plain) in files {
+        write_private(path, &seal_file_bytes(kek, plain))?;
     }
+    Ok(())
 }
 
+/// Unlock `data_dir`. Wrong passphrases are counted in a file next to the key, and after
+/// `FREE_UNLOCK_ATTEMPTS` each further try has to wait twice as long as the last (up to
+/// `MAX_UNLOCK_DELAY_SECS`); a correct passphrase resets the count.
 pub fn unlock_with_passphrase(
     data_dir: &Path,
     passphrase: &str,
 ) -> Result<AtRestKey, super::Error> {
+    let attempts = UnlockAttempts::load(data_dir);
+    if let Some(wait) = attempts.retry_after(now_secs()) {
+        return Err(super::Error::UnlockThrottled(wait));
+    }
     let enc_path = data_dir.join(ENC_KEY_FILE);
     let bytes = fs::read(&enc_path).map_err(|e| super::Error::Serialization(e.to_string()))?;
-    if bytes.len() < 4 + 16 + secretbox::NONCEBYTES {
+    let ring = match open_key_file(&bytes, passphrase) {
+        Ok(ring) => ring,
+        Err(super::Error::WrongPassphrase) => {
+            attempts.failed(now_secs()).save(data_dir)?;
+            return Err(super::Error::WrongPassphrase);
+        }
+        Err(e) => return Err(e),
+    };
+    if attempts.failures > 0 {
+        let _ = fs::remove_file(data_dir.join(ATTEMPTS_FILE));
+    }
+    cache_ring_for(data_dir, &ring);
+    Ok(AtRestKey::from_keys(ring.keys))
+}
+
+/// How long until the next unlock attempt is allowed; `None` when it is allowed now.
+pub fn unlock_retry_after(data_dir: &Path) -> Option<u64> {
+    UnlockAttempts::load(data_dir).retry_after(now_secs())
+}
+
+const ATTEMPTS_FILE: &str = "unlock_attempts";
+pub const FREE_UNLOCK_ATTEMPTS: u32 = 3;
+pub const MAX_UNLOCK_DELAY_SECS: u64 = 60 * 60;
+
+/// Failed unlocks so far and when the last one happened; persisted so restarting the app
+/// does not reset the delay.
+#[derive(Default, Clone, Copy)]
+struct UnlockAttempts {
+    failures: u32,
+    last_failure_at: u64,
+}
+
+impl UnlockAttempts {
+    fn load(data_dir: &Path) -> Self {
+        match fs::read(data_dir.join(ATTEMPTS_FILE)) {
+            Ok(b) if b.len() == 12 => Self {
+                failures: u32::from_be_bytes([b[0], b[1], b[2], b[3]]),
+                last_failure_at: u64::from_be_bytes(b[4..12].try_into().expect("8 bytes")),
+            },
+            _ => Self::default(),
+        }
+    }
+
+    fn save(&self, data_dir: &Path) -> Result<(), super::Error> {
+        let mut out = self.failures.to_be_bytes().to_vec();
+        out.extend_from_slice(&self.last_failure_at.to_be_bytes());
+        write_private(&data_dir.join(ATTEMPTS_FILE), &out)
+    }
+
+    fn failed(self, now: u64) -> Self {
+        Self {
+            failures: self.failures.saturating_add(1),
+            last_failure_at: now,
+        }
+    }
+
+    fn delay_secs(&self) -> u64 {
+        if self.failures < FREE_UNLOCK_ATTEMPTS {
+            return 0;
+        }
+        let doublings = (self.failures - FREE_UNLOCK_ATTEMPTS).min(12);
+        (1u64 << doublings).min(MAX_UNLOCK_DELAY_SECS)
+    }
+
+    fn retry_after(&self, now: u64) -> Option<u64> {
+        let allowed_at = self.last_failure_at.saturating_add(self.delay_secs());
+        (allowed_at > now).then(|| allowed_at - now)
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The cleartext part of a key file: KDF parameters and salt, then the sealed keys.
+struct KeyFileHeader {
+    params: KdfParams,
+    salt: [u8; SALT_LEN],
+}
+
+const SALT_LEN: usize = 16;
+
+/// Seal data keys (current first) under a KEK derived from `passphrase` with a fresh salt.
+/// Returns the file bytes and the KEK.
+fn seal_key_file(
+    keys: &[secretbox::Key],
+    old_keks: &[secretbox::Key],
+    passphrase: &str,
+    params: &KdfParams,
+) -> Result<(Vec<u8>, secretbox::Key), super::Error> {
+    params.check_bounds()?;
+    let mut salt = [0u8; SALT_LEN];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let kek = derive_key_with_params(passphrase, &salt, params)?;
+    let header = KeyFileHeader {
+        params: *params,
+        salt,
+    };
+    let out = seal_key_file_with(&header, &kek, keys, old_keks)?;
+    Ok((out, kek))
+}
+
+/// The `at_rest.key.enc` layout: magic || m_cost || t_cost || p_cost (u32 BE) || salt ||
+/// nonce || sealed key ring. Older files (`PGN1` bare key, `PGN2` ring) have no stored
+/// parameters and used the defaults.
+fn seal_key_file_with(
+    header: &KeyFileHeader,
+    kek: &secretbox::Key,
+    keys: &[secretbox::Key],
+    old_keks: &[secretbox::Key],
+) -> Result<Vec<u8>, super::Error> {
+    let ring = SealedRing {
+        keys: keys.iter().map(|k| k.0).collect(),
+        old_keks: old_keks.iter().map(|k| k.0).collect(),
+    };
+    let inner = bincode::serialize(&ring).map_err(|e| super::Error::Serialization(e.to_string()))?;
+    let nonce = secretbox::gen_nonce();
+    let sealed = secretbox::seal(&inner, &nonce, kek);
+
+    let mut out = Vec::with_capacity(4 + 12 + SALT_LEN + secretbox::NONCEBYTES + sealed.len());
+    out.extend_from_slice(PARAMS_MAGIC);
+    out.extend_from_slice(&header.params.memory_kib.to_be_bytes());
+    out.extend_from_slice(&header.params.iterations.to_be_bytes());
+    out.extend_from_slice(&header.params.parallelism.to_be_bytes());
+    out.extend_from_slice(&header.salt);
+    out.extend_from_slice(nonce.0.as_slice());
+    out.extend_from_slice(&sealed);
+    Ok(out)
+}
+
+/// Split a key file into its header and the nonce || sealed part.
+fn parse_key_file(bytes: &[u8]) -> Result<(KeyFileHeader, &[u8]), super::Error> {
+    let (params, rest) = match bytes.get(0..4) {
+        Some(m) if m == PARAMS_MAGIC => {
+            if bytes.len() < 16 {
+                return Err(super::Error::Crypto("enc keyfile too short".into()));
+            }
+            let word = |i: usize| u32::from_be_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+            let params = KdfParams {
+                memory_kib: word(4),
+                iterations: word(8),
+                parallelism: word(12),
+            };
+            params.check_bounds()?;
+            (params, &bytes[16..])
+        }
+        Some(m) if m == MAGIC || m == RING_MAGIC => (KdfParams::default(), &bytes[4..]),
+        _ => return Err(super::Error::Crypto("enc keyfile bad magic".into())),
+    };
+    if rest.len() < SALT_LEN + secretbox::NONCEBYTES {
         return Err(super::Error::Crypto("enc keyfile too short".into()));
     }
-    if &bytes[0..4] != MAGIC {
-        return Err(super::Error::Crypto("enc keyfile bad magic".into()));
+    let mut salt = [0u8; SALT_LEN];
+    salt.copy_from_slice(&rest[..SALT_LEN]);
+    Ok((KeyFileHeader { params, salt }, &rest[SALT_LEN..]))
+}
+
+/// Open a key file written by `seal_key_file` (or an older layout).
+fn open_key_file(bytes: &[u8], passphrase: &str) -> Result<KeyRing, super::Error> {
+    let (header, body) = parse_key_file(bytes)?;
+    let nonce = secretbox::Nonce::from_slice(&body[..secretbox::NONCEBYTES])
+        .ok_or_else(|| super::Error::Crypto("enc keyfile bad nonce".into()))?;
+    let sealed = &body[secretbox::NONCEBYTES..];
+    let kek = derive_key_with_params(passphrase, &header.salt, &header.params)?;
+    let inner = secretbox::open(sealed, &nonce, &kek)
+        .map_err(|_| super::Error::WrongPassphrase)?;
+    if bytes.starts_with(MAGIC) {
+        let key = secretbox::Key::from_slice(&inner)
+            .ok_or_else(|| super::Error::Crypto("bad inner key size".into()))?;
+        return Ok(KeyRing {
+            keys: vec![key],
+            keks: vec![kek],
+        });
     }
-    let salt = &bytes[4..20];
-    let nonce_bytes = &bytes[20..20 + secretbox::NONCEBYTES];
-    let mut nonce_arr = [0u8; secretbox::NONCEBYTES];
-    nonce_arr.copy_from_slice(nonce_bytes);
-    let nonce = secretbox::Nonce(nonce_arr);
-    let sealed = &bytes[20 + secretbox::NONCEBYTES..];
-    let kek = derive_key_from_passphrase(passphrase, salt)?;
-    let key_bytes = secretbox::open(sealed, &nonce, &kek)
-        .map_err(|_| super::Error::Crypto("unlock failed".into()))?;
-    if key_bytes.len() != secretbox::KEYBYTES {
-        return Err(super::Error::Crypto("bad inner key size".into()));
+    let ring: SealedRing =
+        bincode::deserialize(&inner).map_err(|e| super::Error::Serialization(e.to_string()))?;
+    if ring.keys.is_empty() {
+        return Err(super::Error::Crypto("key ring holds no keys".into()));
+    }
+    let mut keks = vec![kek];
+    keks.extend(ring.old_keks.into_iter().map(secretbox::Key));
+    Ok(KeyRing {
+        keys: ring.keys.into_iter().map(secretbox::Key).collect(),
+        keks,
+    })
+}
+
+/// KDF parameters recorded in the key file of `data_dir`; `None` without a passphrase.
+pub fn kdf_params(data_dir: &Path) -> Result<Option<KdfParams>, super::Error> {
+    match fs::read(data_dir.join(ENC_KEY_FILE)) {
+        Ok(bytes) => Ok(Some(parse_key_file(&bytes)?.0.params)),
+        Err(_) => Ok(None),
     }
-    let mut arr = [0u8; secretbox::KEYBYTES];
-    arr.copy_from_slice(&key_bytes);
-    let key = secretbox::Key(arr);
-    cache_key_for(data_dir, &key);
-    Ok(AtRestKey(key))
 }
 
 pub fn set_passphrase_and_seal(data_dir: &Path, passphrase: &str) -> Result<(), super::Error> {
+    set_passphrase_with_params(data_dir, passphrase, &KdfParams::default())
+}
+
+/// Protect the at-rest key with a passphrase. With a passphrase already set this is
+/// `change_passphrase`.
+pub fn set_passphrase_with_params(
+    data_dir: &Path,
+    passphrase: &str,
+    params: &KdfParams,
+) -> Result<(), super::Error> {
     fs::create_dir_all(data_dir).map_err(|e| super::Error::Serialization(e.to_string()))?;
+    if data_dir.join(ENC_KEY_FILE).exists() {
+        return change_passphrase(data_dir, passphrase, params);
+    }
+    let sealed_files = read_kek_sealed_files(data_dir)?;
     // Load existing key or generate
     let plain_path = data_dir.join(KEY_FILE);
-    let key = if let Ok(bytes) = fs::read(&plain_path) {
-        let mut arr = [0u8; secretbox::KEYBYTES];
-        if bytes.len() != arr.len() {
-            return Err(super::Error::Crypto("invalid key length".into()));
-        }
-        arr.copy_from_slice(&bytes);
-        secretbox::Key(arr)
-    } else if let Some(k) = get_cached_key_for(data_dir) {
-        k
+    let keys = if let Ok(bytes) = fs::read(&plain_path) {
+        vec![secretbox::Key::from_slice(&bytes)
+            .ok_or_else(|| super::Error::Crypto("invalid key length".into()))?]
+    } else if let Some(ring) = get_cached_ring_for(data_dir) {
+        ring.keys
     } else {
-        secretbox::gen_key()
+        vec![secretbox::gen_key()]
     };
 
-    // Derive KEK
-    let mut salt = [0u8; 16];
-    rand::thread_rng().fill_bytes(&mut salt);
-    let kek = derive_key_from_passphrase(passphrase, &salt)?;
-    let nonce = secretbox::gen_nonce();
-    let sealed = secretbox::seal(key.0.as_slice(), &nonce, &kek);
-
-    // Write enc file
-    let mut out = Vec::with_capacity(4 + salt.len() + secretbox::NONCEBYTES + sealed.len());
-    out.extend_from_slice(MAGIC);
-    out.extend_from_slice(&salt);
-    out.extend_from_slice(nonce.0.as_slice());
-    out.extend_from_slice(&sealed);
+    // Seal them under the passphrase and write the enc file
+    let (out, kek) = seal_key_file(&keys, &[], passphrase, params)?;
     let enc_path = data_dir.join(ENC_KEY_FILE);
-    fs::write(&enc_path, &out).map_err(|e| super::Error::Serialization(e.to_string()))?;
+    write_private(&enc_path, &out)?;
+    reseal_files(&sealed_files, &kek)?;
+    // A keyring copy would hold the old KEK
+    let _ = super::os_keyring::clear(data_dir);
     // Remove plaintext key if exists
     let _ = fs::remove_file(&plain_path);
-    cache_key_for(data_dir, &key);
+    cache_ring_for(
+        data_dir,
+        &KeyRing {
+            keys,
+            keks: vec![kek],
+        },
+    );
+    Ok(())
+}
+
+/// Re-wrap the same data keys under a new passphrase (and `params`); records are not
+/// touched. Needs the store unlocked.
+///
+/// The old KEK stays in the ring until the KEK-sealed files are re-sealed, so a crash in
+/// between still leaves them readable after unlocking with the new passphrase.
+pub fn change_passphrase(
+    data_dir: &Path,
+    new_passphrase: &str,
+    params: &KdfParams,
+) -> Result<(), super::Error> {
+    if !data_dir.join(ENC_KEY_FILE).exists() {
+        return Err(super::Error::Crypto("no passphrase is set".into()));
+    }
+    let ring = current_ring(data_dir)?;
+    let sealed_files = read_kek_sealed_files(data_dir)?;
+    let (out, kek) = seal_key_file(&ring.keys, &ring.keks, new_passphrase, params)?;
+    write_private(&data_dir.join(ENC_KEY_FILE), &out)?;
+    let mut keks = vec![kek];
+    keks.extend(ring.keks);
+    let ring = KeyRing {
+        keys: ring.keys,
+        keks,
+    };
+    cache_ring_for(data_dir, &ring);
+    let _ = super::os_keyring::clear(data_dir);
+    reseal_files(&sealed_files, &ring.keks[0])?;
+    drop_retired(data_dir, &ring, ring.keys.len())
+}
+
+/// Go back to a plain key file: the data key is written unprotected and KEK-sealed files
+/// are stored in plaintext again. Needs the store unlocked and no unfinished rotation.
+pub fn remove_passphrase(data_dir: &Path) -> Result<(), super::Error> {
+    let enc_path = data_dir.join(ENC_KEY_FILE);
+    if !enc_path.exists() {
+        return Err(super::Error::Crypto("no passphrase is set".into()));
+    }
+    let ring = current_ring(data_dir)?;
+    if ring.keys.len() > 1 {
+        return Err(super::Error::Crypto(
+            "finish the interrupted key rotation (`security rotate`) first".into(),
+        ));
+    }
+    let plain_files = read_kek_sealed_files(data_dir)?;
+    // The enc file wins while both exist, so every step leaves a store that opens
+    write_private(&data_dir.join(KEY_FILE), ring.keys[0].0.as_slice())?;
+    for (path, plain) in &plain_files {
+        write_private(path, plain)?;
+    }
+    fs::remove_file(&enc_path).map_err(|e| super::Error::Serialization(e.to_string()))?;
+    let _ = super::os_keyring::clear(data_dir);
+    cache_ring_for(
+        data_dir,
+        &KeyRing {
+            keys: ring.keys,
+            keks: Vec::new(),
+        },
+    );
     Ok(())
 }
 
 /// Rotate the at-rest key by generating a fresh key and sealing it with the given passphrase.
+/// Every stored record is re-sealed under the new key (see `rotate_key_with_progress`).
 pub fn rotate_key_and_seal(data_dir: &Path, passphrase: &str) -> Result<(), super::Error> {
+    rotate_key_with_progress(data_dir, passphrase, &KdfParams::default(), |_| {}).map(|_| ())
+}
+
+/// Rotate the at-rest key and re-seal every record in the databases under it, reporting
+/// progress as it goes. Returns how many records were re-sealed.
+///
+/// The new key is written to the key file first, with the old keys (and old KEK) kept in
+/// the ring, so the store stays readable if the re-sealing is interrupted; records name
+/// their key in the header. The old keys are dropped only once every database is flushed.
+/// `resume_rotation` finishes an interrupted rotation.
+pub fn rotate_key_with_progress(
+    data_dir: &Path,
+    passphrase: &str,
+    params: &KdfParams,
+    progress: impl FnMut(&super::rekey::RekeyProgress),
+) -> Result<usize, super::Error> {
     fs::create_dir_all(data_dir).map_err(|e| super::Error::Serialization(e.to_string()))?;
-    // Generate fresh key
-    let key = secretbox::gen_key();
+    let sealed_files = read_kek_sealed_files(data_dir)?;
+    let old = current_ring(data_dir)?;
+    // Take every database lock before writing anything, so a store in use elsewhere fails
+    // the rotation cleanly instead of leaving it half done
+    let dbs = super::rekey::open_databases(data_dir)?;
 
-    // Derive KEK
-    let mut salt = [0u8; 16];
-    rand::thread_rng().fill_bytes(&mut salt);
-    let kek = derive_key_from_passphrase(passphrase, &salt)?;
-    let nonce = secretbox::gen_nonce();
-    let sealed = secretbox::seal(key.0.as_slice(), &nonce, &kek);
+    let mut key = secretbox::gen_key();
+    while old.keys.iter().any(|k| key_id(k) == key_id(&key)) {
+        key = secretbox::gen_key();
+    }
+    let mut keys = vec![key];
+    keys.extend(old.keys);
+    let (out, kek) = seal_key_file(&keys, &old.keks, passphrase, params)?;
+    write_private(&data_dir.join(ENC_KEY_FILE), &out)?;
+    let mut keks = vec![kek];
+    keks.extend(old.keks);
+    let ring = KeyRing { keys, keks };
+    cache_ring_for(data_dir, &ring);
+    let _ = super::os_keyring::clear(data_dir);
+    // The plaintext key (if any) is in the ring now
+    let _ = fs::remove_file(data_dir.join(KEY_FILE));
+    reseal_files(&sealed_files, &ring.keks[0])?;
 
-    // Write enc file
-    let mut out = Vec::with_capacity(4 + salt.len() + secretbox::NONCEBYTES + sealed.len());
-    out.extend_from_slice(MAGIC);
-    out.extend_from_slice(&salt);
-    out.extend_from_slice(nonce.0.as_slice());
-    out.extend_from_slice(&sealed);
+    finish_rotation(data_dir, dbs, progress)
+}
+
+/// Finish a rotation that was interrupted: re-seal what is still under a retired key and
+/// drop the retired keys. Needs the store unlocked (with the new passphrase).
+pub fn resume_rotation(
+    data_dir: &Path,
+    progress: impl FnMut(&super::rekey::RekeyProgress),
+) -> Result<usize, super::Error> {
+    current_keks(data_dir)?;
+    let dbs = super::rekey::open_databases(data_dir)?;
+    finish_rotation(data_dir, dbs, progress)
+}
+
+/// Whether `data_dir` still keeps retired keys from a rotation that did not finish. Needs
+/// the store unlocked.
+pub fn rotation_pending(data_dir: &Path) -> Result<bool, super::Error> {
+    let ring = current_ring(data_dir)?;
+    Ok(ring.keys.len() > 1 || ring.keks.len() > 1)
+}
+
+fn finish_rotation(
+    data_dir: &Path,
+    dbs: Vec<super::rekey::OpenDatabase>,
+    mut progress: impl FnMut(&super::rekey::RekeyProgress),
+) -> Result<usize, super::Error> {
+    let ring = current_ring(data_dir)?;
+    let resealed =
+        super::rekey::reseal_databases(&dbs, &AtRestKey::from_keys(ring.keys.clone()), &mut progress)?;
+    drop(dbs);
+    // Files still under a retired KEK
+    let sealed_files = read_kek_sealed_files(data_dir)?;
+    reseal_files(&sealed_files, &ring.keks[0])?;
+    // Everything is under the current keys now; keep only those
+    drop_retired(data_dir, &ring, 1)?;
+    Ok(resealed)
+}
+
+/// Rewrite the key file with the first `keep` data keys and no retired KEKs, keeping its
+/// salt and KDF parameters (so the current KEK still opens it).
+fn drop_retired(data_dir: &Path, ring: &KeyRing, keep: usize) -> Result<(), super::Error> {
     let enc_path = data_dir.join(ENC_KEY_FILE);
-    fs::write(&enc_path, &out).map_err(|e| super::Error::Serialization(e.to_string()))?;
-    // Remove plaintext key if exists
-    let _ = fs::remove_file(data_dir.join(KEY_FILE));
-    cache_key_for(data_dir, &key);
+    let bytes = fs::read(&enc_path).map_err(|e| super::Error::Serialization(e.to_string()))?;
+    let (header, _) = parse_key_file(&bytes)?;
+    let out = seal_key_file_with(&header, &ring.keks[0], &ring.keys[..keep], &[])?;
+    write_private(&enc_path, &out)?;
+    cache_ring_for(
+        data_dir,
+        &KeyRing {
+            keys: ring.keys[..keep].to_vec(),
+            keks: ring.keks[..1].to_vec(),
+        },
+    );
+    let _ = super::os_keyring::clear(data_dir);
     Ok(())
 }
 
+/// The unlocked ring of `data_dir`: a plaintext key counts as a ring without KEKs, and a
+/// store that never had a key gets one. Errors when the store is sealed and locked.
+fn current_ring(data_dir: &Path) -> Result<KeyRing, super::Error> {
+    let key = AtRestKey::load_or_create(data_dir)?;
+    Ok(get_cached_ring_for(data_dir).unwrap_or_else(|| KeyRing {
+        keys: key.keys.into_iter().map(|(_, k)| k).collect(),
+        keks: Vec::new(),
+    }))
+}
+
 #[cfg(test)]
 pub fn test_clear_cache() {
-    if let Ok(mut guard) = CACHED_KEYS.lock() {
+    if let Ok(mut guard) = CACHED_RINGS.lock() {
         guard.clear();
     }
 }
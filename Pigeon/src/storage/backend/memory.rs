@@ -0,0 +1,208 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::{Backend, Entries, Entry, KvTree, Transaction};
+use crate::storage::Error;
+
+type Tree = BTreeMap<Vec<u8>, Vec<u8>>;
+
+#[derive(Default)]
+struct State {
+    trees: HashMap<String, Tree>,
+    next_id: u64,
+}
+
+/// Trees kept in memory and gone when the last handle is dropped. Clones share the same
+/// data, like several handles on one sled database.
+#[derive(Clone, Default)]
+pub struct MemoryBackend {
+    state: Arc<Mutex<State>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+fn lock(state: &Mutex<State>) -> MutexGuard<'_, State> {
+    // A panic while holding the lock cannot leave a tree half-written: every write is a
+    // single map operation, and transactions only apply their writes once `f` succeeded
+    state.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl Backend for MemoryBackend {
+    fn open_tree(&self, name: &str) -> Result<Arc<dyn KvTree>, Error> {
+        lock(&self.state).trees.entry(name.to_string()).or_default();
+        Ok(Arc::new(MemoryTree {
+            state: self.state.clone(),
+            name: name.to_string(),
+        }))
+    }
+
+    fn tree_names(&self) -> Result<Vec<String>, Error> {
+        let mut names: Vec<String> = lock(&self.state).trees.keys().cloned().collect();
+        names.sort();
+        Ok(names)
+    }
+
+    fn reserve_ids(&self, next: u64) -> Result<(), Error> {
+        let mut state = lock(&self.state);
+        state.next_id = state.next_id.max(next);
+        Ok(())
+    }
+
+    fn generate_id(&self) -> Result<u64, Error> {
+        let mut state = lock(&self.state);
+        let id = state.next_id;
+        state.next_id += 1;
+        Ok(id)
+    }
+
+    fn transaction(
+        &self,
+        trees: &[&str],
+        f: &mut dyn FnMut(&mut dyn Transaction) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        // Holding the lock throughout makes the transaction serial with every other access
+        let mut state = lock(&self.state);
+        for name in trees {
+            state.trees.entry(name.to_string()).or_default();
+        }
+        let mut tx = MemoryTransaction {
+            state: &state,
+            trees,
+            writes: HashMap::new(),
+        };
+        f(&mut tx)?;
+        let writes = tx.writes;
+        for ((tree, key), value) in writes {
+            let tree = state.trees.get_mut(trees[tree]).expect("opened above");
+            match value {
+                Some(v) => tree.insert(key, v),
+                None => tree.remove(&key),
+            };
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+struct MemoryTree {
+    state: Arc<Mutex<State>>,
+    name: String,
+}
+
+impl MemoryTree {
+    fn with<T>(&self, f: impl FnOnce(&mut Tree) -> T) -> T {
+        let mut state = lock(&self.state);
+        f(state.trees.entry(self.name.clone()).or_default())
+    }
+
+    /// Copy out the matching entries, so the lock is not held while the caller iterates.
+    fn collect(&self, f: impl FnOnce(&Tree) -> Vec<Entry>) -> Entries<'_> {
+        let entries = self.with(|t| f(t));
+        Box::new(entries.into_iter().map(Ok))
+    }
+}
+
+impl KvTree for MemoryTree {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.with(|t| t.get(key).cloned()))
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.with(|t| t.insert(key.to_vec(), value.to_vec())))
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.with(|t| t.remove(key)))
+    }
+
+    fn range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Entries<'_> {
+        if empty_range(start, end) {
+            return Box::new(std::iter::empty());
+        }
+        self.collect(|t| {
+            t.range::<[u8], _>((start, end))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect()
+        })
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Entries<'_> {
+        self.collect(|t| {
+            t.range::<[u8], _>((Bound::Included(prefix), Bound::Unbounded))
+                .take_while(|(k, _)| k.starts_with(prefix))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect()
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.with(|t| t.len())
+    }
+
+    fn clear(&self) -> Result<(), Error> {
+        self.with(|t| t.clear());
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Bounds `BTreeMap::range` would panic on; sled just returns nothing for them.
+fn empty_range(start: Bound<&[u8]>, end: Bound<&[u8]>) -> bool {
+    match (start, end) {
+        (Bound::Included(s), Bound::Included(e)) => s > e,
+        (Bound::Included(s) | Bound::Excluded(s), Bound::Excluded(e))
+        | (Bound::Excluded(s), Bound::Included(e)) => s >= e,
+        _ => false,
+    }
+}
+
+struct MemoryTransaction<'a> {
+    state: &'a State,
+    trees: &'a [&'a str],
+    // Pending writes by (tree index, key); `None` removes the key
+    writes: HashMap<(usize, Vec<u8>), Option<Vec<u8>>>,
+}
+
+impl MemoryTransaction<'_> {
+    fn read(&self, tree: usize, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let name = self.trees.get(tree).ok_or_else(|| super::no_such_tree(tree))?;
+        if let Some(pending) = self.writes.get(&(tree, key.to_vec())) {
+            return Ok(pending.clone());
+        }
+        Ok(self.state.trees.get(*name).and_then(|t| t.get(key).cloned()))
+    }
+}
+
+impl Transaction for MemoryTransaction<'_> {
+    fn get(&mut self, tree: usize, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        self.read(tree, key)
+    }
+
+    fn insert(
+        &mut self,
+        tree: usize,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let old = self.read(tree, key)?;
+        self.writes.insert((tree, key.to_vec()), Some(value.to_vec()));
+        Ok(old)
+    }
+
+    fn remove(&mut self, tree: usize, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let old = self.read(tree, key)?;
+        self.writes.insert((tree, key.to_vec()), None);
+        Ok(old)
+    }
+}
@@ -0,0 +1,224 @@
+//! Key-value storage underneath the stores: named trees of byte keys kept in key order, and
+//! transactions spanning several trees. `SledBackend` keeps them in a sled database on disk,
+//! `SqliteBackend` (feature `sqlite`) in a SQLite file, `MemoryBackend` only in memory
+//! (tests, or embedders that should not touch the disk).
+
+mod memory;
+mod sled_backend;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+pub use memory::MemoryBackend;
+pub use sled_backend::SledBackend;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteBackend;
+
+use std::fmt;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use super::Error;
+
+/// A key and its value.
+pub type Entry = (Vec<u8>, Vec<u8>);
+
+/// Entries of a tree in key order.
+pub type Entries<'a> = Box<dyn Iterator<Item = Result<Entry, Error>> + 'a>;
+
+/// The tree a store uses when it needs only one (sled's default tree on disk).
+pub const DEFAULT_TREE: &str = "__default";
+
+/// One database: a set of trees that can be written together.
+pub trait Backend: Send + Sync {
+    /// Open (creating if needed) the tree called `name`.
+    fn open_tree(&self, name: &str) -> Result<Arc<dyn KvTree>, Error>;
+
+    /// Names of the trees opened in this database so far.
+    fn tree_names(&self) -> Result<Vec<String>, Error>;
+
+    /// A new id, unique within this database.
+    fn generate_id(&self) -> Result<u64, Error>;
+
+    /// Make `generate_id` return nothing below `next` from now on (after copying records
+    /// whose keys are ids from another database).
+    fn reserve_ids(&self, next: u64) -> Result<(), Error>;
+
+    /// Run `f` over the trees named in `trees` (addressed by their index there) so that its
+    /// writes all land or, if it returns an error, none do. `f` may run more than once when
+    /// a concurrent write conflicts, so it should not have other side effects.
+    fn transaction(
+        &self,
+        trees: &[&str],
+        f: &mut dyn FnMut(&mut dyn Transaction) -> Result<(), Error>,
+    ) -> Result<(), Error>;
+
+    /// Make everything written so far durable.
+    fn flush(&self) -> Result<(), Error>;
+}
+
+/// An ordered map of byte keys to byte values.
+pub trait KvTree: Send + Sync {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error>;
+
+    /// Store `value` under `key`, returning the value it replaced.
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>, Error>;
+
+    /// Remove `key`, returning its value.
+    fn remove(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error>;
+
+    /// Entries with keys between `start` and `end`, in key order.
+    fn range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Entries<'_>;
+
+    /// Entries whose keys start with `prefix`, in key order.
+    fn scan_prefix(&self, prefix: &[u8]) -> Entries<'_>;
+
+    fn len(&self) -> usize;
+
+    fn clear(&self) -> Result<(), Error>;
+
+    fn flush(&self) -> Result<(), Error>;
+
+    fn contains_key(&self, key: &[u8]) -> Result<bool, Error> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    fn iter(&self) -> Entries<'_> {
+        self.range(Bound::Unbounded, Bound::Unbounded)
+    }
+
+    /// The entry with the smallest key.
+    fn first(&self) -> Result<Option<Entry>, Error> {
+        self.iter().next().transpose()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The trees of a running `Backend::transaction`. Reads see the transaction's own writes.
+pub trait Transaction {
+    fn get(&mut self, tree: usize, key: &[u8]) -> Result<Option<Vec<u8>>, Error>;
+
+    fn insert(&mut self, tree: usize, key: &[u8], value: &[u8])
+        -> Result<Option<Vec<u8>>, Error>;
+
+    fn remove(&mut self, tree: usize, key: &[u8]) -> Result<Option<Vec<u8>>, Error>;
+}
+
+/// The on-disk backends a database can be stored with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    Sled,
+    Sqlite,
+}
+
+impl FromStr for BackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "sled" => Ok(Self::Sled),
+            "sqlite" => Ok(Self::Sqlite),
+            other => Err(format!("unknown storage backend: {other}")),
+        }
+    }
+}
+
+impl fmt::Display for BackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Sled => "sled",
+            Self::Sqlite => "sqlite",
+        })
+    }
+}
+
+/// Where the database named by `path` (e.g. `<data_dir>/contacts_db`) lives with `kind`:
+/// a sled directory at `path` itself, or the SQLite file `path.sqlite`.
+pub fn location(path: &Path, kind: BackendKind) -> PathBuf {
+    match kind {
+        BackendKind::Sled => path.to_path_buf(),
+        BackendKind::Sqlite => with_suffix(path, ".sqlite"),
+    }
+}
+
+pub(crate) fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut p = path.as_os_str().to_owned();
+    p.push(suffix);
+    PathBuf::from(p)
+}
+
+/// Which backend the database named by `path` is stored with; `None` if it does not exist.
+pub fn detect(path: &Path) -> Option<BackendKind> {
+    [BackendKind::Sqlite, BackendKind::Sled]
+        .into_iter()
+        .find(|kind| location(path, *kind).exists())
+}
+
+/// Open the database named by `path` with the backend it is stored in. A database that
+/// does not exist yet is created with sled.
+pub fn open_path(path: &Path) -> Result<Arc<dyn Backend>, Error> {
+    open_kind(path, detect(path).unwrap_or(BackendKind::Sled))
+}
+
+/// Open (creating if needed) the database named by `path` with `kind`.
+pub fn open_kind(path: &Path, kind: BackendKind) -> Result<Arc<dyn Backend>, Error> {
+    open_at(&location(path, kind), kind)
+}
+
+/// Open (creating if needed) a database of `kind` at exactly `location`.
+pub(crate) fn open_at(location: &Path, kind: BackendKind) -> Result<Arc<dyn Backend>, Error> {
+    match kind {
+        BackendKind::Sled => Ok(Arc::new(SledBackend::open(location)?)),
+        BackendKind::Sqlite => open_sqlite(location),
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn open_sqlite(location: &Path) -> Result<Arc<dyn Backend>, Error> {
+    Ok(Arc::new(SqliteBackend::open(location)?))
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn open_sqlite(location: &Path) -> Result<Arc<dyn Backend>, Error> {
+    Err(Error::Backend(format!(
+        "{} is a SQLite store; this build lacks the `sqlite` feature",
+        location.display()
+    )))
+}
+
+/// Delete every file of `kind` stored at `location` (a sled directory, or a SQLite file
+/// with its journal files).
+pub(crate) fn remove_at(location: &Path, kind: BackendKind) -> std::io::Result<()> {
+    match kind {
+        BackendKind::Sled => {
+            if location.exists() {
+                std::fs::remove_dir_all(location)?;
+            }
+        }
+        BackendKind::Sqlite => {
+            for suffix in ["", "-wal", "-shm"] {
+                let file = with_suffix(location, suffix);
+                if file.exists() {
+                    std::fs::remove_file(file)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Delete the database named by `path`, whichever backend it is stored in.
+pub(crate) fn remove_path(path: &Path) -> std::io::Result<()> {
+    for kind in [BackendKind::Sled, BackendKind::Sqlite] {
+        remove_at(&location(path, kind), kind)?;
+    }
+    Ok(())
+}
+
+fn no_such_tree(tree: usize) -> Error {
+    Error::Backend(format!("tree {tree} is not part of this transaction"))
+}
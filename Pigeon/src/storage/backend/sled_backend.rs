@@ -0,0 +1,202 @@
+use std::cell::RefCell;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::Arc;
+
+use sled::transaction::{
+    ConflictableTransactionError, TransactionError, TransactionalTree,
+    UnabortableTransactionError,
+};
+use sled::Transactional;
+
+use super::{Backend, Entries, KvTree, Transaction, DEFAULT_TREE};
+use crate::storage::Error;
+
+// What sled calls the tree a `Db` reads and writes directly
+const SLED_DEFAULT_TREE: &[u8] = b"__sled__default";
+
+/// A sled database on disk.
+#[derive(Clone)]
+pub struct SledBackend {
+    db: sled::Db,
+}
+
+impl SledBackend {
+    /// Open (creating if needed) the database at `path`.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Ok(Self {
+            db: crate::storage::open_db(path)?,
+        })
+    }
+
+    fn tree(&self, name: &str) -> Result<sled::Tree, Error> {
+        if name == DEFAULT_TREE || name.as_bytes() == SLED_DEFAULT_TREE {
+            // Keeps records where stores that used the `Db` directly wrote them
+            Ok((*self.db).clone())
+        } else {
+            Ok(self.db.open_tree(name)?)
+        }
+    }
+}
+
+impl Backend for SledBackend {
+    fn open_tree(&self, name: &str) -> Result<Arc<dyn KvTree>, Error> {
+        Ok(Arc::new(SledTree(self.tree(name)?)))
+    }
+
+    fn tree_names(&self) -> Result<Vec<String>, Error> {
+        Ok(self
+            .db
+            .tree_names()
+            .into_iter()
+            .map(|name| {
+                if name == SLED_DEFAULT_TREE {
+                    DEFAULT_TREE.to_string()
+                } else {
+                    String::from_utf8_lossy(&name).into_owned()
+                }
+            })
+            .collect())
+    }
+
+    fn generate_id(&self) -> Result<u64, Error> {
+        Ok(self.db.generate_id()?)
+    }
+
+    fn reserve_ids(&self, next: u64) -> Result<(), Error> {
+        // sled cannot move its counter, only advance it one id at a time
+        while self.db.generate_id()? + 1 < next {}
+        Ok(())
+    }
+
+    fn transaction(
+        &self,
+        trees: &[&str],
+        f: &mut dyn FnMut(&mut dyn Transaction) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let trees = trees
+            .iter()
+            .map(|name| self.tree(name))
+            .collect::<Result<Vec<_>, _>>()?;
+        // sled wants an `Fn` it can retry; the caller's closure is `FnMut`
+        let f = RefCell::new(f);
+        trees
+            .as_slice()
+            .transaction(|views: &Vec<TransactionalTree>| {
+                let mut tx = SledTransaction {
+                    views,
+                    conflict: false,
+                };
+                let result = (*f.borrow_mut())(&mut tx);
+                if tx.conflict {
+                    return Err(ConflictableTransactionError::Conflict);
+                }
+                result.map_err(ConflictableTransactionError::Abort)
+            })
+            .map_err(|e| match e {
+                TransactionError::Abort(e) => e,
+                TransactionError::Storage(e) => Error::Db(e),
+            })
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+struct SledTree(sled::Tree);
+
+impl KvTree for SledTree {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.0.get(key)?.map(|v| v.to_vec()))
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.0.insert(key, value)?.map(|v| v.to_vec()))
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.0.remove(key)?.map(|v| v.to_vec()))
+    }
+
+    fn range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Entries<'_> {
+        let bounds = (start.map(<[u8]>::to_vec), end.map(<[u8]>::to_vec));
+        Box::new(self.0.range(bounds).map(entry))
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Entries<'_> {
+        Box::new(self.0.scan_prefix(prefix).map(entry))
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn clear(&self) -> Result<(), Error> {
+        self.0.clear()?;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        self.0.flush()?;
+        Ok(())
+    }
+
+    fn first(&self) -> Result<Option<super::Entry>, Error> {
+        Ok(self.0.first()?.map(|(k, v)| (k.to_vec(), v.to_vec())))
+    }
+}
+
+fn entry(item: sled::Result<(sled::IVec, sled::IVec)>) -> Result<super::Entry, Error> {
+    let (k, v) = item?;
+    Ok((k.to_vec(), v.to_vec()))
+}
+
+struct SledTransaction<'a> {
+    views: &'a [TransactionalTree],
+    // Set when sled reports a conflict, so the whole closure is retried instead of aborted
+    conflict: bool,
+}
+
+impl SledTransaction<'_> {
+    fn view(&self, tree: usize) -> Result<&TransactionalTree, Error> {
+        self.views.get(tree).ok_or_else(|| super::no_such_tree(tree))
+    }
+
+    fn check(
+        &mut self,
+        result: Result<Option<sled::IVec>, UnabortableTransactionError>,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        match result {
+            Ok(v) => Ok(v.map(|v| v.to_vec())),
+            Err(UnabortableTransactionError::Conflict) => {
+                self.conflict = true;
+                Err(Error::Backend("transaction conflict".into()))
+            }
+            Err(UnabortableTransactionError::Storage(e)) => Err(Error::Db(e)),
+        }
+    }
+}
+
+impl Transaction for SledTransaction<'_> {
+    fn get(&mut self, tree: usize, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let result = self.view(tree)?.get(key);
+        self.check(result)
+    }
+
+    fn insert(
+        &mut self,
+        tree: usize,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let result = self.view(tree)?.insert(key, value);
+        self.check(result)
+    }
+
+    fn remove(&mut self, tree: usize, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let result = self.view(tree)?.remove(key);
+        self.check(result)
+    }
+}
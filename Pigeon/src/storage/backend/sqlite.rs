@@ -0,0 +1,320 @@
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+
+use super::{Backend, Entries, Entry, KvTree, Transaction};
+use crate::storage::Error;
+
+// Several processes may open the same file; writers wait this long for each other
+const BUSY_TIMEOUT_MS: u64 = 5_000;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS trees (name TEXT PRIMARY KEY) WITHOUT ROWID;
+    CREATE TABLE IF NOT EXISTS kv (
+        tree TEXT NOT NULL,
+        key BLOB NOT NULL,
+        value BLOB NOT NULL,
+        PRIMARY KEY (tree, key)
+    ) WITHOUT ROWID;
+    CREATE TABLE IF NOT EXISTS meta (name TEXT PRIMARY KEY, value INTEGER NOT NULL);
+";
+
+/// A SQLite database file. Unlike sled it does not lock the file for one process, so the
+/// GUI, `listen-net` and CLI commands can use the same store at once.
+#[derive(Clone)]
+pub struct SqliteBackend {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteBackend {
+    /// Open (creating if needed) the database file at `path`.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let conn = Connection::open(path.as_ref()).map_err(db_err)?;
+        conn.busy_timeout(std::time::Duration::from_millis(BUSY_TIMEOUT_MS))
+            .map_err(db_err)?;
+        // WAL lets readers in other processes carry on while one writes
+        conn.pragma_update(None, "journal_mode", "WAL")
+            .map_err(db_err)?;
+        conn.pragma_update(None, "synchronous", "FULL")
+            .map_err(db_err)?;
+        conn.execute_batch(SCHEMA).map_err(db_err)?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+}
+
+fn db_err(e: rusqlite::Error) -> Error {
+    Error::Backend(format!("sqlite: {e}"))
+}
+
+fn lock(conn: &Mutex<Connection>) -> MutexGuard<'_, Connection> {
+    conn.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl Backend for SqliteBackend {
+    fn open_tree(&self, name: &str) -> Result<Arc<dyn KvTree>, Error> {
+        lock(&self.conn)
+            .execute("INSERT OR IGNORE INTO trees (name) VALUES (?1)", [name])
+            .map_err(db_err)?;
+        Ok(Arc::new(SqliteTree {
+            conn: self.conn.clone(),
+            name: name.to_string(),
+        }))
+    }
+
+    fn tree_names(&self) -> Result<Vec<String>, Error> {
+        let conn = lock(&self.conn);
+        let mut stmt = conn
+            .prepare("SELECT name FROM trees ORDER BY name")
+            .map_err(db_err)?;
+        let names = stmt
+            .query_map([], |row| row.get(0))
+            .map_err(db_err)?
+            .collect::<Result<Vec<String>, _>>()
+            .map_err(db_err)?;
+        Ok(names)
+    }
+
+    fn generate_id(&self) -> Result<u64, Error> {
+        let mut conn = lock(&self.conn);
+        let tx = conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(db_err)?;
+        let next: i64 = tx
+            .query_row("SELECT value FROM meta WHERE name = 'next_id'", [], |r| r.get(0))
+            .optional()
+            .map_err(db_err)?
+            .unwrap_or(0);
+        tx.execute(
+            "INSERT OR REPLACE INTO meta (name, value) VALUES ('next_id', ?1)",
+            [next + 1],
+        )
+        .map_err(db_err)?;
+        tx.commit().map_err(db_err)?;
+        Ok(next as u64)
+    }
+
+    fn reserve_ids(&self, next: u64) -> Result<(), Error> {
+        lock(&self.conn)
+            .execute(
+                "INSERT INTO meta (name, value) VALUES ('next_id', ?1)
+                 ON CONFLICT(name) DO UPDATE SET value = MAX(value, excluded.value)",
+                [next as i64],
+            )
+            .map_err(db_err)?;
+        Ok(())
+    }
+
+    fn transaction(
+        &self,
+        trees: &[&str],
+        f: &mut dyn FnMut(&mut dyn Transaction) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let mut conn = lock(&self.conn);
+        // Take the write lock up front, so the transaction never fails half-way on a
+        // writer in another process
+        let tx = conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(db_err)?;
+        for name in trees {
+            tx.execute("INSERT OR IGNORE INTO trees (name) VALUES (?1)", [name])
+                .map_err(db_err)?;
+        }
+        f(&mut SqliteTransaction { conn: &tx, trees })?;
+        // Dropping `tx` without committing (on error above) rolls it back
+        tx.commit().map_err(db_err)
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        // Every statement already commits durably (synchronous = FULL); this only moves
+        // the write-ahead log into the database file, so the file alone is complete
+        lock(&self.conn)
+            .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))
+            .map_err(db_err)
+    }
+}
+
+struct SqliteTree {
+    conn: Arc<Mutex<Connection>>,
+    name: String,
+}
+
+impl SqliteTree {
+    fn query(&self, sql: &str, bounds: &[&[u8]]) -> Entries<'_> {
+        let conn = lock(&self.conn);
+        let rows = conn.prepare_cached(sql).and_then(|mut stmt| {
+            let mut args: Vec<&dyn rusqlite::ToSql> = vec![&self.name];
+            args.extend(bounds.iter().map(|b| b as &dyn rusqlite::ToSql));
+            stmt.query_map(args.as_slice(), |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<Vec<Entry>, _>>()
+        });
+        // Rows are copied out so the connection is not held while the caller iterates
+        match rows {
+            Ok(rows) => Box::new(rows.into_iter().map(Ok)),
+            Err(e) => Box::new(std::iter::once(Err(db_err(e)))),
+        }
+    }
+
+    /// Read the value under `key`, then `write` over it, in one IMMEDIATE transaction so a
+    /// writer in another process cannot change the row in between.
+    fn swap(
+        &self,
+        key: &[u8],
+        write: impl FnOnce(&Connection, &str) -> Result<(), Error>,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let mut conn = lock(&self.conn);
+        let tx = conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(db_err)?;
+        let old = get(&tx, &self.name, key)?;
+        write(&tx, &self.name)?;
+        tx.commit().map_err(db_err)?;
+        Ok(old)
+    }
+}
+
+impl KvTree for SqliteTree {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        get(&lock(&self.conn), &self.name, key)
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        self.swap(key, |tx, name| put(tx, name, key, value))
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        self.swap(key, |tx, name| delete(tx, name, key))
+    }
+
+    fn range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Entries<'_> {
+        let mut sql = String::from("SELECT key, value FROM kv WHERE tree = ?1");
+        let mut bounds = Vec::new();
+        match start {
+            Bound::Included(s) => {
+                bounds.push(s);
+                sql.push_str(&format!(" AND key >= ?{}", bounds.len() + 1));
+            }
+            Bound::Excluded(s) => {
+                bounds.push(s);
+                sql.push_str(&format!(" AND key > ?{}", bounds.len() + 1));
+            }
+            Bound::Unbounded => {}
+        }
+        match end {
+            Bound::Included(e) => {
+                bounds.push(e);
+                sql.push_str(&format!(" AND key <= ?{}", bounds.len() + 1));
+            }
+            Bound::Excluded(e) => {
+                bounds.push(e);
+                sql.push_str(&format!(" AND key < ?{}", bounds.len() + 1));
+            }
+            Bound::Unbounded => {}
+        }
+        // BLOBs compare bytewise, the same order sled keeps keys in
+        sql.push_str(" ORDER BY key");
+        self.query(&sql, &bounds)
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Entries<'_> {
+        match prefix_end(prefix) {
+            Some(end) => self.range(Bound::Included(prefix), Bound::Excluded(&end)),
+            None => self.range(Bound::Included(prefix), Bound::Unbounded),
+        }
+    }
+
+    fn len(&self) -> usize {
+        lock(&self.conn)
+            .query_row("SELECT COUNT(*) FROM kv WHERE tree = ?1", [&self.name], |r| {
+                r.get::<_, i64>(0)
+            })
+            .map(|n| n as usize)
+            .unwrap_or(0)
+    }
+
+    fn clear(&self) -> Result<(), Error> {
+        lock(&self.conn)
+            .execute("DELETE FROM kv WHERE tree = ?1", [&self.name])
+            .map_err(db_err)?;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// The smallest key above every key starting with `prefix`; `None` when there is none
+/// (the prefix is empty or all 0xff).
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+fn get(conn: &Connection, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    conn.prepare_cached("SELECT value FROM kv WHERE tree = ?1 AND key = ?2")
+        .and_then(|mut stmt| stmt.query_row(params![tree, key], |r| r.get(0)).optional())
+        .map_err(db_err)
+}
+
+fn put(conn: &Connection, tree: &str, key: &[u8], value: &[u8]) -> Result<(), Error> {
+    conn.prepare_cached("INSERT OR REPLACE INTO kv (tree, key, value) VALUES (?1, ?2, ?3)")
+        .and_then(|mut stmt| stmt.execute(params![tree, key, value]))
+        .map_err(db_err)?;
+    Ok(())
+}
+
+fn delete(conn: &Connection, tree: &str, key: &[u8]) -> Result<(), Error> {
+    conn.prepare_cached("DELETE FROM kv WHERE tree = ?1 AND key = ?2")
+        .and_then(|mut stmt| stmt.execute(params![tree, key]))
+        .map_err(db_err)?;
+    Ok(())
+}
+
+struct SqliteTransaction<'a> {
+    conn: &'a Connection,
+    trees: &'a [&'a str],
+}
+
+impl SqliteTransaction<'_> {
+    fn tree(&self, tree: usize) -> Result<&str, Error> {
+        self.trees
+            .get(tree)
+            .copied()
+            .ok_or_else(|| super::no_such_tree(tree))
+    }
+}
+
+impl Transaction for SqliteTransaction<'_> {
+    fn get(&mut self, tree: usize, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        get(self.conn, self.tree(tree)?, key)
+    }
+
+    fn insert(
+        &mut self,
+        tree: usize,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let name = self.tree(tree)?;
+        let old = get(self.conn, name, key)?;
+        put(self.conn, name, key, value)?;
+        Ok(old)
+    }
+
+    fn remove(&mut self, tree: usize, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let name = self.tree(tree)?;
+        let old = get(self.conn, name, key)?;
+        delete(self.conn, name, key)?;
+        Ok(old)
+    }
+}
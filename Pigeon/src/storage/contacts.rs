@@ -1,5 +1,22 @@
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
 use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::backend::{self, Backend, KvTree, DEFAULT_TREE};
+use super::schema;
+
+// contact id -> `ContactPolicy`, for contacts not left at `Allow`
+const POLICIES_TREE: &str = "policies";
+// digest of the target -> `BlockEntry`
+const BLOCKLIST_TREE: &str = "blocklist";
+// digest of a PeerId | contact id -> contact id, for contacts bound to that PeerId and
+// contacts with a device connecting as it; kept in step with the contact records
+const PEERS_TREE: &str = "peers";
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Contact {
@@ -8,21 +25,200 @@ pub struct Contact {
     pub addr: String,        // multiaddr string
     pub public_key: Vec<u8>, // 32 bytes (sodium box public key)
     pub ping_interval: u64,  // in seconds
+    pub peer_id: Option<String>, // libp2p PeerId (base58), bound via the Noise handshake
+    pub sign_public_key: Option<Vec<u8>>, // 32 bytes (ed25519), learned from a contact card
+    pub verified: bool,                   // safety number confirmed out of band
+    pub pending_key: Option<KeyChange>,   // different key seen since pinning, awaiting the user
+    pub key_history: Vec<KeyRecord>,      // previously pinned keys, oldest first
+    pub devices: Vec<DeviceKey>,          // the contact's other devices, from its card
+}
+
+impl Contact {
+    /// Automatic sending is held back while a key change awaits the user's decision.
+    pub fn send_blocked(&self) -> bool {
+        self.pending_key.is_some()
+    }
+}
+
+/// How the listener treats messages from a contact, checked before anything is decrypted.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ContactPolicy {
+    /// Delivered to the inbox.
+    #[default]
+    Allow,
+    /// Acknowledged, so the contact stops retrying, but dropped unread.
+    Mute,
+    /// Refused, as a peer that is not a contact would be.
+    Block,
+}
+
+impl FromStr for ContactPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "allow" => Ok(Self::Allow),
+            "mute" => Ok(Self::Mute),
+            "block" => Ok(Self::Block),
+            other => Err(format!("unknown contact policy: {other}")),
+        }
+    }
+}
+
+impl fmt::Display for ContactPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Allow => "allow",
+            Self::Mute => "mute",
+            Self::Block => "block",
+        })
+    }
+}
+
+/// A peer or key whose messages are refused, whether or not it belongs to a contact.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum BlockTarget {
+    /// A libp2p PeerId (base58).
+    Peer(String),
+    /// A 32-byte public key: sodium box or ed25519, of a contact or one of its devices.
+    Key(Vec<u8>),
+}
+
+impl BlockTarget {
+    // Entries are found by digest, so neither PeerIds nor keys are stored in the clear
+    fn record_key(&self) -> [u8; 32] {
+        let mut h = Sha256::new();
+        match self {
+            Self::Peer(p) => {
+                h.update(b"peer:");
+                h.update(p.as_bytes());
+            }
+            Self::Key(k) => {
+                h.update(b"key:");
+                h.update(k);
+            }
+        }
+        h.finalize().into()
+    }
+}
+
+impl fmt::Display for BlockTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Peer(p) => write!(f, "peer {p}"),
+            Self::Key(k) => write!(f, "key {}", hex::encode(k)),
+        }
+    }
+}
+
+/// One blocklist entry.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BlockEntry {
+    pub target: BlockTarget,
+    pub added_at: u64,
+}
+
+/// Keys seen for a contact that differ from the pinned ones.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct KeyChange {
+    pub public_key: Vec<u8>,
+    pub sign_public_key: Option<Vec<u8>>,
+    pub seen_at: u64,
+}
+
+/// A formerly pinned key pair and when it was replaced.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct KeyRecord {
+    pub public_key: Vec<u8>,
+    pub sign_public_key: Option<Vec<u8>>,
+    pub replaced_at: u64,
+}
+
+/// Another device of a contact's account, taken from a verified device certificate.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DeviceKey {
+    pub name: String,
+    pub public_key: Vec<u8>,      // 32 bytes (sodium box)
+    pub sign_public_key: Vec<u8>, // 32 bytes (ed25519)
+    pub peer_id: Option<String>,
+    pub addr: Option<String>,
 }
 
 #[allow(dead_code)]
 pub struct ContactStore {
-    db: sled::Db,
+    backend: Arc<dyn Backend>,
+    contacts: Arc<dyn KvTree>,
+    policies: Arc<dyn KvTree>,
+    blocklist: Arc<dyn KvTree>,
+    peers: Arc<dyn KvTree>,
+    key: super::at_rest::AtRestKey,
 }
 
 #[allow(dead_code)]
 impl ContactStore {
+    /// Open the store of `data_dir`, sealing records under that dir's at-rest key.
     pub fn open_in_dir(data_dir: &Path) -> Result<Self, super::Error> {
         std::fs::create_dir_all(data_dir)
             .map_err(|e| super::Error::Serialization(e.to_string()))?;
-        let path = data_dir.join("contacts_db");
-        let db = sled::open(path)?;
-        Ok(Self { db })
+        let key = super::at_rest::AtRestKey::load_or_create(data_dir)?;
+        Self::open(data_dir, key)
+    }
+
+    /// Open the store of `data_dir` with an already loaded at-rest key, in whichever
+    /// backend it is stored (sled unless migrated). Records in an older schema are upgraded,
+    /// after a snapshot of the database is saved to `schema_backups/`.
+    pub fn open(data_dir: &Path, key: super::at_rest::AtRestKey) -> Result<Self, super::Error> {
+        std::fs::create_dir_all(data_dir)
+            .map_err(|e| super::Error::Serialization(e.to_string()))?;
+        let backend = backend::open_path(&data_dir.join("contacts_db"))?;
+        let backups = data_dir.join(schema::BACKUP_DIR);
+        schema::upgrade(backend.as_ref(), &key, &schema::CONTACTS, Some(&backups))?;
+        Self::with_backend(backend, key)
+    }
+
+    /// Keep contacts in `backend` (e.g. a `MemoryBackend`) instead of the data dir.
+    pub fn with_backend(
+        backend: Arc<dyn Backend>,
+        key: super::at_rest::AtRestKey,
+    ) -> Result<Self, super::Error> {
+        schema::upgrade(backend.as_ref(), &key, &schema::CONTACTS, None)?;
+        let contacts = backend.open_tree(DEFAULT_TREE)?;
+        let policies = backend.open_tree(POLICIES_TREE)?;
+        let blocklist = backend.open_tree(BLOCKLIST_TREE)?;
+        let peers = backend.open_tree(PEERS_TREE)?;
+        let store = Self {
+            backend,
+            contacts,
+            policies,
+            blocklist,
+            peers,
+            key,
+        };
+        // Stores written before the index have contacts but no entries yet
+        if store.peers.is_empty() && !store.contacts.is_empty() {
+            store.index_peers()?;
+        }
+        Ok(store)
+    }
+
+    /// Index the PeerIds of every contact.
+    fn index_peers(&self) -> Result<(), super::Error> {
+        let mut entries = Vec::new();
+        for contact in self.list()? {
+            for prefix in peer_prefixes(&contact) {
+                entries.push((peer_entry_key(&prefix, contact.id), contact.id));
+            }
+        }
+        let sealed = entries
+            .iter()
+            .map(|(k, id)| Ok((k, seal_value(&self.key, id)?)))
+            .collect::<Result<Vec<_>, super::Error>>()?;
+        self.backend.transaction(&[PEERS_TREE], &mut |tx| {
+            for (k, v) in &sealed {
+                tx.insert(0, k.as_slice(), v)?;
+            }
+            Ok(())
+        })
     }
 
     pub fn add(
@@ -49,32 +245,89 @@ impl ContactStore {
             ));
         }
 
-        let id = self.db.generate_id()?;
+        // Trust on first use: the first key added for a PeerId stays pinned
+        let peer_id = crate::discovery::peer_id_from_addr(addr).map(str::to_string);
+        if let Some(peer) = &peer_id {
+            if let Some(other) = self.find_by_peer_id(peer)? {
+                return Err(super::Error::Validation(format!(
+                    "peer id already pinned to contact {}",
+                    other.id
+                )));
+            }
+        }
+
+        let id = self.backend.generate_id()?;
         let contact = Contact {
             id,
             name: name.to_string(),
             addr: addr.to_string(),
             public_key,
             ping_interval: 0,
+            peer_id,
+            sign_public_key: None,
+            verified: false,
+            pending_key: None,
+            key_history: Vec::new(),
+            devices: Vec::new(),
         };
-        let key_bytes = id.to_be_bytes();
+        self.put(&contact)?;
+        Ok(contact)
+    }
+
+    fn put(&self, contact: &Contact) -> Result<(), super::Error> {
+        let key_bytes = contact.id.to_be_bytes();
         // encrypt-at-rest
-        let cfg = crate::config::load();
-        let rest_key = super::at_rest::AtRestKey::load_or_create(&cfg.data_dir)?;
         let serialized =
-            bincode::serialize(&contact).map_err(|e| super::Error::Serialization(e.to_string()))?;
-        let sealed = super::at_rest::encrypt(&rest_key, &serialized)?;
-        self.db.insert(key_bytes, sealed)?;
-        Ok(contact)
+            bincode::serialize(contact).map_err(|e| super::Error::Serialization(e.to_string()))?;
+        let sealed = super::at_rest::encrypt(&self.key, &serialized)?;
+        let entries: Vec<Vec<u8>> = peer_prefixes(contact)
+            .iter()
+            .map(|prefix| peer_entry_key(prefix, contact.id))
+            .collect();
+        let sealed_id = seal_value(&self.key, &contact.id)?;
+        // The record and its index entries land together
+        self.backend
+            .transaction(&[DEFAULT_TREE, PEERS_TREE], &mut |tx| {
+                if let Some(prev) = tx.insert(0, &key_bytes, &sealed)? {
+                    for k in self.entry_keys(&prev)? {
+                        if !entries.contains(&k) {
+                            tx.remove(1, &k)?;
+                        }
+                    }
+                }
+                for k in &entries {
+                    tx.insert(1, k, &sealed_id)?;
+                }
+                Ok(())
+            })
+    }
+
+    /// The index entries of a sealed contact record.
+    fn entry_keys(&self, sealed: &[u8]) -> Result<Vec<Vec<u8>>, super::Error> {
+        let contact: Contact = open_value(&self.key, sealed)?;
+        Ok(peer_prefixes(&contact)
+            .iter()
+            .map(|prefix| peer_entry_key(prefix, contact.id))
+            .collect())
+    }
+
+    /// Contacts indexed under `prefix`, in id order.
+    fn indexed(&self, prefix: &[u8; 32]) -> Result<Vec<Contact>, super::Error> {
+        let mut out = Vec::new();
+        for item in self.peers.scan_prefix(prefix) {
+            let (_k, v) = item?;
+            if let Some(contact) = self.get(open_value(&self.key, &v)?)? {
+                out.push(contact);
+            }
+        }
+        Ok(out)
     }
 
     pub fn get(&self, id: u64) -> Result<Option<Contact>, super::Error> {
         let id_bytes = id.to_be_bytes();
-        if let Some(contact_bytes) = self.db.get(id_bytes)? {
+        if let Some(contact_bytes) = self.contacts.get(&id_bytes)? {
             // decrypt-at-rest
-            let cfg = crate::config::load();
-            let key = super::at_rest::AtRestKey::load_or_create(&cfg.data_dir)?;
-            let plain = super::at_rest::decrypt(&key, &contact_bytes)
+            let plain = super::at_rest::decrypt(&self.key, &contact_bytes)
                 .map_err(|e| super::Error::Serialization(e.to_string()))?;
             let contact = bincode::deserialize::<Contact>(&plain)
                 .map_err(|e| super::Error::Serialization(e.to_string()))?;
@@ -86,11 +339,9 @@ impl ContactStore {
 
     pub fn list(&self) -> Result<Vec<Contact>, super::Error> {
         let mut out = Vec::new();
-        for item in self.db.iter() {
+        for item in self.contacts.iter() {
             let (_k, v) = item?;
-            let cfg = crate::config::load();
-            let key = super::at_rest::AtRestKey::load_or_create(&cfg.data_dir)?;
-            let plain = super::at_rest::decrypt(&key, &v)
+            let plain = super::at_rest::decrypt(&self.key, &v)
                 .map_err(|e| super::Error::Serialization(e.to_string()))?;
             let contact = bincode::deserialize::<Contact>(&plain)
                 .map_err(|e| super::Error::Serialization(e.to_string()))?;
@@ -102,10 +353,96 @@ impl ContactStore {
     }
 
     pub fn remove(&self, id: u64) -> Result<bool, super::Error> {
-        let existed = self.db.remove(id.to_be_bytes())?.is_some();
+        let mut existed = false;
+        self.backend
+            .transaction(&[DEFAULT_TREE, PEERS_TREE], &mut |tx| {
+                existed = false;
+                if let Some(prev) = tx.remove(0, &id.to_be_bytes())? {
+                    for k in self.entry_keys(&prev)? {
+                        tx.remove(1, &k)?;
+                    }
+                    existed = true;
+                }
+                Ok(())
+            })?;
+        self.policies.remove(&id.to_be_bytes())?;
         Ok(existed)
     }
 
+    /// How the listener treats the contact's messages (`Allow` unless set).
+    pub fn policy(&self, id: u64) -> Result<ContactPolicy, super::Error> {
+        match self.policies.get(&id.to_be_bytes())? {
+            Some(v) => open_value(&self.key, &v),
+            None => Ok(ContactPolicy::Allow),
+        }
+    }
+
+    /// Allow, mute or block a contact's messages.
+    pub fn set_policy(&self, id: u64, policy: ContactPolicy) -> Result<Contact, super::Error> {
+        let contact = self
+            .get(id)?
+            .ok_or_else(|| super::Error::ContactNotFound(id.to_string()))?;
+        if policy == ContactPolicy::Allow {
+            self.policies.remove(&id.to_be_bytes())?;
+        } else {
+            self.policies
+                .insert(&id.to_be_bytes(), &seal_value(&self.key, &policy)?)?;
+        }
+        Ok(contact)
+    }
+
+    /// Add `target` to the blocklist; false if it was already there.
+    pub fn block(&self, target: BlockTarget) -> Result<bool, super::Error> {
+        match &target {
+            BlockTarget::Peer(p) => validate_peer_id(p)?,
+            BlockTarget::Key(k) if k.len() != 32 => {
+                return Err(super::Error::Validation("key must be 32 bytes".into()))
+            }
+            BlockTarget::Key(_) => {}
+        }
+        let record_key = target.record_key();
+        if self.blocklist.get(&record_key)?.is_some() {
+            return Ok(false);
+        }
+        let entry = BlockEntry {
+            target,
+            added_at: now_secs(),
+        };
+        self.blocklist
+            .insert(&record_key, &seal_value(&self.key, &entry)?)?;
+        Ok(true)
+    }
+
+    /// Take `target` off the blocklist; false if it was not there.
+    pub fn unblock(&self, target: &BlockTarget) -> Result<bool, super::Error> {
+        Ok(self.blocklist.remove(&target.record_key())?.is_some())
+    }
+
+    /// Every blocklist entry, oldest first.
+    pub fn blocklist(&self) -> Result<Vec<BlockEntry>, super::Error> {
+        let mut out = Vec::new();
+        for item in self.blocklist.iter() {
+            let (_k, v) = item?;
+            out.push(open_value::<BlockEntry>(&self.key, &v)?);
+        }
+        out.sort_by_key(|e| e.added_at);
+        Ok(out)
+    }
+
+    /// Whether `peer_id` or any of `keys` is on the blocklist.
+    pub fn is_blocked(&self, peer_id: Option<&str>, keys: &[&[u8]]) -> Result<bool, super::Error> {
+        let targets = peer_id
+            .map(|p| BlockTarget::Peer(p.to_string()))
+            .into_iter()
+            .chain(keys.iter().map(|k| BlockTarget::Key(k.to_vec())));
+        for target in targets {
+            if self.blocklist.get(&target.record_key())?.is_some() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
     pub fn update(
         &self,
         id: u64,
@@ -131,27 +468,239 @@ impl ContactStore {
             ));
         }
 
-        // Keep ping_interval if existing
-        let prev = self.get(id)?;
-        let ping_interval = prev.map(|c| c.ping_interval).unwrap_or(0);
-
-        let contact = Contact {
-            id,
+        let Some(prev) = self.get(id)? else {
+            return Err(super::Error::ContactNotFound(id.to_string()));
+        };
+        let peer_id = crate::discovery::peer_id_from_addr(addr)
+            .map(str::to_string)
+            .or_else(|| prev.peer_id.clone());
+        // Keys are pinned: a different key is staged as a key change, never overwritten
+        let sign_public_key = prev.sign_public_key.clone();
+        let mut contact = Contact {
             name: name.to_string(),
             addr: addr.to_string(),
-            public_key,
-            ping_interval,
+            peer_id,
+            ..prev
         };
-        let key_bytes = id.to_be_bytes();
-        let cfg = crate::config::load();
-        let rest_key = super::at_rest::AtRestKey::load_or_create(&cfg.data_dir)?;
-        let serialized =
-            bincode::serialize(&contact).map_err(|e| super::Error::Serialization(e.to_string()))?;
-        let sealed = super::at_rest::encrypt(&rest_key, &serialized)?;
-        self.db.insert(key_bytes, sealed)?;
+        stage_keys(&mut contact, &public_key, sign_public_key.as_deref());
+        self.put(&contact)?;
+        Ok(contact)
+    }
+
+    /// Compare keys seen for a contact (e.g. on a re-imported card) with the pinned ones.
+    /// Missing keys are pinned on first sight; different keys become a pending key change.
+    pub fn observe_keys(
+        &self,
+        id: u64,
+        public_key: &[u8],
+        sign_public_key: Option<&[u8]>,
+    ) -> Result<Contact, super::Error> {
+        if public_key.len() != 32 || sign_public_key.is_some_and(|k| k.len() != 32) {
+            return Err(super::Error::Validation("keys must be 32 bytes".into()));
+        }
+        let mut contact = self
+            .get(id)?
+            .ok_or_else(|| super::Error::ContactNotFound(id.to_string()))?;
+        stage_keys(&mut contact, public_key, sign_public_key);
+        self.put(&contact)?;
+        Ok(contact)
+    }
+
+    /// Pin the pending key change, moving the old keys into the history.
+    pub fn accept_key_change(&self, id: u64) -> Result<Contact, super::Error> {
+        let mut contact = self
+            .get(id)?
+            .ok_or_else(|| super::Error::ContactNotFound(id.to_string()))?;
+        let change = contact.pending_key.take().ok_or_else(|| {
+            super::Error::Validation(format!("no pending key change for contact {id}"))
+        })?;
+        contact.key_history.push(KeyRecord {
+            public_key: std::mem::replace(&mut contact.public_key, change.public_key),
+            sign_public_key: std::mem::replace(
+                &mut contact.sign_public_key,
+                change.sign_public_key,
+            ),
+            replaced_at: now_secs(),
+        });
+        contact.verified = false;
+        self.put(&contact)?;
         Ok(contact)
     }
 
+    /// Move a contact to keys it announced in a verified key transition: the old keys go to
+    /// the history, the PeerId binding (and any `/p2p/` suffix on the addr) follows the new
+    /// transport key, and the safety number needs comparing again.
+    pub fn apply_key_transition(
+        &self,
+        id: u64,
+        public_key: &[u8],
+        sign_public_key: &[u8],
+        peer_id: Option<&str>,
+    ) -> Result<Contact, super::Error> {
+        if public_key.len() != 32 || sign_public_key.len() != 32 {
+            return Err(super::Error::Validation("keys must be 32 bytes".into()));
+        }
+        if let Some(p) = peer_id {
+            validate_peer_id(p)?;
+            if let Some(other) = self.find_by_peer_id(p)? {
+                if other.id != id {
+                    return Err(super::Error::Validation(format!(
+                        "peer id already bound to contact {}",
+                        other.id
+                    )));
+                }
+            }
+        }
+        let mut contact = self
+            .get(id)?
+            .ok_or_else(|| super::Error::ContactNotFound(id.to_string()))?;
+        contact.key_history.push(KeyRecord {
+            public_key: std::mem::replace(&mut contact.public_key, public_key.to_vec()),
+            sign_public_key: contact.sign_public_key.replace(sign_public_key.to_vec()),
+            replaced_at: now_secs(),
+        });
+        if let Some(p) = peer_id {
+            if let Some(old) = crate::discovery::peer_id_from_addr(&contact.addr) {
+                contact.addr = contact.addr.replace(&format!("/p2p/{old}"), &format!("/p2p/{p}"));
+            }
+            contact.peer_id = Some(p.to_string());
+        }
+        contact.pending_key = None;
+        contact.verified = false;
+        self.put(&contact)?;
+        Ok(contact)
+    }
+
+    /// Drop the pending key change and keep the pinned keys.
+    pub fn reject_key_change(&self, id: u64) -> Result<Contact, super::Error> {
+        let mut contact = self
+            .get(id)?
+            .ok_or_else(|| super::Error::ContactNotFound(id.to_string()))?;
+        if contact.pending_key.take().is_none() {
+            return Err(super::Error::Validation(format!(
+                "no pending key change for contact {id}"
+            )));
+        }
+        self.put(&contact)?;
+        Ok(contact)
+    }
+
+    /// Contacts with a key change waiting for the user.
+    pub fn pending_key_changes(&self) -> Result<Vec<Contact>, super::Error> {
+        Ok(self
+            .list()?
+            .into_iter()
+            .filter(|c| c.pending_key.is_some())
+            .collect())
+    }
+
+    /// Replace only the dialable address of an existing contact.
+    pub fn update_addr(&self, id: u64, addr: &str) -> Result<Contact, super::Error> {
+        if !addr.trim_start().starts_with('/') {
+            return Err(super::Error::Validation(
+                "addr must be a multiaddr starting with '/'".into(),
+            ));
+        }
+        let mut contact = self
+            .get(id)?
+            .ok_or_else(|| super::Error::ContactNotFound(id.to_string()))?;
+        contact.addr = addr.to_string();
+        self.put(&contact)?;
+        Ok(contact)
+    }
+
+    /// Bind a contact to the libp2p PeerId its Noise handshake must present.
+    pub fn set_peer_id(&self, id: u64, peer_id: &str) -> Result<Contact, super::Error> {
+        validate_peer_id(peer_id)?;
+        let mut contact = self
+            .get(id)?
+            .ok_or_else(|| super::Error::ContactNotFound(id.to_string()))?;
+        if let Some(other) = self.find_by_peer_id(peer_id)? {
+            if other.id != id {
+                return Err(super::Error::Validation(format!(
+                    "peer id already bound to contact {}",
+                    other.id
+                )));
+            }
+        }
+        contact.peer_id = Some(peer_id.to_string());
+        self.put(&contact)?;
+        Ok(contact)
+    }
+
+    /// Record the ed25519 key the contact signs envelopes with. A key different from an
+    /// already pinned one is staged as a key change.
+    pub fn set_sign_key(&self, id: u64, sign_public_key: &[u8]) -> Result<Contact, super::Error> {
+        let contact = self
+            .get(id)?
+            .ok_or_else(|| super::Error::ContactNotFound(id.to_string()))?;
+        self.observe_keys(id, &contact.public_key, Some(sign_public_key))
+    }
+
+    /// Replace the contact's list of other devices (from a freshly imported card).
+    pub fn set_devices(&self, id: u64, devices: Vec<DeviceKey>) -> Result<Contact, super::Error> {
+        let mut contact = self
+            .get(id)?
+            .ok_or_else(|| super::Error::ContactNotFound(id.to_string()))?;
+        contact.devices = devices;
+        self.put(&contact)?;
+        Ok(contact)
+    }
+
+    /// Mark a contact's safety number as compared (or not).
+    pub fn set_verified(&self, id: u64, verified: bool) -> Result<Contact, super::Error> {
+        let mut contact = self
+            .get(id)?
+            .ok_or_else(|| super::Error::ContactNotFound(id.to_string()))?;
+        contact.verified = verified;
+        self.put(&contact)?;
+        Ok(contact)
+    }
+
+    /// Add a contact record from another install (e.g. an identity export) under a new id.
+    /// Returns `None` when a contact with the same PeerId or public key already exists.
+    pub fn import(&self, contact: Contact) -> Result<Option<Contact>, super::Error> {
+        let existing = self.list()?;
+        let duplicate = existing.iter().any(|c| {
+            c.public_key == contact.public_key
+                || (contact.peer_id.is_some() && c.peer_id == contact.peer_id)
+        });
+        if duplicate {
+            return Ok(None);
+        }
+        let contact = Contact {
+            id: self.backend.generate_id()?,
+            ..contact
+        };
+        self.put(&contact)?;
+        Ok(Some(contact))
+    }
+
+    /// Find the contact one of whose other devices connects as `peer_id`.
+    pub fn find_by_device_peer(
+        &self,
+        peer_id: &str,
+    ) -> Result<Option<(Contact, DeviceKey)>, super::Error> {
+        let prefix = peer_prefix(DEVICE_PEER, peer_id);
+        Ok(self.indexed(&prefix)?.into_iter().find_map(|c| {
+            let device = c
+                .devices
+                .iter()
+                .find(|d| d.peer_id.as_deref() == Some(peer_id))
+                .cloned()?;
+            Some((c, device))
+        }))
+    }
+
+    /// Find the contact bound to `peer_id`, falling back to a `/p2p/<peer_id>` addr suffix.
+    pub fn find_by_peer_id(&self, peer_id: &str) -> Result<Option<Contact>, super::Error> {
+        let prefix = peer_prefix(CONTACT_PEER, peer_id);
+        Ok(self
+            .indexed(&prefix)?
+            .into_iter()
+            .find(|c| contact_peer_id(c) == Some(peer_id)))
+    }
+
     pub fn find_by_name_case_insensitive(
         &self,
         name: &str,
@@ -165,3 +714,153 @@ impl ContactStore {
         Ok(None)
     }
 }
+
+const CONTACT_PEER: &[u8] = b"peer:";
+const DEVICE_PEER: &[u8] = b"device:";
+
+/// The PeerId a contact connects as: the bound one, else the `/p2p/` suffix of its addr.
+fn contact_peer_id(contact: &Contact) -> Option<&str> {
+    match &contact.peer_id {
+        Some(p) => Some(p),
+        None => crate::discovery::peer_id_from_addr(&contact.addr),
+    }
+}
+
+// Entries are found by digest, so PeerIds are not stored in the clear
+fn peer_prefix(kind: &[u8], peer_id: &str) -> [u8; 32] {
+    let mut h = Sha256::new();
+    h.update(kind);
+    h.update(peer_id.as_bytes());
+    h.finalize().into()
+}
+
+/// Index prefixes for the PeerIds of `contact` and of its devices.
+fn peer_prefixes(contact: &Contact) -> Vec<[u8; 32]> {
+    let own = contact_peer_id(contact).map(|p| peer_prefix(CONTACT_PEER, p));
+    let devices = contact
+        .devices
+        .iter()
+        .filter_map(|d| d.peer_id.as_deref())
+        .map(|p| peer_prefix(DEVICE_PEER, p));
+    own.into_iter().chain(devices).collect()
+}
+
+fn peer_entry_key(prefix: &[u8; 32], id: u64) -> Vec<u8> {
+    let mut key = prefix.to_vec();
+    key.extend_from_slice(&id.to_be_bytes());
+    key
+}
+
+fn stage_keys(contact: &mut Contact, public_key: &[u8], sign_public_key: Option<&[u8]>) {
+    // A signing key seen for the first time is pinned as-is
+    if contact.sign_public_key.is_none() && contact.public_key == public_key {
+        if let Some(k) = sign_public_key {
+            contact.sign_public_key = Some(k.to_vec());
+        }
+    }
+    let sign_differs = match (sign_public_key, &contact.sign_public_key) {
+        (Some(new), Some(pinned)) => new != pinned.as_slice(),
+        _ => false,
+    };
+    if contact.public_key == public_key && !sign_differs {
+        return;
+    }
+    let sign_public_key = sign_public_key
+        .map(<[u8]>::to_vec)
+        .or_else(|| contact.sign_public_key.clone());
+    if contact.pending_key.as_ref().is_some_and(|p| {
+        p.public_key == public_key && p.sign_public_key == sign_public_key
+    }) {
+        return;
+    }
+    log::warn!(
+        "keys of contact {} ({}) changed; sending is held until the change is accepted",
+        contact.id,
+        contact.name
+    );
+    contact.pending_key = Some(KeyChange {
+        public_key: public_key.to_vec(),
+        sign_public_key,
+        seen_at: now_secs(),
+    });
+    // Whatever safety number was compared before no longer applies
+    contact.verified = false;
+}
+
+/// `Contact` as stored before schema versioning (version 1).
+#[derive(Deserialize)]
+struct ContactV1 {
+    id: u64,
+    name: String,
+    addr: String,
+    public_key: Vec<u8>,
+    ping_interval: u64,
+}
+
+/// contacts_db version 2: contacts gain a PeerId, a signing key, verification, key history
+/// and devices, all empty until learned.
+pub(crate) fn upgrade_contact_v1(plain: &[u8]) -> Result<Vec<u8>, super::Error> {
+    let old: ContactV1 =
+        bincode::deserialize(plain).map_err(|e| super::Error::Serialization(e.to_string()))?;
+    let contact = Contact {
+        id: old.id,
+        name: old.name,
+        addr: old.addr,
+        public_key: old.public_key,
+        ping_interval: old.ping_interval,
+        peer_id: None,
+        sign_public_key: None,
+        verified: false,
+        pending_key: None,
+        key_history: Vec::new(),
+        devices: Vec::new(),
+    };
+    bincode::serialize(&contact).map_err(|e| super::Error::Serialization(e.to_string()))
+}
+
+fn seal_value<T: Serialize>(
+    key: &super::at_rest::AtRestKey,
+    value: &T,
+) -> Result<Vec<u8>, super::Error> {
+    let serialized =
+        bincode::serialize(value).map_err(|e| super::Error::Serialization(e.to_string()))?;
+    super::at_rest::encrypt(key, &serialized)
+}
+
+fn open_value<T: DeserializeOwned>(
+    key: &super::at_rest::AtRestKey,
+    bytes: &[u8],
+) -> Result<T, super::Error> {
+    let plain = super::at_rest::decrypt(key, bytes)
+        .map_err(|e| super::Error::Serialization(e.to_string()))?;
+    bincode::deserialize(&plain).map_err(|e| super::Error::Serialization(e.to_string()))
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(feature = "network")]
+fn validate_peer_id(peer_id: &str) -> Result<(), super::Error> {
+    use std::str::FromStr;
+    libp2p::PeerId::from_str(peer_id)
+        .map(|_| ())
+        .map_err(|e| super::Error::Validation(format!("invalid peer id: {e}")))
+}
+
+#[cfg(not(feature = "network"))]
+fn validate_peer_id(peer_id: &str) -> Result<(), super::Error> {
+    // Without libp2p we can only check the base58 alphabet
+    let ok = !peer_id.is_empty()
+        && peer_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() && !matches!(c, '0' | 'O' | 'I' | 'l'));
+    if ok {
+        Ok(())
+    } else {
+        Err(super::Error::Validation("invalid peer id".into()))
+    }
+}
@@ -0,0 +1,70 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use super::backend::{self, Backend, KvTree, DEFAULT_TREE};
+use super::schema;
+use crate::devices::LinkedDevices;
+
+const LINKED_KEY: &[u8] = b"linked";
+
+/// The device certificates of the account this install belongs to, encrypted at rest like
+/// the other stores.
+pub struct DeviceStore {
+    backend: Arc<dyn Backend>,
+    devices: Arc<dyn KvTree>,
+    key: super::at_rest::AtRestKey,
+}
+
+impl DeviceStore {
+    /// Open the store of `data_dir`, in whichever backend it is stored (sled unless
+    /// migrated), upgrading records in an older schema.
+    pub fn open_in_dir(data_dir: &Path) -> Result<Self, super::Error> {
+        std::fs::create_dir_all(data_dir)
+            .map_err(|e| super::Error::Serialization(e.to_string()))?;
+        let key = super::at_rest::AtRestKey::load_or_create(data_dir)?;
+        let backend = backend::open_path(&data_dir.join("devices_db"))?;
+        let backups = data_dir.join(schema::BACKUP_DIR);
+        schema::upgrade(backend.as_ref(), &key, &schema::DEVICES, Some(&backups))?;
+        Self::with_backend(backend, key)
+    }
+
+    /// Keep the certificates in `backend` (e.g. a `MemoryBackend`) instead of the data dir.
+    pub fn with_backend(
+        backend: Arc<dyn Backend>,
+        key: super::at_rest::AtRestKey,
+    ) -> Result<Self, super::Error> {
+        schema::upgrade(backend.as_ref(), &key, &schema::DEVICES, None)?;
+        let devices = backend.open_tree(DEFAULT_TREE)?;
+        Ok(Self {
+            backend,
+            devices,
+            key,
+        })
+    }
+
+    pub fn load(&self) -> Result<Option<LinkedDevices>, super::Error> {
+        let Some(bytes) = self.devices.get(LINKED_KEY)? else {
+            return Ok(None);
+        };
+        let plain = super::at_rest::decrypt(&self.key, &bytes)
+            .map_err(|e| super::Error::Serialization(e.to_string()))?;
+        bincode::deserialize(&plain)
+            .map(Some)
+            .map_err(|e| super::Error::Serialization(e.to_string()))
+    }
+
+    pub fn save(&self, linked: &LinkedDevices) -> Result<(), super::Error> {
+        let serialized =
+            bincode::serialize(linked).map_err(|e| super::Error::Serialization(e.to_string()))?;
+        self.devices
+            .insert(LINKED_KEY, &super::at_rest::encrypt(&self.key, &serialized)?)?;
+        self.backend.flush()?;
+        Ok(())
+    }
+
+    pub fn clear(&self) -> Result<(), super::Error> {
+        self.devices.remove(LINKED_KEY)?;
+        self.backend.flush()?;
+        Ok(())
+    }
+}
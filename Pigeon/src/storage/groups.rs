@@ -0,0 +1,126 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::at_rest::AtRestKey;
+use super::backend::{self, Backend, KvTree, DEFAULT_TREE};
+use super::schema;
+use crate::groups::{Group, GroupMessage, HeldItem};
+
+/// Groups with their sender keys, each group's message thread, and key distributions or
+/// messages that arrived before what they depend on. Encrypted at rest like the other stores.
+pub struct GroupStore {
+    backend: Arc<dyn Backend>,
+    groups: Arc<dyn KvTree>,
+    thread: Arc<dyn KvTree>,
+    held: Arc<dyn KvTree>,
+    key: AtRestKey,
+}
+
+impl GroupStore {
+    /// Open the store of `data_dir`, in whichever backend it is stored (sled unless
+    /// migrated), upgrading records in an older schema.
+    pub fn open_in_dir(data_dir: &Path) -> Result<Self, super::Error> {
+        std::fs::create_dir_all(data_dir)
+            .map_err(|e| super::Error::Serialization(e.to_string()))?;
+        let key = AtRestKey::load_or_create(data_dir)?;
+        let backend = backend::open_path(&data_dir.join("groups_db"))?;
+        let backups = data_dir.join(schema::BACKUP_DIR);
+        schema::upgrade(backend.as_ref(), &key, &schema::GROUPS, Some(&backups))?;
+        Self::with_backend(backend, key)
+    }
+
+    /// Keep groups in `backend` (e.g. a `MemoryBackend`) instead of the data dir.
+    pub fn with_backend(backend: Arc<dyn Backend>, key: AtRestKey) -> Result<Self, super::Error> {
+        schema::upgrade(backend.as_ref(), &key, &schema::GROUPS, None)?;
+        let groups = backend.open_tree(DEFAULT_TREE)?;
+        let thread = backend.open_tree("thread")?;
+        let held = backend.open_tree("held")?;
+        Ok(Self {
+            backend,
+            groups,
+            thread,
+            held,
+            key,
+        })
+    }
+
+    pub fn get(&self, group_id: Uuid) -> Result<Option<Group>, super::Error> {
+        self.groups
+            .get(group_id.as_bytes())?
+            .map(|b| open_value(&self.key, &b))
+            .transpose()
+    }
+
+    pub fn save(&self, group: &Group) -> Result<(), super::Error> {
+        self.groups
+            .insert(group.id.as_bytes(), &seal_value(&self.key, group)?)?;
+        // Chain keys must not roll back after a crash, or message keys would be reused
+        self.backend.flush()?;
+        Ok(())
+    }
+
+    /// All groups, ordered by name.
+    pub fn list(&self) -> Result<Vec<Group>, super::Error> {
+        let mut out = Vec::new();
+        for item in self.groups.iter() {
+            let (_k, v) = item?;
+            out.push(open_value::<Group>(&self.key, &v)?);
+        }
+        out.sort_by_key(|g| g.name.to_lowercase());
+        Ok(out)
+    }
+
+    pub fn append_message(&self, message: &GroupMessage) -> Result<(), super::Error> {
+        // Keyed in arrival order; timestamps only have second resolution
+        let mut key = message.group_id.as_bytes().to_vec();
+        key.extend_from_slice(&self.backend.generate_id()?.to_be_bytes());
+        self.thread.insert(&key, &seal_value(&self.key, message)?)?;
+        Ok(())
+    }
+
+    /// A group's messages, oldest first.
+    pub fn thread(&self, group_id: Uuid) -> Result<Vec<GroupMessage>, super::Error> {
+        let mut out = Vec::new();
+        for item in self.thread.scan_prefix(group_id.as_bytes()) {
+            let (_k, v) = item?;
+            out.push(open_value(&self.key, &v)?);
+        }
+        Ok(out)
+    }
+
+    pub fn hold(&self, group_id: Uuid, item: &HeldItem) -> Result<(), super::Error> {
+        let mut key = group_id.as_bytes().to_vec();
+        key.extend_from_slice(Uuid::new_v4().as_bytes());
+        self.held.insert(&key, &seal_value(&self.key, item)?)?;
+        self.held.flush()?;
+        Ok(())
+    }
+
+    /// Remove and return everything held for a group.
+    pub fn take_held(&self, group_id: Uuid) -> Result<Vec<HeldItem>, super::Error> {
+        let mut out = Vec::new();
+        for item in self.held.scan_prefix(group_id.as_bytes()) {
+            let (k, v) = item?;
+            out.push(open_value(&self.key, &v)?);
+            self.held.remove(&k)?;
+        }
+        self.held.flush()?;
+        Ok(out)
+    }
+}
+
+fn seal_value<T: Serialize>(key: &AtRestKey, value: &T) -> Result<Vec<u8>, super::Error> {
+    let serialized =
+        bincode::serialize(value).map_err(|e| super::Error::Serialization(e.to_string()))?;
+    super::at_rest::encrypt(key, &serialized)
+}
+
+fn open_value<T: DeserializeOwned>(key: &AtRestKey, bytes: &[u8]) -> Result<T, super::Error> {
+    let plain = super::at_rest::decrypt(key, bytes)
+        .map_err(|e| super::Error::Serialization(e.to_string()))?;
+    bincode::deserialize(&plain).map_err(|e| super::Error::Serialization(e.to_string()))
+}
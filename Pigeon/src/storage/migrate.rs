@@ -0,0 +1,143 @@
+//! Moving databases from one storage backend to another. Records are copied exactly as
+//! stored, so they stay sealed under the at-rest key and no unlock is needed.
+
+use std::path::{Path, PathBuf};
+
+use super::backend::{self, BackendKind};
+
+/// The databases whose stores run on any backend: contacts_db, queue_db with the queue,
+/// inbox, dead letters and seen nonces, and the session, prekey, device and group stores.
+pub const MIGRATABLE: &[&str] = &[
+    "contacts_db",
+    "queue_db",
+    "sessions_db",
+    "prekeys_db",
+    "devices_db",
+    "groups_db",
+];
+
+// Report progress every this many records, and at the end of each database
+const PROGRESS_EVERY: usize = 100;
+
+/// How far the copy of one database has got.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrateProgress {
+    pub database: &'static str,
+    pub done: usize,
+    pub total: usize,
+}
+
+/// Where a database moved away from `kind` is kept until the user deletes it.
+pub fn retired_path(path: &Path, kind: BackendKind) -> PathBuf {
+    backend::with_suffix(path, &format!(".{kind}-old"))
+}
+
+fn partial_path(path: &Path, kind: BackendKind) -> PathBuf {
+    backend::with_suffix(&backend::location(path, kind), ".partial")
+}
+
+/// Copy every migratable database of `data_dir` from `from` to `to`. Each copy is written
+/// beside the original and only moved into place once complete; the original is then
+/// kept as `<name>.<from>-old`. Nothing else may have the stores open meanwhile. Returns
+/// how many records were copied.
+pub fn migrate(
+    data_dir: &Path,
+    from: BackendKind,
+    to: BackendKind,
+    progress: &mut dyn FnMut(&MigrateProgress),
+) -> Result<usize, super::Error> {
+    if from == to {
+        return Err(super::Error::Validation(format!(
+            "the stores already use {to}"
+        )));
+    }
+    // Check every database before touching any, so a refusal leaves the dir as it was
+    for name in MIGRATABLE {
+        let path = data_dir.join(name);
+        if let Some(kind) = backend::detect(&path) {
+            if kind != from {
+                return Err(super::Error::Validation(format!(
+                    "{name} is stored with {kind}, not {from}"
+                )));
+            }
+        }
+        let retired = retired_path(&path, from);
+        if retired.exists() {
+            return Err(super::Error::Validation(format!(
+                "{} is left from an earlier migration; delete it first",
+                retired.display()
+            )));
+        }
+        if backend::location(&path, to).exists() {
+            return Err(super::Error::Validation(format!(
+                "{} already exists",
+                backend::location(&path, to).display()
+            )));
+        }
+    }
+
+    let mut copied = 0;
+    for name in MIGRATABLE {
+        copied += migrate_one(data_dir, name, from, to, progress)?;
+    }
+    Ok(copied)
+}
+
+fn migrate_one(
+    data_dir: &Path,
+    name: &'static str,
+    from: BackendKind,
+    to: BackendKind,
+    progress: &mut dyn FnMut(&MigrateProgress),
+) -> Result<usize, super::Error> {
+    let path = data_dir.join(name);
+    let partial = partial_path(&path, to);
+    // Left behind by an interrupted run; the original is still in place
+    backend::remove_at(&partial, to).map_err(io_err)?;
+
+    let exists = backend::detect(&path).is_some();
+    let mut done = 0;
+    {
+        let target = backend::open_at(&partial, to)?;
+        if exists {
+            let source = backend::open_kind(&path, from)?;
+            let names = source.tree_names()?;
+            let mut trees = Vec::new();
+            for tree_name in &names {
+                trees.push((source.open_tree(tree_name)?, target.open_tree(tree_name)?));
+            }
+            let total = trees.iter().map(|(src, _)| src.len()).sum();
+            for (src, dst) in &trees {
+                for item in src.iter() {
+                    let (k, v) = item?;
+                    dst.insert(&k, &v)?;
+                    done += 1;
+                    if done % PROGRESS_EVERY == 0 {
+                        progress(&MigrateProgress { database: name, done, total });
+                    }
+                }
+            }
+            // Contact ids are generated, and must not be handed out again
+            target.reserve_ids(source.generate_id()?)?;
+            source.flush()?;
+            progress(&MigrateProgress { database: name, done, total });
+        } else {
+            progress(&MigrateProgress { database: name, done: 0, total: 0 });
+        }
+        target.flush()?;
+        // Both handles close here, before their files are moved
+    }
+
+    std::fs::rename(&partial, backend::location(&path, to)).map_err(io_err)?;
+    if exists {
+        std::fs::rename(backend::location(&path, from), retired_path(&path, from))
+            .map_err(io_err)?;
+    }
+    // Whatever is left of the old backend (SQLite journal files) goes too
+    backend::remove_at(&backend::location(&path, from), from).map_err(io_err)?;
+    Ok(done)
+}
+
+fn io_err(e: std::io::Error) -> super::Error {
+    super::Error::Backend(e.to_string())
+}
@@ -1,7 +1,17 @@
 pub mod at_rest;
+pub mod backend;
 pub mod contacts;
+pub mod devices;
+pub mod groups;
+pub mod migrate;
 pub mod nonce_store;
+pub mod os_keyring;
+pub mod prekeys;
 pub mod queue;
+pub mod rekey;
+pub mod schema;
+pub mod sessions;
+pub mod snapshot;
 
 #[allow(unused_imports)]
 pub use contacts::ContactStore;
@@ -14,6 +24,8 @@ use thiserror::Error;
 pub enum Error {
     #[error("Database error: {0}")]
     Db(#[from] sled::Error),
+    #[error("Storage backend error: {0}")]
+    Backend(String),
 
     #[error("Serialization error: {0}")]
     Serialization(String),
@@ -21,8 +33,31 @@ pub enum Error {
     Validation(String),
     #[error("Crypto error: {0}")]
     Crypto(String),
+    #[error("Wrong passphrase")]
+    WrongPassphrase,
+    #[error("Too many failed unlock attempts; try again in {0}s")]
+    UnlockThrottled(u64),
 
     #[allow(dead_code)]
     #[error("Contact not found: {0}")]
     ContactNotFound(String),
 }
+
+const LOCK_RETRIES: u32 = 40;
+
+/// Open a sled database, retrying briefly while a just-dropped handle in this or another
+/// process still holds the file lock (sled releases it from a background thread).
+pub(crate) fn open_db<P: AsRef<std::path::Path>>(path: P) -> Result<sled::Db, Error> {
+    let mut attempt = 0;
+    loop {
+        match sled::open(path.as_ref()) {
+            Err(sled::Error::Io(e))
+                if attempt < LOCK_RETRIES && e.to_string().contains("acquire lock") =>
+            {
+                attempt += 1;
+                std::thread::sleep(std::time::Duration::from_millis(25));
+            }
+            other => return other.map_err(Error::Db),
+        }
+    }
+}
@@ -1,33 +1,80 @@
-use sled::Tree;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::ops::Bound;
+use std::sync::Arc;
 
+use super::backend::Backend;
+
+// (sender key | nonce) -> send time; the time index orders the same entries for pruning
+const SEEN: &str = "seen_nonces";
+const SEEN_BY_TIME: &str = "seen_nonces_by_time";
+// Entries keyed by the envelope's (always 0) sender id, from before the time index
+const LEGACY_NONCES: &str = "nonces";
+
+/// Nonces of envelopes already accepted, per sender, kept for as long as an envelope with
+/// that send time could still be inside the replay window.
 #[allow(dead_code)]
 pub struct NonceStore {
-    tree: Tree,
+    backend: Arc<dyn Backend>,
 }
 
 #[allow(dead_code)]
 impl NonceStore {
-    pub fn open(db: &sled::Db) -> Result<Self, super::Error> {
-        let tree = db.open_tree("nonces").map_err(super::Error::Db)?;
-        Ok(Self { tree })
+    pub fn open(backend: Arc<dyn Backend>) -> Result<Self, super::Error> {
+        backend.open_tree(SEEN)?;
+        backend.open_tree(SEEN_BY_TIME)?;
+        // Their envelopes carry no timestamp and fall outside every window now
+        let legacy = backend.open_tree(LEGACY_NONCES)?;
+        if legacy.first()?.is_some() {
+            legacy.clear()?;
+        }
+        Ok(Self { backend })
     }
 
-    pub fn insert_if_fresh(&self, sender_id: u64, nonce: &[u8]) -> Result<bool, super::Error> {
-        let mut key = sender_id.to_be_bytes().to_vec();
+    /// Record `nonce` from `sender` (its public key), sent at `sent_at`. False if it was
+    /// already recorded, i.e. the envelope is a replay.
+    pub fn insert_if_fresh(
+        &self,
+        sender: &[u8],
+        nonce: &[u8],
+        sent_at: u64,
+    ) -> Result<bool, super::Error> {
+        let mut key = sender.to_vec();
         key.extend_from_slice(nonce);
-        // if exists -> replay
-        if self.tree.contains_key(&key).map_err(super::Error::Db)? {
-            return Ok(false);
-        }
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs();
-        let ts = now.to_be_bytes();
-        self.tree
-            .insert(key, ts.as_slice())
-            .map_err(super::Error::Db)?;
-        Ok(true)
+        let ts = sent_at.to_be_bytes();
+        let mut by_time = ts.to_vec();
+        by_time.extend_from_slice(&key);
+        // Check and record in one transaction, so a replay racing the original is caught
+        let mut fresh = false;
+        self.backend.transaction(&[SEEN, SEEN_BY_TIME], &mut |tx| {
+            fresh = tx.get(0, &key)?.is_none();
+            if fresh {
+                tx.insert(0, &key, &ts)?;
+                tx.insert(1, &by_time, &[])?;
+            }
+            Ok(())
+        })?;
+        Ok(fresh)
+    }
+
+    /// Forget nonces of envelopes sent before `cutoff` (Unix seconds); returns how many.
+    pub fn prune(&self, cutoff: u64) -> Result<usize, super::Error> {
+        let end = cutoff.to_be_bytes();
+        let expired = self
+            .backend
+            .open_tree(SEEN_BY_TIME)?
+            .range(Bound::Unbounded, Bound::Excluded(end.as_slice()))
+            .map(|item| item.map(|(k, _)| k))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.backend.transaction(&[SEEN, SEEN_BY_TIME], &mut |tx| {
+            for k in &expired {
+                tx.remove(0, &k[8..])?;
+                tx.remove(1, k)?;
+            }
+            Ok(())
+        })?;
+        Ok(expired.len())
+    }
+
+    pub fn count(&self) -> Result<usize, super::Error> {
+        Ok(self.backend.open_tree(SEEN)?.len())
     }
 }
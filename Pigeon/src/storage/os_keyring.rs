@@ -0,0 +1,148 @@
+//! Optional cache of the unlocked at-rest key in the OS keyring (Secret Service or kernel
+//! keyutils on Linux, Keychain on macOS, Credential Manager on Windows), so separate CLI
+//! runs share one `security unlock` instead of needing PIGEON_PASSPHRASE. Entries carry
+//! their own expiry because not every backend can time keys out.
+
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use sodiumoxide::crypto::secretbox;
+
+#[cfg(feature = "os-keyring")]
+const SERVICE: &str = "pigeon";
+// expires_at, then the number of data keys and of KEKs
+const ENTRY_HEADER: usize = 8 + 2;
+
+/// Whether this build has a keyring backend (the `os-keyring` feature).
+pub fn available() -> bool {
+    cfg!(feature = "os-keyring")
+}
+
+/// Keep the data keys and passphrase KEKs of `data_dir` (current first) in the keyring
+/// for `ttl`.
+pub fn store(
+    data_dir: &Path,
+    keys: &[secretbox::Key],
+    keks: &[secretbox::Key],
+    ttl: Duration,
+) -> Result<(), super::Error> {
+    let expires_at = now_secs().saturating_add(ttl.as_secs());
+    let mut secret =
+        Vec::with_capacity(ENTRY_HEADER + (keys.len() + keks.len()) * secretbox::KEYBYTES);
+    secret.extend_from_slice(&expires_at.to_be_bytes());
+    secret.push(u8::try_from(keys.len()).map_err(|_| super::Error::Crypto("too many keys".into()))?);
+    secret.push(u8::try_from(keks.len()).map_err(|_| super::Error::Crypto("too many keys".into()))?);
+    for k in keys.iter().chain(keks) {
+        secret.extend_from_slice(&k.0);
+    }
+    backend::set(&account(data_dir), &secret)
+}
+
+/// The cached (data keys, KEKs), if an unexpired entry exists. Expired or malformed
+/// entries are removed.
+pub fn load(data_dir: &Path) -> Option<(Vec<secretbox::Key>, Vec<secretbox::Key>)> {
+    let account = account(data_dir);
+    let secret = backend::get(&account)?;
+    let parsed = parse_entry(&secret);
+    if parsed.is_none() {
+        let _ = backend::delete(&account);
+    }
+    parsed
+}
+
+fn parse_entry(secret: &[u8]) -> Option<(Vec<secretbox::Key>, Vec<secretbox::Key>)> {
+    if secret.len() < ENTRY_HEADER {
+        return None;
+    }
+    let mut expires = [0u8; 8];
+    expires.copy_from_slice(&secret[..8]);
+    if u64::from_be_bytes(expires) <= now_secs() {
+        return None;
+    }
+    let (n_keys, n_keks) = (secret[8] as usize, secret[9] as usize);
+    let body = &secret[ENTRY_HEADER..];
+    if n_keys == 0 || body.len() != (n_keys + n_keks) * secretbox::KEYBYTES {
+        return None;
+    }
+    let mut all = body
+        .chunks(secretbox::KEYBYTES)
+        .map(secretbox::Key::from_slice)
+        .collect::<Option<Vec<_>>>()?;
+    let keks = all.split_off(n_keys);
+    Some((all, keks))
+}
+
+/// Remove the entry for `data_dir`; true when there was one.
+pub fn clear(data_dir: &Path) -> Result<bool, super::Error> {
+    backend::delete(&account(data_dir))
+}
+
+/// One entry per data dir, named by a hash of its path.
+fn account(data_dir: &Path) -> String {
+    use sha2::{Digest, Sha256};
+    let path = data_dir
+        .canonicalize()
+        .unwrap_or_else(|_| data_dir.to_path_buf());
+    let digest = Sha256::digest(path.to_string_lossy().as_bytes());
+    format!("at-rest-{}", hex::encode(&digest[..16]))
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(feature = "os-keyring")]
+mod backend {
+    use super::SERVICE;
+
+    // The Secret Service backend drives its own async runtime, so keyring calls run on a
+    // plain thread rather than inside whatever runtime the caller is on
+    fn off_runtime<T: Send>(f: impl FnOnce() -> T + Send) -> T {
+        std::thread::scope(|s| s.spawn(f).join().expect("keyring thread panicked"))
+    }
+
+    fn entry(account: &str) -> Result<::keyring::Entry, crate::storage::Error> {
+        ::keyring::Entry::new(SERVICE, account)
+            .map_err(|e| crate::storage::Error::Crypto(format!("keyring: {e}")))
+    }
+
+    pub fn set(account: &str, secret: &[u8]) -> Result<(), crate::storage::Error> {
+        off_runtime(|| {
+            entry(account)?
+                .set_secret(secret)
+                .map_err(|e| crate::storage::Error::Crypto(format!("keyring: {e}")))
+        })
+    }
+
+    pub fn get(account: &str) -> Option<Vec<u8>> {
+        off_runtime(|| entry(account).ok()?.get_secret().ok())
+    }
+
+    pub fn delete(account: &str) -> Result<bool, crate::storage::Error> {
+        off_runtime(|| match entry(account)?.delete_credential() {
+            Ok(()) => Ok(true),
+            Err(::keyring::Error::NoEntry) => Ok(false),
+            Err(e) => Err(crate::storage::Error::Crypto(format!("keyring: {e}"))),
+        })
+    }
+}
+
+#[cfg(not(feature = "os-keyring"))]
+mod backend {
+    pub fn set(_account: &str, _secret: &[u8]) -> Result<(), crate::storage::Error> {
+        Err(crate::storage::Error::Crypto(
+            "this build has no OS keyring support (enable the os-keyring feature)".into(),
+        ))
+    }
+
+    pub fn get(_account: &str) -> Option<Vec<u8>> {
+        None
+    }
+
+    pub fn delete(_account: &str) -> Result<bool, crate::storage::Error> {
+        Ok(false)
+    }
+}
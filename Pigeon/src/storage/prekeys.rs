@@ -0,0 +1,118 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::at_rest::AtRestKey;
+use super::backend::{self, Backend, KvTree, DEFAULT_TREE};
+use super::schema;
+use crate::session::prekeys::LocalPreKeys;
+use crate::session::x3dh::PreKeyBundle;
+
+const LOCAL_KEY: &[u8] = b"local";
+
+/// Our prekey secrets, the ids of one-time prekeys already used, and bundles fetched from
+/// contacts. Everything is encrypted at rest like the other stores.
+#[allow(dead_code)]
+pub struct PreKeyStore {
+    backend: Arc<dyn Backend>,
+    local: Arc<dyn KvTree>,
+    consumed: Arc<dyn KvTree>,
+    remote: Arc<dyn KvTree>,
+    key: AtRestKey,
+}
+
+#[allow(dead_code)]
+impl PreKeyStore {
+    /// Open the store of `data_dir`, in whichever backend it is stored (sled unless
+    /// migrated), upgrading records in an older schema.
+    pub fn open_in_dir(data_dir: &Path) -> Result<Self, super::Error> {
+        std::fs::create_dir_all(data_dir)
+            .map_err(|e| super::Error::Serialization(e.to_string()))?;
+        let key = AtRestKey::load_or_create(data_dir)?;
+        let backend = backend::open_path(&data_dir.join("prekeys_db"))?;
+        let backups = data_dir.join(schema::BACKUP_DIR);
+        schema::upgrade(backend.as_ref(), &key, &schema::PREKEYS, Some(&backups))?;
+        Self::with_backend(backend, key)
+    }
+
+    /// Keep prekeys in `backend` (e.g. a `MemoryBackend`) instead of the data dir.
+    pub fn with_backend(backend: Arc<dyn Backend>, key: AtRestKey) -> Result<Self, super::Error> {
+        schema::upgrade(backend.as_ref(), &key, &schema::PREKEYS, None)?;
+        let local = backend.open_tree(DEFAULT_TREE)?;
+        let consumed = backend.open_tree("consumed")?;
+        let remote = backend.open_tree("remote")?;
+        Ok(Self {
+            backend,
+            local,
+            consumed,
+            remote,
+            key,
+        })
+    }
+
+    pub fn load_local(&self) -> Result<Option<LocalPreKeys>, super::Error> {
+        self.local.get(LOCAL_KEY)?.map(|b| open_value(&self.key, &b)).transpose()
+    }
+
+    pub fn save_local(&self, keys: &LocalPreKeys) -> Result<(), super::Error> {
+        self.local.insert(LOCAL_KEY, &seal_value(&self.key, keys)?)?;
+        // A handed-out one-time prekey must not come back after a crash
+        self.backend.flush()?;
+        Ok(())
+    }
+
+    /// Drop our prekeys (e.g. when the identity that signed them is replaced).
+    pub fn clear_local(&self) -> Result<(), super::Error> {
+        self.local.remove(LOCAL_KEY)?;
+        self.backend.flush()?;
+        Ok(())
+    }
+
+    /// Record that a one-time prekey was used by a handshake.
+    pub fn mark_consumed(&self, prekey_id: u32, at: u64) -> Result<(), super::Error> {
+        self.consumed
+            .insert(&prekey_id.to_be_bytes(), &at.to_be_bytes())?;
+        self.consumed.flush()?;
+        Ok(())
+    }
+
+    pub fn is_consumed(&self, prekey_id: u32) -> Result<bool, super::Error> {
+        self.consumed.contains_key(&prekey_id.to_be_bytes())
+    }
+
+    pub fn consumed_count(&self) -> usize {
+        self.consumed.len()
+    }
+
+    pub fn load_remote(&self, contact_id: u64) -> Result<Option<PreKeyBundle>, super::Error> {
+        self.remote
+            .get(&contact_id.to_be_bytes())?
+            .map(|b| open_value(&self.key, &b))
+            .transpose()
+    }
+
+    pub fn save_remote(&self, contact_id: u64, bundle: &PreKeyBundle) -> Result<(), super::Error> {
+        self.remote
+            .insert(&contact_id.to_be_bytes(), &seal_value(&self.key, bundle)?)?;
+        self.remote.flush()?;
+        Ok(())
+    }
+
+    pub fn remove_remote(&self, contact_id: u64) -> Result<bool, super::Error> {
+        Ok(self.remote.remove(&contact_id.to_be_bytes())?.is_some())
+    }
+}
+
+fn seal_value<T: Serialize>(key: &AtRestKey, value: &T) -> Result<Vec<u8>, super::Error> {
+    let serialized =
+        bincode::serialize(value).map_err(|e| super::Error::Serialization(e.to_string()))?;
+    super::at_rest::encrypt(key, &serialized)
+}
+
+fn open_value<T: DeserializeOwned>(key: &AtRestKey, bytes: &[u8]) -> Result<T, super::Error> {
+    let plain = super::at_rest::decrypt(key, bytes)
+        .map_err(|e| super::Error::Serialization(e.to_string()))?;
+    bincode::deserialize(&plain).map_err(|e| super::Error::Serialization(e.to_string()))
+}
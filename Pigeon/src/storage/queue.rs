@@ -1,8 +1,11 @@
 use serde::{Deserialize, Serialize};
-use sled::Tree;
+use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 
+use super::backend::{self, Backend, KvTree};
+use super::schema;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct QueuedMessage {
     pub id: Uuid,
@@ -14,6 +17,7 @@ pub struct QueuedMessage {
     pub retry_count: u32,
     pub next_attempt_at: u64, // Unix timestamp when eligible for retry/dequeue
     pub max_retries: u32,
+    pub device: Option<Vec<u8>>, // box key of the one device to deliver to; None = the contact
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -34,35 +38,133 @@ pub struct DeadLetterRecord {
     pub last_error: String,
 }
 
+/// `QueuedMessage` as stored before schema versioning (version 1).
+#[derive(Deserialize)]
+struct QueuedMessageV1 {
+    id: Uuid,
+    contact_id: u64,
+    payload: Vec<u8>,
+    created: u64,
+    priority: u8,
+    status: MessageStatus,
+    retry_count: u32,
+    next_attempt_at: u64,
+    max_retries: u32,
+}
+
+/// queue_db version 2: queued messages gain the device they are for (none: the contact).
+pub(crate) fn upgrade_queued_v1(plain: &[u8]) -> Result<Vec<u8>, super::Error> {
+    let old: QueuedMessageV1 =
+        bincode::deserialize(plain).map_err(|e| super::Error::Serialization(e.to_string()))?;
+    let message = QueuedMessage {
+        id: old.id,
+        contact_id: old.contact_id,
+        payload: old.payload,
+        created: old.created,
+        priority: old.priority,
+        status: old.status,
+        retry_count: old.retry_count,
+        next_attempt_at: old.next_attempt_at,
+        max_retries: old.max_retries,
+        device: None,
+    };
+    bincode::serialize(&message).map_err(|e| super::Error::Serialization(e.to_string()))
+}
+
+/// Raw inbound envelope from a peer that is not a contact, held until the user decides.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QuarantinedMessage {
+    pub id: Uuid,
+    pub peer_id: String,
+    pub received_at: u64,
+    pub envelope: Vec<u8>,
+}
+
+/// A message this account sent, from this device or synced from one of its others.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SentMessage {
+    pub id: Uuid,
+    pub contact_id: Option<u64>, // None when the recipient is not a contact on this device
+    pub body: Vec<u8>,
+    pub sent_at: u64,
+    pub from_device: Option<String>, // name of the device it was synced from
+}
+
 #[allow(dead_code)]
 pub struct MessageQueue {
-    db: sled::Db,
-    messages: Tree,
-    by_due_p0: Tree, // high/urgent
-    by_due_p1: Tree, // normal/bulk (default)
-    inbox: Tree,
-    dead_letter: Tree,
+    backend: Arc<dyn Backend>,
+    messages: Arc<dyn KvTree>,
+    by_due_p0: Arc<dyn KvTree>, // high/urgent
+    by_due_p1: Arc<dyn KvTree>, // normal/bulk (default)
+    inbox: Arc<dyn KvTree>,
+    dead_letter: Arc<dyn KvTree>,
+    quarantine: Arc<dyn KvTree>,
+    sent: Arc<dyn KvTree>,
+    key: super::at_rest::AtRestKey,
 }
 
+const MESSAGES: &str = "messages";
+const BY_DUE_P0: &str = "index_by_due_p0";
+const BY_DUE_P1: &str = "index_by_due_p1";
+
 #[allow(dead_code)]
 impl MessageQueue {
-    pub fn new(path: &str) -> Result<Self, super::Error> {
-        let db = sled::open(path)?;
-        let messages = db.open_tree("messages")?;
-        let by_due_p0 = db.open_tree("index_by_due_p0")?;
-        let by_due_p1 = db.open_tree("index_by_due_p1")?;
-        let inbox = db.open_tree("inbox")?;
-        let dead_letter = db.open_tree("dead_letter")?;
+    /// Open the queue of `data_dir`, sealing under that dir's at-rest key.
+    pub fn open_in_dir(data_dir: &std::path::Path) -> Result<Self, super::Error> {
+        std::fs::create_dir_all(data_dir)
+            .map_err(|e| super::Error::Serialization(e.to_string()))?;
+        let key = super::at_rest::AtRestKey::load_or_create(data_dir)?;
+        Self::open(data_dir.join("queue_db"), key)
+    }
+
+    /// Open the queue at `path` with an already loaded at-rest key, in whichever backend
+    /// it is stored (sled unless migrated). Records in an older schema are upgraded, after
+    /// a snapshot of the database is saved to `schema_backups/` beside it.
+    pub fn open(
+        path: impl AsRef<std::path::Path>,
+        key: super::at_rest::AtRestKey,
+    ) -> Result<Self, super::Error> {
+        let path = path.as_ref();
+        let backend = backend::open_path(path)?;
+        let backups = path
+            .parent()
+            .unwrap_or(std::path::Path::new("."))
+            .join(schema::BACKUP_DIR);
+        schema::upgrade(backend.as_ref(), &key, &schema::QUEUE, Some(&backups))?;
+        Self::with_backend(backend, key)
+    }
+
+    /// Keep the queue in `backend` (e.g. a `MemoryBackend`) instead of a sled database.
+    pub fn with_backend(
+        backend: Arc<dyn Backend>,
+        key: super::at_rest::AtRestKey,
+    ) -> Result<Self, super::Error> {
+        schema::upgrade(backend.as_ref(), &key, &schema::QUEUE, None)?;
+        let messages = backend.open_tree(MESSAGES)?;
+        let by_due_p0 = backend.open_tree(BY_DUE_P0)?;
+        let by_due_p1 = backend.open_tree(BY_DUE_P1)?;
+        let inbox = backend.open_tree("inbox")?;
+        let dead_letter = backend.open_tree("dead_letter")?;
+        let quarantine = backend.open_tree("quarantine")?;
+        let sent = backend.open_tree("sent")?;
         Ok(Self {
-            db,
+            backend,
             messages,
             by_due_p0,
             by_due_p1,
             inbox,
             dead_letter,
+            quarantine,
+            sent,
+            key,
         })
     }
 
+    /// Replay-protection store sharing this queue's database.
+    pub fn nonce_store(&self) -> Result<super::nonce_store::NonceStore, super::Error> {
+        super::nonce_store::NonceStore::open(self.backend.clone())
+    }
+
     pub fn enqueue(&self, mut message: QueuedMessage) -> Result<(), super::Error> {
         if message.created == 0 {
             message.created = SystemTime::now()
@@ -75,29 +177,31 @@ impl MessageQueue {
         }
         let id_bytes = message.id.as_bytes();
         // encrypt-at-rest
-        let cfg = crate::config::load();
-        let key = super::at_rest::AtRestKey::load_or_create(&cfg.data_dir)?;
         let message_bytes =
             bincode::serialize(&message).map_err(|e| super::Error::Serialization(e.to_string()))?;
-        let sealed = super::at_rest::encrypt(&key, &message_bytes)?;
-        self.messages.insert(id_bytes, sealed)?;
+        let sealed = super::at_rest::encrypt(&self.key, &message_bytes)?;
         // Index by next_attempt_at per priority lane
         let mut key = Vec::with_capacity(8 + id_bytes.len());
         key.extend_from_slice(&message.next_attempt_at.to_be_bytes());
         key.extend_from_slice(id_bytes);
-        match message.priority {
-            0 => {
-                self.by_due_p0.insert(key, id_bytes)?;
-            }
-            _ => {
-                self.by_due_p1.insert(key, id_bytes)?;
-            }
-        }
-        Ok(())
+        let lane = match message.priority {
+            0 => BY_DUE_P0,
+            _ => BY_DUE_P1,
+        };
+        // The record and its index entry land together
+        self.backend.transaction(&[MESSAGES, lane], &mut |tx| {
+            tx.insert(0, id_bytes, &sealed)?;
+            tx.insert(1, &key, id_bytes)?;
+            Ok(())
+        })
     }
 
-    fn dequeue_from_tree(&self, tree: &Tree) -> Result<Option<QueuedMessage>, super::Error> {
-        if let Some(Ok((k, v))) = tree.iter().next() {
+    fn dequeue_from_tree(
+        &self,
+        tree: &dyn KvTree,
+        lane: &str,
+    ) -> Result<Option<QueuedMessage>, super::Error> {
+        if let Some((k, v)) = tree.first()? {
             // Only dequeue if the message is due (next_attempt_at <= now)
             if k.len() >= 8 {
                 let mut ts_bytes = [0u8; 8];
@@ -111,18 +215,26 @@ impl MessageQueue {
                     return Ok(None);
                 }
             }
-            let id_bytes = v.as_ref();
-            if let Some(bytes) = self.messages.get(id_bytes)? {
-                let cfg = crate::config::load();
-                let key = super::at_rest::AtRestKey::load_or_create(&cfg.data_dir)?;
-                let plain = super::at_rest::decrypt(&key, &bytes)
-                    .map_err(|e| super::Error::Serialization(e.to_string()))?;
-                let msg: QueuedMessage = bincode::deserialize(&plain)
-                    .map_err(|e| super::Error::Serialization(e.to_string()))?;
-                self.messages.remove(id_bytes)?;
-                tree.remove(k)?;
-                return Ok(Some(msg));
-            }
+            // Take the record and its index entry together, so two consumers racing for
+            // the same entry cannot both get the message
+            let mut taken = None;
+            self.backend.transaction(&[MESSAGES, lane], &mut |tx| {
+                taken = None;
+                if tx.get(1, &k)?.is_none() {
+                    return Ok(());
+                }
+                if let Some(bytes) = tx.get(0, &v)? {
+                    let plain = super::at_rest::decrypt(&self.key, &bytes)
+                        .map_err(|e| super::Error::Serialization(e.to_string()))?;
+                    let msg: QueuedMessage = bincode::deserialize(&plain)
+                        .map_err(|e| super::Error::Serialization(e.to_string()))?;
+                    tx.remove(0, &v)?;
+                    tx.remove(1, &k)?;
+                    taken = Some(msg);
+                }
+                Ok(())
+            })?;
+            return Ok(taken);
         }
         Ok(None)
     }
@@ -132,17 +244,17 @@ impl MessageQueue {
         priority: u8,
     ) -> Result<Option<QueuedMessage>, super::Error> {
         match priority {
-            0 => self.dequeue_from_tree(&self.by_due_p0),
-            _ => self.dequeue_from_tree(&self.by_due_p1),
+            0 => self.dequeue_from_tree(self.by_due_p0.as_ref(), BY_DUE_P0),
+            _ => self.dequeue_from_tree(self.by_due_p1.as_ref(), BY_DUE_P1),
         }
     }
 
     // Default dequeue: prefer high lane, then normal
     pub fn dequeue(&self) -> Result<Option<QueuedMessage>, super::Error> {
-        if let Some(msg) = self.dequeue_from_tree(&self.by_due_p0)? {
+        if let Some(msg) = self.dequeue_from_priority(0)? {
             return Ok(Some(msg));
         }
-        self.dequeue_from_tree(&self.by_due_p1)
+        self.dequeue_from_priority(1)
     }
 
     pub fn update_status(
@@ -152,28 +264,24 @@ impl MessageQueue {
     ) -> Result<(), super::Error> {
         let id_bytes = message_id.as_bytes();
         if let Some(cipher) = self.messages.get(id_bytes)? {
-            let cfg = crate::config::load();
-            let key = super::at_rest::AtRestKey::load_or_create(&cfg.data_dir)?;
-            let message_bytes = super::at_rest::decrypt(&key, &cipher)
+            let message_bytes = super::at_rest::decrypt(&self.key, &cipher)
                 .map_err(|e| super::Error::Serialization(e.to_string()))?;
             let mut message: QueuedMessage = bincode::deserialize(&message_bytes)
                 .map_err(|e| super::Error::Serialization(e.to_string()))?;
             message.status = status;
             let serialized = bincode::serialize(&message)
                 .map_err(|e| super::Error::Serialization(e.to_string()))?;
-            let sealed = super::at_rest::encrypt(&key, &serialized)?;
-            self.messages.insert(id_bytes, sealed)?;
+            let sealed = super::at_rest::encrypt(&self.key, &serialized)?;
+            self.messages.insert(id_bytes, &sealed)?;
         }
         Ok(())
     }
 
     pub fn get_pending_messages(&self) -> Result<Vec<QueuedMessage>, super::Error> {
-        let cfg = crate::config::load();
-        let key = super::at_rest::AtRestKey::load_or_create(&cfg.data_dir)?;
         let mut out = Vec::new();
         for item in self.messages.iter() {
             let (_k, v) = item?;
-            let pt = super::at_rest::decrypt(&key, &v)
+            let pt = super::at_rest::decrypt(&self.key, &v)
                 .map_err(|e| super::Error::Serialization(e.to_string()))?;
             let msg: QueuedMessage = bincode::deserialize(&pt)
                 .map_err(|e| super::Error::Serialization(e.to_string()))?;
@@ -193,18 +301,14 @@ impl MessageQueue {
     }
 
     pub fn store_inbox(&self, message_id: Uuid, plaintext: Vec<u8>) -> Result<(), super::Error> {
-        let cfg = crate::config::load();
-        let key = super::at_rest::AtRestKey::load_or_create(&cfg.data_dir)?;
-        let sealed = super::at_rest::encrypt(&key, &plaintext)?;
-        self.inbox.insert(message_id.as_bytes(), sealed)?;
+        let sealed = super::at_rest::encrypt(&self.key, &plaintext)?;
+        self.inbox.insert(message_id.as_bytes(), &sealed)?;
         Ok(())
     }
 
     pub fn get_inbox(&self, message_id: Uuid) -> Result<Option<Vec<u8>>, super::Error> {
         if let Some(v) = self.inbox.get(message_id.as_bytes())? {
-            let cfg = crate::config::load();
-            let key = super::at_rest::AtRestKey::load_or_create(&cfg.data_dir)?;
-            let pt = super::at_rest::decrypt(&key, &v)
+            let pt = super::at_rest::decrypt(&self.key, &v)
                 .map_err(|e| super::Error::Serialization(e.to_string()))?;
             Ok(Some(pt))
         } else {
@@ -217,15 +321,13 @@ impl MessageQueue {
     }
 
     pub fn list_inbox(&self) -> Result<Vec<(Uuid, Vec<u8>)>, super::Error> {
-        let cfg = crate::config::load();
-        let key = super::at_rest::AtRestKey::load_or_create(&cfg.data_dir)?;
         let mut out = Vec::new();
         for item in self.inbox.iter() {
             let (k, v) = item?;
             let mut id_bytes = [0u8; 16];
             id_bytes.copy_from_slice(&k);
             let id = Uuid::from_bytes(id_bytes);
-            let pt = super::at_rest::decrypt(&key, &v)
+            let pt = super::at_rest::decrypt(&self.key, &v)
                 .map_err(|e| super::Error::Serialization(e.to_string()))?;
             out.push((id, pt));
         }
@@ -265,12 +367,10 @@ impl MessageQueue {
             attempts: message.retry_count,
             last_error: reason.to_string(),
         };
-        let cfg = crate::config::load();
-        let key = super::at_rest::AtRestKey::load_or_create(&cfg.data_dir)?;
         let bytes =
             bincode::serialize(&record).map_err(|e| super::Error::Serialization(e.to_string()))?;
-        let sealed = super::at_rest::encrypt(&key, &bytes)?;
-        self.dead_letter.insert(record.id.as_bytes(), sealed)?;
+        let sealed = super::at_rest::encrypt(&self.key, &bytes)?;
+        self.dead_letter.insert(record.id.as_bytes(), &sealed)?;
         Ok(())
     }
 
@@ -298,13 +398,72 @@ impl MessageQueue {
             .iter()
             .filter_map(|item| item.ok())
             .map(|(_, v)| {
-                let cfg = crate::config::load();
-                let key = super::at_rest::AtRestKey::load_or_create(&cfg.data_dir)?;
-                let pt = super::at_rest::decrypt(&key, &v)
+                let pt = super::at_rest::decrypt(&self.key, &v)
                     .map_err(|e| super::Error::Serialization(e.to_string()))?;
                 bincode::deserialize::<DeadLetterRecord>(&pt)
                     .map_err(|e| super::Error::Serialization(e.to_string()))
             })
             .collect()
     }
+
+    pub fn store_quarantine(&self, record: &QuarantinedMessage) -> Result<(), super::Error> {
+        let bytes =
+            bincode::serialize(record).map_err(|e| super::Error::Serialization(e.to_string()))?;
+        let sealed = super::at_rest::encrypt(&self.key, &bytes)?;
+        self.quarantine.insert(record.id.as_bytes(), &sealed)?;
+        Ok(())
+    }
+
+    pub fn quarantine_len(&self) -> usize {
+        self.quarantine.len()
+    }
+
+    pub fn list_quarantine(&self) -> Result<Vec<QuarantinedMessage>, super::Error> {
+        let mut out = Vec::new();
+        for item in self.quarantine.iter() {
+            let (_k, v) = item?;
+            let pt = super::at_rest::decrypt(&self.key, &v)
+                .map_err(|e| super::Error::Serialization(e.to_string()))?;
+            let record: QuarantinedMessage = bincode::deserialize(&pt)
+                .map_err(|e| super::Error::Serialization(e.to_string()))?;
+            out.push(record);
+        }
+        out.sort_by_key(|r| r.received_at);
+        Ok(out)
+    }
+
+    /// Remove and return a quarantined envelope.
+    pub fn take_quarantine(&self, id: Uuid) -> Result<Option<QuarantinedMessage>, super::Error> {
+        let Some(v) = self.quarantine.remove(id.as_bytes())? else {
+            return Ok(None);
+        };
+        let pt = super::at_rest::decrypt(&self.key, &v)
+            .map_err(|e| super::Error::Serialization(e.to_string()))?;
+        let record = bincode::deserialize(&pt)
+            .map_err(|e| super::Error::Serialization(e.to_string()))?;
+        Ok(Some(record))
+    }
+
+    pub fn store_sent(&self, record: &SentMessage) -> Result<(), super::Error> {
+        let bytes =
+            bincode::serialize(record).map_err(|e| super::Error::Serialization(e.to_string()))?;
+        let sealed = super::at_rest::encrypt(&self.key, &bytes)?;
+        self.sent.insert(record.id.as_bytes(), &sealed)?;
+        Ok(())
+    }
+
+    /// Sent messages, oldest first.
+    pub fn list_sent(&self) -> Result<Vec<SentMessage>, super::Error> {
+        let mut out = Vec::new();
+        for item in self.sent.iter() {
+            let (_k, v) = item?;
+            let pt = super::at_rest::decrypt(&self.key, &v)
+                .map_err(|e| super::Error::Serialization(e.to_string()))?;
+            let record: SentMessage = bincode::deserialize(&pt)
+                .map_err(|e| super::Error::Serialization(e.to_string()))?;
+            out.push(record);
+        }
+        out.sort_by_key(|r| r.sent_at);
+        Ok(out)
+    }
 }
@@ -0,0 +1,85 @@
+//! Re-sealing every stored record under the current at-rest key, for key rotation.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use super::at_rest::{self, AtRestKey};
+use super::backend::{self, Backend};
+
+/// Every database whose values are sealed with the at-rest key; queue_db holds the queue,
+/// inbox, sent messages, dead letters, quarantine and seen nonces.
+pub const DATABASES: &[&str] = &[
+    "contacts_db",
+    "queue_db",
+    "sessions_db",
+    "prekeys_db",
+    "devices_db",
+    "groups_db",
+];
+
+// Report progress every this many records, and at the end of each database
+const PROGRESS_EVERY: usize = 100;
+
+/// How far the re-sealing of one database has got.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RekeyProgress {
+    pub database: &'static str,
+    pub done: usize,
+    pub total: usize,
+}
+
+/// A database of `DATABASES` by name, opened.
+pub(crate) type OpenDatabase = (&'static str, Arc<dyn Backend>);
+
+/// Open every database that exists in `data_dir`, in whichever backend it is stored
+/// (opening a sled database also locks it).
+pub(crate) fn open_databases(
+    data_dir: &Path,
+) -> Result<Vec<OpenDatabase>, super::Error> {
+    let mut out = Vec::new();
+    for name in DATABASES {
+        let path = data_dir.join(name);
+        if let Some(kind) = backend::detect(&path) {
+            out.push((*name, backend::open_kind(&path, kind)?));
+        }
+    }
+    Ok(out)
+}
+
+/// Re-seal every value whose header names a retired key (or that has no header) under
+/// the current key of `keys`. Values already under the current key, and values no key
+/// opens (plain index entries), are left as they are. Each database is flushed before
+/// moving on, so a crash loses at most unflushed work that the next attempt redoes.
+pub(crate) fn reseal_databases(
+    dbs: &[OpenDatabase],
+    keys: &AtRestKey,
+    progress: &mut dyn FnMut(&RekeyProgress),
+) -> Result<usize, super::Error> {
+    let mut resealed = 0;
+    for (name, db) in dbs {
+        let mut trees = Vec::new();
+        for tree_name in db.tree_names()? {
+            trees.push(db.open_tree(&tree_name)?);
+        }
+        let total = trees.iter().map(|t| t.len()).sum();
+        let mut done = 0;
+        for tree in &trees {
+            for item in tree.iter() {
+                let (k, v) = item?;
+                if at_rest::record_key_id(&v).ok().flatten() != Some(keys.key_id()) {
+                    if let Ok(plain) = at_rest::decrypt(keys, &v) {
+                        tree.insert(&k, &at_rest::encrypt(keys, &plain)?)?;
+                        resealed += 1;
+                    }
+                }
+                done += 1;
+                if done % PROGRESS_EVERY == 0 {
+                    progress(&RekeyProgress { database: name, done, total });
+                }
+            }
+        }
+        db.flush()?;
+        progress(&RekeyProgress { database: name, done, total });
+    }
+    Ok(resealed)
+}
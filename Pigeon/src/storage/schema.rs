@@ -0,0 +1,267 @@
+//! Schema versions of the stored records. Each database keeps the version of its record
+//! layouts in the `__schema` tree; opening a store upgrades older records one version at a
+//! time, after writing a snapshot of the database to `schema_backups/` in the data dir.
+//! Changing a stored struct (a new field on `Contact`, say) means bumping the version in
+//! its `Schema` and adding a `Migration` that rewrites records from the previous layout.
+
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::at_rest::{self, AtRestKey};
+use super::backend::{Backend, DEFAULT_TREE};
+
+/// Tree holding a database's schema version.
+pub const SCHEMA_TREE: &str = "__schema";
+
+const VERSION_KEY: &[u8] = b"version";
+
+/// Where the snapshots taken before an upgrade go, inside the data dir.
+pub const BACKUP_DIR: &str = "schema_backups";
+
+/// Rewrites the plaintext of one record from the previous version's layout.
+pub type Upgrade = fn(&[u8]) -> Result<Vec<u8>, super::Error>;
+
+/// One step of a database's history: how the records of `tree` change to reach `to`.
+pub struct Migration {
+    pub to: u32,
+    pub tree: &'static str,
+    pub description: &'static str,
+    pub upgrade: Upgrade,
+}
+
+/// The record layouts a database is read with, and how older ones get there.
+pub struct Schema {
+    pub database: &'static str,
+    pub version: u32,
+    /// In order of `to`; several may share a version when it changes several trees.
+    pub migrations: &'static [Migration],
+}
+
+/// contacts_db: `Contact` records, contact policies and `BlockEntry` records. Version 1 is
+/// the layout from before versioning.
+pub const CONTACTS: Schema = Schema {
+    database: "contacts_db",
+    version: 2,
+    migrations: &[Migration {
+        to: 2,
+        tree: DEFAULT_TREE,
+        description: "add PeerId, signing key, verification, key history and devices",
+        upgrade: super::contacts::upgrade_contact_v1,
+    }],
+};
+
+/// queue_db: `QueuedMessage`, inbox, `SentMessage`, `DeadLetterRecord`,
+/// `QuarantinedMessage` and seen nonce records. Version 1 is the layout from before
+/// versioning.
+pub const QUEUE: Schema = Schema {
+    database: "queue_db",
+    version: 2,
+    migrations: &[Migration {
+        to: 2,
+        tree: "messages",
+        description: "add the device a queued message is for",
+        upgrade: super::queue::upgrade_queued_v1,
+    }],
+};
+
+/// sessions_db: `SessionRecord`s per contact.
+pub const SESSIONS: Schema = Schema {
+    database: "sessions_db",
+    version: 1,
+    migrations: &[],
+};
+
+/// prekeys_db: our `LocalPreKeys`, consumed one-time prekey ids and fetched bundles.
+pub const PREKEYS: Schema = Schema {
+    database: "prekeys_db",
+    version: 1,
+    migrations: &[],
+};
+
+/// devices_db: the `LinkedDevices` of this account.
+pub const DEVICES: Schema = Schema {
+    database: "devices_db",
+    version: 1,
+    migrations: &[],
+};
+
+/// groups_db: `Group`s, their threads and held items.
+pub const GROUPS: Schema = Schema {
+    database: "groups_db",
+    version: 1,
+    migrations: &[],
+};
+
+/// Every versioned database.
+pub const SCHEMAS: &[&Schema] = &[&CONTACTS, &QUEUE, &SESSIONS, &PREKEYS, &DEVICES, &GROUPS];
+
+/// The version recorded in `db`; `None` for a database written before versioning, or a
+/// new one.
+pub fn stored_version(db: &dyn Backend) -> Result<Option<u32>, super::Error> {
+    let tree = db.open_tree(SCHEMA_TREE)?;
+    match tree.get(VERSION_KEY)? {
+        None => Ok(None),
+        Some(v) => {
+            let bytes: [u8; 4] = v.as_slice().try_into().map_err(|_| {
+                super::Error::Serialization("schema version is not 4 bytes".into())
+            })?;
+            Ok(Some(u32::from_be_bytes(bytes)))
+        }
+    }
+}
+
+/// The version `db` holds records in: a database without a recorded version is new when
+/// it holds no records, and otherwise holds the layouts from before versioning (1).
+pub fn effective_version(db: &dyn Backend, schema: &Schema) -> Result<u32, super::Error> {
+    if let Some(v) = stored_version(db)? {
+        return Ok(v);
+    }
+    for name in db.tree_names()? {
+        if name != SCHEMA_TREE && !db.open_tree(&name)?.is_empty() {
+            return Ok(1);
+        }
+    }
+    Ok(schema.version)
+}
+
+/// Bring the records of `db` up to `schema.version`, first writing a snapshot of it to
+/// `backup_dir` when there is anything to upgrade. Refuses a database written by a newer
+/// build. Returns the version the database had.
+pub fn upgrade(
+    db: &dyn Backend,
+    key: &AtRestKey,
+    schema: &Schema,
+    backup_dir: Option<&Path>,
+) -> Result<u32, super::Error> {
+    let found = effective_version(db, schema)?;
+    if found > schema.version {
+        return Err(super::Error::Validation(format!(
+            "{} has schema version {found}, newer than the {} this build reads",
+            schema.database, schema.version
+        )));
+    }
+    if found < schema.version {
+        if let Some(dir) = backup_dir {
+            backup(db, schema.database, found, dir)?;
+        }
+        let mut version = found;
+        while version < schema.version {
+            version += 1;
+            let steps: Vec<&Migration> =
+                schema.migrations.iter().filter(|m| m.to == version).collect();
+            apply(db, key, &steps, version)?;
+        }
+        db.flush()?;
+    } else if stored_version(db)?.is_none() {
+        set_version(db, schema.version)?;
+    }
+    Ok(found)
+}
+
+fn set_version(db: &dyn Backend, version: u32) -> Result<(), super::Error> {
+    db.open_tree(SCHEMA_TREE)?
+        .insert(VERSION_KEY, &version.to_be_bytes())?;
+    Ok(())
+}
+
+/// Rewrite every record the `steps` of one version touch and record `version`, all in one
+/// transaction so an interrupted upgrade is redone from the start rather than applied twice.
+fn apply(
+    db: &dyn Backend,
+    key: &AtRestKey,
+    steps: &[&Migration],
+    version: u32,
+) -> Result<(), super::Error> {
+    let mut trees: Vec<&str> = vec![SCHEMA_TREE];
+    let mut work = Vec::new();
+    for step in steps {
+        let index = match trees.iter().position(|t| *t == step.tree) {
+            Some(i) => i,
+            None => {
+                trees.push(step.tree);
+                trees.len() - 1
+            }
+        };
+        let keys = db
+            .open_tree(step.tree)?
+            .iter()
+            .map(|item| item.map(|(k, _)| k))
+            .collect::<Result<Vec<_>, _>>()?;
+        work.push((index, step.upgrade, keys));
+    }
+    db.transaction(&trees, &mut |tx| {
+        for (tree, upgrade, keys) in &work {
+            for k in keys {
+                let Some(sealed) = tx.get(*tree, k)? else {
+                    continue;
+                };
+                let plain = at_rest::decrypt(key, &sealed)?;
+                tx.insert(*tree, k, &at_rest::encrypt(key, &upgrade(&plain)?)?)?;
+            }
+        }
+        tx.insert(0, VERSION_KEY, &version.to_be_bytes())?;
+        Ok(())
+    })
+}
+
+/// Write a snapshot of `db` (records still sealed) before upgrading it from `version`.
+fn backup(db: &dyn Backend, name: &str, version: u32, dir: &Path) -> Result<PathBuf, super::Error> {
+    let snap = super::snapshot::snapshot_backend(db)?;
+    let bytes =
+        bincode::serialize(&snap).map_err(|e| super::Error::Serialization(e.to_string()))?;
+    std::fs::create_dir_all(dir).map_err(|e| super::Error::Serialization(e.to_string()))?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let path = dir.join(format!("{name}-v{version}-{now}.snapshot"));
+    at_rest::write_private(&path, &bytes)?;
+    Ok(path)
+}
+
+/// Schema state of one database in a data dir.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DatabaseStatus {
+    pub database: &'static str,
+    /// `None` when the database does not exist yet.
+    pub backend: Option<super::backend::BackendKind>,
+    /// The version its records are in (`None` when it does not exist yet).
+    pub version: Option<u32>,
+    /// The version this build reads and writes.
+    pub supported: u32,
+    pub records: usize,
+}
+
+/// Report the schema version of every versioned database in `data_dir`, without upgrading
+/// anything. A sled database that is open elsewhere cannot be inspected.
+pub fn status(data_dir: &Path) -> Result<Vec<DatabaseStatus>, super::Error> {
+    let mut out = Vec::new();
+    for schema in SCHEMAS {
+        let path = data_dir.join(schema.database);
+        let Some(kind) = super::backend::detect(&path) else {
+            out.push(DatabaseStatus {
+                database: schema.database,
+                backend: None,
+                version: None,
+                supported: schema.version,
+                records: 0,
+            });
+            continue;
+        };
+        let db = super::backend::open_kind(&path, kind)?;
+        let mut records = 0;
+        for name in db.tree_names()? {
+            if name != SCHEMA_TREE {
+                records += db.open_tree(&name)?.len();
+            }
+        }
+        out.push(DatabaseStatus {
+            database: schema.database,
+            backend: Some(kind),
+            version: Some(effective_version(db.as_ref(), schema)?),
+            supported: schema.version,
+            records,
+        });
+    }
+    Ok(out)
+}
@@ -0,0 +1,74 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use super::backend::{self, Backend, KvTree, DEFAULT_TREE};
+use super::schema;
+use crate::session::SessionRecord;
+
+/// Ratchet sessions per contact id, encrypted at rest like the other stores.
+#[allow(dead_code)]
+pub struct SessionStore {
+    backend: Arc<dyn Backend>,
+    sessions: Arc<dyn KvTree>,
+    key: super::at_rest::AtRestKey,
+}
+
+#[allow(dead_code)]
+impl SessionStore {
+    /// Open the store of `data_dir`, in whichever backend it is stored (sled unless
+    /// migrated), upgrading records in an older schema.
+    pub fn open_in_dir(data_dir: &Path) -> Result<Self, super::Error> {
+        std::fs::create_dir_all(data_dir)
+            .map_err(|e| super::Error::Serialization(e.to_string()))?;
+        let key = super::at_rest::AtRestKey::load_or_create(data_dir)?;
+        let backend = backend::open_path(&data_dir.join("sessions_db"))?;
+        let backups = data_dir.join(schema::BACKUP_DIR);
+        schema::upgrade(backend.as_ref(), &key, &schema::SESSIONS, Some(&backups))?;
+        Self::with_backend(backend, key)
+    }
+
+    /// Keep sessions in `backend` (e.g. a `MemoryBackend`) instead of the data dir.
+    pub fn with_backend(
+        backend: Arc<dyn Backend>,
+        key: super::at_rest::AtRestKey,
+    ) -> Result<Self, super::Error> {
+        schema::upgrade(backend.as_ref(), &key, &schema::SESSIONS, None)?;
+        let sessions = backend.open_tree(DEFAULT_TREE)?;
+        Ok(Self {
+            backend,
+            sessions,
+            key,
+        })
+    }
+
+    pub fn load(&self, contact_id: u64) -> Result<Option<SessionRecord>, super::Error> {
+        let Some(bytes) = self.sessions.get(&contact_id.to_be_bytes())? else {
+            return Ok(None);
+        };
+        let plain = super::at_rest::decrypt(&self.key, &bytes)
+            .map_err(|e| super::Error::Serialization(e.to_string()))?;
+        let record = bincode::deserialize::<SessionRecord>(&plain)
+            .map_err(|e| super::Error::Serialization(e.to_string()))?;
+        Ok(Some(record))
+    }
+
+    pub fn save(&self, contact_id: u64, record: &SessionRecord) -> Result<(), super::Error> {
+        let serialized =
+            bincode::serialize(record).map_err(|e| super::Error::Serialization(e.to_string()))?;
+        let sealed = super::at_rest::encrypt(&self.key, &serialized)?;
+        self.sessions.insert(&contact_id.to_be_bytes(), &sealed)?;
+        // Ratchet state must not roll back after a crash, or message keys would be reused
+        self.backend.flush()?;
+        Ok(())
+    }
+
+    pub fn clear(&self) -> Result<(), super::Error> {
+        self.sessions.clear()?;
+        self.backend.flush()?;
+        Ok(())
+    }
+
+    pub fn remove(&self, contact_id: u64) -> Result<bool, super::Error> {
+        Ok(self.sessions.remove(&contact_id.to_be_bytes())?.is_some())
+    }
+}
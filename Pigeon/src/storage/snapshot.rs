@@ -0,0 +1,86 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::backend::{self, Backend, BackendKind};
+
+/// Every tree of one database, values exactly as stored (still sealed at rest).
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DbSnapshot {
+    pub trees: Vec<TreeSnapshot>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TreeSnapshot {
+    pub name: Vec<u8>,
+    pub entries: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl DbSnapshot {
+    /// Stored records, not counting the schema version.
+    pub fn record_count(&self) -> usize {
+        self.trees
+            .iter()
+            .filter(|t| t.name != super::schema::SCHEMA_TREE.as_bytes())
+            .map(|t| t.entries.len())
+            .sum()
+    }
+}
+
+/// Dump the database at `path`, in whichever backend it is stored. Opening a sled
+/// database takes its exclusive file lock, so no other writer can change it while the
+/// trees are read; a database in use elsewhere fails to open instead of producing a torn
+/// copy. SQLite does not lock the file for one process, so snapshot it while nothing else
+/// is running.
+pub fn snapshot(path: &Path) -> Result<DbSnapshot, super::Error> {
+    let Some(kind) = backend::detect(path) else {
+        return Ok(DbSnapshot::default());
+    };
+    let db = backend::open_kind(path, kind)?;
+    snapshot_backend(db.as_ref())
+}
+
+/// Dump every tree of an open database.
+pub fn snapshot_backend(db: &dyn Backend) -> Result<DbSnapshot, super::Error> {
+    db.flush()?;
+    let mut trees = Vec::new();
+    for name in db.tree_names()? {
+        let tree = db.open_tree(&name)?;
+        let entries = tree.iter().collect::<Result<Vec<_>, _>>()?;
+        trees.push(TreeSnapshot {
+            name: name.into_bytes(),
+            entries,
+        });
+    }
+    Ok(DbSnapshot { trees })
+}
+
+/// Recreate the database at `path` from a snapshot, stored with `kind`. The path must not
+/// hold a database.
+pub fn restore(path: &Path, snapshot: &DbSnapshot, kind: BackendKind) -> Result<(), super::Error> {
+    if backend::detect(path).is_some() {
+        return Err(super::Error::Validation(format!(
+            "{} already exists",
+            path.display()
+        )));
+    }
+    restore_at(&backend::location(path, kind), snapshot, kind)
+}
+
+/// Write a snapshot into a new database of `kind` at exactly `location`, for callers that
+/// stage it beside the one it replaces.
+pub(crate) fn restore_at(
+    location: &Path,
+    snapshot: &DbSnapshot,
+    kind: BackendKind,
+) -> Result<(), super::Error> {
+    let db = backend::open_at(location, kind)?;
+    for t in &snapshot.trees {
+        let tree = db.open_tree(&String::from_utf8_lossy(&t.name))?;
+        for (k, v) in &t.entries {
+            tree.insert(k, v)?;
+        }
+    }
+    db.flush()?;
+    Ok(())
+}
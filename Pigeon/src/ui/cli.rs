@@ -20,13 +20,36 @@ pub struct Cli {
 
 #[derive(Subcommand)]
 enum Commands {
-    /// Show local identity information
-    Identity,
+    /// Show local identity information, or export/import it
+    Identity {
+        #[command(subcommand)]
+        action: Option<IdentityAction>,
+    },
     /// Contacts management
     Contacts {
         #[command(subcommand)]
         action: ContactsAction,
     },
+    /// Encrypted backup and restore of the whole data dir
+    Backup {
+        #[command(subcommand)]
+        action: BackupAction,
+    },
+    /// Link other devices to this identity (desktop + laptop)
+    Devices {
+        #[command(subcommand)]
+        action: DevicesAction,
+    },
+    /// Group conversations encrypted with per-member sender keys
+    Groups {
+        #[command(subcommand)]
+        action: GroupsAction,
+    },
+    /// Signed and one-time prekeys for offline session setup
+    Prekeys {
+        #[command(subcommand)]
+        action: PrekeysAction,
+    },
     /// Security operations
     Security {
         #[command(subcommand)]
@@ -37,6 +60,11 @@ enum Commands {
         #[command(subcommand)]
         action: OpsAction,
     },
+    /// Storage backend maintenance
+    Storage {
+        #[command(subcommand)]
+        action: StorageAction,
+    },
 
     /// Compose and queue a message
     Compose {
@@ -118,6 +146,15 @@ enum Commands {
         /// Enable mDNS LAN discovery
         #[arg(long)]
         mdns: bool,
+        /// What to do with messages from peers that are not contacts (accept, quarantine, refuse)
+        #[arg(long)]
+        unknown_peers: Option<crate::config::UnknownPeerPolicy>,
+        /// Refuse everything that is not from a contact, whatever the unknown-peer policy says
+        #[arg(long)]
+        contacts_only: bool,
+        /// Serve this listener's /metrics on an HTTP socket (e.g. 127.0.0.1:9100)
+        #[arg(long)]
+        metrics_addr: Option<String>,
     },
 }
 
@@ -139,6 +176,9 @@ enum ContactsAction {
         /// Sodium box public key hex (64 hex chars)
         #[arg(long = "pubkey_hex")]
         pubkey_hex: String,
+        /// libp2p PeerId the contact must present (defaults to the addr's /p2p/ suffix)
+        #[arg(long)]
+        peer_id: Option<String>,
     },
     /// List contacts
     List,
@@ -146,16 +186,192 @@ enum ContactsAction {
     Show { sel: String },
     /// Remove a contact by id
     Remove { id: u64 },
+    /// Bind a contact to the libp2p PeerId its connections must present
+    SetPeer { id: u64, peer_id: String },
+    /// Mark a contact verified after comparing safety numbers out of band
+    Verify { id: u64 },
+    /// Clear a contact's verified flag
+    Unverify { id: u64 },
+    /// List contacts whose keys changed since they were pinned
+    KeyChanges,
+    /// Accept a contact's new keys (old keys go to its key history)
+    AcceptKey { id: u64 },
+    /// Reject a contact's new keys and keep the pinned ones
+    RejectKey { id: u64 },
+    /// Allow, mute (acknowledge but drop) or block a contact's messages
+    Policy {
+        id: u64,
+        policy: crate::storage::contacts::ContactPolicy,
+    },
+    /// Refuse messages from a PeerId or a public key, contact or not
+    Block {
+        #[arg(long, conflicts_with = "key", required_unless_present = "key")]
+        peer: Option<String>,
+        /// Box or signing public key hex (64 hex chars)
+        #[arg(long)]
+        key: Option<String>,
+    },
+    /// Take a PeerId or public key off the blocklist
+    Unblock {
+        #[arg(long, conflicts_with = "key", required_unless_present = "key")]
+        peer: Option<String>,
+        /// Box or signing public key hex (64 hex chars)
+        #[arg(long)]
+        key: Option<String>,
+    },
+    /// List blocked PeerIds and keys
+    Blocklist,
+    /// Print your signed contact card (pigeon:// URI) for others to import
+    Card {
+        /// Display name to put on the card (saved to the profile)
+        #[arg(long)]
+        name: Option<String>,
+        /// Dialable multiaddr to advertise; repeatable (saved to the profile)
+        #[arg(long = "addr")]
+        addrs: Vec<String>,
+    },
+    /// Add or refresh a contact from a pigeon:// contact card
+    Import { card: String },
+    /// List peers discovered over mDNS that are not contacts yet
+    Nearby,
+    /// Add a nearby peer as a contact using its discovered address
+    AddNearby {
+        peer_id: String,
+        name: String,
+        /// Sodium box public key hex (64 hex chars)
+        #[arg(long = "pubkey_hex")]
+        pubkey_hex: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum IdentityAction {
+    /// Write the identity to a passphrase-encrypted archive; the archive passphrase is
+    /// asked for (or read from PIGEON_PASSPHRASE)
+    Export {
+        #[arg(long, value_name = "PATH")]
+        out: PathBuf,
+        /// Also include all contacts
+        #[arg(long)]
+        with_contacts: bool,
+    },
+    /// Restore an identity from an archive made by `identity export`
+    Import {
+        path: PathBuf,
+        /// Replace the identity already in this data dir
+        #[arg(long)]
+        force: bool,
+    },
+    /// Replace all keys and announce the change to every contact, signed with the old key
+    Rotate,
+}
+
+#[derive(Subcommand)]
+enum BackupAction {
+    /// Write an encrypted snapshot of the data dir; the backup passphrase is asked for (or
+    /// read from PIGEON_PASSPHRASE)
+    Create {
+        #[arg(long, value_name = "PATH")]
+        out: PathBuf,
+    },
+    /// Restore a snapshot made by `backup create` into the data dir
+    Restore {
+        path: PathBuf,
+        /// Replace the identity and data already in this data dir
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum DevicesAction {
+    /// On the new device: print a link request to authorize on the primary device
+    Request {
+        /// Name for this device (defaults to the profile display name)
+        #[arg(long, default_value = "")]
+        name: String,
+    },
+    /// On the primary device: authorize a link request and print the grant for the new device
+    Authorize { request: String },
+    /// On the new device: install the grant printed by `devices authorize`
+    Accept { grant: String },
+    /// List the devices linked to this identity
+    List,
+    /// Revoke a device (on the primary) or leave the account (on a linked device)
+    Unlink { name: String },
+}
+
+#[derive(Subcommand)]
+enum GroupsAction {
+    /// Create a group with these contact ids; you become its admin
+    Create {
+        name: String,
+        #[arg(required = true)]
+        members: Vec<u64>,
+    },
+    /// List groups with their members
+    List,
+    /// Admin: add contacts to a group (by group id or name)
+    Add {
+        group: String,
+        #[arg(required = true)]
+        members: Vec<u64>,
+    },
+    /// Admin: remove contacts from a group; everyone rekeys
+    Remove {
+        group: String,
+        #[arg(required = true)]
+        members: Vec<u64>,
+    },
+    /// Replace your sender key in a group
+    Rekey { group: String },
+    /// Queue a message to every member of a group
+    Send { group: String, message: String },
+    /// Show a group's messages
+    Thread { group: String },
+}
+
+#[derive(Subcommand)]
+enum PrekeysAction {
+    /// Show the signed prekey and one-time prekey counts
+    Status,
+    /// Replace the signed prekey now (it also rotates weekly)
+    Rotate,
+    /// Fetch a contact's prekey bundle over /pigeon/prekeys/1 for the next session
+    #[cfg(feature = "network")]
+    Fetch {
+        /// Contact id or name
+        sel: String,
+        #[arg(long, default_value_t = 5000u64)]
+        timeout_ms: u64,
+    },
 }
 
 #[derive(Subcommand)]
 enum SecurityAction {
     /// Derive and print a KDF key preview from a passphrase (dev aid)
-    PreviewKey { passphrase: String },
-    /// Set/rotate a passphrase and seal the at-rest key
-    SetPassphrase { passphrase: String },
-    /// Unlock an existing sealed at-rest key for this session
-    Unlock { passphrase: String },
+    PreviewKey,
+    /// Set a passphrase and seal the at-rest key
+    SetPassphrase,
+    /// Re-wrap the at-rest key under a new passphrase (stored data is not re-encrypted)
+    ChangePassphrase,
+    /// Remove the passphrase and keep the at-rest key in a plain file
+    RemovePassphrase,
+    /// Unlock the sealed at-rest key; with `use_keyring` (or --keyring) the unlock is kept
+    /// in the OS keyring so later commands need no passphrase
+    Unlock {
+        /// Keep the unlock in the OS keyring even if `use_keyring` is off
+        #[arg(long)]
+        keyring: bool,
+        /// How long the keyring keeps the unlock (default: keyring_timeout_secs)
+        #[arg(long)]
+        timeout_secs: Option<u64>,
+    },
+    /// Lock now: forget the unlocked key here and in the OS keyring
+    Lock,
+    /// Replace the at-rest key and re-seal every stored record under it; run it again to
+    /// finish an interrupted rotation
+    Rotate,
 }
 
 #[derive(Subcommand)]
@@ -168,6 +384,19 @@ enum OpsAction {
     },
 }
 
+#[derive(Subcommand)]
+enum StorageAction {
+    /// Show each database's backend and schema version, and the identity.bin format
+    Status,
+    /// Move the databases to another backend (sled or sqlite)
+    Migrate {
+        #[arg(long)]
+        from: crate::storage::backend::BackendKind,
+        #[arg(long)]
+        to: crate::storage::backend::BackendKind,
+    },
+}
+
 #[derive(Subcommand)]
 enum InboxAction {
     /// List inbox messages (preview lines)
@@ -199,6 +428,64 @@ enum InboxAction {
         #[arg(long)]
         limit: Option<usize>,
     },
+    /// List sent messages, including those sent from your other linked devices
+    Sent,
+    /// List messages held back from peers that are not contacts
+    Quarantine,
+    /// Decrypt a quarantined message into the inbox (sender must now be a contact)
+    Release { id: String },
+    /// Delete a quarantined message
+    Discard { id: String },
+}
+
+/// PIGEON_PASSPHRASE when set (scripts), otherwise ask on the terminal without echo.
+fn read_passphrase(prompt: &str) -> Result<String, crate::error::Error> {
+    if let Ok(pass) = std::env::var("PIGEON_PASSPHRASE") {
+        return Ok(pass);
+    }
+    rpassword::prompt_password(prompt).map_err(crate::error::Error::Io)
+}
+
+/// Like `read_passphrase`, but asks twice and refuses an empty passphrase.
+fn read_new_passphrase() -> Result<String, crate::error::Error> {
+    if let Ok(pass) = std::env::var("PIGEON_PASSPHRASE") {
+        return Ok(pass);
+    }
+    let pass = rpassword::prompt_password("New passphrase: ")?;
+    if pass.is_empty() {
+        return Err(crate::error::Error::Config("passphrase must not be empty".into()));
+    }
+    if rpassword::prompt_password("Repeat passphrase: ")? != pass {
+        return Err(crate::error::Error::Config("passphrases do not match".into()));
+    }
+    Ok(pass)
+}
+
+/// The queue database at `--queue`, or the data dir's own; either way sealed under the
+/// data dir's at-rest key.
+fn open_queue(queue: Option<String>) -> Result<MessageQueue, crate::error::Error> {
+    let data_dir = crate::config::load().data_dir;
+    match queue {
+        Some(path) => crate::storage::at_rest::AtRestKey::load_or_create(&data_dir)
+            .and_then(|key| MessageQueue::open(path, key)),
+        None => MessageQueue::open_in_dir(&data_dir),
+    }
+    .map_err(crate::error::Error::Storage)
+}
+
+/// The blocklist entry named by `--peer` or `--key` (clap requires exactly one).
+fn block_target(
+    peer: Option<String>,
+    key: Option<String>,
+) -> Result<crate::storage::contacts::BlockTarget, crate::error::Error> {
+    use crate::storage::contacts::BlockTarget;
+    match (peer, key) {
+        (Some(p), _) => Ok(BlockTarget::Peer(p)),
+        (None, Some(k)) => hex::decode(k.trim())
+            .map(BlockTarget::Key)
+            .map_err(|e| crate::error::Error::Config(format!("invalid key hex: {e}"))),
+        (None, None) => Err(crate::error::Error::Config("give --peer or --key".into())),
+    }
 }
 
 #[allow(dead_code)]
@@ -224,7 +511,39 @@ impl Cli {
         // initialize logger after overrides
         let _ = pretty_env_logger::try_init();
         match self.command {
-            Commands::Identity => {
+            Commands::Identity {
+                action:
+                    Some(IdentityAction::Export { out, with_contacts }),
+            } => {
+                let passphrase = read_new_passphrase()?;
+                crate::api::Core::new().export_identity(&out, &passphrase, with_contacts)?;
+                println!("exported identity to {}", out.display());
+                if with_contacts {
+                    println!("contacts included");
+                }
+            }
+            Commands::Identity {
+                action:
+                    Some(IdentityAction::Import { path, force }),
+            } => {
+                let passphrase = read_passphrase("Archive passphrase: ")?;
+                let imported = crate::api::Core::new().import_identity(&path, &passphrase, force)?;
+                println!("imported identity {}", imported.preview.sodium_box_pk_hex);
+                if imported.contacts_added > 0 {
+                    println!("added {} contacts", imported.contacts_added);
+                }
+            }
+            Commands::Identity {
+                action: Some(IdentityAction::Rotate),
+            } => {
+                let rotated = crate::api::Core::new().rotate_identity()?;
+                println!("new sodium box pk: {}", rotated.preview.sodium_box_pk_hex);
+                println!(
+                    "key transition queued for {} contacts; `send-loop` delivers it",
+                    rotated.announced
+                );
+            }
+            Commands::Identity { action: None } => {
                 let cfg = crate::config::load();
                 let id = crate::identity::Identity::load_or_generate(&cfg.data_dir)?;
                 let path = cfg.data_dir.join("identity.bin");
@@ -251,21 +570,29 @@ impl Cli {
                         name,
                         addr,
                         pubkey_hex,
+                        peer_id,
                     } => {
-                        let c = store
+                        let mut c = store
                             .add(&name, &addr, &pubkey_hex)
                             .map_err(crate::error::Error::Storage)?;
+                        if let Some(peer) = peer_id {
+                            c = store
+                                .set_peer_id(c.id, &peer)
+                                .map_err(crate::error::Error::Storage)?;
+                        }
                         println!("added contact {} (id: {}) -> {}", c.name, c.id, c.addr);
                     }
                     ContactsAction::List => {
                         let list = store.list().map_err(crate::error::Error::Storage)?;
                         for c in list {
                             println!(
-                                "{}\t{}\t{}\t{}",
+                                "{}\t{}\t{}\t{}\t{}\t{}",
                                 c.id,
                                 c.name,
                                 c.addr,
-                                hex::encode(c.public_key)
+                                hex::encode(c.public_key),
+                                c.peer_id.as_deref().unwrap_or("-"),
+                                if c.verified { "verified" } else { "unverified" }
                             );
                         }
                     }
@@ -278,13 +605,36 @@ impl Cli {
                         };
                         match found {
                             Some(c) => {
+                                let me = crate::identity::Identity::load_or_generate(&cfg.data_dir)?;
                                 println!(
-                                    "id: {}\nname: {}\naddr: {}\npubkey: {}",
+                                    "id: {}\nname: {}\naddr: {}\npubkey: {}\npeer id: {}\nverified: {}\nsafety number: {}",
                                     c.id,
                                     c.name,
                                     c.addr,
-                                    hex::encode(c.public_key)
+                                    hex::encode(&c.public_key),
+                                    c.peer_id.as_deref().unwrap_or("(unbound)"),
+                                    if c.verified { "yes" } else { "no" },
+                                    crate::crypto::safety_number(&me.sodium_box_pk.0, &c.public_key)
                                 );
+                                let policy =
+                                    store.policy(c.id).map_err(crate::error::Error::Storage)?;
+                                if policy != crate::storage::contacts::ContactPolicy::Allow {
+                                    println!("messages: {}", policy);
+                                }
+                                if let Some(p) = &c.pending_key {
+                                    println!(
+                                        "pending key change: {} (seen {}); sending is held",
+                                        hex::encode(&p.public_key),
+                                        p.seen_at
+                                    );
+                                }
+                                for k in &c.key_history {
+                                    println!(
+                                        "previous key: {} (replaced {})",
+                                        hex::encode(&k.public_key),
+                                        k.replaced_at
+                                    );
+                                }
                             }
                             None => println!("not found: {}", sel),
                         }
@@ -297,6 +647,134 @@ impl Cli {
                             println!("not found: {}", id);
                         }
                     }
+                    ContactsAction::SetPeer { id, peer_id } => {
+                        let c = store
+                            .set_peer_id(id, &peer_id)
+                            .map_err(crate::error::Error::Storage)?;
+                        println!("contact {} bound to {}", c.id, peer_id);
+                    }
+                    ContactsAction::Verify { id } => {
+                        let c = store
+                            .set_verified(id, true)
+                            .map_err(crate::error::Error::Storage)?;
+                        println!("contact {} ({}) marked verified", c.name, c.id);
+                    }
+                    ContactsAction::Unverify { id } => {
+                        let c = store
+                            .set_verified(id, false)
+                            .map_err(crate::error::Error::Storage)?;
+                        println!("contact {} ({}) marked unverified", c.name, c.id);
+                    }
+                    ContactsAction::KeyChanges => {
+                        for c in store
+                            .pending_key_changes()
+                            .map_err(crate::error::Error::Storage)?
+                        {
+                            if let Some(p) = &c.pending_key {
+                                println!(
+                                    "{}\t{}\t{} -> {}",
+                                    c.id,
+                                    c.name,
+                                    hex::encode(&c.public_key),
+                                    hex::encode(&p.public_key)
+                                );
+                            }
+                        }
+                    }
+                    ContactsAction::AcceptKey { id } => {
+                        drop(store);
+                        let c = crate::api::Core::new().contacts_accept_key_change(id)?;
+                        println!("accepted new keys for {} ({})", c.name, c.id);
+                        crate::ui::warn_if_unverified(&c);
+                    }
+                    ContactsAction::RejectKey { id } => {
+                        let c = store
+                            .reject_key_change(id)
+                            .map_err(crate::error::Error::Storage)?;
+                        println!("kept pinned keys for {} ({})", c.name, c.id);
+                    }
+                    ContactsAction::Policy { id, policy } => {
+                        let c = store
+                            .set_policy(id, policy)
+                            .map_err(crate::error::Error::Storage)?;
+                        println!("messages from {} ({}): {}", c.name, c.id, policy);
+                    }
+                    ContactsAction::Block { peer, key } => {
+                        let target = block_target(peer, key)?;
+                        if store
+                            .block(target.clone())
+                            .map_err(crate::error::Error::Storage)?
+                        {
+                            println!("blocked {}", target);
+                        } else {
+                            println!("already blocked: {}", target);
+                        }
+                    }
+                    ContactsAction::Unblock { peer, key } => {
+                        let target = block_target(peer, key)?;
+                        if store.unblock(&target).map_err(crate::error::Error::Storage)? {
+                            println!("unblocked {}", target);
+                        } else {
+                            println!("not blocked: {}", target);
+                        }
+                    }
+                    ContactsAction::Blocklist => {
+                        for entry in store.blocklist().map_err(crate::error::Error::Storage)? {
+                            println!("{}\t{}", entry.added_at, entry.target);
+                        }
+                    }
+                    ContactsAction::Card { name, addrs } => {
+                        let core = crate::api::Core::new();
+                        if name.is_some() || !addrs.is_empty() {
+                            let mut profile = core.get_profile()?;
+                            if let Some(n) = name {
+                                profile.display_name = n;
+                            }
+                            if !addrs.is_empty() {
+                                profile.advertised_addrs = addrs;
+                            }
+                            core.set_profile(&profile)?;
+                        }
+                        println!("{}", core.my_contact_card()?.to_uri()?);
+                    }
+                    ContactsAction::Import { card } => {
+                        // Release the store so the facade can open it
+                        drop(store);
+                        let c = crate::api::Core::new().contacts_import_card(&card)?;
+                        println!("imported contact {} (id: {}) -> {}", c.name, c.id, c.addr);
+                        if c.send_blocked() {
+                            eprintln!(
+                                "warning: card keys differ from the pinned ones; review with `contacts show {}` then `contacts accept-key {}` or `contacts reject-key {}`",
+                                c.id, c.id, c.id
+                            );
+                        } else {
+                            crate::ui::warn_if_unverified(&c);
+                        }
+                    }
+                    ContactsAction::Nearby => {
+                        for p in crate::discovery::load_nearby_peers(&cfg.data_dir)? {
+                            println!("{}\t{}", p.peer_id, p.addrs.join(","));
+                        }
+                    }
+                    ContactsAction::AddNearby {
+                        peer_id,
+                        name,
+                        pubkey_hex,
+                    } => {
+                        let peer = crate::discovery::load_nearby_peers(&cfg.data_dir)?
+                            .into_iter()
+                            .find(|p| p.peer_id == peer_id);
+                        match peer.and_then(|p| p.dial_addr()) {
+                            Some(addr) => {
+                                let c = store
+                                    .add(&name, &addr, &pubkey_hex)
+                                    .map_err(crate::error::Error::Storage)?;
+                                crate::discovery::forget_nearby_peer(&cfg.data_dir, &peer_id)?;
+                                println!("added contact {} (id: {}) -> {}", c.name, c.id, c.addr);
+                            }
+                            None => println!("not found: {}", peer_id),
+                        }
+                    }
                 }
             }
             Commands::Compose {
@@ -304,10 +782,14 @@ impl Cli {
                 message,
                 queue,
             } => {
-                let queue_path = queue.unwrap_or_else(|| "queue_db".to_string());
-                let id =
-                    crate::messaging::compose::compose_message(recipient_id, &message, &queue_path)
-                        .await?;
+                if let Some(c) = crate::api::Core::new().contacts_get(recipient_id)? {
+                    if c.send_blocked() {
+                        return Err(crate::api::key_change_pending(&c));
+                    }
+                    crate::ui::warn_if_unverified(&c);
+                }
+                let q = open_queue(queue)?;
+                let id = crate::messaging::compose::compose_on(&q, recipient_id, &message)?;
                 println!("Queued message {} for {}", id, recipient_id);
             }
             Commands::Queue { action } => match action {
@@ -321,8 +803,7 @@ impl Cli {
                 }
             },
             Commands::Fetch { queue } => {
-                let queue_path = queue.unwrap_or_else(|| "queue_db".to_string());
-                let q = MessageQueue::new(&queue_path).map_err(crate::error::Error::Storage)?;
+                let q = open_queue(queue)?;
                 for (id, bytes) in q.list_inbox().map_err(crate::error::Error::Storage)? {
                     let preview = String::from_utf8_lossy(&bytes);
                     println!("{}: {}", id, preview);
@@ -330,8 +811,7 @@ impl Cli {
             }
             Commands::Inbox { action } => match action {
                 InboxAction::List { queue, limit } => {
-                    let queue_path = queue.unwrap_or_else(|| "queue_db".to_string());
-                    let q = MessageQueue::new(&queue_path).map_err(crate::error::Error::Storage)?;
+                    let q = open_queue(queue)?;
                     let mut items = q.list_inbox().map_err(crate::error::Error::Storage)?;
                     if let Some(n) = limit {
                         items.truncate(n);
@@ -342,8 +822,7 @@ impl Cli {
                     }
                 }
                 InboxAction::Show { queue, id } => {
-                    let queue_path = queue.unwrap_or_else(|| "queue_db".to_string());
-                    let q = MessageQueue::new(&queue_path).map_err(crate::error::Error::Storage)?;
+                    let q = open_queue(queue)?;
                     let uid = uuid::Uuid::parse_str(&id)
                         .map_err(|e| crate::error::Error::Serialization(e.to_string()))?;
                     match q.get_inbox(uid).map_err(crate::error::Error::Storage)? {
@@ -355,8 +834,7 @@ impl Cli {
                     }
                 }
                 InboxAction::Export { queue, id, out } => {
-                    let queue_path = queue.unwrap_or_else(|| "queue_db".to_string());
-                    let q = MessageQueue::new(&queue_path).map_err(crate::error::Error::Storage)?;
+                    let q = open_queue(queue)?;
                     let uid = uuid::Uuid::parse_str(&id)
                         .map_err(|e| crate::error::Error::Serialization(e.to_string()))?;
                     match q.get_inbox(uid).map_err(crate::error::Error::Storage)? {
@@ -372,8 +850,7 @@ impl Cli {
                     }
                 }
                 InboxAction::Search { queue, term, limit } => {
-                    let queue_path = queue.unwrap_or_else(|| "queue_db".to_string());
-                    let q = MessageQueue::new(&queue_path).map_err(crate::error::Error::Storage)?;
+                    let q = open_queue(queue)?;
                     let items = q.list_inbox().map_err(crate::error::Error::Storage)?;
                     let needle = term.to_lowercase();
                     let mut count = 0usize;
@@ -391,36 +868,313 @@ impl Cli {
                         }
                     }
                 }
+                InboxAction::Sent => {
+                    for m in crate::api::Core::new().sent_list()? {
+                        let to = m
+                            .contact_id
+                            .map(|c| c.to_string())
+                            .unwrap_or_else(|| "?".into());
+                        let from = m.from_device.as_deref().unwrap_or("this device");
+                        println!(
+                            "{}\tto {}\tfrom {}\t{}",
+                            m.sent_at,
+                            to,
+                            from,
+                            String::from_utf8_lossy(&m.body)
+                        );
+                    }
+                }
+                InboxAction::Quarantine => {
+                    let core = crate::api::Core::new();
+                    for m in core.quarantine_list()? {
+                        println!("{}\t{}\t{}", m.id, m.peer_id, m.received_at);
+                    }
+                }
+                InboxAction::Release { id } => {
+                    let uid = uuid::Uuid::parse_str(&id)
+                        .map_err(|e| crate::error::Error::Serialization(e.to_string()))?;
+                    crate::api::Core::new().quarantine_release(uid)?;
+                    println!("released {}", uid);
+                }
+                InboxAction::Discard { id } => {
+                    let uid = uuid::Uuid::parse_str(&id)
+                        .map_err(|e| crate::error::Error::Serialization(e.to_string()))?;
+                    if crate::api::Core::new().quarantine_discard(uid)? {
+                        println!("discarded {}", uid);
+                    } else {
+                        println!("not found: {}", id);
+                    }
+                }
+            },
+            Commands::Backup { action } => match action {
+                BackupAction::Create { out } => {
+                    let passphrase = read_new_passphrase()?;
+                    let m = crate::api::Core::new().backup_create(&out, &passphrase)?;
+                    println!("backup written to {}", out.display());
+                    for db in &m.databases {
+                        println!("  {}: {} records", db.name, db.records);
+                    }
+                    println!("  files: {}", m.files.join(", "));
+                }
+                BackupAction::Restore { path, force } => {
+                    let passphrase = read_passphrase("Backup passphrase: ")?;
+                    let m = crate::api::Core::new().backup_restore(&path, &passphrase, force)?;
+                    println!(
+                        "restored backup from {} (made by v{})",
+                        path.display(),
+                        m.app_version
+                    );
+                    for db in &m.databases {
+                        println!("  {}: {} records", db.name, db.records);
+                    }
+                }
+            },
+            Commands::Devices { action } => match action {
+                DevicesAction::Request { name } => {
+                    let req = crate::api::Core::new().devices_link_request(&name)?;
+                    println!("{}", req.to_uri()?);
+                }
+                DevicesAction::Authorize { request } => {
+                    let grant = crate::api::Core::new().devices_authorize(&request)?;
+                    println!("{}", grant.to_uri()?);
+                    if grant.certs.len() > 2 {
+                        println!("(accept this grant on your other linked devices too)");
+                    }
+                    println!("share a fresh contact card so contacts reach the new device");
+                }
+                DevicesAction::Accept { grant } => {
+                    let linked = crate::api::Core::new().devices_accept(&grant)?;
+                    println!("linked to account with {} devices", linked.certs.len());
+                }
+                DevicesAction::List => match crate::api::Core::new().devices_list()? {
+                    Some(linked) => {
+                        let cfg = crate::config::load();
+                        let id = crate::identity::Identity::load_or_generate(&cfg.data_dir)?;
+                        for c in &linked.certs {
+                            let this = if c.sign_key == id.sign_pk.0 {
+                                " (this device)"
+                            } else {
+                                ""
+                            };
+                            println!(
+                                "{}{}\t{}\t{}",
+                                c.device.name,
+                                this,
+                                hex::encode(c.box_key),
+                                c.device.peer_id.as_deref().unwrap_or("-")
+                            );
+                        }
+                    }
+                    None => println!("no linked devices"),
+                },
+                DevicesAction::Unlink { name } => {
+                    if crate::api::Core::new().devices_unlink(&name)? {
+                        println!("unlinked {}", name);
+                    } else {
+                        println!("not found: {}", name);
+                    }
+                }
+            },
+            Commands::Groups { action } => {
+                let core = crate::api::Core::new();
+                match action {
+                    GroupsAction::Create { name, members } => {
+                        let g = core.groups_create(&name, &members)?;
+                        println!("created group {} ({}) with {} members", g.name, g.id, g.members.len());
+                    }
+                    GroupsAction::List => {
+                        let groups = core.groups_list()?;
+                        if groups.is_empty() {
+                            println!("no groups");
+                        }
+                        for g in groups {
+                            let state = if g.active { "" } else { " (removed)" };
+                            let names: Vec<&str> = g.members.iter().map(|m| m.name.as_str()).collect();
+                            println!("{}\t{}{}\t{}", g.id, g.name, state, names.join(", "));
+                        }
+                    }
+                    GroupsAction::Add { group, members } => {
+                        let g = core.groups_find(&group)?;
+                        let g = core.groups_add_members(g.id, &members)?;
+                        println!("{} now has {} members", g.name, g.members.len());
+                    }
+                    GroupsAction::Remove { group, members } => {
+                        let g = core.groups_find(&group)?;
+                        let g = core.groups_remove_members(g.id, &members)?;
+                        println!("{} now has {} members; sender keys rotated", g.name, g.members.len());
+                    }
+                    GroupsAction::Rekey { group } => {
+                        let g = core.groups_find(&group)?;
+                        core.groups_rekey(g.id)?;
+                        println!("new sender key queued for the members of {}", g.name);
+                    }
+                    GroupsAction::Send { group, message } => {
+                        let g = core.groups_find(&group)?;
+                        core.groups_send(g.id, message.as_bytes())?;
+                        println!("queued to {} members of {}", g.members.len().saturating_sub(1), g.name);
+                    }
+                    GroupsAction::Thread { group } => {
+                        let g = core.groups_find(&group)?;
+                        for m in core.groups_thread(g.id)? {
+                            println!("[{}] {}: {}", m.at, m.sender_name, String::from_utf8_lossy(&m.body));
+                        }
+                    }
+                }
+            }
+            Commands::Prekeys { action } => match action {
+                PrekeysAction::Status => {
+                    let st = crate::api::Core::new().prekeys_status()?;
+                    println!("signed prekey: {}", st.signed_prekey_id);
+                    println!("created at: {}", st.signed_prekey_created_at);
+                    println!(
+                        "one-time prekeys: {} available, {} handed out, {} used",
+                        st.one_time_available, st.one_time_issued, st.one_time_consumed
+                    );
+                }
+                PrekeysAction::Rotate => {
+                    let id = crate::api::Core::new().prekeys_rotate()?;
+                    println!("signed prekey rotated (id {})", id);
+                }
+                #[cfg(feature = "network")]
+                PrekeysAction::Fetch { sel, timeout_ms } => {
+                    let cfg = crate::config::load();
+                    let c = crate::ui::find_contact(&cfg.data_dir, &sel)?
+                        .ok_or_else(|| crate::error::Error::Config("contact not found".into()))?;
+                    let addr = match &c.peer_id {
+                        Some(peer) => crate::discovery::with_peer_suffix(&c.addr, peer),
+                        None => c.addr.clone(),
+                    };
+                    let id = crate::identity::Identity::load_or_generate(&cfg.data_dir)?;
+                    let response = crate::network::prekeys::fetch_bundle(
+                        id.libp2p,
+                        &addr,
+                        std::time::Duration::from_millis(timeout_ms),
+                    )
+                    .await?;
+                    let bundle =
+                        crate::api::Core::new().contacts_store_prekey_bundle(c.id, &response)?;
+                    println!(
+                        "stored prekey bundle for {} (signed prekey {}, one-time prekey: {})",
+                        c.name,
+                        bundle.signed_prekey_id,
+                        if bundle.one_time_prekey.is_some() { "yes" } else { "none left" }
+                    );
+                }
             },
             Commands::Security { action } => {
                 match action {
-                    SecurityAction::PreviewKey { passphrase } => {
+                    SecurityAction::PreviewKey => {
+                        let passphrase = read_passphrase("Passphrase: ")?;
                         let salt = b"pigeon-dev-salt"; // dev-only static salt preview
                         let key =
                             crate::storage::at_rest::derive_key_from_passphrase(&passphrase, salt)
                                 .map_err(crate::error::Error::Storage)?;
                         println!("kdf key preview: {}", hex::encode(key.0));
                     }
-                    SecurityAction::SetPassphrase { passphrase } => {
-                        let cfg = crate::config::load();
-                        crate::storage::at_rest::set_passphrase_and_seal(
-                            &cfg.data_dir,
-                            &passphrase,
-                        )
-                        .map_err(crate::error::Error::Storage)?;
+                    SecurityAction::SetPassphrase => {
+                        let core = crate::api::Core::new();
+                        if core.is_locked() {
+                            core.unlock(&read_passphrase("Current passphrase: ")?)?;
+                        }
+                        core.set_passphrase(&read_new_passphrase()?)?;
                         println!("sealed at-rest key");
                     }
-                    SecurityAction::Unlock { passphrase } => {
-                        let cfg = crate::config::load();
-                        let _ = crate::storage::at_rest::unlock_with_passphrase(
-                            &cfg.data_dir,
-                            &passphrase,
-                        )
-                        .map_err(crate::error::Error::Storage)?;
-                        println!("unlocked for this session");
+                    SecurityAction::ChangePassphrase => {
+                        let core = crate::api::Core::new();
+                        if core.is_locked() {
+                            core.unlock(&read_passphrase("Current passphrase: ")?)?;
+                        }
+                        core.change_passphrase(&read_new_passphrase()?)?;
+                        if let Some(p) = core.kdf_params()? {
+                            println!(
+                                "passphrase changed (argon2id m={} KiB, t={}, p={})",
+                                p.memory_kib, p.iterations, p.parallelism
+                            );
+                        }
+                    }
+                    SecurityAction::RemovePassphrase => {
+                        let core = crate::api::Core::new();
+                        if core.is_locked() {
+                            core.unlock(&read_passphrase("Current passphrase: ")?)?;
+                        }
+                        core.remove_passphrase()?;
+                        println!("passphrase removed; the at-rest key is stored unprotected");
+                    }
+                    SecurityAction::Unlock {
+                        keyring,
+                        timeout_secs,
+                    } => {
+                        let core = crate::api::Core::new();
+                        core.unlock(&read_passphrase("Passphrase: ")?)?;
+                        if keyring || core.keyring_enabled() {
+                            let secs = core.remember_unlock(timeout_secs)?;
+                            println!("unlocked; the OS keyring keeps the unlock for {}s", secs);
+                        } else {
+                            println!("unlocked for this session");
+                        }
+                    }
+                    SecurityAction::Rotate => {
+                        let core = crate::api::Core::new();
+                        if core.is_locked() {
+                            core.unlock(&read_passphrase("Current passphrase: ")?)?;
+                        }
+                        let report = |p: &crate::storage::rekey::RekeyProgress| {
+                            println!("  {}: {}/{}", p.database, p.done, p.total)
+                        };
+                        let resealed = if core.at_rest_rotation_pending()? {
+                            println!("finishing an interrupted rotation");
+                            core.resume_at_rest_rotation(report)?
+                        } else {
+                            core.rotate_at_rest_key_with_progress(&read_new_passphrase()?, report)?
+                        };
+                        println!("rotated at-rest key; re-sealed {} records", resealed);
+                    }
+                    SecurityAction::Lock => {
+                        if crate::api::Core::new().lock()? {
+                            println!("locked; removed the unlock from the OS keyring");
+                        } else {
+                            println!("locked");
+                        }
                     }
                 }
             }
+            Commands::Storage { action } => match action {
+                StorageAction::Status => {
+                    let core = crate::api::Core::new();
+                    let status = core.storage_status()?;
+                    for db in &status.databases {
+                        match (db.backend, db.version) {
+                            (Some(backend), Some(version)) => println!(
+                                "{}: {}, schema v{} (this build: v{}), {} records",
+                                db.database, backend, version, db.supported, db.records
+                            ),
+                            _ => println!(
+                                "{}: not created yet (this build: v{})",
+                                db.database, db.supported
+                            ),
+                        }
+                    }
+                    match status.identity_format {
+                        Some(v) => println!(
+                            "identity.bin: format v{} (this build: v{})",
+                            v,
+                            crate::identity::IDENTITY_FORMAT
+                        ),
+                        None if core.is_locked() => println!("identity.bin: locked"),
+                        None => println!("identity.bin: not created yet"),
+                    }
+                }
+                StorageAction::Migrate { from, to } => {
+                    let core = crate::api::Core::new();
+                    let copied = core.storage_migrate(from, to, |p| {
+                        println!("  {}: {}/{}", p.database, p.done, p.total)
+                    })?;
+                    println!(
+                        "migrated {} records from {} to {}; the old databases are kept as *.{}-old",
+                        copied, from, to, from
+                    );
+                }
+            },
             Commands::Ops { action } => match action {
                 OpsAction::Serve { addr } => {
                     let addr: std::net::SocketAddr =
@@ -448,6 +1202,7 @@ impl Cli {
                     base_backoff_secs: base_backoff,
                     interval_ms,
                     high_to_normal_ratio: high_ratio,
+                    static_key_fallback: cfg.static_key_fallback,
                 };
                 println!("Starting send loop (queue: {}, backoff: {}s, interval: {}ms, high:normal={}:{})...",
                     conf.queue_path, conf.base_backoff_secs, conf.interval_ms, conf.high_to_normal_ratio, 1);
@@ -475,6 +1230,75 @@ impl Cli {
                 )?;
 
                 let id = crate::identity::Identity::load_or_generate(&cfg.data_dir)?;
+                // Seal once up front so reconnect retries resend the same envelope
+                let saved = match contact.as_deref() {
+                    Some(sel) => crate::ui::find_contact(&cfg.data_dir, sel)?,
+                    None => None,
+                };
+                let data = match &saved {
+                    // Contacts get a forward-secret ratchet session (envelope v2); copies for
+                    // their other devices and ours go through the queue
+                    Some(c) => {
+                        let mut data = crate::messaging::send::encode_for_contact(
+                            &cfg.data_dir,
+                            &id,
+                            c,
+                            message.as_bytes(),
+                        )?;
+                        if crate::messaging::send::is_pending(&data)
+                            && !crate::devices::is_linked_device(&cfg.data_dir)?
+                        {
+                            // No session yet: fetch the contact's prekey bundle and try again
+                            let response = crate::network::prekeys::fetch_bundle(
+                                id.libp2p.clone(),
+                                &addr_str,
+                                std::time::Duration::from_millis(timeout_ms),
+                            )
+                            .await?;
+                            crate::session::prekeys::accept_bundle(&cfg.data_dir, c, &response)?;
+                            data = crate::messaging::send::seal_pending(
+                                &cfg.data_dir,
+                                &id,
+                                c,
+                                &data,
+                            )?
+                            .unwrap_or(data);
+                        }
+                        if crate::messaging::send::is_pending(&data) {
+                            if !cfg.static_key_fallback {
+                                return Err(crate::error::Error::Config(format!(
+                                    "no session with {} and static_key_fallback is off",
+                                    c.name
+                                )));
+                            }
+                            eprintln!("warning: sending to {} without forward secrecy", c.name);
+                            data = crate::messaging::send::seal_static(&id, c, &data)?;
+                        }
+                        let queue_path = cfg.data_dir.join("queue_db");
+                        let copies = crate::messaging::send::fan_out_copies(
+                            queue_path.to_str().unwrap_or("queue_db"),
+                            &cfg.data_dir,
+                            &id,
+                            c,
+                            message.as_bytes(),
+                        )?;
+                        if copies > 0 {
+                            println!("queued {} copies for other devices", copies);
+                        }
+                        data
+                    }
+                    None => {
+                        // Envelope v1 with box ciphertext for ad-hoc --to/--pubkey_hex sends
+                        let env = crate::messaging::send::seal_box_envelope(
+                            1,
+                            &id,
+                            &remote_pk,
+                            message.as_bytes(),
+                        );
+                        bincode::serialize(&env)
+                            .map_err(|e| crate::error::Error::Serialization(e.to_string()))?
+                    }
+                };
                 let local_key = id.libp2p;
                 let transport = libp2p::tcp::tokio::Transport::new(
                     libp2p::tcp::Config::default().nodelay(true),
@@ -494,6 +1318,7 @@ impl Cli {
                     crate::network::rr::PigeonCodec,
                 > = libp2p::request_response::Behaviour::new(protocols, cfg);
                 let peer_id = local_key.public().to_peer_id();
+                let me = peer_id.to_string();
                 let mut swarm = libp2p::Swarm::new(
                     transport,
                     behaviour,
@@ -508,6 +1333,7 @@ impl Cli {
                 let mut delay = backoff_ms;
                 let mut done = false;
                 let mut need_dial = true;
+                let mut stamped = false;
                 use libp2p::futures::StreamExt;
                 while !done && attempt <= retries {
                     if need_dial {
@@ -525,53 +1351,44 @@ impl Cli {
                     tokio::pin!(step_timeout);
                     match swarm.select_next_some().await {
                         SwarmEvent::ConnectionEstablished { peer_id, .. } => {
-                            // Build envelope v1 with sealedbox ciphertext
-                            let plaintext = message.clone().into_bytes();
-                            let nonce = sodiumoxide::crypto::box_::gen_nonce();
-                            let ciphertext = sodiumoxide::crypto::box_::seal(
-                                &plaintext,
-                                &nonce,
-                                &remote_pk,
-                                &id.sodium_box_sk,
-                            );
-                            // sign over (version|sender_id=0|recipient_id=0|nonce|ciphertext)
-                            let mut to_sign = Vec::new();
-                            to_sign.push(1u8);
-                            to_sign.extend_from_slice(&0u64.to_be_bytes());
-                            to_sign.extend_from_slice(&0u64.to_be_bytes());
-                            to_sign.extend_from_slice(nonce.as_ref());
-                            to_sign.extend_from_slice(&ciphertext);
-                            let sig =
-                                sodiumoxide::crypto::sign::sign_detached(&to_sign, &id.sign_sk);
-                            // Convert nonce to fixed array for envelope
-                            let mut nonce_arr = [0u8; 24];
-                            nonce_arr.copy_from_slice(nonce.as_ref());
-                            let env = crate::messaging::message::EnvelopeV1::new(
-                                0,
-                                0,
-                                nonce_arr,
-                                ciphertext,
-                                sig.to_bytes().to_vec(),
-                            );
-                            let data = bincode::serialize(&env)
-                                .map_err(|e| crate::error::Error::Serialization(e.to_string()))?;
-                            let _ = swarm.behaviour_mut().send_request(&peer_id, data);
+                            let _ = swarm.behaviour_mut().send_request(&peer_id, data.clone());
                         }
                         SwarmEvent::Behaviour(libp2p::request_response::Event::<
                             Vec<u8>,
                             Vec<u8>,
                         >::Message {
+                            peer,
                             message,
-                            ..
                         }) => match message {
                             libp2p::request_response::Message::<Vec<u8>, Vec<u8>>::Response {
                                 response,
                                 ..
-                            } => {
-                                let txt = String::from_utf8_lossy(&response);
-                                println!("response: {}", txt);
-                                done = true;
-                            }
+                            } => match crate::messaging::stamp::requested_bits(&response) {
+                                // Recipients that do not know us may ask for a stamp first
+                                Some(bits) if !stamped => {
+                                    println!("stamping the message ({} bits)", bits);
+                                    stamped = true;
+                                    let (request, sender) = (data.clone(), me.clone());
+                                    let stamped_data = tokio::task::spawn_blocking(move || {
+                                        crate::messaging::stamp::stamp(
+                                            &request,
+                                            &sender,
+                                            &remote_pk.0,
+                                            bits,
+                                        )
+                                    })
+                                    .await
+                                    .map_err(|e| {
+                                        crate::error::Error::Config(format!("stamping failed: {e}"))
+                                    })?;
+                                    let _ = swarm.behaviour_mut().send_request(&peer, stamped_data);
+                                }
+                                _ => {
+                                    let txt = String::from_utf8_lossy(&response);
+                                    println!("response: {}", txt);
+                                    done = true;
+                                }
+                            },
                             libp2p::request_response::Message::<Vec<u8>, Vec<u8>>::Request {
                                 ..
                             } => {}
@@ -608,12 +1425,34 @@ impl Cli {
                 port,
                 listen_addr,
                 mdns,
+                unknown_peers,
+                contacts_only,
+                metrics_addr,
             } => {
                 use libp2p::{Multiaddr, Transport};
                 // use crate::network::rr; // unused here
                 let cfg = crate::config::load();
+                let mut policy = crate::messaging::inbound::InboundPolicy::from_config(&cfg);
+                if let Some(p) = unknown_peers {
+                    policy.unknown_peers = p;
+                }
+                policy.contacts_only |= contacts_only;
+                let metrics = ops::Metrics::default();
+                if let Some(addr) = metrics_addr {
+                    let addr: std::net::SocketAddr =
+                        addr.parse().map_err(|e: std::net::AddrParseError| {
+                            crate::error::Error::Config(e.to_string())
+                        })?;
+                    println!("serving /metrics on http://{}", addr);
+                    let served = metrics.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = ops::serve(addr, served).await {
+                            log::warn!("metrics server: {e}");
+                        }
+                    });
+                }
                 let id = crate::identity::Identity::load_or_generate(&cfg.data_dir)?;
-                let local_key = id.libp2p;
+                let local_key = id.libp2p.clone();
                 let transport = libp2p::tcp::tokio::Transport::new(
                     libp2p::tcp::Config::default().nodelay(true),
                 )
@@ -628,7 +1467,8 @@ impl Cli {
                 struct NodeBehaviour {
                     request_response:
                         libp2p::request_response::Behaviour<crate::network::rr::PigeonCodec>,
-                    mdns: libp2p::mdns::tokio::Behaviour,
+                    prekeys: libp2p::request_response::Behaviour<crate::network::rr::PigeonCodec>,
+                    mdns: libp2p::swarm::behaviour::toggle::Toggle<libp2p::mdns::tokio::Behaviour>,
                 }
                 let rr_cfg = libp2p::request_response::Config::default();
                 let protocols: Vec<(String, libp2p::request_response::ProtocolSupport)> = vec![(
@@ -638,13 +1478,19 @@ impl Cli {
                 let rr_behaviour: libp2p::request_response::Behaviour<
                     crate::network::rr::PigeonCodec,
                 > = libp2p::request_response::Behaviour::new(protocols, rr_cfg);
-                let mdns_behaviour = libp2p::mdns::tokio::Behaviour::new(
-                    libp2p::mdns::Config::default(),
-                    local_key.public().to_peer_id(),
-                )?;
+                // Only construct mDNS when requested so it never probes the LAN otherwise
+                let mdns_behaviour = if mdns || cfg.enable_mdns {
+                    Some(libp2p::mdns::tokio::Behaviour::new(
+                        libp2p::mdns::Config::default(),
+                        local_key.public().to_peer_id(),
+                    )?)
+                } else {
+                    None
+                };
                 let behaviour = NodeBehaviour {
                     request_response: rr_behaviour,
-                    mdns: mdns_behaviour,
+                    prekeys: crate::network::prekeys::behaviour(),
+                    mdns: mdns_behaviour.into(),
                 };
                 let peer_id = local_key.public().to_peer_id();
                 let mut swarm = libp2p::Swarm::new(
@@ -674,102 +1520,212 @@ impl Cli {
                     )))
                 })?;
 
+                tokio::spawn(crate::messaging::inbound::prune_nonces_every(
+                    cfg.data_dir.clone(),
+                    policy,
+                    crate::messaging::inbound::NONCE_PRUNE_INTERVAL,
+                ));
                 println!("Listening (libp2p rr)... [Ctrl+C to exit]");
+                let mut limiter = crate::messaging::limits::Limiter::new(cfg.inbound_limits);
+                // Connections closed for going over the per-peer cap were never counted
+                let mut over_cap = std::collections::HashSet::new();
                 use libp2p::futures::StreamExt;
                 loop {
                     match swarm.select_next_some().await {
                         libp2p::swarm::SwarmEvent::NewListenAddr { address, .. } => {
                             println!("Listening on {}", address)
                         }
+                        libp2p::swarm::SwarmEvent::ConnectionEstablished {
+                            peer_id,
+                            connection_id,
+                            ..
+                        } => {
+                            if !limiter.connection_opened(&peer_id.to_string()) {
+                                log::warn!("closing connection from {peer_id}: too many open");
+                                metrics
+                                    .refused_connections
+                                    .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                                over_cap.insert(connection_id);
+                                let _ = swarm.close_connection(connection_id);
+                            }
+                        }
+                        libp2p::swarm::SwarmEvent::ConnectionClosed {
+                            peer_id,
+                            connection_id,
+                            ..
+                        } => {
+                            if !over_cap.remove(&connection_id) {
+                                limiter.connection_closed(&peer_id.to_string());
+                            }
+                        }
                         libp2p::swarm::SwarmEvent::Behaviour(ev) => {
                             match ev {
                                 // Events from our derived behaviour enum
                                 NodeBehaviourEvent::RequestResponse(
                                     libp2p::request_response::Event::<Vec<u8>, Vec<u8>>::Message {
+                                        peer,
                                         message,
-                                        ..
                                     },
                                 ) => {
-                                    match message {
-                                        libp2p::request_response::Message::<Vec<u8>, Vec<u8>>::Request { request, channel, .. } => {
-                                            // Try decode as EnvelopeV1 and compute a single response
-                                            let response = if let Ok(env) = bincode::deserialize::<crate::messaging::message::EnvelopeV1>(&request) {
-                                                // verify signature
-                                                let mut to_verify = Vec::new();
-                                                to_verify.push(env.version);
-                                                to_verify.extend_from_slice(&env.sender_id.to_be_bytes());
-                                                to_verify.extend_from_slice(&env.recipient_id.to_be_bytes());
-                                                to_verify.extend_from_slice(&env.nonce);
-                                                to_verify.extend_from_slice(&env.payload);
-                                                if env.signature.len() != 64 {
-                                                    println!("received: <invalid signature>");
-                                                    b"NACK".to_vec()
-                                                } else {
-                                                    let mut arr = [0u8; 64];
-                                                    arr.copy_from_slice(&env.signature);
-                                                    let sig = ed25519_dalek::Signature::from_bytes(&arr);
-                                                    if let Ok(vk) = ed25519_dalek::VerifyingKey::from_bytes(&id.sign_pk.0) {
-                                                        if vk.verify_strict(&to_verify, &sig).is_ok() {
-                                                        // replay protection via nonce store
-                                                        let db = sled::open(crate::config::load().data_dir.join("queue_db")).map_err(|e| crate::error::Error::Storage(crate::storage::Error::Db(e)))?;
-                                                        let ns = crate::storage::nonce_store::NonceStore::open(&db).map_err(crate::error::Error::Storage)?;
-                                                        if ns.insert_if_fresh(env.sender_id, &env.nonce).map_err(crate::error::Error::Storage)? {
-                                                            // decrypt
-                                                            let nonce = sodiumoxide::crypto::box_::Nonce::from_slice(&env.nonce).unwrap();
-                                                            if let Ok(plaintext) = sodiumoxide::crypto::box_::open(&env.payload, &nonce, &id.sodium_box_pk, &id.sodium_box_sk) {
-                                                                // Store to inbox (best-effort)
-                                                                if let Ok(q) = crate::storage::queue::MessageQueue::new("queue_db") {
-                                                                    let _ = q.store_inbox(uuid::Uuid::new_v4(), plaintext.clone());
-                                                                }
-                                                                // Increment received metric (process-local)
-                                                                static METRICS: once_cell::sync::Lazy<crate::ops::Metrics> = once_cell::sync::Lazy::new(Default::default);
-                                                                METRICS.received_messages.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-                                                                let txt = String::from_utf8_lossy(&plaintext);
-                                                                println!("received: {}", txt);
-                                                            } else {
-                                                                println!("received: <failed to decrypt>");
-                                                            }
-                                                            b"ACK".to_vec()
-                                                        } else {
-                                                            println!("replay detected (nonce)");
-                                                            b"REPLAY".to_vec()
-                                                        }
-                                                        } else {
-                                                            println!("received: <signature verify failed>");
-                                                            b"NACK".to_vec()
-                                                        }
-                                                    } else {
-                                                        println!("received: <invalid verify key>");
-                                                        b"NACK".to_vec()
-                                                    }
+                                    if let libp2p::request_response::Message::<Vec<u8>, Vec<u8>>::Request {
+                                        request,
+                                        channel,
+                                        ..
+                                    } = message
+                                    {
+                                        use crate::messaging::inbound::{self, InboundOutcome};
+                                        // `peer` was authenticated by the Noise handshake; the
+                                        // limits are checked before any store is opened
+                                        let outcome = match limiter.check_request(
+                                            &peer.to_string(),
+                                            request.len(),
+                                            std::time::Instant::now(),
+                                        ) {
+                                            Err(throttle) => InboundOutcome::Throttled(throttle),
+                                            // One request that fails is answered NACK;
+                                            // the listener keeps serving
+                                            Ok(()) => inbound::handle_request(
+                                                &cfg.data_dir,
+                                                &id,
+                                                &peer.to_string(),
+                                                &request,
+                                                policy,
+                                            )
+                                            .unwrap_or_else(|e| {
+                                                log::warn!("request from {peer}: {e}");
+                                                InboundOutcome::Rejected(e.to_string())
+                                            }),
+                                        };
+                                        metrics.count_inbound(&outcome);
+                                        match &outcome {
+                                            InboundOutcome::Delivered { plaintext, contact_id, .. } => {
+                                                let from = contact_id.map(|c| c.to_string()).unwrap_or_else(|| peer.to_string());
+                                                println!("received from {}: {}", from, String::from_utf8_lossy(plaintext));
+                                            }
+                                            InboundOutcome::Quarantined(qid) => {
+                                                println!("quarantined {} from unknown peer {}", qid, peer)
+                                            }
+                                            InboundOutcome::Refused => println!("refused unknown peer {}", peer),
+                                            InboundOutcome::Blocked => println!("refused blocked peer {}", peer),
+                                            InboundOutcome::Muted => {}
+                                            InboundOutcome::StampRequired(bits) => {
+                                                println!("asked unknown peer {} for a {}-bit stamp", peer, bits)
+                                            }
+                                            InboundOutcome::Throttled(throttle) => {
+                                                log::debug!("throttled request from {peer}: {throttle:?}")
+                                            }
+                                            InboundOutcome::Replay => println!("replay detected (nonce)"),
+                                            InboundOutcome::Synced(sid) => println!("synced sent message {} from another device", sid),
+                                            InboundOutcome::KeysRotated { contact_id, staged: false } => {
+                                                println!("contact {} rotated its keys; compare the new safety number", contact_id)
+                                            }
+                                            InboundOutcome::KeysRotated { contact_id, staged: true } => {
+                                                println!("contact {} announced new keys; review with `contacts key-changes`", contact_id)
+                                            }
+                                            InboundOutcome::GroupDelivered { group_id, contact_id, plaintext, .. } => {
+                                                println!("group {} from {}: {}", group_id, contact_id, String::from_utf8_lossy(plaintext))
+                                            }
+                                            InboundOutcome::Group(event) => match event {
+                                                crate::groups::GroupEvent::Joined(g) => println!("added to group {}", g),
+                                                crate::groups::GroupEvent::Updated(g) => println!("group {} updated", g),
+                                                crate::groups::GroupEvent::Removed(g) => println!("removed from group {}", g),
+                                                crate::groups::GroupEvent::Held(g) => {
+                                                    println!("holding a message for group {} until its key arrives", g)
                                                 }
-                                            } else {
-                                                // Fallback to plain text
-                                                let txt = String::from_utf8_lossy(&request);
-                                                println!("received: {}", txt);
-                                                b"ACK".to_vec()
-                                            };
-                                            let _ = swarm.behaviour_mut().request_response.send_response(channel, response);
+                                            },
+                                            InboundOutcome::Rejected(why) => println!("received: <{}>", why),
+                                            InboundOutcome::PlainText(bytes) => {
+                                                println!("received: {}", String::from_utf8_lossy(bytes))
+                                            }
                                         }
-                                        libp2p::request_response::Message::<Vec<u8>, Vec<u8>>::Response { .. } => {}
+                                        let _ = swarm.behaviour_mut().request_response.send_response(channel, outcome.response());
                                     }
                                 }
-                                NodeBehaviourEvent::Mdns(event) => {
-                                    if mdns || cfg.enable_mdns {
-                                        match event {
-                                            libp2p::mdns::Event::Discovered(list) => {
-                                                for (peer, addr) in list {
-                                                    println!("mdns: discovered {peer} at {addr}");
-                                                }
-                                            }
-                                            libp2p::mdns::Event::Expired(list) => {
-                                                for (peer, addr) in list {
-                                                    println!("mdns: expired {peer} at {addr}");
+                                NodeBehaviourEvent::Prekeys(
+                                    libp2p::request_response::Event::<Vec<u8>, Vec<u8>>::Message {
+                                        peer,
+                                        message:
+                                            libp2p::request_response::Message::<Vec<u8>, Vec<u8>>::Request {
+                                                request,
+                                                channel,
+                                                ..
+                                            },
+                                    },
+                                ) => {
+                                    use crate::messaging::inbound::{self, InboundOutcome};
+                                    // Bundle requests go through the same limits and screening
+                                    // as messages; an empty response tells the peer we have no
+                                    // bundle for it
+                                    let screened = match limiter.check_request(
+                                        &peer.to_string(),
+                                        request.len(),
+                                        std::time::Instant::now(),
+                                    ) {
+                                        Err(throttle) => Some(InboundOutcome::Throttled(throttle)),
+                                        Ok(()) => inbound::screen_prekey_request(
+                                            &cfg.data_dir,
+                                            &peer.to_string(),
+                                            policy,
+                                        )
+                                        .unwrap_or_else(|e| {
+                                            log::warn!("prekey request from {peer}: {e}");
+                                            Some(InboundOutcome::Rejected(e.to_string()))
+                                        }),
+                                    };
+                                    let response = match screened {
+                                        Some(outcome) => {
+                                            log::debug!("prekey request from {peer}: {outcome:?}");
+                                            metrics.count_inbound(&outcome);
+                                            Vec::new()
+                                        }
+                                        None => crate::session::prekeys::serve_request(
+                                            &cfg.data_dir,
+                                            &id,
+                                            &request,
+                                        )
+                                        .unwrap_or_else(|e| {
+                                            log::warn!("prekey request from {peer}: {e}");
+                                            metrics.count_inbound(&InboundOutcome::Rejected(e.to_string()));
+                                            Vec::new()
+                                        }),
+                                    };
+                                    let _ = swarm.behaviour_mut().prekeys.send_response(channel, response);
+                                }
+                                NodeBehaviourEvent::Mdns(event) => match event {
+                                    libp2p::mdns::Event::Discovered(list) => {
+                                        for (peer, addr) in list {
+                                            match crate::discovery::apply_discovery(
+                                                &cfg.data_dir,
+                                                &peer.to_string(),
+                                                &addr.to_string(),
+                                            ) {
+                                                Ok(crate::discovery::Discovery::ContactUpdated {
+                                                    contact_id,
+                                                    addr,
+                                                }) => println!(
+                                                    "mdns: contact {contact_id} now at {addr}"
+                                                ),
+                                                Ok(crate::discovery::Discovery::ContactUnchanged {
+                                                    ..
+                                                }) => {}
+                                                Ok(crate::discovery::Discovery::Nearby(_)) => {
+                                                    println!("mdns: nearby peer {peer} at {addr}")
                                                 }
+                                                Err(e) => log::warn!("mdns: discovery for {peer}: {e}"),
                                             }
                                         }
                                     }
-                                }
+                                    libp2p::mdns::Event::Expired(list) => {
+                                        for (peer, addr) in list {
+                                            let _ = crate::discovery::apply_expiry(
+                                                &cfg.data_dir,
+                                                &peer.to_string(),
+                                                &addr.to_string(),
+                                            );
+                                            println!("mdns: expired {peer} at {addr}");
+                                        }
+                                    }
+                                },
                                 _ => {}
                             }
                         }
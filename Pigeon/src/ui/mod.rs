@@ -11,18 +11,20 @@ pub fn resolve_contact_or_args(
     pubkey_hex: Option<&str>,
 ) -> Result<(String, sodiumoxide::crypto::box_::PublicKey), crate::error::Error> {
     if let Some(sel) = contact {
-        let store = crate::storage::contacts::ContactStore::open_in_dir(data_dir)
-            .map_err(crate::error::Error::Storage)?;
-        let found = if let Ok(id) = sel.parse::<u64>() {
-            store.get(id).map_err(crate::error::Error::Storage)?
-        } else {
-            let list = store.list().map_err(crate::error::Error::Storage)?;
-            list.into_iter().find(|c| c.name.eq_ignore_ascii_case(sel))
-        };
-        let c = found.ok_or_else(|| crate::error::Error::Config("contact not found".into()))?;
+        let c = find_contact(data_dir, sel)?
+            .ok_or_else(|| crate::error::Error::Config("contact not found".into()))?;
+        if c.send_blocked() {
+            return Err(crate::api::key_change_pending(&c));
+        }
+        warn_if_unverified(&c);
         let pk = sodiumoxide::crypto::box_::PublicKey::from_slice(&c.public_key)
             .ok_or_else(|| crate::error::Error::Config("contact has invalid pubkey".into()))?;
-        Ok((c.addr, pk))
+        // Pin the dial to the contact's PeerId so Noise rejects anyone else at that address
+        let addr = match &c.peer_id {
+            Some(peer) => crate::discovery::with_peer_suffix(&c.addr, peer),
+            None => c.addr,
+        };
+        Ok((addr, pk))
     } else {
         let addr =
             to.ok_or_else(|| crate::error::Error::Config("--to or --contact required".into()))?;
@@ -36,3 +38,28 @@ pub fn resolve_contact_or_args(
         Ok((addr.to_string(), pk))
     }
 }
+
+/// Look up a contact by id or case-insensitive name.
+pub fn find_contact(
+    data_dir: &Path,
+    sel: &str,
+) -> Result<Option<crate::storage::contacts::Contact>, crate::error::Error> {
+    let store = crate::storage::contacts::ContactStore::open_in_dir(data_dir)
+        .map_err(crate::error::Error::Storage)?;
+    if let Ok(id) = sel.parse::<u64>() {
+        store.get(id).map_err(crate::error::Error::Storage)
+    } else {
+        let list = store.list().map_err(crate::error::Error::Storage)?;
+        Ok(list.into_iter().find(|c| c.name.eq_ignore_ascii_case(sel)))
+    }
+}
+
+/// Nudge the user to compare safety numbers before trusting a contact's key.
+pub fn warn_if_unverified(c: &crate::storage::contacts::Contact) {
+    if !c.verified {
+        eprintln!(
+            "warning: contact {} ({}) is not verified; compare the safety number from `contacts show {}` and run `contacts verify {}`",
+            c.name, c.id, c.id, c.id
+        );
+    }
+}
@@ -18,7 +18,7 @@ fn set_passphrase_creates_encrypted_keyfile() {
     // Check magic header
     let bytes = std::fs::read(enc).unwrap();
     assert!(bytes.len() > 4 + 16 + 24);
-    assert_eq!(&bytes[0..4], b"PGN1");
+    assert_eq!(&bytes[0..4], b"PGN3");
 }
 
 #[test]
@@ -70,3 +70,164 @@ fn load_or_create_env_unlocks() {
     // Cleanup env to avoid leaking into other tests
     std::env::remove_var("PIGEON_PASSPHRASE");
 }
+
+#[test]
+#[serial]
+fn lock_forgets_the_unlocked_key() {
+    sodiumoxide::init().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let data_dir = dir.path();
+
+    secure_p2p_msg::storage::at_rest::set_passphrase_and_seal(data_dir, "pw-lock").unwrap();
+    secure_p2p_msg::storage::at_rest::unlock_with_passphrase(data_dir, "pw-lock").unwrap();
+    assert!(secure_p2p_msg::storage::at_rest::AtRestKey::load_or_create(data_dir).is_ok());
+
+    secure_p2p_msg::storage::at_rest::lock(data_dir).unwrap();
+    assert!(secure_p2p_msg::storage::at_rest::AtRestKey::load_or_create(data_dir).is_err());
+    // Nothing left to hand to the keyring either
+    assert!(secure_p2p_msg::storage::at_rest::remember_in_keyring(
+        data_dir,
+        std::time::Duration::from_secs(60)
+    )
+    .is_err());
+}
+
+#[test]
+#[serial]
+fn change_passphrase_rewraps_the_same_key_with_new_kdf_params() {
+    use secure_p2p_msg::storage::at_rest::{self, KdfParams};
+    sodiumoxide::init().unwrap();
+    std::env::remove_var("PIGEON_PASSPHRASE");
+    let dir = tempfile::tempdir().unwrap();
+    let data_dir = dir.path();
+
+    at_rest::set_passphrase_and_seal(data_dir, "old-pw").unwrap();
+    assert_eq!(at_rest::kdf_params(data_dir).unwrap(), Some(KdfParams::default()));
+    let key = at_rest::AtRestKey::load_or_create(data_dir).unwrap();
+    let record = at_rest::encrypt(&key, b"kept as is").unwrap();
+
+    let stronger = KdfParams {
+        memory_kib: 32 * 1024,
+        iterations: 3,
+        parallelism: 1,
+    };
+    at_rest::change_passphrase(data_dir, "new-pw", &stronger).unwrap();
+    assert_eq!(at_rest::kdf_params(data_dir).unwrap(), Some(stronger));
+
+    // A fresh process only gets in with the new passphrase, and finds the same key
+    at_rest::lock(data_dir).unwrap();
+    assert!(at_rest::unlock_with_passphrase(data_dir, "old-pw").is_err());
+    let reopened = at_rest::unlock_with_passphrase(data_dir, "new-pw").unwrap();
+    assert_eq!(reopened.key_id(), key.key_id());
+    assert_eq!(at_rest::decrypt(&reopened, &record).unwrap(), b"kept as is");
+}
+
+#[test]
+#[serial]
+fn key_files_asking_for_excessive_kdf_costs_are_refused() {
+    use secure_p2p_msg::storage::at_rest::{self, KdfParams, MAX_KDF_MEMORY_KIB};
+    sodiumoxide::init().unwrap();
+    std::env::remove_var("PIGEON_PASSPHRASE");
+    let dir = tempfile::tempdir().unwrap();
+    let data_dir = dir.path();
+
+    let too_costly = KdfParams {
+        memory_kib: MAX_KDF_MEMORY_KIB + 1,
+        ..KdfParams::default()
+    };
+    assert!(at_rest::set_passphrase_with_params(data_dir, "pw", &too_costly).is_err());
+
+    // A key file edited to ask for 4 TiB is refused without running the KDF
+    at_rest::set_passphrase_and_seal(data_dir, "pw").unwrap();
+    at_rest::lock(data_dir).unwrap();
+    let path = data_dir.join("at_rest.key.enc");
+    let mut bytes = std::fs::read(&path).unwrap();
+    bytes[4..8].copy_from_slice(&u32::MAX.to_be_bytes());
+    std::fs::write(&path, &bytes).unwrap();
+    let Err(err) = at_rest::unlock_with_passphrase(data_dir, "pw") else {
+        panic!("unlocked a key file with excessive kdf costs");
+    };
+    assert!(err.to_string().contains("unsupported kdf parameters"), "{err}");
+    assert!(at_rest::kdf_params(data_dir).is_err());
+}
+
+#[test]
+#[serial]
+fn remove_passphrase_goes_back_to_a_plain_key_file() {
+    use secure_p2p_msg::identity::Identity;
+    use secure_p2p_msg::storage::at_rest;
+    sodiumoxide::init().unwrap();
+    std::env::remove_var("PIGEON_PASSPHRASE");
+    let dir = tempfile::tempdir().unwrap();
+    let data_dir = dir.path();
+    let before = Identity::load_or_generate(data_dir).unwrap();
+
+    at_rest::set_passphrase_and_seal(data_dir, "pw").unwrap();
+    let key = at_rest::AtRestKey::load_or_create(data_dir).unwrap();
+    let record = at_rest::encrypt(&key, b"still readable").unwrap();
+    at_rest::lock(data_dir).unwrap();
+    assert!(at_rest::remove_passphrase(data_dir).is_err(), "needs an unlock");
+
+    at_rest::unlock_with_passphrase(data_dir, "pw").unwrap();
+    at_rest::remove_passphrase(data_dir).unwrap();
+    assert!(!data_dir.join(at_rest::ENC_KEY_FILE).exists());
+    assert!(!std::fs::read(data_dir.join("identity.bin"))
+        .unwrap()
+        .starts_with(b"PGS1"));
+
+    at_rest::lock(data_dir).unwrap();
+    let plain = at_rest::AtRestKey::load_or_create(data_dir).unwrap();
+    assert_eq!(at_rest::decrypt(&plain, &record).unwrap(), b"still readable");
+    assert_eq!(Identity::load_or_generate(data_dir).unwrap().sign_pk, before.sign_pk);
+}
+
+#[test]
+#[serial]
+fn repeated_wrong_passphrases_are_throttled_across_restarts() {
+    use secure_p2p_msg::storage::{at_rest, Error};
+    sodiumoxide::init().unwrap();
+    std::env::remove_var("PIGEON_PASSPHRASE");
+    let dir = tempfile::tempdir().unwrap();
+    let data_dir = dir.path();
+    at_rest::set_passphrase_and_seal(data_dir, "right").unwrap();
+    at_rest::lock(data_dir).unwrap();
+
+    for _ in 0..at_rest::FREE_UNLOCK_ATTEMPTS {
+        assert!(matches!(
+            at_rest::unlock_with_passphrase(data_dir, "wrong"),
+            Err(Error::WrongPassphrase)
+        ));
+    }
+    // The counter lives in the data dir, so the next try waits even with the right one
+    let wait = at_rest::unlock_retry_after(data_dir).expect("throttled");
+    assert!((1..=at_rest::MAX_UNLOCK_DELAY_SECS).contains(&wait));
+    assert!(matches!(
+        at_rest::unlock_with_passphrase(data_dir, "right"),
+        Err(Error::UnlockThrottled(_))
+    ));
+    assert!(data_dir.join("unlock_attempts").exists());
+}
+
+#[test]
+#[serial]
+fn auto_lock_forgets_an_idle_key() {
+    use secure_p2p_msg::storage::at_rest;
+    use std::time::Duration;
+    sodiumoxide::init().unwrap();
+    std::env::remove_var("PIGEON_PASSPHRASE");
+    let dir = tempfile::tempdir().unwrap();
+    let data_dir = dir.path();
+    at_rest::set_passphrase_and_seal(data_dir, "pw").unwrap();
+    assert!(at_rest::is_unlocked(data_dir));
+
+    at_rest::set_auto_lock(data_dir, Some(Duration::from_millis(200)));
+    std::thread::sleep(Duration::from_millis(50));
+    assert!(at_rest::AtRestKey::load_or_create(data_dir).is_ok());
+    std::thread::sleep(Duration::from_millis(300));
+    assert!(!at_rest::is_unlocked(data_dir));
+    assert!(at_rest::AtRestKey::load_or_create(data_dir).is_err());
+    at_rest::set_auto_lock(data_dir, None);
+
+    at_rest::unlock_with_passphrase(data_dir, "pw").unwrap();
+    assert!(at_rest::is_unlocked(data_dir));
+}
@@ -0,0 +1,89 @@
+use secure_p2p_msg::storage::at_rest::{self, AtRestKey};
+use sodiumoxide::crypto::secretbox;
+
+#[test]
+fn records_carry_a_header_naming_their_key() {
+    sodiumoxide::init().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let key = AtRestKey::load_or_create(dir.path()).unwrap();
+
+    let sealed = at_rest::encrypt(&key, b"hello").unwrap();
+    assert_eq!(&sealed[0..3], b"PGR");
+    assert_eq!(sealed[3], at_rest::RECORD_VERSION);
+    assert_eq!(sealed[4], at_rest::ALG_SECRETBOX);
+    assert_eq!(at_rest::record_key_id(&sealed).unwrap(), Some(key.key_id()));
+    assert_eq!(key.key_id(), at_rest::key_id(key.key()));
+    assert_eq!(at_rest::decrypt(&key, &sealed).unwrap(), b"hello");
+
+    // Another store's key is reported by id rather than as a generic failure
+    let other_dir = tempfile::tempdir().unwrap();
+    let other = AtRestKey::load_or_create(other_dir.path()).unwrap();
+    let err = at_rest::decrypt(&other, &sealed).unwrap_err();
+    assert!(err.to_string().contains(&format!("{:08x}", key.key_id())));
+}
+
+#[test]
+fn records_from_a_newer_format_are_reported_as_unsupported() {
+    sodiumoxide::init().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let key = AtRestKey::load_or_create(dir.path()).unwrap();
+    let sealed = at_rest::encrypt(&key, b"hello").unwrap();
+
+    let mut newer = sealed.clone();
+    newer[3] = at_rest::RECORD_VERSION + 1;
+    let err = at_rest::record_key_id(&newer).unwrap_err();
+    assert!(err.to_string().contains("unsupported record version"), "{err}");
+    let err = at_rest::decrypt(&key, &newer).unwrap_err();
+    assert!(err.to_string().contains("unsupported record version"), "{err}");
+
+    let mut other_alg = sealed;
+    other_alg[4] = at_rest::ALG_SECRETBOX + 1;
+    let err = at_rest::decrypt(&key, &other_alg).unwrap_err();
+    assert!(err.to_string().contains("unsupported record algorithm"), "{err}");
+}
+
+#[test]
+fn legacy_headerless_records_still_open() {
+    sodiumoxide::init().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let key = AtRestKey::load_or_create(dir.path()).unwrap();
+
+    let nonce = secretbox::gen_nonce();
+    let mut legacy = nonce.0.to_vec();
+    legacy.extend_from_slice(&secretbox::seal(b"written before headers", &nonce, key.key()));
+    assert_eq!(at_rest::record_key_id(&legacy).unwrap(), None);
+    assert_eq!(
+        at_rest::decrypt(&key, &legacy).unwrap(),
+        b"written before headers"
+    );
+}
+
+#[test]
+#[serial_test::serial]
+fn stores_seal_under_their_own_data_dir_key() {
+    use secure_p2p_msg::storage::{contacts::ContactStore, queue::MessageQueue};
+    sodiumoxide::init().unwrap();
+    // The configured data dir is locked, so nothing may fall back to its key
+    let configured = tempfile::tempdir().unwrap();
+    std::env::set_var("PIGEON_DATA_DIR", configured.path());
+    std::env::remove_var("PIGEON_PASSPHRASE");
+    at_rest::set_passphrase_and_seal(configured.path(), "pw").unwrap();
+    at_rest::lock(configured.path()).unwrap();
+
+    let dir = tempfile::tempdir().unwrap();
+    let key_id = AtRestKey::load_or_create(dir.path()).unwrap().key_id();
+    {
+        let store = ContactStore::open_in_dir(dir.path()).unwrap();
+        store
+            .add("Dana", "/ip4/10.0.0.4/tcp/4001", &"11".repeat(32))
+            .unwrap();
+        assert_eq!(store.list().unwrap().len(), 1);
+        let q = MessageQueue::open_in_dir(dir.path()).unwrap();
+        q.store_inbox(uuid::Uuid::new_v4(), b"hi".to_vec()).unwrap();
+        assert_eq!(q.list_inbox().unwrap()[0].1, b"hi");
+    }
+    let db = sled::open(dir.path().join("contacts_db")).unwrap();
+    let (_, record) = db.iter().next().unwrap().unwrap();
+    assert_eq!(at_rest::record_key_id(&record).unwrap(), Some(key_id));
+    std::env::remove_var("PIGEON_DATA_DIR");
+}
@@ -0,0 +1,99 @@
+use secure_p2p_msg::api::Core;
+use secure_p2p_msg::storage::queue::MessageQueue;
+use serial_test::serial;
+
+const PK: &str = "0101010101010101010101010101010101010101010101010101010101010101";
+
+fn copy_dir(from: &std::path::Path, to: &std::path::Path) {
+    for entry in std::fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        let target = to.join(entry.file_name());
+        if entry.file_type().unwrap().is_dir() {
+            std::fs::create_dir_all(&target).unwrap();
+            copy_dir(&entry.path(), &target);
+        } else {
+            std::fs::copy(entry.path(), target).unwrap();
+        }
+    }
+}
+
+/// A data dir with a passphrase, a few contacts and an inbox message.
+fn populated(dir: &std::path::Path) -> Core {
+    let core = Core::with_data_dir(dir);
+    core.ensure_identity_and_preview().unwrap();
+    core.set_passphrase("p1").unwrap();
+    for name in ["alice", "bob", "carol"] {
+        core.contacts_add(name, "/ip4/127.0.0.1/tcp/4001", PK).unwrap();
+    }
+    let q = MessageQueue::open_in_dir(dir).unwrap();
+    q.store_inbox(uuid::Uuid::new_v4(), b"received before rotation".to_vec())
+        .unwrap();
+    core
+}
+
+/// Open `dir` from scratch with `passphrase`, as a new process would.
+fn reopen(dir: &std::path::Path, passphrase: &str) -> Core {
+    let core = Core::with_data_dir(dir);
+    core.unlock(passphrase).unwrap();
+    core
+}
+
+#[test]
+#[serial]
+fn rotation_reseals_stored_records() {
+    sodiumoxide::init().unwrap();
+    std::env::remove_var("PIGEON_PASSPHRASE");
+    let dir = tempfile::tempdir().unwrap();
+    let core = populated(dir.path());
+
+    let mut reports = Vec::new();
+    let resealed = core
+        .rotate_at_rest_key_with_progress("p2", |p| reports.push(p.clone()))
+        .unwrap();
+    assert_eq!(resealed, 4);
+    assert!(reports.iter().any(|p| p.database == "contacts_db" && p.done == p.total));
+    assert!(!core.at_rest_rotation_pending().unwrap());
+
+    let copy = tempfile::tempdir().unwrap();
+    copy_dir(dir.path(), copy.path());
+    let fresh = reopen(copy.path(), "p2");
+    assert_eq!(fresh.contacts_list().unwrap().len(), 3);
+    assert_eq!(fresh.inbox_list().unwrap()[0].1, b"received before rotation");
+}
+
+#[test]
+#[serial]
+fn interrupted_rotation_stays_readable_and_resumes() {
+    sodiumoxide::init().unwrap();
+    std::env::remove_var("PIGEON_PASSPHRASE");
+    let dir = tempfile::tempdir().unwrap();
+    let core = populated(dir.path());
+
+    // Crash once contacts_db is re-sealed and flushed, before queue_db is touched
+    let crashed = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        core.rotate_at_rest_key_with_progress("p2", |p| {
+            if p.database == "contacts_db" && p.done == p.total {
+                panic!("simulated crash");
+            }
+        })
+    }));
+    assert!(crashed.is_err());
+
+    // A new process sees both keys: contacts under the new one, the inbox under the old
+    let copy = tempfile::tempdir().unwrap();
+    copy_dir(dir.path(), copy.path());
+    let fresh = reopen(copy.path(), "p2");
+    assert!(fresh.at_rest_rotation_pending().unwrap());
+    assert_eq!(fresh.contacts_list().unwrap().len(), 3);
+    assert_eq!(fresh.inbox_list().unwrap().len(), 1);
+
+    let resealed = fresh.resume_at_rest_rotation(|_| {}).unwrap();
+    assert_eq!(resealed, 1);
+    assert!(!fresh.at_rest_rotation_pending().unwrap());
+
+    let again = tempfile::tempdir().unwrap();
+    copy_dir(copy.path(), again.path());
+    let done = reopen(again.path(), "p2");
+    assert_eq!(done.contacts_list().unwrap().len(), 3);
+    assert_eq!(done.inbox_list().unwrap()[0].1, b"received before rotation");
+}
@@ -0,0 +1,90 @@
+use secure_p2p_msg::api::Core;
+use secure_p2p_msg::settings::Profile;
+use secure_p2p_msg::storage::queue::MessageQueue;
+
+const PK_HEX: &str = "0202020202020202020202020202020202020202020202020202020202020202";
+
+#[test]
+fn backup_restores_identity_contacts_inbox_and_settings() {
+    sodiumoxide::init().unwrap();
+    let src = tempfile::tempdir().unwrap();
+    let dst = tempfile::tempdir().unwrap();
+    let out = src.path().join("pigeon.backup");
+
+    let old = Core::with_data_dir(src.path());
+    let me = old.ensure_identity_and_preview().unwrap();
+    old.contacts_add("Carol", "/ip4/10.0.0.3/tcp/4001", PK_HEX)
+        .unwrap();
+    old.set_profile(&Profile {
+        display_name: "Me".into(),
+        advertised_addrs: vec![],
+    })
+    .unwrap();
+    {
+        let q = MessageQueue::open_in_dir(src.path()).unwrap();
+        q.store_inbox(uuid::Uuid::new_v4(), b"kept".to_vec()).unwrap();
+    }
+    let manifest = old.backup_create(&out, "backup-pw").unwrap();
+    assert!(manifest.files.iter().any(|f| f == "identity.bin"));
+    assert!(manifest
+        .databases
+        .iter()
+        .any(|d| d.name == "contacts_db" && d.records == 1));
+
+    let new = Core::with_data_dir(dst.path());
+    assert!(new.backup_restore(&out, "wrong", false).is_err());
+    new.backup_restore(&out, "backup-pw", false).unwrap();
+    assert_eq!(new.ensure_identity_and_preview().unwrap(), me);
+    assert_eq!(new.contacts_list().unwrap()[0].name, "Carol");
+    assert_eq!(new.get_profile().unwrap().display_name, "Me");
+    let inbox = new.inbox_list().unwrap();
+    assert_eq!(inbox.len(), 1);
+    assert_eq!(inbox[0].1, b"kept");
+
+    // Restoring over an existing identity needs an explicit overwrite
+    assert!(new.backup_restore(&out, "backup-pw", false).is_err());
+    new.contacts_add("Dave", "/ip4/10.0.0.4/tcp/4001", &"03".repeat(32))
+        .unwrap();
+    new.backup_restore(&out, "backup-pw", true).unwrap();
+    assert_eq!(new.contacts_list().unwrap().len(), 1);
+}
+
+#[test]
+fn identity_export_is_not_accepted_as_a_backup() {
+    sodiumoxide::init().unwrap();
+    let src = tempfile::tempdir().unwrap();
+    let out = src.path().join("me.pgar");
+    let core = Core::with_data_dir(src.path());
+    core.ensure_identity_and_preview().unwrap();
+    core.export_identity(&out, "pw", false).unwrap();
+
+    let dst = tempfile::tempdir().unwrap();
+    assert!(Core::with_data_dir(dst.path())
+        .backup_restore(&out, "pw", false)
+        .is_err());
+}
+
+#[test]
+fn failed_restore_leaves_the_data_dir_as_it_was() {
+    sodiumoxide::init().unwrap();
+    let src = tempfile::tempdir().unwrap();
+    let out = src.path().join("pigeon.backup");
+    let old = Core::with_data_dir(src.path());
+    old.ensure_identity_and_preview().unwrap();
+    old.contacts_add("Carol", "/ip4/10.0.0.3/tcp/4001", PK_HEX)
+        .unwrap();
+    old.backup_create(&out, "backup-pw").unwrap();
+
+    let dst = tempfile::tempdir().unwrap();
+    let new = Core::with_data_dir(dst.path());
+    let me = new.ensure_identity_and_preview().unwrap();
+    new.contacts_add("Dave", "/ip4/10.0.0.4/tcp/4001", &"03".repeat(32))
+        .unwrap();
+    // Something in the way of staging the contacts database
+    std::fs::write(dst.path().join("contacts_db.partial"), b"not a database").unwrap();
+
+    assert!(new.backup_restore(&out, "backup-pw", true).is_err());
+    assert_eq!(new.ensure_identity_and_preview().unwrap(), me);
+    assert_eq!(new.contacts_list().unwrap()[0].name, "Dave");
+    assert!(!dst.path().join("identity.bin.partial").exists());
+}
@@ -0,0 +1,80 @@
+use std::path::Path;
+use std::process::{Command, Output};
+
+/// Run the binary against `dir`, with the config file kept under it too. The data dir
+/// comes from the environment, as it does for a default install.
+fn pigeon(dir: &Path, passphrase: Option<&str>, args: &[&str]) -> Output {
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_secure-p2p-msg"));
+    cmd.args(args)
+        .env("HOME", dir)
+        .env("XDG_CONFIG_HOME", dir.join("config"))
+        .env("PIGEON_DATA_DIR", dir.join("data"))
+        .env_remove("PIGEON_PASSPHRASE");
+    if let Some(pass) = passphrase {
+        cmd.env("PIGEON_PASSPHRASE", pass);
+    }
+    cmd.output().expect("run secure-p2p-msg")
+}
+
+fn assert_ok(out: &Output) {
+    assert!(
+        out.status.success(),
+        "exit {:?}\nstdout: {}\nstderr: {}",
+        out.status.code(),
+        String::from_utf8_lossy(&out.stdout),
+        String::from_utf8_lossy(&out.stderr)
+    );
+}
+
+#[test]
+fn commands_run_after_a_passphrase_is_set() {
+    let dir = tempfile::tempdir().unwrap();
+    assert_ok(&pigeon(dir.path(), None, &["identity"]));
+    assert_ok(&pigeon(dir.path(), Some("hunter2"), &["security", "set-passphrase"]));
+
+    // Commands that do not need the key run while it is locked
+    assert_ok(&pigeon(dir.path(), None, &["security", "lock"]));
+    let out = pigeon(dir.path(), None, &["security", "unlock"]);
+    assert!(!String::from_utf8_lossy(&out.stderr).contains("panicked"));
+
+    assert_ok(&pigeon(dir.path(), Some("hunter2"), &["security", "unlock"]));
+    assert_ok(&pigeon(dir.path(), Some("hunter2"), &["identity"]));
+}
+
+#[test]
+fn identity_imports_into_an_empty_data_dir() {
+    let from = tempfile::tempdir().unwrap();
+    let to = tempfile::tempdir().unwrap();
+    let archive = from.path().join("me.pgar");
+    let archive = archive.to_str().unwrap();
+    assert_ok(&pigeon(from.path(), None, &["identity"]));
+    assert_ok(&pigeon(from.path(), Some("archive-pw"), &["identity", "export", "--out", archive]));
+
+    let out = pigeon(to.path(), Some("archive-pw"), &["identity", "import", archive]);
+    assert_ok(&out);
+    assert!(String::from_utf8_lossy(&out.stdout).starts_with("imported identity"));
+    let exported = std::fs::read(from.path().join("data/identity.bin")).unwrap();
+    let imported = std::fs::read(to.path().join("data/identity.bin")).unwrap();
+    assert_eq!(exported, imported);
+
+    // A second import needs --force
+    assert!(!pigeon(to.path(), Some("archive-pw"), &["identity", "import", archive])
+        .status
+        .success());
+}
+
+#[test]
+fn backup_restores_into_an_empty_data_dir() {
+    let from = tempfile::tempdir().unwrap();
+    let to = tempfile::tempdir().unwrap();
+    let backup = from.path().join("pigeon.backup");
+    let backup = backup.to_str().unwrap();
+    assert_ok(&pigeon(from.path(), None, &["identity"]));
+    assert_ok(&pigeon(from.path(), Some("backup-pw"), &["backup", "create", "--out", backup]));
+
+    assert_ok(&pigeon(to.path(), Some("backup-pw"), &["backup", "restore", backup]));
+    let before = std::fs::read(from.path().join("data/identity.bin")).unwrap();
+    let after = std::fs::read(to.path().join("data/identity.bin")).unwrap();
+    assert_eq!(before, after);
+    assert!(!to.path().join("data/contacts_db.partial").exists());
+}
@@ -0,0 +1,158 @@
+//! Fixtures shared by the integration tests: installs with a data dir and identity of their
+//! own, contacts between them, and requests delivered through the inbound path.
+#![allow(dead_code)] // each test file uses its own subset
+
+use secure_p2p_msg::api::Core;
+use secure_p2p_msg::config::UnknownPeerPolicy;
+use secure_p2p_msg::contact_card::ContactCard;
+use secure_p2p_msg::identity::Identity;
+use secure_p2p_msg::messaging::inbound::{handle_request, InboundOutcome};
+use secure_p2p_msg::messaging::message::{timestamped_nonce, EnvelopeV1};
+use secure_p2p_msg::session::{self, prekeys};
+use secure_p2p_msg::settings::Profile;
+use secure_p2p_msg::storage::contacts::Contact;
+use sodiumoxide::crypto::{box_, sign};
+
+pub const PEER_A: &str = "12D3KooWQYhTNQdmr3ArTeUHRYzFg94BKyTkoWBDWez9kSCVe2Xo";
+pub const PEER_B: &str = "12D3KooWRBhwfeP2Y4TCx1SM6s9rUoHhR5STiGwxBhgFRcw3UERE";
+pub const PEER_C: &str = "12D3KooWJWoaqZhDaoEFshF7Rh1bpY9ohihFhzcW6d69Lr2NASuq";
+
+/// One install: its data dir, its identity and the PeerId it dials from.
+pub struct Side {
+    pub dir: tempfile::TempDir,
+    pub id: Identity,
+    pub peer: &'static str,
+}
+
+impl Side {
+    pub fn core(&self) -> Core {
+        Core::with_data_dir(self.dir.path())
+    }
+}
+
+pub fn side(peer: &'static str) -> Side {
+    let dir = tempfile::tempdir().unwrap();
+    let id = Identity::load_or_generate(dir.path()).unwrap();
+    Side { dir, id, peer }
+}
+
+/// A side with a profile, so the card it makes for itself carries a name and an address.
+pub fn named_side(name: &str, peer: &'static str) -> Side {
+    let side = side(peer);
+    side.core()
+        .set_profile(&Profile {
+            display_name: name.into(),
+            advertised_addrs: vec![format!("/ip4/10.0.0.5/tcp/4001/p2p/{peer}")],
+        })
+        .unwrap();
+    side
+}
+
+/// Import the card `other` makes for itself (see `named_side`) on `me`.
+pub fn import_card(me: &Side, other: &Side) -> Contact {
+    let card = other.core().my_contact_card().unwrap();
+    me.core()
+        .contacts_import_card(&card.to_uri().unwrap())
+        .unwrap()
+}
+
+/// Import `other`'s card on `me` and return the resulting contact.
+pub fn befriend(me: &Side, other: &Side, name: &str) -> Contact {
+    let card = ContactCard::new_signed(
+        &other.id,
+        name,
+        Some(other.peer.to_string()),
+        vec![format!("/ip4/10.0.0.5/tcp/4001/p2p/{}", other.peer)],
+    )
+    .unwrap();
+    me.core()
+        .contacts_import_card(&card.to_uri().unwrap())
+        .unwrap()
+}
+
+/// `befriend`, plus the prekey bundle a session with `other` starts from.
+pub fn befriend_with_bundle(me: &Side, other: &Side, name: &str) -> Contact {
+    let contact = befriend(me, other, name);
+    store_bundle(me, &contact, other);
+    contact
+}
+
+/// Keep the bundle `other` serves as `contact`'s on `me`, as `prekeys fetch` does.
+pub fn store_bundle(me: &Side, contact: &Contact, other: &Side) {
+    let bundle =
+        prekeys::serve_request(other.dir.path(), &other.id, prekeys::BUNDLE_REQUEST).unwrap();
+    me.core()
+        .contacts_store_prekey_bundle(contact.id, &bundle)
+        .unwrap();
+}
+
+/// A session message from `from` to `to`, serialized for the wire.
+pub fn seal(from: &Side, to: &Contact, body: &[u8]) -> Vec<u8> {
+    let env = session::seal_for_contact(from.dir.path(), &from.id, to, body).unwrap();
+    bincode::serialize(&env).unwrap()
+}
+
+pub fn deliver(to: &Side, from_peer: &str, req: &[u8]) -> InboundOutcome {
+    handle_request(
+        to.dir.path(),
+        &to.id,
+        from_peer,
+        req,
+        UnknownPeerPolicy::Refuse,
+    )
+    .unwrap()
+}
+
+pub fn delivered_text(out: InboundOutcome) -> Vec<u8> {
+    match out {
+        InboundOutcome::Delivered { plaintext, .. } => plaintext,
+        other => panic!("expected delivery, got {other:?}"),
+    }
+}
+
+pub fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// A box envelope sealed now, with a blank signature: receivers that have no signing key
+/// for the sender only check the box.
+pub fn envelope(
+    sender_sk: &box_::SecretKey,
+    recipient_pk: &box_::PublicKey,
+    body: &[u8],
+) -> Vec<u8> {
+    envelope_at(sender_sk, recipient_pk, body, now())
+}
+
+/// `envelope`, sealed at `sent_at`.
+pub fn envelope_at(
+    sender_sk: &box_::SecretKey,
+    recipient_pk: &box_::PublicKey,
+    body: &[u8],
+    sent_at: u64,
+) -> Vec<u8> {
+    let nonce = box_::Nonce(timestamped_nonce(sent_at));
+    let ct = box_::seal(body, &nonce, recipient_pk, sender_sk);
+    bincode::serialize(&EnvelopeV1::new(0, 0, nonce.0, ct, vec![0u8; 64])).unwrap()
+}
+
+/// A box envelope from `from`, signed with `sign_sk` (which need not be `from`'s key).
+pub fn signed_envelope(
+    from: &Identity,
+    to: &box_::PublicKey,
+    body: &[u8],
+    sign_sk: &sign::SecretKey,
+) -> Vec<u8> {
+    let nonce = box_::Nonce(timestamped_nonce(now()));
+    let ct = box_::seal(body, &nonce, to, &from.sodium_box_sk);
+    let mut to_sign = vec![1u8];
+    to_sign.extend_from_slice(&0u64.to_be_bytes());
+    to_sign.extend_from_slice(&0u64.to_be_bytes());
+    to_sign.extend_from_slice(nonce.as_ref());
+    to_sign.extend_from_slice(&ct);
+    let sig = sign::sign_detached(&to_sign, sign_sk);
+    bincode::serialize(&EnvelopeV1::new(0, 0, nonce.0, ct, sig.to_bytes().to_vec())).unwrap()
+}
@@ -0,0 +1,90 @@
+mod common;
+
+use common::{signed_envelope, PEER_A as PEER};
+use secure_p2p_msg::api::Core;
+use secure_p2p_msg::config::UnknownPeerPolicy;
+use secure_p2p_msg::contact_card::ContactCard;
+use secure_p2p_msg::identity::Identity;
+use secure_p2p_msg::messaging::inbound::{handle_request, InboundOutcome};
+use secure_p2p_msg::settings::Profile;
+use sodiumoxide::crypto::sign;
+
+#[test]
+fn card_round_trips_into_contact_and_rejects_tampering() {
+    sodiumoxide::init().unwrap();
+    let alice_dir = tempfile::tempdir().unwrap();
+    let bob_dir = tempfile::tempdir().unwrap();
+    let alice = Core::with_data_dir(alice_dir.path());
+    let bob = Core::with_data_dir(bob_dir.path());
+
+    alice
+        .set_profile(&Profile {
+            display_name: "Alice".into(),
+            advertised_addrs: vec![format!("/ip4/10.0.0.5/tcp/4001/p2p/{PEER}")],
+        })
+        .unwrap();
+    let uri = alice.my_contact_card().unwrap().to_uri().unwrap();
+    assert!(uri.starts_with("pigeon://"));
+
+    let c = bob.contacts_import_card(&uri).unwrap();
+    let me = alice.ensure_identity_and_preview().unwrap();
+    assert_eq!(c.name, "Alice");
+    assert_eq!(c.peer_id.as_deref(), Some(PEER));
+    assert_eq!(hex::encode(&c.public_key), me.sodium_box_pk_hex);
+    assert_eq!(hex::encode(c.sign_public_key.unwrap()), me.sign_pk_hex);
+
+    // Re-importing refreshes the same contact instead of duplicating it
+    bob.contacts_import_card(&uri).unwrap();
+    assert_eq!(bob.contacts_list().unwrap().len(), 1);
+
+    let mut card = ContactCard::from_uri(&uri).unwrap();
+    card.name = "Mallory".into();
+    assert!(card.verify().is_err());
+    assert!(bob.contacts_import_card(&card.to_uri().unwrap()).is_err());
+}
+
+#[test]
+fn envelopes_from_card_contacts_must_carry_their_signature() {
+    sodiumoxide::init().unwrap();
+    let alice_dir = tempfile::tempdir().unwrap();
+    let bob_dir = tempfile::tempdir().unwrap();
+    let alice_id = Identity::load_or_generate(alice_dir.path()).unwrap();
+    let bob_id = Identity::load_or_generate(bob_dir.path()).unwrap();
+    let card = ContactCard::new_signed(
+        &alice_id,
+        "Alice",
+        Some(PEER.to_string()),
+        vec!["/ip4/10.0.0.5/tcp/4001".into()],
+    )
+    .unwrap();
+    let bob = Core::with_data_dir(bob_dir.path());
+    bob.contacts_import_card(&card.to_uri().unwrap()).unwrap();
+
+    let (_, forged_sk) = sign::gen_keypair();
+    let req = signed_envelope(&alice_id, &bob_id.sodium_box_pk, b"forged", &forged_sk);
+    let out = handle_request(
+        bob_dir.path(),
+        &bob_id,
+        PEER,
+        &req,
+        UnknownPeerPolicy::Refuse,
+    )
+    .unwrap();
+    assert!(matches!(out, InboundOutcome::Rejected(_)));
+
+    let req = signed_envelope(
+        &alice_id,
+        &bob_id.sodium_box_pk,
+        b"genuine",
+        &alice_id.sign_sk,
+    );
+    let out = handle_request(
+        bob_dir.path(),
+        &bob_id,
+        PEER,
+        &req,
+        UnknownPeerPolicy::Refuse,
+    )
+    .unwrap();
+    assert!(matches!(out, InboundOutcome::Delivered { .. }));
+}
@@ -39,8 +39,7 @@ fn contacts_crud_and_find() {
 async fn inbox_list_limited_works() {
     let dir = tempfile::tempdir().unwrap();
     let core = Core::with_data_dir(dir.path());
-    let queue_path = dir.path().join("queue_db");
-    let q = secure_p2p_msg::storage::queue::MessageQueue::new(queue_path.to_str().unwrap()).unwrap();
+    let q = secure_p2p_msg::storage::queue::MessageQueue::open_in_dir(dir.path()).unwrap();
     // store two items
     let id1 = uuid::Uuid::new_v4();
     q.store_inbox(id1, b"one".to_vec()).unwrap();
@@ -0,0 +1,150 @@
+mod common;
+
+use common::{deliver, import_card, named_side, Side};
+use common::{PEER_A as DESKTOP, PEER_B as BOB, PEER_C as LAPTOP};
+use secure_p2p_msg::contact_card::ContactCard;
+use secure_p2p_msg::devices::{self, DeviceInfo, LinkRequest, OWN_DEVICES};
+use secure_p2p_msg::messaging::inbound::InboundOutcome;
+use secure_p2p_msg::messaging::send::{seal_pending, seal_static, send_to_contact};
+use secure_p2p_msg::storage::contacts::Contact;
+use secure_p2p_msg::storage::queue::QueuedMessage;
+
+fn info(name: &str, peer: &str) -> DeviceInfo {
+    DeviceInfo {
+        name: name.into(),
+        peer_id: Some(peer.into()),
+        addrs: vec![format!("/ip4/10.0.0.7/tcp/4001/p2p/{peer}")],
+    }
+}
+
+/// Link `laptop` to `desktop`'s account, as `devices request/authorize/accept` do.
+fn link(desktop: &Side, laptop: &Side) {
+    let request = LinkRequest::new_signed(&laptop.id, info("laptop", LAPTOP));
+    let uri = request.to_uri().unwrap();
+    let grant = devices::authorize(
+        desktop.dir.path(),
+        &desktop.id,
+        info("desktop", DESKTOP),
+        &LinkRequest::from_uri(&uri).unwrap(),
+    )
+    .unwrap();
+    assert_eq!(grant.certs.len(), 2);
+    laptop
+        .core()
+        .devices_accept(&grant.to_uri().unwrap())
+        .unwrap();
+}
+
+fn send(from: &Side, to: &Contact, body: &[u8]) -> Vec<QueuedMessage> {
+    let queue = from.dir.path().join("queue_db");
+    send_to_contact(queue.to_str().unwrap(), from.dir.path(), &from.id, to, body).unwrap();
+    let core = from.core();
+    let pending = core.queue_list_pending().unwrap();
+    // Drain so the next send in the test starts from an empty queue
+    std::fs::remove_dir_all(&queue).unwrap();
+    pending
+}
+
+#[test]
+fn linked_device_is_on_the_card_and_receives_its_own_copy() {
+    sodiumoxide::init().unwrap();
+    let desktop = named_side("Alice", DESKTOP);
+    let laptop = named_side("Alice", LAPTOP);
+    let bob = named_side("Bob", BOB);
+    link(&desktop, &laptop);
+
+    let alice_at_bob = import_card(&bob, &desktop);
+    assert_eq!(alice_at_bob.devices.len(), 1);
+    assert_eq!(alice_at_bob.devices[0].name, "laptop");
+    assert_eq!(alice_at_bob.devices[0].peer_id.as_deref(), Some(LAPTOP));
+    assert_eq!(alice_at_bob.devices[0].public_key, laptop.id.sodium_box_pk.0.to_vec());
+    import_card(&laptop, &bob);
+
+    let queued = send(&bob, &alice_at_bob, b"hi both");
+    assert_eq!(queued.len(), 2);
+    assert!(queued.iter().any(|m| m.device.is_none()));
+    let copy = queued
+        .iter()
+        .find(|m| m.device.as_deref() == Some(laptop.id.sodium_box_pk.0.as_slice()))
+        .expect("copy for the laptop");
+    match deliver(&laptop, BOB, &copy.payload) {
+        InboundOutcome::Delivered { plaintext, .. } => assert_eq!(plaintext, b"hi both"),
+        other => panic!("expected delivery, got {other:?}"),
+    }
+    // The laptop's copy is useless to the desktop
+    assert!(!matches!(
+        deliver(&desktop, LAPTOP, &copy.payload),
+        InboundOutcome::Delivered { .. }
+    ));
+}
+
+#[test]
+fn messages_sent_from_a_linked_device_sync_to_the_primary() {
+    sodiumoxide::init().unwrap();
+    let desktop = named_side("Alice", DESKTOP);
+    let laptop = named_side("Alice", LAPTOP);
+    let bob = named_side("Bob", BOB);
+    link(&desktop, &laptop);
+    let bob_at_desktop = import_card(&desktop, &bob);
+    let bob_at_laptop = import_card(&laptop, &bob);
+    import_card(&bob, &desktop);
+
+    let queued = send(&laptop, &bob_at_laptop, b"from the laptop");
+    assert_eq!(queued.len(), 2);
+    let to_bob = queued.iter().find(|m| m.contact_id == bob_at_laptop.id).unwrap();
+    let sync = queued.iter().find(|m| m.contact_id == OWN_DEVICES).unwrap();
+    assert_eq!(sync.device.as_deref(), Some(desktop.id.sodium_box_pk.0.as_slice()));
+
+    // Sessions run between primary devices, so the laptop can only reach Bob under the
+    // static keys, which the send loop uses when static_key_fallback is on
+    let dir = laptop.dir.path();
+    assert!(
+        seal_pending(dir, &laptop.id, &bob_at_laptop, &to_bob.payload)
+            .unwrap()
+            .is_none()
+    );
+    let to_bob = seal_static(&laptop.id, &bob_at_laptop, &to_bob.payload).unwrap();
+
+    // Bob recognises the laptop as one of Alice's devices
+    match deliver(&bob, LAPTOP, &to_bob) {
+        InboundOutcome::Delivered { plaintext, .. } => assert_eq!(plaintext, b"from the laptop"),
+        other => panic!("expected delivery, got {other:?}"),
+    }
+
+    assert!(matches!(
+        deliver(&desktop, LAPTOP, &sync.payload),
+        InboundOutcome::Synced(_)
+    ));
+    assert_eq!(deliver(&desktop, LAPTOP, &sync.payload), InboundOutcome::Replay);
+    let sent = desktop.core().sent_list().unwrap();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].contact_id, Some(bob_at_desktop.id));
+    assert_eq!(sent[0].body, b"from the laptop");
+    assert_eq!(sent[0].from_device.as_deref(), Some("laptop"));
+
+    // A peer outside the account cannot inject sent messages
+    assert!(matches!(
+        deliver(&desktop, BOB, &sync.payload),
+        InboundOutcome::Rejected(_)
+    ));
+}
+
+#[test]
+fn cards_reject_device_certificates_from_another_account() {
+    sodiumoxide::init().unwrap();
+    let desktop = named_side("Alice", DESKTOP);
+    let laptop = named_side("Alice", LAPTOP);
+    let mallory = named_side("Mallory", BOB);
+    link(&desktop, &laptop);
+
+    let certs = devices::linked(desktop.dir.path()).unwrap().unwrap().certs;
+    let card = ContactCard::new_signed_with_devices(&mallory.id, "Mallory", None, vec![], certs)
+        .unwrap();
+    assert!(card.verify().is_err());
+
+    // Only the primary can link further devices
+    let other = named_side("Alice", BOB);
+    let request = LinkRequest::new_signed(&other.id, info("phone", BOB));
+    assert!(devices::authorize(laptop.dir.path(), &laptop.id, info("laptop", LAPTOP), &request)
+        .is_err());
+}
@@ -0,0 +1,55 @@
+use secure_p2p_msg::api::Core;
+use secure_p2p_msg::discovery::{self, Discovery};
+
+const PEER: &str = "12D3KooWQYhTNQdmr3ArTeUHRYzFg94BKyTkoWBDWez9kSCVe2Xo";
+const OTHER: &str = "12D3KooWRBhwfeP2Y4TCx1SM6s9rUoHhR5STiGwxBhgFRcw3UERE";
+
+#[test]
+fn discovery_refreshes_known_contact_addr() {
+    sodiumoxide::init().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let core = Core::with_data_dir(dir.path());
+    let c = core
+        .contacts_add(
+            "Bob",
+            &format!("/ip4/10.0.0.1/tcp/4001/p2p/{PEER}"),
+            &hex::encode([3u8; 32]),
+        )
+        .unwrap();
+
+    let out = discovery::apply_discovery(dir.path(), PEER, "/ip4/192.168.1.7/tcp/4001").unwrap();
+    let expected = format!("/ip4/192.168.1.7/tcp/4001/p2p/{PEER}");
+    assert_eq!(
+        out,
+        Discovery::ContactUpdated {
+            contact_id: c.id,
+            addr: expected.clone()
+        }
+    );
+    assert_eq!(core.contacts_get(c.id).unwrap().unwrap().addr, expected);
+    assert!(core.nearby_peers().unwrap().is_empty());
+}
+
+#[test]
+fn unknown_peer_is_offered_and_can_be_added() {
+    sodiumoxide::init().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let core = Core::with_data_dir(dir.path());
+
+    let out = discovery::apply_discovery(dir.path(), PEER, "/ip4/192.168.1.9/tcp/4001").unwrap();
+    assert!(matches!(out, Discovery::Nearby(_)));
+    let nearby = core.nearby_peers().unwrap();
+    assert_eq!(nearby.len(), 1);
+    assert_eq!(nearby[0].peer_id, PEER);
+
+    let c = core
+        .add_nearby_peer(PEER, "Carol", &hex::encode([4u8; 32]))
+        .unwrap();
+    assert_eq!(c.addr, format!("/ip4/192.168.1.9/tcp/4001/p2p/{PEER}"));
+    assert!(core.nearby_peers().unwrap().is_empty());
+
+    discovery::apply_discovery(dir.path(), OTHER, "/ip4/192.168.1.10/tcp/4001").unwrap();
+    assert_eq!(core.nearby_peers().unwrap().len(), 1);
+    discovery::apply_expiry(dir.path(), OTHER, "/ip4/192.168.1.10/tcp/4001").unwrap();
+    assert!(discovery::load_nearby_peers(dir.path()).unwrap().is_empty());
+}
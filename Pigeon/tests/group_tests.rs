@@ -0,0 +1,204 @@
+mod common;
+
+use common::{deliver, import_card, named_side, store_bundle, Side};
+use common::{PEER_A as ALICE, PEER_B as BOB, PEER_C as CAROL};
+use secure_p2p_msg::groups::GroupEvent;
+use secure_p2p_msg::messaging::inbound::InboundOutcome;
+use secure_p2p_msg::messaging::message::GroupEnvelope;
+use secure_p2p_msg::storage::contacts::Contact;
+use secure_p2p_msg::storage::queue::{MessageQueue, QueuedMessage};
+
+/// Import `other`'s card on `me` along with the prekey bundle sessions with it start from.
+fn join(me: &Side, other: &Side) -> Contact {
+    let contact = import_card(me, other);
+    store_bundle(me, &contact, other);
+    contact
+}
+
+/// Take everything `node` queued so far, high lane first, as the send loop would.
+fn drain(node: &Side) -> Vec<QueuedMessage> {
+    let q = MessageQueue::open_in_dir(node.dir.path()).unwrap();
+    std::iter::from_fn(|| q.dequeue().unwrap()).collect()
+}
+
+/// Deliver everything `from` has queued to the node each message is addressed to.
+fn flush(from: &Side, routes: &[(u64, &Side)]) -> Vec<InboundOutcome> {
+    drain(from)
+        .into_iter()
+        .map(|m| {
+            let (_, to) = routes
+                .iter()
+                .find(|(cid, _)| *cid == m.contact_id)
+                .expect("queued for a known contact");
+            deliver(to, from.peer, &m.payload)
+        })
+        .collect()
+}
+
+struct Trio {
+    alice: Side,
+    bob: Side,
+    carol: Side,
+    // Contact ids as seen by each node
+    a_bob: u64,
+    a_carol: u64,
+    b_alice: u64,
+    b_carol: u64,
+    c_alice: u64,
+    c_bob: u64,
+}
+
+fn trio() -> Trio {
+    sodiumoxide::init().unwrap();
+    let alice = named_side("Alice", ALICE);
+    let bob = named_side("Bob", BOB);
+    let carol = named_side("Carol", CAROL);
+    Trio {
+        a_bob: join(&alice, &bob).id,
+        a_carol: join(&alice, &carol).id,
+        b_alice: join(&bob, &alice).id,
+        b_carol: join(&bob, &carol).id,
+        c_alice: join(&carol, &alice).id,
+        c_bob: join(&carol, &bob).id,
+        alice,
+        bob,
+        carol,
+    }
+}
+
+impl Trio {
+    fn flush_alice(&self) -> Vec<InboundOutcome> {
+        flush(&self.alice, &[(self.a_bob, &self.bob), (self.a_carol, &self.carol)])
+    }
+    fn flush_bob(&self) -> Vec<InboundOutcome> {
+        flush(&self.bob, &[(self.b_alice, &self.alice), (self.b_carol, &self.carol)])
+    }
+    fn flush_carol(&self) -> Vec<InboundOutcome> {
+        flush(&self.carol, &[(self.c_alice, &self.alice), (self.c_bob, &self.bob)])
+    }
+
+    /// Alice creates a group of the three and everyone exchanges sender keys.
+    fn group(&self) -> uuid::Uuid {
+        let g = self
+            .alice
+            .core()
+            .groups_create("Hiking", &[self.a_bob, self.a_carol])
+            .unwrap();
+        let out = self.flush_alice();
+        assert_eq!(out.iter().filter(|o| **o == InboundOutcome::Group(GroupEvent::Joined(g.id))).count(), 2);
+        self.flush_bob();
+        self.flush_carol();
+        g.id
+    }
+}
+
+fn delivered(out: &InboundOutcome) -> &[u8] {
+    match out {
+        InboundOutcome::GroupDelivered { plaintext, .. } => plaintext,
+        other => panic!("expected a group message, got {other:?}"),
+    }
+}
+
+#[test]
+fn group_message_is_encrypted_once_and_read_by_every_member() {
+    let t = trio();
+    let gid = t.group();
+    assert_eq!(t.bob.core().groups_find("hiking").unwrap().members.len(), 3);
+
+    t.alice.core().groups_send(gid, b"summit at noon").unwrap();
+    let queued = drain(&t.alice);
+    assert_eq!(queued.len(), 2);
+    // Same ciphertext for both members
+    assert_eq!(queued[0].payload, queued[1].payload);
+    for m in &queued {
+        let to = if m.contact_id == t.a_bob { &t.bob } else { &t.carol };
+        assert_eq!(delivered(&deliver(to, t.alice.peer, &m.payload)), b"summit at noon");
+    }
+    assert_eq!(deliver(&t.bob, t.alice.peer, &queued[0].payload), InboundOutcome::Replay);
+    // Group messages carry their send time and stale ones are refused
+    let mut stale: GroupEnvelope = bincode::deserialize(&queued[0].payload).unwrap();
+    stale.sent_at -= 2 * 86_400;
+    assert!(matches!(
+        deliver(&t.bob, t.alice.peer, &bincode::serialize(&stale).unwrap()),
+        InboundOutcome::Rejected(_)
+    ));
+
+    t.carol.core().groups_send(gid, b"bringing snacks").unwrap();
+    for out in t.flush_carol() {
+        assert_eq!(delivered(&out), b"bringing snacks");
+    }
+    let thread = t.bob.core().groups_thread(gid).unwrap();
+    let bodies: Vec<&[u8]> = thread.iter().map(|m| m.body.as_slice()).collect();
+    assert_eq!(bodies, vec![&b"summit at noon"[..], &b"bringing snacks"[..]]);
+    assert_eq!(thread[1].sender_name, "Carol");
+    // Nothing about the group reached the ordinary inbox
+    assert!(t.bob.core().inbox_list().unwrap().is_empty());
+}
+
+#[test]
+fn removed_member_cannot_read_after_rekey() {
+    let t = trio();
+    let gid = t.group();
+
+    t.alice.core().groups_remove_members(gid, &[t.a_carol]).unwrap();
+    for out in t.flush_alice() {
+        assert!(matches!(
+            out,
+            InboundOutcome::Group(GroupEvent::Updated(_) | GroupEvent::Removed(_))
+        ));
+    }
+    let at_carol = t.carol.core().groups_find("Hiking").unwrap();
+    assert!(!at_carol.active);
+    assert!(t.carol.core().groups_send(gid, b"still here?").is_err());
+    // Bob rekeys on seeing the removal, and only tells Alice
+    let bob_keys = drain(&t.bob);
+    assert_eq!(bob_keys.len(), 1);
+    assert_eq!(bob_keys[0].contact_id, t.b_alice);
+    deliver(&t.alice, t.bob.peer, &bob_keys[0].payload);
+
+    t.alice.core().groups_send(gid, b"new plan").unwrap();
+    let queued = drain(&t.alice);
+    assert_eq!(queued.len(), 1);
+    assert_eq!(delivered(&deliver(&t.bob, t.alice.peer, &queued[0].payload)), b"new plan");
+    // Even with the ciphertext in hand, Carol has no key for it
+    assert!(matches!(
+        deliver(&t.carol, t.alice.peer, &queued[0].payload),
+        InboundOutcome::Rejected(_)
+    ));
+
+    t.bob.core().groups_send(gid, b"ok").unwrap();
+    assert_eq!(delivered(&t.flush_bob()[0]), b"ok");
+}
+
+#[test]
+fn only_the_admin_changes_membership_and_early_messages_wait_for_keys() {
+    let t = trio();
+    let gid = t.group();
+    assert!(t.bob.core().groups_remove_members(gid, &[t.b_carol]).is_err());
+
+    // A new sender key from Bob, but his next message overtakes it on the way to Carol
+    t.bob.core().groups_rekey(gid).unwrap();
+    let keys = drain(&t.bob);
+    t.bob.core().groups_send(gid, b"after rekey").unwrap();
+    let messages = drain(&t.bob);
+    let to_carol = |list: &[QueuedMessage]| {
+        list.iter()
+            .find(|m| m.contact_id == t.b_carol)
+            .unwrap()
+            .payload
+            .clone()
+    };
+    assert_eq!(
+        deliver(&t.carol, t.bob.peer, &to_carol(&messages)),
+        InboundOutcome::Group(GroupEvent::Held(gid))
+    );
+    assert!(t.carol.core().groups_thread(gid).unwrap().is_empty());
+    assert_eq!(
+        deliver(&t.carol, t.bob.peer, &to_carol(&keys)),
+        InboundOutcome::Group(GroupEvent::Updated(gid))
+    );
+    let thread = t.carol.core().groups_thread(gid).unwrap();
+    assert_eq!(thread.len(), 1);
+    assert_eq!(thread[0].body, b"after rekey");
+    assert_eq!(thread[0].contact_id, Some(t.c_bob));
+}
@@ -9,10 +9,7 @@ async fn core_facade_compose_and_inbox_roundtrip() {
     let id = core.compose(1, "hello world").await.unwrap();
 
     // Simulate delivery by directly moving to inbox via storage API for smoke test
-    let q = secure_p2p_msg::storage::queue::MessageQueue::new(
-        dir.path().join("queue_db").to_str().unwrap(),
-    )
-    .unwrap();
+    let q = secure_p2p_msg::storage::queue::MessageQueue::open_in_dir(dir.path()).unwrap();
     // Pull one pending and store to inbox (mimicking receive logic)
     if let Some(msg) = q.dequeue().unwrap() {
         q.store_inbox(msg.id, msg.payload).unwrap();
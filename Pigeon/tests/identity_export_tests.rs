@@ -0,0 +1,57 @@
+use secure_p2p_msg::api::Core;
+
+const PK_HEX: &str = "0101010101010101010101010101010101010101010101010101010101010101";
+
+#[test]
+fn export_and_import_round_trips_identity_and_contacts() {
+    sodiumoxide::init().unwrap();
+    let src = tempfile::tempdir().unwrap();
+    let dst = tempfile::tempdir().unwrap();
+    let out = src.path().join("me.pgar");
+    let old = Core::with_data_dir(src.path());
+    let me = old.ensure_identity_and_preview().unwrap();
+    old.contacts_add("Bob", "/ip4/10.0.0.2/tcp/4001", PK_HEX)
+        .unwrap();
+    old.export_identity(&out, "archive-pw", true).unwrap();
+
+    let new = Core::with_data_dir(dst.path());
+    let imported = new.import_identity(&out, "archive-pw", false).unwrap();
+    assert_eq!(imported.preview, me);
+    assert_eq!(imported.contacts_added, 1);
+    assert_eq!(new.ensure_identity_and_preview().unwrap(), me);
+    assert_eq!(new.contacts_list().unwrap()[0].name, "Bob");
+
+    // An identity is already there now: only replaced on request, contacts not duplicated
+    assert!(new.import_identity(&out, "archive-pw", false).is_err());
+    let again = new.import_identity(&out, "archive-pw", true).unwrap();
+    assert_eq!(again.contacts_added, 0);
+    assert_eq!(new.contacts_list().unwrap().len(), 1);
+}
+
+#[test]
+fn import_rejects_wrong_passphrase_and_tampering() {
+    sodiumoxide::init().unwrap();
+    let src = tempfile::tempdir().unwrap();
+    let dst = tempfile::tempdir().unwrap();
+    let out = src.path().join("me.pgar");
+    let old = Core::with_data_dir(src.path());
+    old.ensure_identity_and_preview().unwrap();
+    old.export_identity(&out, "archive-pw", false).unwrap();
+
+    let new = Core::with_data_dir(dst.path());
+    assert!(new.import_identity(&out, "nope", false).is_err());
+
+    let mut bytes = std::fs::read(&out).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0x01;
+    let tampered = src.path().join("tampered.pgar");
+    std::fs::write(&tampered, &bytes).unwrap();
+    assert!(new.import_identity(&tampered, "archive-pw", false).is_err());
+    assert!(new.first_run_required());
+
+    // The raw-file import refuses archives and never clobbers an identity
+    assert!(new.import_identity_from_file(&out).is_err());
+    new.ensure_identity_and_preview().unwrap();
+    let raw = src.path().join("identity.bin");
+    assert!(new.import_identity_from_file(&raw).is_err());
+}
@@ -0,0 +1,70 @@
+use secure_p2p_msg::identity::Identity;
+use secure_p2p_msg::storage::at_rest;
+use serial_test::serial;
+
+fn is_sealed(dir: &std::path::Path) -> bool {
+    std::fs::read(dir.join("identity.bin"))
+        .unwrap()
+        .starts_with(b"PGS1")
+}
+
+fn copy_dir(from: &std::path::Path, to: &std::path::Path) {
+    for name in ["identity.bin", "at_rest.key.enc"] {
+        std::fs::copy(from.join(name), to.join(name)).unwrap();
+    }
+}
+
+#[test]
+#[serial]
+fn setting_a_passphrase_seals_the_identity_and_unlock_opens_it() {
+    sodiumoxide::init().unwrap();
+    std::env::remove_var("PIGEON_PASSPHRASE");
+    let dir = tempfile::tempdir().unwrap();
+    let before = Identity::load_or_generate(dir.path()).unwrap();
+    assert!(!is_sealed(dir.path()));
+
+    at_rest::set_passphrase_and_seal(dir.path(), "pw").unwrap();
+    assert!(is_sealed(dir.path()));
+    let after = Identity::load_or_generate(dir.path()).unwrap();
+    assert_eq!(before.sign_pk, after.sign_pk);
+
+    // A fresh location has nothing cached, so the identity stays locked until unlocked
+    let other = tempfile::tempdir().unwrap();
+    copy_dir(dir.path(), other.path());
+    assert!(Identity::load_or_generate(other.path()).is_err());
+    assert!(at_rest::unlock_with_passphrase(other.path(), "wrong").is_err());
+    at_rest::unlock_with_passphrase(other.path(), "pw").unwrap();
+    let unlocked = Identity::load_or_generate(other.path()).unwrap();
+    assert_eq!(before.sodium_box_pk, unlocked.sodium_box_pk);
+
+    // Changing the passphrase re-seals the identity under the new key
+    at_rest::rotate_key_and_seal(dir.path(), "pw2").unwrap();
+    let third = tempfile::tempdir().unwrap();
+    copy_dir(dir.path(), third.path());
+    std::env::set_var("PIGEON_PASSPHRASE", "pw2");
+    let reopened = Identity::load_or_generate(third.path());
+    std::env::remove_var("PIGEON_PASSPHRASE");
+    assert_eq!(reopened.unwrap().sign_pk, before.sign_pk);
+}
+
+#[test]
+#[serial]
+fn plaintext_identity_is_migrated_on_unlocked_load() {
+    sodiumoxide::init().unwrap();
+    std::env::remove_var("PIGEON_PASSPHRASE");
+    let legacy = tempfile::tempdir().unwrap();
+    let original = Identity::load_or_generate(legacy.path()).unwrap();
+
+    let dir = tempfile::tempdir().unwrap();
+    at_rest::set_passphrase_and_seal(dir.path(), "pw").unwrap();
+    std::fs::copy(
+        legacy.path().join("identity.bin"),
+        dir.path().join("identity.bin"),
+    )
+    .unwrap();
+    assert!(!is_sealed(dir.path()));
+
+    let loaded = Identity::load_or_generate(dir.path()).unwrap();
+    assert_eq!(loaded.sign_pk, original.sign_pk);
+    assert!(is_sealed(dir.path()));
+}
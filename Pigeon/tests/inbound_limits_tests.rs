@@ -0,0 +1,178 @@
+use std::time::{Duration, Instant};
+
+use secure_p2p_msg::api::Core;
+use secure_p2p_msg::config::UnknownPeerPolicy;
+use secure_p2p_msg::identity::Identity;
+use secure_p2p_msg::messaging::inbound::{handle_request, InboundOutcome, InboundPolicy};
+use secure_p2p_msg::messaging::limits::{InboundLimits, Limiter, Throttle, MAX_TRACKED_PEERS};
+use secure_p2p_msg::messaging::stamp::{self, Stamped};
+use secure_p2p_msg::ops::Metrics;
+
+const PEER: &str = "12D3KooWQYhTNQdmr3ArTeUHRYzFg94BKyTkoWBDWez9kSCVe2Xo";
+const OTHER: &str = "12D3KooWRBhwfeP2Y4TCx1SM6s9rUoHhR5STiGwxBhgFRcw3UERE";
+
+#[test]
+fn peers_are_limited_separately_and_refill_over_time() {
+    let t0 = Instant::now();
+    let limits = InboundLimits {
+        peer_requests_per_min: 60,
+        peer_burst: 3,
+        max_inbound_bytes_per_sec: 0,
+        ..Default::default()
+    };
+    let mut limiter = Limiter::starting_at(limits, t0);
+    let metrics = Metrics::default();
+
+    for _ in 0..3 {
+        assert_eq!(limiter.check_request(PEER, 100, t0), Ok(()));
+    }
+    assert_eq!(limiter.check_request(PEER, 100, t0), Err(Throttle::Peer));
+    metrics.count_inbound(&InboundOutcome::Throttled(Throttle::Peer));
+    assert_eq!(limiter.check_request(OTHER, 100, t0), Ok(()));
+
+    // One request a second comes back
+    let t1 = t0 + Duration::from_secs(1);
+    assert_eq!(limiter.check_request(PEER, 100, t1), Ok(()));
+    assert_eq!(limiter.check_request(PEER, 100, t1), Err(Throttle::Peer));
+    assert!(metrics
+        .render_prometheus()
+        .contains("pigeon_inbound_rejected{reason=\"rate_limited\"} 1"));
+}
+
+#[test]
+fn tracked_peers_are_capped_by_evicting_the_least_recently_seen() {
+    let t0 = Instant::now();
+    let limits = InboundLimits {
+        peer_requests_per_min: 1,
+        peer_burst: 1,
+        max_inbound_bytes_per_sec: 0,
+        ..Default::default()
+    };
+    let mut limiter = Limiter::starting_at(limits, t0);
+    let at = |i: usize| t0 + Duration::from_millis(i as u64);
+    // Every peer has spent its bucket, so none can be dropped as idle
+    for i in 0..=MAX_TRACKED_PEERS {
+        assert_eq!(limiter.check_request(&format!("peer-{i}"), 1, at(i)), Ok(()));
+    }
+    assert_eq!(limiter.tracked_peers(), MAX_TRACKED_PEERS);
+
+    // The first peer was evicted and starts over; the latest is still limited
+    let now = at(MAX_TRACKED_PEERS + 1);
+    assert_eq!(limiter.check_request("peer-0", 1, now), Ok(()));
+    let latest = format!("peer-{MAX_TRACKED_PEERS}");
+    assert_eq!(limiter.check_request(&latest, 1, now), Err(Throttle::Peer));
+    assert_eq!(limiter.tracked_peers(), MAX_TRACKED_PEERS);
+}
+
+#[test]
+fn bandwidth_is_shared_by_all_peers() {
+    let t0 = Instant::now();
+    let limits = InboundLimits {
+        peer_requests_per_min: 0,
+        max_inbound_bytes_per_sec: 1000,
+        ..Default::default()
+    };
+    let mut limiter = Limiter::starting_at(limits, t0);
+    assert_eq!(limiter.check_request(PEER, 600, t0), Ok(()));
+    assert_eq!(limiter.check_request(OTHER, 600, t0), Err(Throttle::Bandwidth));
+    assert_eq!(limiter.check_request(OTHER, 400, t0), Ok(()));
+
+    // A request over a second's budget passes on a full bucket and is then paid back
+    let t1 = t0 + Duration::from_secs(1);
+    assert_eq!(limiter.check_request(PEER, 3000, t1), Ok(()));
+    let t2 = t1 + Duration::from_secs(1);
+    assert_eq!(limiter.check_request(PEER, 10, t2), Err(Throttle::Bandwidth));
+    let t3 = t1 + Duration::from_secs(3);
+    assert_eq!(limiter.check_request(PEER, 10, t3), Ok(()));
+}
+
+#[test]
+fn connections_per_peer_are_capped() {
+    let limits = InboundLimits {
+        max_connections_per_peer: 2,
+        ..Default::default()
+    };
+    let mut limiter = Limiter::new(limits);
+    assert!(limiter.connection_opened(PEER));
+    assert!(limiter.connection_opened(PEER));
+    assert!(!limiter.connection_opened(PEER));
+    assert!(limiter.connection_opened(OTHER));
+    limiter.connection_closed(PEER);
+    assert!(limiter.connection_opened(PEER));
+}
+
+#[test]
+fn unknown_peers_must_stamp_their_requests() {
+    sodiumoxide::init().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let me = Identity::load_or_generate(dir.path()).unwrap();
+    let core = Core::with_data_dir(dir.path());
+    let policy = InboundPolicy {
+        stamp_bits: 8,
+        ..InboundPolicy::from(UnknownPeerPolicy::Quarantine)
+    };
+    let request = b"\x01not really an envelope".to_vec();
+
+    let out = handle_request(dir.path(), &me, PEER, &request, policy).unwrap();
+    assert_eq!(out, InboundOutcome::StampRequired(8));
+    assert_eq!(stamp::requested_bits(&out.response()), Some(8));
+    assert!(core.quarantine_list().unwrap().is_empty());
+
+    let me_pk = me.sodium_box_pk.0;
+    let stamped = stamp::stamp(&request, PEER, &me_pk, 8);
+    assert!(stamp::open(&stamped).unwrap().bits(PEER, &me_pk) >= 8);
+    let out = handle_request(dir.path(), &me, PEER, &stamped, policy).unwrap();
+    assert!(matches!(out, InboundOutcome::Quarantined(_)), "{out:?}");
+    // The stamp is taken off before the request is kept
+    assert_eq!(core.quarantine_list().unwrap()[0].envelope, request);
+
+    // A stamp buys one request, and only from the PeerId it was made for
+    let out = handle_request(dir.path(), &me, PEER, &stamped, policy).unwrap();
+    assert_eq!(out, InboundOutcome::Replay);
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let for_peer = (0u64..)
+        .map(|counter| Stamped {
+            version: stamp::STAMP_VERSION,
+            counter,
+            stamped_at: now,
+            request: request.clone(),
+        })
+        .find(|s| s.bits(PEER, &me_pk) >= 8 && s.bits(OTHER, &me_pk) < 8)
+        .unwrap();
+    let for_peer = bincode::serialize(&for_peer).unwrap();
+    let out = handle_request(dir.path(), &me, OTHER, &for_peer, policy).unwrap();
+    assert_eq!(out, InboundOutcome::StampRequired(8));
+    assert_eq!(core.quarantine_list().unwrap().len(), 1);
+
+    // Too little work, or a stamp older than the replay window, is asked again; senders
+    // refuse absurd demands
+    let weak = (0u64..)
+        .map(|counter| Stamped {
+            version: stamp::STAMP_VERSION,
+            counter,
+            stamped_at: now,
+            request: request.clone(),
+        })
+        .find(|s| s.bits(PEER, &me_pk) < 8)
+        .unwrap();
+    let weak = bincode::serialize(&weak).unwrap();
+    let out = handle_request(dir.path(), &me, PEER, &weak, policy).unwrap();
+    assert_eq!(out, InboundOutcome::StampRequired(8));
+    let stale = (0u64..)
+        .map(|counter| Stamped {
+            version: stamp::STAMP_VERSION,
+            counter,
+            stamped_at: now - 2 * policy.replay_window_secs,
+            request: request.clone(),
+        })
+        .find(|s| s.bits(PEER, &me_pk) >= 8)
+        .unwrap();
+    let stale = bincode::serialize(&stale).unwrap();
+    let out = handle_request(dir.path(), &me, PEER, &stale, policy).unwrap();
+    assert_eq!(out, InboundOutcome::StampRequired(8));
+    assert_eq!(stamp::requested_bits(b"STAMP 40"), None);
+    assert_eq!(stamp::requested_bits(b"ACK"), None);
+}
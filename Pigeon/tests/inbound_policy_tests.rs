@@ -0,0 +1,186 @@
+mod common;
+
+use common::{envelope, envelope_at, now, PEER_A as PEER};
+use secure_p2p_msg::api::Core;
+use secure_p2p_msg::config::UnknownPeerPolicy;
+use secure_p2p_msg::identity::Identity;
+use secure_p2p_msg::messaging::inbound::{
+    handle_request, prune_nonces, InboundOutcome, InboundPolicy,
+};
+use secure_p2p_msg::ops::Metrics;
+use secure_p2p_msg::storage::contacts::{BlockTarget, ContactPolicy};
+use sodiumoxide::crypto::box_;
+
+#[test]
+fn contact_peer_is_delivered_and_unknown_is_refused() {
+    sodiumoxide::init().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let me = Identity::load_or_generate(dir.path()).unwrap();
+    let (alice_pk, alice_sk) = box_::gen_keypair();
+    let core = Core::with_data_dir(dir.path());
+    let alice = core
+        .contacts_add("Alice", "/ip4/127.0.0.1/tcp/4001", &hex::encode(alice_pk.0))
+        .unwrap();
+    core.contacts_set_peer_id(alice.id, PEER).unwrap();
+
+    let req = envelope(&alice_sk, &me.sodium_box_pk, b"hi bob");
+    let out = handle_request(dir.path(), &me, PEER, &req, UnknownPeerPolicy::Refuse).unwrap();
+    match out {
+        InboundOutcome::Delivered {
+            contact_id,
+            plaintext,
+            ..
+        } => {
+            assert_eq!(contact_id, Some(alice.id));
+            assert_eq!(plaintext, b"hi bob");
+        }
+        other => panic!("unexpected {other:?}"),
+    }
+
+    let req = envelope(&alice_sk, &me.sodium_box_pk, b"who am i");
+    let out = handle_request(dir.path(), &me, "12D3KooWUnknown", &req, UnknownPeerPolicy::Refuse)
+        .unwrap();
+    assert_eq!(out, InboundOutcome::Refused);
+    assert_eq!(out.response(), b"REFUSED");
+    assert_eq!(core.inbox_list().unwrap().len(), 1);
+}
+
+#[test]
+fn quarantined_message_released_after_adding_contact() {
+    sodiumoxide::init().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let me = Identity::load_or_generate(dir.path()).unwrap();
+    let (carol_pk, carol_sk) = box_::gen_keypair();
+    let core = Core::with_data_dir(dir.path());
+
+    let req = envelope(&carol_sk, &me.sodium_box_pk, b"let me in");
+    let out = handle_request(dir.path(), &me, PEER, &req, UnknownPeerPolicy::Quarantine).unwrap();
+    let InboundOutcome::Quarantined(qid) = out else {
+        panic!("expected quarantine, got {out:?}");
+    };
+    assert!(core.inbox_list().unwrap().is_empty());
+    assert_eq!(core.quarantine_list().unwrap().len(), 1);
+
+    // Not a contact yet: release fails and the message stays put
+    assert!(core.quarantine_release(qid).is_err());
+    assert_eq!(core.quarantine_list().unwrap().len(), 1);
+
+    core.contacts_add(
+        "Carol",
+        &format!("/ip4/127.0.0.1/tcp/4002/p2p/{PEER}"),
+        &hex::encode(carol_pk.0),
+    )
+    .unwrap();
+    core.quarantine_release(qid).unwrap();
+    assert!(core.quarantine_list().unwrap().is_empty());
+    let inbox = core.inbox_list().unwrap();
+    assert_eq!(inbox.len(), 1);
+    assert_eq!(inbox[0].1, b"let me in");
+}
+
+#[test]
+fn replayed_and_stale_envelopes_are_rejected() {
+    sodiumoxide::init().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let me = Identity::load_or_generate(dir.path()).unwrap();
+    let (dave_pk, dave_sk) = box_::gen_keypair();
+    let core = Core::with_data_dir(dir.path());
+    let dave = core
+        .contacts_add("Dave", "/ip4/127.0.0.1/tcp/4003", &hex::encode(dave_pk.0))
+        .unwrap();
+    core.contacts_set_peer_id(dave.id, PEER).unwrap();
+    let policy = InboundPolicy {
+        unknown_peers: UnknownPeerPolicy::Refuse,
+        contacts_only: false,
+        replay_window_secs: 600,
+        stamp_bits: 0,
+    };
+
+    let req = envelope(&dave_sk, &me.sodium_box_pk, b"once");
+    let out = handle_request(dir.path(), &me, PEER, &req, policy).unwrap();
+    assert!(matches!(out, InboundOutcome::Delivered { .. }), "{out:?}");
+    let out = handle_request(dir.path(), &me, PEER, &req, policy).unwrap();
+    assert_eq!(out, InboundOutcome::Replay);
+
+    // Too old (or too far ahead) to still have its nonce on record; a few seconds past the
+    // edge so the clock ticking during the call cannot bring them back in
+    for sent_at in [now() - 605, now() + 605] {
+        let req = envelope_at(&dave_sk, &me.sodium_box_pk, b"late", sent_at);
+        let out = handle_request(dir.path(), &me, PEER, &req, policy).unwrap();
+        assert!(matches!(out, InboundOutcome::Rejected(_)), "{out:?}");
+    }
+    assert_eq!(core.inbox_list().unwrap().len(), 1);
+
+    // Inside the window, but older than a shorter one: pruned once that applies
+    let req = envelope_at(&dave_sk, &me.sodium_box_pk, b"earlier", now() - 300);
+    let out = handle_request(dir.path(), &me, PEER, &req, policy).unwrap();
+    assert!(matches!(out, InboundOutcome::Delivered { .. }), "{out:?}");
+    assert_eq!(prune_nonces(dir.path(), &policy).unwrap(), 0);
+    let shorter = InboundPolicy {
+        replay_window_secs: 60,
+        ..policy
+    };
+    assert_eq!(prune_nonces(dir.path(), &shorter).unwrap(), 1);
+}
+
+#[test]
+fn muted_blocked_and_blocklisted_senders_are_not_delivered() {
+    sodiumoxide::init().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let me = Identity::load_or_generate(dir.path()).unwrap();
+    let (erin_pk, erin_sk) = box_::gen_keypair();
+    let core = Core::with_data_dir(dir.path());
+    let erin = core
+        .contacts_add("Erin", "/ip4/127.0.0.1/tcp/4004", &hex::encode(erin_pk.0))
+        .unwrap();
+    core.contacts_set_peer_id(erin.id, PEER).unwrap();
+    let metrics = Metrics::default();
+    let receive = |peer: &str, policy: InboundPolicy| {
+        let req = envelope(&erin_sk, &me.sodium_box_pk, b"hello?");
+        let out = handle_request(dir.path(), &me, peer, &req, policy).unwrap();
+        metrics.count_inbound(&out);
+        out
+    };
+    let accept = InboundPolicy::from(UnknownPeerPolicy::Accept);
+
+    core.contacts_set_policy(erin.id, ContactPolicy::Mute).unwrap();
+    let out = receive(PEER, accept);
+    assert_eq!(out, InboundOutcome::Muted);
+    assert_eq!(out.response(), b"ACK");
+
+    core.contacts_set_policy(erin.id, ContactPolicy::Block).unwrap();
+    let out = receive(PEER, accept);
+    assert_eq!(out, InboundOutcome::Blocked);
+    assert_eq!(out.response(), b"REFUSED");
+    assert!(core.inbox_list().unwrap().is_empty());
+
+    core.contacts_set_policy(erin.id, ContactPolicy::Allow).unwrap();
+    assert!(matches!(receive(PEER, accept), InboundOutcome::Delivered { .. }));
+
+    // Blocking her key covers her contact; blocking a PeerId covers peers that are not contacts
+    let key = BlockTarget::Key(erin_pk.0.to_vec());
+    assert!(core.blocklist_add(key.clone()).unwrap());
+    assert!(!core.blocklist_add(key.clone()).unwrap());
+    assert_eq!(receive(PEER, accept), InboundOutcome::Blocked);
+    assert!(core.blocklist_remove(&key).unwrap());
+    let stranger = "12D3KooWJWoaqZhDaoEFshF7Rh1bpY9ohihFhzcW6d69Lr2NASuq";
+    core.blocklist_add(BlockTarget::Peer(stranger.into())).unwrap();
+    assert_eq!(receive(stranger, accept), InboundOutcome::Blocked);
+    assert_eq!(core.blocklist().unwrap().len(), 1);
+
+    // Contacts only: unknown peers are refused whatever the unknown-peer policy says
+    let contacts_only = InboundPolicy {
+        contacts_only: true,
+        ..accept
+    };
+    assert_eq!(receive("12D3KooWOtherPeer", contacts_only), InboundOutcome::Refused);
+    let plain = handle_request(dir.path(), &me, PEER, b"just text", contacts_only).unwrap();
+    assert!(matches!(plain, InboundOutcome::Rejected(_)), "{plain:?}");
+    assert_eq!(core.inbox_list().unwrap().len(), 1);
+
+    let rendered = metrics.render_prometheus();
+    assert!(rendered.contains("pigeon_inbound_rejected{reason=\"blocked\"} 3"));
+    assert!(rendered.contains("pigeon_inbound_rejected{reason=\"muted\"} 1"));
+    assert!(rendered.contains("pigeon_inbound_rejected{reason=\"unknown_peer\"} 1"));
+    assert!(rendered.contains("pigeon_received_messages 1"));
+}
@@ -4,8 +4,7 @@ use secure_p2p_msg::storage::queue::MessageQueue;
 fn inbox_export_roundtrip() {
     // No crypto required here; we're only exercising storage/inbox
     let dir = tempfile::tempdir().unwrap();
-    let path = dir.path().join("queue_db");
-    let q = MessageQueue::new(path.to_str().unwrap()).unwrap();
+    let q = MessageQueue::open_in_dir(dir.path()).unwrap();
 
     let id = uuid::Uuid::new_v4();
     let body = b"hello world".to_vec();
@@ -24,8 +23,7 @@ fn inbox_export_roundtrip() {
 #[test]
 fn inbox_search_case_insensitive() {
     let dir = tempfile::tempdir().unwrap();
-    let path = dir.path().join("queue_db");
-    let q = MessageQueue::new(path.to_str().unwrap()).unwrap();
+    let q = MessageQueue::open_in_dir(dir.path()).unwrap();
 
     let msgs = [
         (uuid::Uuid::new_v4(), "Hello there".to_string()),
@@ -8,7 +8,7 @@ async fn round_trip_and_drain_on_reconnect() {
     let (pk_b, sk_b) = sodiumoxide::crypto::box_::gen_keypair();
 
     let dir_b = tempfile::tempdir().unwrap();
-    let queue_b = dir_b.path().to_str().unwrap();
+    let queue_b = dir_b.path();
 
     // A sends two messages to B (persisted in B's queue)
     let _ = send_now(queue_b, &sk_a, &pk_b, 100, b"hello")
@@ -21,7 +21,7 @@ async fn round_trip_and_drain_on_reconnect() {
     // Simulate reconnect by draining with successive opens to avoid sled lock contention
     loop {
         let empty = {
-            let qtmp = MessageQueue::new(queue_b).unwrap();
+            let qtmp = MessageQueue::open_in_dir(queue_b).unwrap();
             qtmp.is_empty()
         };
         if empty {
@@ -32,7 +32,7 @@ async fn round_trip_and_drain_on_reconnect() {
     }
 
     // Inbox should contain both plaintext messages
-    let q = MessageQueue::new(queue_b).unwrap();
+    let q = MessageQueue::open_in_dir(queue_b).unwrap();
     let inbox = q.list_inbox().unwrap();
     let texts: Vec<String> = inbox
         .into_iter()
@@ -0,0 +1,83 @@
+mod common;
+
+use common::{envelope, PEER_A as PEER};
+use secure_p2p_msg::api::Core;
+use secure_p2p_msg::config::UnknownPeerPolicy;
+use secure_p2p_msg::identity::Identity;
+use secure_p2p_msg::messaging::inbound::{handle_request, InboundOutcome};
+use sodiumoxide::crypto::box_;
+
+#[tokio::test]
+async fn changed_key_is_staged_and_blocks_sending_until_accepted() {
+    sodiumoxide::init().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let core = Core::with_data_dir(dir.path());
+    let old_key = hex::encode([7u8; 32]);
+    let new_key = hex::encode([8u8; 32]);
+    let c = core
+        .contacts_add("Erin", "/ip4/127.0.0.1/tcp/4001", &old_key)
+        .unwrap();
+
+    let staged = core
+        .contacts_update(c.id, "Erin", "/ip4/127.0.0.1/tcp/4001", &new_key)
+        .unwrap();
+    assert_eq!(hex::encode(&staged.public_key), old_key);
+    assert_eq!(
+        staged.pending_key.as_ref().unwrap().public_key,
+        vec![8u8; 32]
+    );
+    assert_eq!(core.contacts_key_changes().unwrap().len(), 1);
+    assert!(core
+        .send_encrypt_and_enqueue(&old_key, c.id, "hi", false)
+        .await
+        .is_err());
+
+    let accepted = core.contacts_accept_key_change(c.id).unwrap();
+    assert_eq!(hex::encode(&accepted.public_key), new_key);
+    assert_eq!(accepted.key_history.len(), 1);
+    assert_eq!(accepted.key_history[0].public_key, vec![7u8; 32]);
+    assert!(core.contacts_key_changes().unwrap().is_empty());
+    core.send_encrypt_and_enqueue(&new_key, c.id, "hi", false)
+        .await
+        .unwrap();
+
+    // Only one contact per pinned PeerId
+    core.contacts_set_peer_id(c.id, PEER).unwrap();
+    assert!(core
+        .contacts_add(
+            "Erin 2",
+            &format!("/ip4/127.0.0.1/tcp/4002/p2p/{PEER}"),
+            &old_key
+        )
+        .is_err());
+}
+
+#[test]
+fn message_under_new_key_waits_in_quarantine_for_acceptance() {
+    sodiumoxide::init().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let me = Identity::load_or_generate(dir.path()).unwrap();
+    let core = Core::with_data_dir(dir.path());
+    let (old_pk, _) = box_::gen_keypair();
+    let (new_pk, new_sk) = box_::gen_keypair();
+    let c = core
+        .contacts_add(
+            "Frank",
+            &format!("/ip4/127.0.0.1/tcp/4001/p2p/{PEER}"),
+            &hex::encode(old_pk.0),
+        )
+        .unwrap();
+
+    let req = envelope(&new_sk, &me.sodium_box_pk, b"new phone");
+    let out = handle_request(dir.path(), &me, PEER, &req, UnknownPeerPolicy::Refuse).unwrap();
+    let InboundOutcome::Quarantined(qid) = out else {
+        panic!("expected quarantine, got {out:?}");
+    };
+
+    core.contacts_update(c.id, "Frank", &c.addr, &hex::encode(new_pk.0))
+        .unwrap();
+    assert!(core.quarantine_release(qid).is_err());
+    core.contacts_accept_key_change(c.id).unwrap();
+    core.quarantine_release(qid).unwrap();
+    assert_eq!(core.inbox_list().unwrap()[0].1, b"new phone");
+}
@@ -8,12 +8,12 @@ async fn send_enqueues_ciphertext() {
     let (pk_b, _sk_b) = sodiumoxide::crypto::box_::gen_keypair();
 
     let dir = tempfile::tempdir().unwrap();
-    let queue_path = dir.path().to_str().unwrap();
+    let queue_path = dir.path();
 
     let id = send_now(queue_path, &sk_a, &pk_b, 42, b"hello")
         .await
         .unwrap();
-    let q = MessageQueue::new(queue_path).unwrap();
+    let q = MessageQueue::open_in_dir(queue_path).unwrap();
     assert_eq!(q.len(), 1);
     let item = q.dequeue().unwrap().unwrap();
     assert_eq!(item.id, id);
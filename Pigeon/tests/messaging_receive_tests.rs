@@ -8,7 +8,7 @@ async fn receive_decrypts_and_stores_inbox() {
     let (pk_b, sk_b) = sodiumoxide::crypto::box_::gen_keypair();
 
     let dir = tempfile::tempdir().unwrap();
-    let queue_path = dir.path().to_str().unwrap();
+    let queue_path = dir.path();
 
     let _id = send_now(queue_path, &sk_a, &pk_b, 42, b"secret")
         .await
@@ -16,7 +16,7 @@ async fn receive_decrypts_and_stores_inbox() {
     receive_and_ack(queue_path, &pk_a, &sk_b).await.unwrap();
 
     // Check inbox stored
-    let q = MessageQueue::new(queue_path).unwrap();
+    let q = MessageQueue::open_in_dir(queue_path).unwrap();
     // We don't know the UUID here easily; just ensure inbox has at least one entry by peeking known id requires API
     // So we re-enqueue and then fetch first inbox key; for simplicity we ensure there is at least some data by retrieving via iteration over tree
     // Queue should be empty after successful receive (drained)
@@ -1,4 +1,7 @@
-use secure_p2p_msg::messaging::message::EnvelopeV1;
+use secure_p2p_msg::identity::Identity;
+use secure_p2p_msg::messaging::inbound::verify_signature;
+use secure_p2p_msg::messaging::message::{key_id, EnvelopeV1};
+use secure_p2p_msg::messaging::send::seal_box_envelope;
 
 #[test]
 fn envelope_roundtrip_bincode() {
@@ -7,3 +10,20 @@ fn envelope_roundtrip_bincode() {
     let back: EnvelopeV1 = bincode::deserialize(&bytes).unwrap();
     assert_eq!(env, back);
 }
+
+#[test]
+fn box_envelopes_name_the_sender_and_recipient_keys() {
+    sodiumoxide::init().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let me = Identity::load_or_generate(dir.path()).unwrap();
+    let (them, _) = sodiumoxide::crypto::box_::gen_keypair();
+    let env = seal_box_envelope(1, &me, &them, b"hello");
+    assert_eq!(env.sender_id, key_id(&me.sodium_box_pk.0));
+    assert_eq!(env.recipient_id, key_id(&them.0));
+    assert_ne!(env.sender_id, 0);
+    // The ids are covered by the signature
+    assert!(verify_signature(&env, &me.sign_pk));
+    let mut altered = env.clone();
+    altered.sender_id ^= 1;
+    assert!(!verify_signature(&altered, &me.sign_pk));
+}
@@ -7,10 +7,7 @@ async fn inbox_watcher_emits_on_change() {
     let mut watcher = core.watch_inbox(50);
 
     // Initially empty; no immediate event guaranteed. Add one inbox item.
-    let q = secure_p2p_msg::storage::queue::MessageQueue::new(
-        dir.path().join("queue_db").to_str().unwrap(),
-    )
-    .unwrap();
+    let q = secure_p2p_msg::storage::queue::MessageQueue::open_in_dir(dir.path()).unwrap();
     let id = uuid::Uuid::new_v4();
     q.store_inbox(id, b"hello".to_vec()).unwrap();
     drop(q);
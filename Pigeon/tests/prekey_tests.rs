@@ -0,0 +1,147 @@
+mod common;
+
+use common::{befriend, deliver, seal, side, store_bundle, PEER_A, PEER_B};
+use secure_p2p_msg::api::Core;
+use secure_p2p_msg::config::UnknownPeerPolicy;
+use secure_p2p_msg::messaging::inbound::{screen_prekey_request, InboundOutcome, InboundPolicy};
+use secure_p2p_msg::session::{self, prekeys};
+use secure_p2p_msg::storage::contacts::BlockTarget;
+
+#[test]
+fn bundles_are_signed_and_hand_out_distinct_one_time_prekeys() {
+    sodiumoxide::init().unwrap();
+    let bob = side(PEER_B);
+    let first = bob.id.prekey_bundle(bob.dir.path()).unwrap();
+    let second = bob.id.prekey_bundle(bob.dir.path()).unwrap();
+    first.verify().unwrap();
+    assert_eq!(first.identity_key, bob.id.sodium_box_pk.0);
+    assert_eq!(first.signed_prekey_id, second.signed_prekey_id);
+    let (a, _) = first.one_time_prekey.unwrap();
+    let (b, _) = second.one_time_prekey.unwrap();
+    assert_ne!(a, b);
+
+    let mut forged = first.clone();
+    forged.signed_prekey[0] ^= 1;
+    assert!(forged.verify().is_err());
+
+    let old = first.signed_prekey_id;
+    let new = bob.id.rotate_signed_prekey(bob.dir.path()).unwrap();
+    assert_ne!(old, new);
+    let status = bob.core().prekeys_status().unwrap();
+    assert_eq!(status.signed_prekey_id, new);
+    assert_eq!(status.one_time_issued, 2);
+}
+
+#[test]
+fn session_from_fetched_bundle_uses_each_one_time_prekey_once() {
+    sodiumoxide::init().unwrap();
+    let alice = side(PEER_A);
+    let bob = side(PEER_B);
+    let bob_at_alice = befriend(&alice, &bob, "Bob");
+    befriend(&bob, &alice, "Alice");
+    let alice_core = alice.core();
+
+    let bundle = prekeys::serve_request(bob.dir.path(), &bob.id, prekeys::BUNDLE_REQUEST).unwrap();
+    alice_core
+        .contacts_store_prekey_bundle(bob_at_alice.id, &bundle)
+        .unwrap();
+    let env = session::seal_for_contact(
+        alice.dir.path(),
+        &alice.id,
+        &bob_at_alice,
+        b"while you were out",
+    )
+    .unwrap();
+    let hs = env.prekey.unwrap();
+    assert_ne!(hs.signed_prekey_id, prekeys::IDENTITY_PREKEY_ID);
+    assert!(hs.one_time_prekey_id.is_some());
+    assert!(matches!(
+        deliver(&bob, PEER_A, &bincode::serialize(&env).unwrap()),
+        InboundOutcome::Delivered { .. }
+    ));
+    let status = bob.core().prekeys_status().unwrap();
+    assert_eq!(status.one_time_consumed, 1);
+
+    // A second handshake against the same one-time prekey is refused
+    session::reset(alice.dir.path(), bob_at_alice.id).unwrap();
+    alice_core
+        .contacts_store_prekey_bundle(bob_at_alice.id, &bundle)
+        .unwrap();
+    let again = session::seal_for_contact(
+        alice.dir.path(),
+        &alice.id,
+        &bob_at_alice,
+        b"replayed prekey",
+    )
+    .unwrap();
+    assert_eq!(
+        again.prekey.unwrap().one_time_prekey_id,
+        hs.one_time_prekey_id
+    );
+    assert!(matches!(
+        deliver(&bob, PEER_A, &bincode::serialize(&again).unwrap()),
+        InboundOutcome::Quarantined(_)
+    ));
+}
+
+#[test]
+fn handshake_against_previous_signed_prekey_still_opens() {
+    sodiumoxide::init().unwrap();
+    let alice = side(PEER_A);
+    let bob = side(PEER_B);
+    let bob_at_alice = befriend(&alice, &bob, "Bob");
+    befriend(&bob, &alice, "Alice");
+
+    store_bundle(&alice, &bob_at_alice, &bob);
+    bob.id.rotate_signed_prekey(bob.dir.path()).unwrap();
+    let env = seal(&alice, &bob_at_alice, b"late");
+    assert!(matches!(
+        deliver(&bob, PEER_A, &env),
+        InboundOutcome::Delivered { .. }
+    ));
+}
+
+#[test]
+fn bundle_from_another_identity_is_rejected() {
+    sodiumoxide::init().unwrap();
+    let alice = side(PEER_A);
+    let bob = side(PEER_B);
+    let mallory = side(PEER_B);
+    let bob_at_alice = befriend(&alice, &bob, "Bob");
+
+    let forged =
+        prekeys::serve_request(mallory.dir.path(), &mallory.id, prekeys::BUNDLE_REQUEST).unwrap();
+    let err = alice
+        .core()
+        .contacts_store_prekey_bundle(bob_at_alice.id, &forged);
+    assert!(err.is_err());
+}
+
+#[test]
+fn bundles_are_only_served_to_peers_messages_would_be_taken_from() {
+    sodiumoxide::init().unwrap();
+    let alice = side(PEER_A);
+    let bob = side(PEER_B);
+    let dir = alice.dir.path();
+    let quarantine = InboundPolicy::from(UnknownPeerPolicy::Quarantine);
+    let accept = InboundPolicy::from(UnknownPeerPolicy::Accept);
+
+    // Strangers take one-time prekeys only where their messages would be accepted
+    let out = screen_prekey_request(dir, PEER_B, quarantine).unwrap();
+    assert_eq!(out, Some(InboundOutcome::Refused));
+    assert_eq!(screen_prekey_request(dir, PEER_B, accept).unwrap(), None);
+    let contacts_only = InboundPolicy {
+        contacts_only: true,
+        ..accept
+    };
+    let out = screen_prekey_request(dir, PEER_B, contacts_only).unwrap();
+    assert_eq!(out, Some(InboundOutcome::Refused));
+
+    befriend(&alice, &bob, "Bob");
+    assert_eq!(screen_prekey_request(dir, PEER_B, contacts_only).unwrap(), None);
+    Core::with_data_dir(dir)
+        .blocklist_add(BlockTarget::Peer(PEER_B.into()))
+        .unwrap();
+    let out = screen_prekey_request(dir, PEER_B, accept).unwrap();
+    assert_eq!(out, Some(InboundOutcome::Blocked));
+}
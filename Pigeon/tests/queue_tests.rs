@@ -9,7 +9,7 @@ fn temp_db() -> tempfile::TempDir {
 fn enqueue_dequeue_persists() {
     let dir = temp_db();
     {
-        let q = MessageQueue::new(dir.path().to_str().unwrap()).unwrap();
+        let q = MessageQueue::open_in_dir(dir.path()).unwrap();
         let msg = QueuedMessage {
             id: Uuid::new_v4(),
             contact_id: 1,
@@ -20,12 +20,13 @@ fn enqueue_dequeue_persists() {
             retry_count: 0,
             next_attempt_at: 0,
             max_retries: 3,
+            device: None,
         };
         q.enqueue(msg).unwrap();
         assert_eq!(q.len(), 1);
     }
     {
-        let q = MessageQueue::new(dir.path().to_str().unwrap()).unwrap();
+        let q = MessageQueue::open_in_dir(dir.path()).unwrap();
         let out = q.dequeue().unwrap();
         assert!(out.is_some());
         assert_eq!(q.len(), 0);
@@ -36,8 +37,7 @@ fn enqueue_dequeue_persists() {
 fn priority_lane_prefers_high() {
     sodiumoxide::init().unwrap();
     let dir = temp_db();
-    let path = dir.path().to_str().unwrap();
-    let q = MessageQueue::new(path).unwrap();
+    let q = MessageQueue::open_in_dir(dir.path()).unwrap();
 
     // Enqueue a normal message due now
     let normal = QueuedMessage {
@@ -50,6 +50,7 @@ fn priority_lane_prefers_high() {
         retry_count: 0,
         next_attempt_at: 0,
         max_retries: 3,
+        device: None,
     };
     q.enqueue(normal).unwrap();
 
@@ -64,6 +65,7 @@ fn priority_lane_prefers_high() {
         retry_count: 0,
         next_attempt_at: 0,
         max_retries: 3,
+        device: None,
     };
     q.enqueue(high).unwrap();
 
@@ -79,8 +81,7 @@ fn priority_lane_prefers_high() {
 fn weighted_fairness_pattern() {
     sodiumoxide::init().unwrap();
     let dir = temp_db();
-    let path = dir.path().to_str().unwrap();
-    let q = MessageQueue::new(path).unwrap();
+    let q = MessageQueue::open_in_dir(dir.path()).unwrap();
 
     // Enqueue 4 high (h1..h4) and 2 normal (n1..n2)
     let make = |b: &str, prio: u8| QueuedMessage {
@@ -93,6 +94,7 @@ fn weighted_fairness_pattern() {
         retry_count: 0,
         next_attempt_at: 0,
         max_retries: 3,
+        device: None,
     };
     for name in ["h1", "h2", "h3", "h4"] {
         q.enqueue(make(name, 0)).unwrap();
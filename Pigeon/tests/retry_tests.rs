@@ -4,7 +4,7 @@ use uuid::Uuid;
 #[test]
 fn exponential_backoff_and_dead_letter() {
     let dir = tempfile::tempdir().unwrap();
-    let q = MessageQueue::new(dir.path().to_str().unwrap()).unwrap();
+    let q = MessageQueue::open_in_dir(dir.path()).unwrap();
 
     let id = Uuid::new_v4();
     let msg = QueuedMessage {
@@ -17,6 +17,7 @@ fn exponential_backoff_and_dead_letter() {
         retry_count: 0,
         next_attempt_at: 0,
         max_retries: 2,
+        device: None,
     };
     q.enqueue(msg).unwrap();
 
@@ -39,6 +40,7 @@ fn exponential_backoff_and_dead_letter() {
         retry_count: 2,
         next_attempt_at: 0,
         max_retries: 2,
+        device: None,
     };
     q.requeue_or_dead_letter(m, 1, "fail").unwrap();
     // Since retry_count >= max_retries, it should be placed into DLQ
@@ -0,0 +1,161 @@
+mod common;
+
+use common::{
+    befriend, deliver, seal, side, store_bundle, Side, PEER_A, PEER_B, PEER_C as PEER_A2,
+};
+use secure_p2p_msg::identity::Identity;
+use secure_p2p_msg::messaging::inbound::InboundOutcome;
+use secure_p2p_msg::messaging::message::KeyTransition;
+use secure_p2p_msg::rotation;
+use secure_p2p_msg::session;
+use secure_p2p_msg::storage::contacts::{Contact, ContactStore};
+use sodiumoxide::crypto::sign;
+
+fn resign(t: &mut KeyTransition, old: &Identity, new: &Identity) {
+    let bytes = t.signed_bytes();
+    t.old_signature = sign::sign_detached(&bytes, &old.sign_sk).to_bytes().to_vec();
+    t.new_signature = sign::sign_detached(&bytes, &new.sign_sk).to_bytes().to_vec();
+}
+
+fn contact(on: &Side, id: u64) -> Contact {
+    ContactStore::open_in_dir(on.dir.path())
+        .unwrap()
+        .get(id)
+        .unwrap()
+        .unwrap()
+}
+
+#[test]
+fn rotation_moves_contact_to_new_keys_and_sessions_restart() {
+    sodiumoxide::init().unwrap();
+    let alice = side(PEER_A);
+    let bob = side(PEER_B);
+    let bob_at_alice = befriend(&alice, &bob, "Bob");
+    let alice_at_bob = befriend(&bob, &alice, "Alice");
+    store_bundle(&alice, &bob_at_alice, &bob);
+    ContactStore::open_in_dir(bob.dir.path())
+        .unwrap()
+        .set_verified(alice_at_bob.id, true)
+        .unwrap();
+
+    let out = deliver(&bob, PEER_A, &seal(&alice, &bob_at_alice, b"before"));
+    assert!(matches!(out, InboundOutcome::Delivered { .. }));
+
+    let (alice_new, mut transition) = rotation::rotate_identity(alice.dir.path()).unwrap();
+    assert_ne!(alice_new.sodium_box_pk, alice.id.sodium_box_pk);
+    assert_eq!(
+        Identity::load_or_generate(alice.dir.path()).unwrap().sign_pk,
+        alice_new.sign_pk
+    );
+    // The rotated node dials from its new transport key
+    transition.new_peer_id = Some(PEER_A2.to_string());
+    resign(&mut transition, &alice.id, &alice_new);
+    let req = bincode::serialize(&transition).unwrap();
+
+    let out = deliver(&bob, PEER_A2, &req);
+    assert_eq!(
+        out,
+        InboundOutcome::KeysRotated {
+            contact_id: alice_at_bob.id,
+            staged: false
+        }
+    );
+    let updated = contact(&bob, alice_at_bob.id);
+    assert_eq!(updated.public_key, alice_new.sodium_box_pk.0.to_vec());
+    assert_eq!(updated.sign_public_key, Some(alice_new.sign_pk.0.to_vec()));
+    assert_eq!(updated.peer_id.as_deref(), Some(PEER_A2));
+    assert!(updated.addr.ends_with(&format!("/p2p/{PEER_A2}")));
+    assert_eq!(updated.key_history.len(), 1);
+    assert!(!updated.verified);
+    assert!(updated.pending_key.is_none());
+
+    // A resent announcement changes nothing
+    let again = deliver(&bob, PEER_A2, &req);
+    assert!(matches!(again, InboundOutcome::KeysRotated { staged: false, .. }));
+    assert_eq!(contact(&bob, alice_at_bob.id).key_history.len(), 1);
+
+    // A fresh handshake under the new keys goes through
+    let env = session::seal_for_contact(alice.dir.path(), &alice_new, &bob_at_alice, b"after")
+        .unwrap();
+    assert!(env.prekey.is_some());
+    match deliver(&bob, PEER_A2, &bincode::serialize(&env).unwrap()) {
+        InboundOutcome::Delivered { plaintext, .. } => assert_eq!(plaintext, b"after"),
+        other => panic!("expected delivery, got {other:?}"),
+    }
+}
+
+#[test]
+fn forged_or_misrouted_transitions_are_rejected() {
+    sodiumoxide::init().unwrap();
+    let alice = side(PEER_A);
+    let bob = side(PEER_B);
+    let mallory = side(PEER_B);
+    let replacement = side(PEER_A2);
+    let alice_at_bob = befriend(&bob, &alice, "Alice");
+
+    // Claims Alice's old keys but is signed by Mallory
+    let mut forged = rotation::sign_transition(&mallory.id, &replacement.id, 1);
+    forged.old_box_key = alice.id.sodium_box_pk.0;
+    forged.old_sign_key = alice.id.sign_pk.0;
+    let bytes = forged.signed_bytes();
+    forged.new_signature = sign::sign_detached(&bytes, &replacement.id.sign_sk)
+        .to_bytes()
+        .to_vec();
+    let out = deliver(&bob, PEER_A2, &bincode::serialize(&forged).unwrap());
+    assert!(matches!(out, InboundOutcome::Rejected(_)), "{out:?}");
+
+    // Genuine, but delivered by a peer other than the announced one
+    let mut genuine = rotation::sign_transition(&alice.id, &replacement.id, 1);
+    genuine.new_peer_id = Some(PEER_A2.to_string());
+    resign(&mut genuine, &alice.id, &replacement.id);
+    let out = deliver(&bob, PEER_B, &bincode::serialize(&genuine).unwrap());
+    assert!(matches!(out, InboundOutcome::Rejected(_)), "{out:?}");
+
+    // Tampering after signing breaks both signatures
+    genuine.new_box_key = mallory.id.sodium_box_pk.0;
+    let out = deliver(&bob, PEER_A2, &bincode::serialize(&genuine).unwrap());
+    assert!(matches!(out, InboundOutcome::Rejected(_)), "{out:?}");
+
+    let unchanged = contact(&bob, alice_at_bob.id);
+    assert_eq!(unchanged.public_key, alice.id.sodium_box_pk.0.to_vec());
+    assert_eq!(unchanged.peer_id.as_deref(), Some(PEER_A));
+    assert!(unchanged.key_history.is_empty());
+}
+
+#[test]
+fn unpinned_transitions_are_only_staged_from_the_contacts_peer() {
+    sodiumoxide::init().unwrap();
+    let alice = side(PEER_A);
+    let bob = side(PEER_B);
+    let replacement = side(PEER_A2);
+    // Added by hand: the box key and PeerId are pinned, the signing key is not
+    let alice_at_bob = bob
+        .core()
+        .contacts_add(
+            "Alice",
+            &format!("/ip4/10.0.0.5/tcp/4001/p2p/{PEER_A}"),
+            &hex::encode(alice.id.sodium_box_pk.0),
+        )
+        .unwrap();
+    assert!(alice_at_bob.sign_public_key.is_none());
+    let mut t = rotation::sign_transition(&alice.id, &replacement.id, 1);
+    t.new_peer_id = None;
+    resign(&mut t, &alice.id, &replacement.id);
+    let req = bincode::serialize(&t).unwrap();
+
+    let out = deliver(&bob, PEER_B, &req);
+    assert!(matches!(out, InboundOutcome::Rejected(_)), "{out:?}");
+    assert!(contact(&bob, alice_at_bob.id).pending_key.is_none());
+
+    let out = deliver(&bob, PEER_A, &req);
+    assert_eq!(
+        out,
+        InboundOutcome::KeysRotated {
+            contact_id: alice_at_bob.id,
+            staged: true
+        }
+    );
+    let staged = contact(&bob, alice_at_bob.id);
+    assert_eq!(staged.public_key, alice.id.sodium_box_pk.0.to_vec());
+    assert!(staged.pending_key.is_some());
+}
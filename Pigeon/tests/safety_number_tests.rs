@@ -0,0 +1,52 @@
+use secure_p2p_msg::api::Core;
+use secure_p2p_msg::crypto::safety_number;
+
+#[test]
+fn safety_number_is_symmetric_and_key_dependent() {
+    let a = [1u8; 32];
+    let b = [2u8; 32];
+    let n = safety_number(&a, &b);
+    assert_eq!(n, safety_number(&b, &a));
+    let groups: Vec<&str> = n.split(' ').collect();
+    assert_eq!(groups.len(), 12);
+    assert!(groups
+        .iter()
+        .all(|g| g.len() == 5 && g.chars().all(|c| c.is_ascii_digit())));
+    assert_ne!(n, safety_number(&a, &[3u8; 32]));
+}
+
+#[test]
+fn verified_flag_persists_until_key_changes() {
+    sodiumoxide::init().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let core = Core::with_data_dir(dir.path());
+    let c = core
+        .contacts_add("Dave", "/ip4/127.0.0.1/tcp/4001", &hex::encode([5u8; 32]))
+        .unwrap();
+    assert!(!c.verified);
+    let me = core.ensure_identity_and_preview().unwrap();
+    let expected = safety_number(&hex::decode(me.sodium_box_pk_hex).unwrap(), &[5u8; 32]);
+    assert_eq!(core.contacts_safety_number(c.id).unwrap(), expected);
+
+    core.contacts_set_verified(c.id, true).unwrap();
+    let same_key = core
+        .contacts_update(
+            c.id,
+            "David",
+            "/ip4/127.0.0.1/tcp/4002",
+            &hex::encode([5u8; 32]),
+        )
+        .unwrap();
+    assert!(same_key.verified);
+
+    let new_key = core
+        .contacts_update(
+            c.id,
+            "David",
+            "/ip4/127.0.0.1/tcp/4002",
+            &hex::encode([6u8; 32]),
+        )
+        .unwrap();
+    assert!(!new_key.verified);
+    assert!(!core.contacts_get(c.id).unwrap().unwrap().verified);
+}
@@ -0,0 +1,219 @@
+use secure_p2p_msg::identity::{self, Identity};
+use secure_p2p_msg::storage::at_rest::{self, AtRestKey};
+use secure_p2p_msg::storage::backend::{self, Backend, SledBackend, DEFAULT_TREE};
+use secure_p2p_msg::storage::queue::MessageStatus;
+use secure_p2p_msg::storage::schema::{self, Migration, Schema};
+use secure_p2p_msg::storage::{ContactStore, Error, MessageQueue};
+use serde::Serialize;
+
+fn add_suffix(plain: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut out = plain.to_vec();
+    out.extend_from_slice(b"+v2");
+    Ok(out)
+}
+
+const NOTES_V1: Schema = Schema {
+    database: "notes_db",
+    version: 1,
+    migrations: &[],
+};
+
+const NOTES_V2: Schema = Schema {
+    database: "notes_db",
+    version: 2,
+    migrations: &[Migration {
+        to: 2,
+        tree: "notes",
+        description: "append a marker",
+        upgrade: add_suffix,
+    }],
+};
+
+#[test]
+fn older_records_are_upgraded_after_a_backup() {
+    sodiumoxide::init().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let backups = dir.path().join(schema::BACKUP_DIR);
+    let key = AtRestKey::ephemeral();
+    let db = SledBackend::open(dir.path().join("notes_db")).unwrap();
+
+    // Records written before the database had a version count as version 1
+    let notes = db.open_tree("notes").unwrap();
+    notes.insert(b"a", &at_rest::encrypt(&key, b"one").unwrap()).unwrap();
+    notes.insert(b"b", &at_rest::encrypt(&key, b"two").unwrap()).unwrap();
+    assert_eq!(schema::stored_version(&db).unwrap(), None);
+    assert_eq!(schema::effective_version(&db, &NOTES_V2).unwrap(), 1);
+
+    assert_eq!(schema::upgrade(&db, &key, &NOTES_V2, Some(&backups)).unwrap(), 1);
+    assert_eq!(schema::stored_version(&db).unwrap(), Some(2));
+    let read = |k: &[u8]| at_rest::decrypt(&key, &notes.get(k).unwrap().unwrap()).unwrap();
+    assert_eq!(read(b"a"), b"one+v2");
+    assert_eq!(read(b"b"), b"two+v2");
+    let saved: Vec<_> = std::fs::read_dir(&backups).unwrap().collect();
+    assert_eq!(saved.len(), 1);
+
+    // Already current: nothing is rewritten again
+    assert_eq!(schema::upgrade(&db, &key, &NOTES_V2, Some(&backups)).unwrap(), 2);
+    assert_eq!(read(b"a"), b"one+v2");
+
+    // An older build refuses the newer records instead of misreading them
+    assert!(matches!(
+        schema::upgrade(&db, &key, &NOTES_V1, None),
+        Err(Error::Validation(_))
+    ));
+}
+
+/// `Contact` as the first release stored it.
+#[derive(Serialize)]
+struct BaselineContact {
+    id: u64,
+    name: String,
+    addr: String,
+    public_key: Vec<u8>,
+    ping_interval: u64,
+}
+
+/// `QueuedMessage` as the first release stored it.
+#[derive(Serialize)]
+struct BaselineQueuedMessage {
+    id: uuid::Uuid,
+    contact_id: u64,
+    payload: Vec<u8>,
+    created: u64,
+    priority: u8,
+    status: MessageStatus,
+    retry_count: u32,
+    next_attempt_at: u64,
+    max_retries: u32,
+}
+
+fn seal<T: Serialize>(key: &AtRestKey, value: &T) -> Vec<u8> {
+    at_rest::encrypt(key, &bincode::serialize(value).unwrap()).unwrap()
+}
+
+#[test]
+fn baseline_contacts_and_queue_are_upgraded() {
+    sodiumoxide::init().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let key = AtRestKey::load_or_create(dir.path()).unwrap();
+    let queued = uuid::Uuid::new_v4();
+    {
+        let contacts = SledBackend::open(dir.path().join("contacts_db")).unwrap();
+        let erin = BaselineContact {
+            id: 7,
+            name: "Erin".into(),
+            addr: "/ip4/10.0.0.5/tcp/4001".into(),
+            public_key: vec![0x22; 32],
+            ping_interval: 30,
+        };
+        contacts
+            .open_tree(DEFAULT_TREE)
+            .unwrap()
+            .insert(&7u64.to_be_bytes(), &seal(&key, &erin))
+            .unwrap();
+        contacts.flush().unwrap();
+
+        let queue = SledBackend::open(dir.path().join("queue_db")).unwrap();
+        let message = BaselineQueuedMessage {
+            id: queued,
+            contact_id: 7,
+            payload: b"hi".to_vec(),
+            created: 100,
+            priority: 1,
+            status: MessageStatus::Pending,
+            retry_count: 0,
+            next_attempt_at: 100,
+            max_retries: 5,
+        };
+        let mut due = 100u64.to_be_bytes().to_vec();
+        due.extend_from_slice(queued.as_bytes());
+        queue
+            .open_tree("messages")
+            .unwrap()
+            .insert(queued.as_bytes(), &seal(&key, &message))
+            .unwrap();
+        queue
+            .open_tree("index_by_due_p1")
+            .unwrap()
+            .insert(&due, queued.as_bytes())
+            .unwrap();
+        queue.flush().unwrap();
+    }
+
+    let contacts = ContactStore::open_in_dir(dir.path()).unwrap();
+    let erin = contacts.get(7).unwrap().unwrap();
+    assert_eq!((erin.name.as_str(), erin.ping_interval), ("Erin", 30));
+    assert!(erin.peer_id.is_none() && erin.sign_public_key.is_none() && !erin.verified);
+    assert!(erin.key_history.is_empty() && erin.devices.is_empty());
+    drop(contacts);
+
+    let q = MessageQueue::open_in_dir(dir.path()).unwrap();
+    let message = q.dequeue().unwrap().unwrap();
+    assert_eq!((message.id, message.payload.as_slice()), (queued, b"hi".as_slice()));
+    assert_eq!(message.device, None);
+    drop(q);
+
+    let status = schema::status(dir.path()).unwrap();
+    for db in ["contacts_db", "queue_db"] {
+        let db = status.iter().find(|s| s.database == db).unwrap();
+        assert_eq!(db.version, Some(2));
+    }
+    // One snapshot per upgraded database
+    assert_eq!(
+        std::fs::read_dir(dir.path().join(schema::BACKUP_DIR))
+            .unwrap()
+            .count(),
+        2
+    );
+}
+
+#[test]
+fn new_and_unversioned_stores_are_stamped_with_the_current_version() {
+    sodiumoxide::init().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let status = schema::status(dir.path()).unwrap();
+    assert!(status.iter().all(|s| s.backend.is_none() && s.version.is_none()));
+
+    let contacts = ContactStore::open_in_dir(dir.path()).unwrap();
+    contacts
+        .add("Erin", "/ip4/10.0.0.5/tcp/4001", &"22".repeat(32))
+        .unwrap();
+    drop(contacts);
+
+    let status = schema::status(dir.path()).unwrap();
+    let db = status.iter().find(|s| s.database == "contacts_db").unwrap();
+    assert_eq!(db.version, Some(schema::CONTACTS.version));
+    assert_eq!(db.records, 1);
+
+    // Drop the version, as in a database from before versioning; it opens as version 1
+    {
+        let db = backend::open_path(&dir.path().join("contacts_db")).unwrap();
+        db.open_tree(schema::SCHEMA_TREE).unwrap().clear().unwrap();
+        db.flush().unwrap();
+    }
+    let contacts = ContactStore::open_in_dir(dir.path()).unwrap();
+    assert_eq!(contacts.list().unwrap().len(), 1);
+}
+
+#[test]
+fn unversioned_identity_file_is_rewritten_with_a_header() {
+    sodiumoxide::init().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let id = Identity::load_or_generate(dir.path()).unwrap();
+    let path = dir.path().join("identity.bin");
+    let current = std::fs::read(&path).unwrap();
+    assert_eq!(identity::format_version(&current), identity::IDENTITY_FORMAT);
+
+    // Format 1 is the same record without the header
+    std::fs::write(&path, &current[4..]).unwrap();
+    assert_eq!(Identity::stored_format(dir.path()).unwrap(), Some(1));
+    let again = Identity::load_or_generate(dir.path()).unwrap();
+    assert_eq!(again.sign_pk, id.sign_pk);
+    assert_eq!(std::fs::read(&path).unwrap(), current);
+    assert_eq!(
+        std::fs::read_dir(dir.path().join(schema::BACKUP_DIR))
+            .unwrap()
+            .count(),
+        1
+    );
+}
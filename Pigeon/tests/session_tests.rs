@@ -0,0 +1,153 @@
+mod common;
+
+use common::{befriend, befriend_with_bundle, deliver, delivered_text, seal, side, PEER_A, PEER_B};
+use secure_p2p_msg::messaging::inbound::InboundOutcome;
+use secure_p2p_msg::messaging::message::Envelope;
+use secure_p2p_msg::messaging::send::{self, encode_for_contact};
+use secure_p2p_msg::session::{self, prekeys};
+use std::path::Path;
+
+fn session_count(dir: &Path, contact_id: u64) -> usize {
+    secure_p2p_msg::storage::sessions::SessionStore::open_in_dir(dir)
+        .unwrap()
+        .load(contact_id)
+        .unwrap()
+        .map(|r| r.sessions.len())
+        .unwrap_or(0)
+}
+
+#[test]
+fn handshake_then_replies_ratchet_in_both_directions() {
+    sodiumoxide::init().unwrap();
+    let alice = side(PEER_A);
+    let bob = side(PEER_B);
+    let bob_at_alice = befriend_with_bundle(&alice, &bob, "Bob");
+    let alice_at_bob = befriend_with_bundle(&bob, &alice, "Alice");
+
+    let first = seal(&alice, &bob_at_alice, b"hi bob");
+    assert_eq!(delivered_text(deliver(&bob, PEER_A, &first)), b"hi bob");
+    assert_eq!(session_count(bob.dir.path(), alice_at_bob.id), 1);
+
+    let reply = seal(&bob, &alice_at_bob, b"hi alice");
+    assert_eq!(delivered_text(deliver(&alice, PEER_B, &reply)), b"hi alice");
+
+    // After the reply Alice stops attaching her handshake
+    let env = session::seal_for_contact(alice.dir.path(), &alice.id, &bob_at_alice, b"again")
+        .unwrap();
+    assert!(env.prekey.is_none());
+    let req = bincode::serialize(&env).unwrap();
+    assert_eq!(delivered_text(deliver(&bob, PEER_A, &req)), b"again");
+    assert_eq!(session_count(alice.dir.path(), bob_at_alice.id), 1);
+}
+
+#[test]
+fn out_of_order_messages_open_and_duplicates_are_replays() {
+    sodiumoxide::init().unwrap();
+    let alice = side(PEER_A);
+    let bob = side(PEER_B);
+    let bob_at_alice = befriend_with_bundle(&alice, &bob, "Bob");
+    befriend_with_bundle(&bob, &alice, "Alice");
+
+    let m0 = seal(&alice, &bob_at_alice, b"zero");
+    let m1 = seal(&alice, &bob_at_alice, b"one");
+    let m2 = seal(&alice, &bob_at_alice, b"two");
+
+    assert_eq!(delivered_text(deliver(&bob, PEER_A, &m2)), b"two");
+    assert_eq!(delivered_text(deliver(&bob, PEER_A, &m0)), b"zero");
+    assert_eq!(delivered_text(deliver(&bob, PEER_A, &m1)), b"one");
+
+    // Message keys are single use
+    assert!(matches!(deliver(&bob, PEER_A, &m1), InboundOutcome::Replay));
+    assert!(matches!(deliver(&bob, PEER_A, &m2), InboundOutcome::Replay));
+}
+
+#[test]
+fn stale_session_messages_are_rejected_before_they_open() {
+    sodiumoxide::init().unwrap();
+    let alice = side(PEER_A);
+    let bob = side(PEER_B);
+    let bob_at_alice = befriend_with_bundle(&alice, &bob, "Bob");
+    befriend_with_bundle(&bob, &alice, "Alice");
+
+    let mut env =
+        session::seal_for_contact(alice.dir.path(), &alice.id, &bob_at_alice, b"late").unwrap();
+    env.sent_at -= 2 * 86_400;
+    // The send time is signed
+    let forged = bincode::serialize(&env).unwrap();
+    assert!(matches!(
+        deliver(&bob, PEER_A, &forged),
+        InboundOutcome::Rejected(_)
+    ));
+    let sig = sodiumoxide::crypto::sign::sign_detached(&env.signed_bytes(), &alice.id.sign_sk);
+    env.signature = sig.to_bytes().to_vec();
+    let stale = bincode::serialize(&env).unwrap();
+    assert!(matches!(
+        deliver(&bob, PEER_A, &stale),
+        InboundOutcome::Rejected(_)
+    ));
+
+    // Bob's side was left alone, so the next message still starts the session
+    let next = seal(&alice, &bob_at_alice, b"on time");
+    assert_eq!(delivered_text(deliver(&bob, PEER_A, &next)), b"on time");
+}
+
+#[test]
+fn handshake_from_unpinned_identity_is_not_delivered() {
+    sodiumoxide::init().unwrap();
+    let alice = side(PEER_A);
+    let bob = side(PEER_B);
+    let mallory = side(PEER_A);
+    befriend_with_bundle(&bob, &alice, "Alice");
+    let bob_at_mallory = befriend_with_bundle(&mallory, &bob, "Bob");
+
+    // Mallory claims Alice's PeerId but handshakes with her own identity key
+    let req = seal(&mallory, &bob_at_mallory, b"trust me");
+    let out = deliver(&bob, PEER_A, &req);
+    assert!(
+        matches!(
+            out,
+            InboundOutcome::Rejected(_) | InboundOutcome::Quarantined(_)
+        ),
+        "{out:?}"
+    );
+}
+
+#[test]
+fn without_a_bundle_messages_wait_for_one() {
+    sodiumoxide::init().unwrap();
+    let alice = side(PEER_A);
+    let bob = side(PEER_B);
+    let bob_at_alice = befriend(&alice, &bob, "Bob");
+    befriend(&bob, &alice, "Alice");
+
+    let err = session::seal_for_contact(alice.dir.path(), &alice.id, &bob_at_alice, b"hi");
+    assert!(matches!(
+        err,
+        Err(secure_p2p_msg::error::Error::Session(session::Error::NoBundle))
+    ));
+    // Held back unsealed rather than downgraded to the static keys
+    let pending =
+        encode_for_contact(alice.dir.path(), &alice.id, &bob_at_alice, b"hi bob").unwrap();
+    assert!(send::is_pending(&pending));
+    assert!(Envelope::decode(&pending).is_none());
+    assert!(
+        send::seal_pending(alice.dir.path(), &alice.id, &bob_at_alice, &pending)
+            .unwrap()
+            .is_none()
+    );
+
+    // Only the static_key_fallback opt-in sends it as a box envelope
+    let req = send::seal_static(&alice.id, &bob_at_alice, &pending).unwrap();
+    assert!(matches!(Envelope::decode(&req), Some(Envelope::V1(_))));
+    assert_eq!(delivered_text(deliver(&bob, PEER_A, &req)), b"hi bob");
+    assert_eq!(session_count(alice.dir.path(), bob_at_alice.id), 0);
+
+    // Once a bundle is fetched, the held message goes out in a session
+    let bundle = prekeys::serve_request(bob.dir.path(), &bob.id, prekeys::BUNDLE_REQUEST).unwrap();
+    prekeys::accept_bundle(alice.dir.path(), &bob_at_alice, &bundle).unwrap();
+    let req = send::seal_pending(alice.dir.path(), &alice.id, &bob_at_alice, &pending)
+        .unwrap()
+        .unwrap();
+    assert!(matches!(Envelope::decode(&req), Some(Envelope::V2(_))));
+    assert_eq!(delivered_text(deliver(&bob, PEER_A, &req)), b"hi bob");
+}
@@ -0,0 +1,271 @@
+use std::ops::Bound;
+use std::sync::Arc;
+
+use secure_p2p_msg::storage::at_rest::AtRestKey;
+use secure_p2p_msg::storage::backend::{Backend, MemoryBackend, SledBackend};
+use secure_p2p_msg::storage::queue::{MessageQueue, MessageStatus, QueuedMessage};
+use secure_p2p_msg::storage::{ContactStore, Error};
+
+/// What every backend has to do the same way.
+fn exercise(backend: &dyn Backend) {
+    let tree = backend.open_tree("t").unwrap();
+    assert!(tree.is_empty());
+    for k in [b"b2".as_slice(), b"a1", b"b1", b"c1"] {
+        tree.insert(k, k).unwrap();
+    }
+    assert_eq!(tree.insert(b"a1", b"x").unwrap().as_deref(), Some(b"a1".as_slice()));
+    assert_eq!(tree.get(b"a1").unwrap().as_deref(), Some(b"x".as_slice()));
+    assert_eq!(tree.len(), 4);
+
+    let keys = |it: secure_p2p_msg::storage::backend::Entries| -> Vec<Vec<u8>> {
+        it.map(|e| e.unwrap().0).collect()
+    };
+    assert_eq!(keys(tree.iter()), [b"a1", b"b1", b"b2", b"c1"]);
+    assert_eq!(keys(tree.scan_prefix(b"b")), [b"b1", b"b2"]);
+    assert_eq!(
+        keys(tree.range(Bound::Excluded(b"a1"), Bound::Included(b"b2"))),
+        [b"b1", b"b2"]
+    );
+    assert!(keys(tree.range(Bound::Included(b"c"), Bound::Excluded(b"a"))).is_empty());
+    assert_eq!(tree.first().unwrap().unwrap().0, b"a1");
+    assert_eq!(tree.remove(b"c1").unwrap().as_deref(), Some(b"c1".as_slice()));
+    assert!(!tree.contains_key(b"c1").unwrap());
+
+    // A transaction sees its own writes and lands across trees at once
+    let other = backend.open_tree("u").unwrap();
+    backend
+        .transaction(&["t", "u"], &mut |tx| {
+            let v = tx.remove(0, b"b1")?.expect("present");
+            tx.insert(1, b"moved", &v)?;
+            assert_eq!(tx.get(1, b"moved")?.as_deref(), Some(b"b1".as_slice()));
+            Ok(())
+        })
+        .unwrap();
+    assert!(tree.get(b"b1").unwrap().is_none());
+    assert_eq!(other.get(b"moved").unwrap().as_deref(), Some(b"b1".as_slice()));
+
+    // An error leaves nothing behind
+    let err = backend.transaction(&["t", "u"], &mut |tx| {
+        tx.insert(0, b"half", b"done")?;
+        tx.remove(1, b"moved")?;
+        Err(Error::Validation("stop".into()))
+    });
+    assert!(matches!(err, Err(Error::Validation(_))));
+    assert!(tree.get(b"half").unwrap().is_none());
+    assert!(other.get(b"moved").unwrap().is_some());
+
+    let a = backend.generate_id().unwrap();
+    assert_ne!(a, backend.generate_id().unwrap());
+}
+
+#[test]
+fn sled_and_memory_backends_behave_alike() {
+    let dir = tempfile::tempdir().unwrap();
+    exercise(&SledBackend::open(dir.path().join("db")).unwrap());
+    exercise(&MemoryBackend::new());
+}
+
+#[test]
+fn stores_run_on_the_memory_backend() {
+    sodiumoxide::init().unwrap();
+    let backend = Arc::new(MemoryBackend::new());
+
+    let contacts = ContactStore::with_backend(backend.clone(), AtRestKey::ephemeral()).unwrap();
+    let c = contacts
+        .add("Erin", "/ip4/10.0.0.5/tcp/4001", &"22".repeat(32))
+        .unwrap();
+    assert_eq!(contacts.get(c.id).unwrap().unwrap().name, "Erin");
+
+    let q = MessageQueue::with_backend(backend, AtRestKey::ephemeral()).unwrap();
+    let id = uuid::Uuid::new_v4();
+    q.enqueue(QueuedMessage {
+        id,
+        contact_id: c.id,
+        payload: b"hi".to_vec(),
+        created: 0,
+        priority: 0,
+        status: MessageStatus::Pending,
+        retry_count: 0,
+        next_attempt_at: 0,
+        max_retries: 5,
+        device: None,
+    })
+    .unwrap();
+    assert_eq!(q.len(), 1);
+    assert_eq!(q.dequeue().unwrap().unwrap().id, id);
+    assert!(q.dequeue().unwrap().is_none());
+
+    let nonces = q.nonce_store().unwrap();
+    assert!(nonces.insert_if_fresh(b"alice", b"n1", 100).unwrap());
+    assert!(!nonces.insert_if_fresh(b"alice", b"n1", 100).unwrap());
+    assert!(nonces.insert_if_fresh(b"bob", b"n1", 100).unwrap());
+    assert!(nonces.insert_if_fresh(b"bob", b"n2", 200).unwrap());
+    assert_eq!(nonces.prune(150).unwrap(), 2);
+    assert_eq!(nonces.count().unwrap(), 1);
+    assert!(nonces.insert_if_fresh(b"alice", b"n1", 100).unwrap());
+}
+
+#[test]
+fn contacts_are_found_by_peer_id_through_the_index() {
+    use secure_p2p_msg::storage::backend;
+    use secure_p2p_msg::storage::contacts::DeviceKey;
+    const BOB: &str = "12D3KooWQYhTNQdmr3ArTeUHRYzFg94BKyTkoWBDWez9kSCVe2Xo";
+    const BOB_NEW: &str = "12D3KooWRBhwfeP2Y4TCx1SM6s9rUoHhR5STiGwxBhgFRcw3UERE";
+    const CAROL: &str = "12D3KooWJWoaqZhDaoEFshF7Rh1bpY9ohihFhzcW6d69Lr2NASuq";
+    const CAROL_LAPTOP: &str = "12D3KooWHk1r3yQt8WcdoDBJZwDkbHG5cqC8XvdzK7h7LxjmyTLE";
+    sodiumoxide::init().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let contacts = ContactStore::open_in_dir(dir.path()).unwrap();
+    let bob = contacts
+        .add(
+            "Bob",
+            &format!("/ip4/10.0.0.5/tcp/4001/p2p/{BOB}"),
+            &"22".repeat(32),
+        )
+        .unwrap();
+    let carol = contacts
+        .add("Carol", "/ip4/10.0.0.6/tcp/4001", &"33".repeat(32))
+        .unwrap();
+    contacts.set_peer_id(carol.id, CAROL).unwrap();
+    let laptop = DeviceKey {
+        name: "laptop".into(),
+        public_key: vec![0x44; 32],
+        sign_public_key: vec![0x55; 32],
+        peer_id: Some(CAROL_LAPTOP.into()),
+        addr: None,
+    };
+    contacts.set_devices(carol.id, vec![laptop]).unwrap();
+    let found = |peer| contacts.find_by_peer_id(peer).unwrap().map(|c| c.id);
+    assert_eq!(found(BOB), Some(bob.id));
+    assert_eq!(found(CAROL), Some(carol.id));
+    assert_eq!(found(CAROL_LAPTOP), None);
+    let (owner, device) = contacts.find_by_device_peer(CAROL_LAPTOP).unwrap().unwrap();
+    assert_eq!((owner.id, device.name.as_str()), (carol.id, "laptop"));
+
+    // The index follows the PeerId to the new key, and forgets removed contacts
+    contacts
+        .apply_key_transition(bob.id, &[0x66; 32], &[0x77; 32], Some(BOB_NEW))
+        .unwrap();
+    assert_eq!(found(BOB), None);
+    assert_eq!(found(BOB_NEW), Some(bob.id));
+    assert!(contacts.remove(carol.id).unwrap());
+    assert_eq!(found(CAROL), None);
+    assert!(contacts
+        .find_by_device_peer(CAROL_LAPTOP)
+        .unwrap()
+        .is_none());
+    drop(contacts);
+
+    // A store written before the index gets one when it opens
+    {
+        let db = backend::open_path(&dir.path().join("contacts_db")).unwrap();
+        let peers = db.open_tree("peers").unwrap();
+        assert!(!peers.is_empty());
+        peers.clear().unwrap();
+        db.flush().unwrap();
+    }
+    let contacts = ContactStore::open_in_dir(dir.path()).unwrap();
+    let found = contacts.find_by_peer_id(BOB_NEW).unwrap().unwrap();
+    assert_eq!(found.id, bob.id);
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn sqlite_backend_behaves_like_the_others() {
+    use secure_p2p_msg::storage::backend::SqliteBackend;
+    let dir = tempfile::tempdir().unwrap();
+    exercise(&SqliteBackend::open(dir.path().join("db.sqlite")).unwrap());
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn sqlite_inserts_from_two_connections_see_each_others_writes() {
+    use secure_p2p_msg::storage::backend::SqliteBackend;
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.sqlite");
+    let writers: Vec<_> = (0..2u8)
+        .map(|n| {
+            let backend = SqliteBackend::open(&path).unwrap();
+            std::thread::spawn(move || {
+                let tree = backend.open_tree("t").unwrap();
+                (0..50)
+                    .filter(|_| tree.insert(b"k", &[n]).unwrap().is_none())
+                    .count()
+            })
+        })
+        .collect();
+    // Only the very first insert, on either connection, finds the key missing
+    let fresh: usize = writers.into_iter().map(|w| w.join().unwrap()).sum();
+    assert_eq!(fresh, 1);
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn migrate_moves_stores_from_sled_to_sqlite() {
+    use secure_p2p_msg::storage::backend::{detect, BackendKind};
+    use secure_p2p_msg::storage::migrate;
+    use secure_p2p_msg::storage::prekeys::PreKeyStore;
+
+    sodiumoxide::init().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let (erin, msg) = {
+        let contacts = ContactStore::open_in_dir(dir.path()).unwrap();
+        let erin = contacts
+            .add("Erin", "/ip4/10.0.0.5/tcp/4001", &"22".repeat(32))
+            .unwrap();
+        let q = MessageQueue::open_in_dir(dir.path()).unwrap();
+        let msg = uuid::Uuid::new_v4();
+        q.store_inbox(msg, b"hello".to_vec()).unwrap();
+        PreKeyStore::open_in_dir(dir.path())
+            .unwrap()
+            .mark_consumed(7, 100)
+            .unwrap();
+        (erin, msg)
+    };
+
+    let mut seen = Vec::new();
+    let copied = migrate::migrate(dir.path(), BackendKind::Sled, BackendKind::Sqlite, &mut |p| {
+        seen.push(p.database)
+    })
+    .unwrap();
+    assert!(copied >= 2);
+    assert!(seen.contains(&"contacts_db") && seen.contains(&"queue_db"));
+    assert!(seen.contains(&"groups_db"));
+    let contacts_db = dir.path().join("contacts_db");
+    assert_eq!(detect(&contacts_db), Some(BackendKind::Sqlite));
+    assert!(dir.path().join("contacts_db.sled-old").exists());
+
+    // The stores pick up the SQLite files, records still sealed under the same key
+    let contacts = ContactStore::open_in_dir(dir.path()).unwrap();
+    assert_eq!(contacts.get(erin.id).unwrap().unwrap().name, "Erin");
+    let next = contacts
+        .add("Finn", "/ip4/10.0.0.6/tcp/4001", &"33".repeat(32))
+        .unwrap();
+    assert_ne!(next.id, erin.id);
+    let q = MessageQueue::open_in_dir(dir.path()).unwrap();
+    assert_eq!(q.get_inbox(msg).unwrap().as_deref(), Some(b"hello".as_slice()));
+    assert_eq!(detect(&dir.path().join("prekeys_db")), Some(BackendKind::Sqlite));
+    assert!(PreKeyStore::open_in_dir(dir.path()).unwrap().is_consumed(7).unwrap());
+
+    drop((contacts, q));
+
+    // And back again
+    migrate::migrate(dir.path(), BackendKind::Sqlite, BackendKind::Sled, &mut |_| {}).unwrap();
+    assert_eq!(detect(&contacts_db), Some(BackendKind::Sled));
+    let contacts = ContactStore::open_in_dir(dir.path()).unwrap();
+    assert_eq!(contacts.list().unwrap().len(), 2);
+}
+
+#[test]
+fn migrate_refuses_a_backend_the_stores_do_not_use() {
+    use secure_p2p_msg::storage::backend::BackendKind;
+    use secure_p2p_msg::storage::migrate;
+
+    sodiumoxide::init().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    ContactStore::open_in_dir(dir.path()).unwrap();
+    let err = migrate::migrate(dir.path(), BackendKind::Sqlite, BackendKind::Sled, &mut |_| {});
+    assert!(matches!(err, Err(Error::Validation(_))));
+    assert!(migrate::migrate(dir.path(), BackendKind::Sled, BackendKind::Sled, &mut |_| {}).is_err());
+    assert!(dir.path().join("contacts_db").exists());
+}